@@ -0,0 +1,25 @@
+use sea_orm::entity::prelude::*;
+
+use super::decision_rule::DecisionRule;
+
+/// A sender domain, recipient or client host name accepted without
+/// greylisting, outside the configured `allow_sender_domains`,
+/// `allow_recipients` and `trusted_suffixes`. With the rule `AllowedSender`,
+/// `address` is a domain; with `AllowedRecipient` it's an address, a local
+/// part and `@` for any domain, or a domain; with `TrustedHostname` it's a
+/// host name, which covers the names under it too.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
+#[sea_orm(table_name = "allowed_address")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub address: String,
+    pub rule: DecisionRule,
+    pub source: String,
+    pub time_added: DateTimeWithTimeZone,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
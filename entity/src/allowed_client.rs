@@ -0,0 +1,24 @@
+use sea_orm::entity::prelude::*;
+
+use super::decision_rule::DecisionRule;
+
+/// A client network accepted without greylisting, outside the configured
+/// `allow_from_ranges`. `rule` is either `AllowedNetwork` or `KnownGood`.
+/// With a `sender_domain`, only mail from that domain is accepted.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
+#[sea_orm(table_name = "allowed_client")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub network: String,
+    /// Lowercase, or empty for any sender
+    pub sender_domain: String,
+    pub rule: DecisionRule,
+    pub source: String,
+    pub time_added: DateTimeWithTimeZone,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,36 @@
+use sea_orm::entity::prelude::*;
+
+use super::{decision_rule::DecisionRule, email_status::EmailStatus};
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
+#[sea_orm(table_name = "decision")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub mail_id: i32,
+    /// The `time_received` of the mail, which partitions this table along
+    /// with `mail`
+    pub mail_time_received: DateTimeWithTimeZone,
+    pub time_decided: DateTimeWithTimeZone,
+    pub status: EmailStatus,
+    pub rule: DecisionRule,
+    pub detail: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::mail::Entity",
+        from = "Column::MailId",
+        to = "super::mail::Column::Id"
+    )]
+    Mail,
+}
+
+impl Related<super::mail::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Mail.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,40 @@
+use sea_orm::{DeriveActiveEnum, EnumIter};
+
+#[derive(Clone, PartialEq, Eq, Debug, EnumIter, DeriveActiveEnum)]
+#[sea_orm(rs_type = "String", db_type = "String(Some(20))")]
+pub enum DecisionRule {
+    #[sea_orm(string_value = "loopback")]
+    Loopback,
+    #[sea_orm(string_value = "authenticated")]
+    Authenticated,
+    #[sea_orm(string_value = "allowed_network")]
+    AllowedNetwork,
+    #[sea_orm(string_value = "existing_message")]
+    ExistingMessage,
+    #[sea_orm(string_value = "known_good")]
+    KnownGood,
+    #[sea_orm(string_value = "greylisted")]
+    Greylisted,
+    #[sea_orm(string_value = "greylisting_disabled")]
+    GreylistingDisabled,
+    #[sea_orm(string_value = "dns_allowlisted")]
+    DnsAllowlisted,
+    #[sea_orm(string_value = "dns_blocklisted")]
+    DnsBlocklisted,
+    #[sea_orm(string_value = "spf_trusted")]
+    SpfTrusted,
+    #[sea_orm(string_value = "spf_known_good")]
+    SpfKnownGood,
+    #[sea_orm(string_value = "spf_fail")]
+    SpfFail,
+    #[sea_orm(string_value = "trusted_hostname")]
+    TrustedHostname,
+    #[sea_orm(string_value = "dynamic_hostname")]
+    DynamicHostname,
+    #[sea_orm(string_value = "no_reverse_dns")]
+    NoReverseDns,
+    #[sea_orm(string_value = "allowed_sender")]
+    AllowedSender,
+    #[sea_orm(string_value = "allowed_recipient")]
+    AllowedRecipient,
+}
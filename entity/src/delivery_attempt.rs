@@ -0,0 +1,36 @@
+use sea_orm::entity::prelude::*;
+
+use super::email_status::EmailStatus;
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
+#[sea_orm(table_name = "delivery_attempt")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub mail_id: i32,
+    /// The `time_received` of the mail, which partitions this table along
+    /// with `mail`
+    pub mail_time_received: DateTimeWithTimeZone,
+    pub time_attempted: DateTimeWithTimeZone,
+    pub sending_ip: String,
+    pub sending_host_name: Option<String>,
+    pub status: EmailStatus,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::mail::Entity",
+        from = "Column::MailId",
+        to = "super::mail::Column::Id"
+    )]
+    Mail,
+}
+
+impl Related<super::mail::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Mail.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
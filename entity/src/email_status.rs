@@ -1,5 +1,6 @@
 use sea_orm::{DeriveActiveEnum, EnumIter};
 
+/// Also listed by name in the email_status table; keep the two in step
 #[derive(Clone, PartialEq, Eq, Debug, EnumIter, DeriveActiveEnum)]
 #[sea_orm(rs_type = "i16", db_type = "Integer")]
 pub enum EmailStatus {
@@ -9,6 +10,12 @@ pub enum EmailStatus {
     PassedGreylistAccepted = 4,
     KnownGoodAccepted = 5,
     OtherAccepted = 6,
+    SpfAccepted = 7,
+    AllowlistAccepted = 8,
     Greylisted = 10,
+    /// Set by hand; retries are discarded
     Denied = 20,
+    /// Turned away by a policy, e.g. a DNS block list; retries are rejected
+    /// the same way
+    Rejected = 21,
 }
@@ -1,6 +1,16 @@
 pub mod prelude;
 
+pub mod allowed_address;
+pub mod allowed_client;
+pub mod decision;
+pub mod decision_rule;
+pub mod delivery_attempt;
 pub mod email_status;
 pub mod mail;
 pub mod mail_recipient;
+pub mod mail_status_history;
 pub mod recipient;
+pub mod rollup_daily;
+pub mod rollup_dimension;
+pub mod rollup_hourly;
+pub mod spf_result;
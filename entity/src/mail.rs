@@ -1,6 +1,6 @@
 use sea_orm::entity::prelude::*;
 
-use super::email_status::EmailStatus;
+use super::{email_status::EmailStatus, spf_result::SpfResult};
 
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
 #[sea_orm(table_name = "mail")]
@@ -15,12 +15,26 @@ pub struct Model {
     pub time_received: DateTimeWithTimeZone,
     pub time_accepted: Option<DateTimeWithTimeZone>,
     pub status: EmailStatus,
+    pub helo_name: Option<String>,
+    pub queue_id: Option<String>,
+    pub auth_user: Option<String>,
+    pub tls_version: Option<String>,
+    pub tls_cipher: Option<String>,
+    pub message_size: Option<i64>,
+    pub daemon_name: Option<String>,
+    pub spf_result: Option<SpfResult>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {
     #[sea_orm(has_many = "super::recipient::Entity")]
     Recipient,
+    #[sea_orm(has_many = "super::decision::Entity")]
+    Decision,
+    #[sea_orm(has_many = "super::delivery_attempt::Entity")]
+    DeliveryAttempt,
+    #[sea_orm(has_many = "super::mail_status_history::Entity")]
+    MailStatusHistory,
 }
 
 impl Related<super::recipient::Entity> for Entity {
@@ -33,4 +47,22 @@ impl Related<super::recipient::Entity> for Entity {
     }
 }
 
+impl Related<super::decision::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Decision.def()
+    }
+}
+
+impl Related<super::delivery_attempt::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::DeliveryAttempt.def()
+    }
+}
+
+impl Related<super::mail_status_history::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::MailStatusHistory.def()
+    }
+}
+
 impl ActiveModelBehavior for ActiveModel {}
@@ -6,6 +6,9 @@ pub struct Model {
     #[sea_orm(primary_key)]
     pub id: i32,
     pub mail_id: i32,
+    /// The `time_received` of the mail, which partitions this table along
+    /// with `mail`
+    pub mail_time_received: DateTimeWithTimeZone,
     pub recipient_id: i32,
 }
 
@@ -0,0 +1,39 @@
+use sea_orm::entity::prelude::*;
+
+use super::email_status::EmailStatus;
+
+/// One change to `mail.status`, written in the same transaction as the change
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
+#[sea_orm(table_name = "mail_status_history")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub mail_id: i32,
+    /// The `time_received` of the mail, which partitions this table along
+    /// with `mail`
+    pub mail_time_received: DateTimeWithTimeZone,
+    pub old_status: EmailStatus,
+    pub new_status: EmailStatus,
+    pub time_changed: DateTimeWithTimeZone,
+    /// `milter`, or the admin who made the change
+    pub actor: String,
+    pub note: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::mail::Entity",
+        from = "Column::MailId",
+        to = "super::mail::Column::Id"
+    )]
+    Mail,
+}
+
+impl Related<super::mail::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Mail.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -1,9 +1,30 @@
+pub use super::allowed_address::ActiveModel as AllowedAddressActive;
+pub use super::allowed_address::Entity as AllowedAddressEntity;
+pub use super::allowed_address::Model as AllowedAddressModel;
+pub use super::allowed_client::ActiveModel as AllowedClientActive;
+pub use super::allowed_client::Entity as AllowedClientEntity;
+pub use super::allowed_client::Model as AllowedClientModel;
+pub use super::decision::ActiveModel as DecisionActive;
+pub use super::decision::Entity as DecisionEntity;
+pub use super::decision::Model as DecisionModel;
+pub use super::delivery_attempt::ActiveModel as DeliveryAttemptActive;
+pub use super::delivery_attempt::Entity as DeliveryAttemptEntity;
+pub use super::delivery_attempt::Model as DeliveryAttemptModel;
 pub use super::mail::ActiveModel as MailActive;
 pub use super::mail::Entity as MailEntity;
 pub use super::mail::Model as MailModel;
 pub use super::mail_recipient::ActiveModel as MailRecipientActive;
 pub use super::mail_recipient::Entity as MailRecipientEntity;
 pub use super::mail_recipient::Model as MailRecipientModel;
+pub use super::mail_status_history::ActiveModel as MailStatusHistoryActive;
+pub use super::mail_status_history::Entity as MailStatusHistoryEntity;
+pub use super::mail_status_history::Model as MailStatusHistoryModel;
 pub use super::recipient::ActiveModel as RecipientActive;
 pub use super::recipient::Entity as RecipientEntity;
 pub use super::recipient::Model as RecipientModel;
+pub use super::rollup_daily::ActiveModel as RollupDailyActive;
+pub use super::rollup_daily::Entity as RollupDailyEntity;
+pub use super::rollup_daily::Model as RollupDailyModel;
+pub use super::rollup_hourly::ActiveModel as RollupHourlyActive;
+pub use super::rollup_hourly::Entity as RollupHourlyEntity;
+pub use super::rollup_hourly::Model as RollupHourlyModel;
@@ -5,7 +5,19 @@ use sea_orm::entity::prelude::*;
 pub struct Model {
     #[sea_orm(primary_key)]
     pub id: i32,
-    pub recipient: String,
+    pub local_part: String,
+    pub domain: String,
+}
+
+impl Model {
+    #[must_use]
+    pub fn address(&self) -> String {
+        if self.domain.is_empty() {
+            self.local_part.clone()
+        } else {
+            format!("{}@{}", self.local_part, self.domain)
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -0,0 +1,22 @@
+use sea_orm::entity::prelude::*;
+
+use super::rollup_dimension::RollupDimension;
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
+#[sea_orm(table_name = "rollup_daily")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub period_start: DateTimeWithTimeZone,
+    pub dimension: RollupDimension,
+    pub name: String,
+    pub messages: i32,
+    pub greylisted: i32,
+    pub never_retried: i32,
+    pub median_retry_seconds: Option<i64>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
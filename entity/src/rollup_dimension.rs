@@ -0,0 +1,15 @@
+use sea_orm::{DeriveActiveEnum, EnumIter};
+
+/// What the figures in a rollup row are broken down by
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, EnumIter, DeriveActiveEnum)]
+#[sea_orm(rs_type = "String", db_type = "String(Some(20))")]
+pub enum RollupDimension {
+    /// Final `EmailStatus` of the message, by name
+    #[sea_orm(string_value = "status")]
+    Status,
+    #[sea_orm(string_value = "sender_domain")]
+    SenderDomain,
+    /// A message to several domains counts once for each
+    #[sea_orm(string_value = "recipient_domain")]
+    RecipientDomain,
+}
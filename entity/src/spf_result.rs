@@ -0,0 +1,21 @@
+use sea_orm::{DeriveActiveEnum, EnumIter};
+
+/// The result of checking the sender domain's SPF record, RFC 7208 section 2.6
+#[derive(Clone, Copy, PartialEq, Eq, Debug, EnumIter, DeriveActiveEnum)]
+#[sea_orm(rs_type = "String", db_type = "String(Some(10))")]
+pub enum SpfResult {
+    #[sea_orm(string_value = "none")]
+    None,
+    #[sea_orm(string_value = "neutral")]
+    Neutral,
+    #[sea_orm(string_value = "pass")]
+    Pass,
+    #[sea_orm(string_value = "fail")]
+    Fail,
+    #[sea_orm(string_value = "softfail")]
+    SoftFail,
+    #[sea_orm(string_value = "temperror")]
+    TempError,
+    #[sea_orm(string_value = "permerror")]
+    PermError,
+}
@@ -1,12 +1,90 @@
+use std::net::IpAddr;
+
 pub use sea_orm_migration::prelude::*;
+use tracing::error;
 
 mod m20220101_000001_create_tables;
+mod m20261018_000001_create_decision;
+mod m20261018_000002_create_delivery_attempt;
+mod m20261018_000003_add_session_metadata;
+mod m20261018_000004_native_types;
+mod m20261018_000005_normalize_ips;
+mod m20261018_000006_split_recipient;
+mod m20261018_000007_create_rollups;
+mod m20261018_000008_create_allowed_client;
+mod m20261018_000009_import_incoming_mail;
+mod m20261018_000010_create_mail_status_history;
+mod m20261018_000011_add_spf_result;
+mod m20261018_000012_create_allowed_address;
+mod m20261018_000013_index_change_times;
+mod m20261018_000014_add_mail_time_received;
 
 pub struct Migrator;
 
+// RFC 5321 section 4.5.3.1 limits, which the recipient columns are sized to.
+// Same as sql_greylist_milter::address::MAX_LOCAL_PART_LENGTH and
+// MAX_DOMAIN_LENGTH
+const MAX_LOCAL_PART_LENGTH: u32 = 64;
+const MAX_DOMAIN_LENGTH: u32 = 255;
+
+/// Lowercases the domain the way the milter does. Local parts keep their
+/// case, as migrations can't see the configuration; the store lowercases them
+/// on startup if lowercase_local_part is set. `None` if either part is over
+/// its limit: the milter refuses such addresses, and cutting them short would
+/// merge them with others.
+fn normalize_address(local_part: &str, domain: &str) -> Option<(String, String)> {
+    let domain = domain.to_lowercase();
+    if local_part.len() > MAX_LOCAL_PART_LENGTH as usize
+        || domain.len() > MAX_DOMAIN_LENGTH as usize
+    {
+        return None;
+    }
+    Some((local_part.to_string(), domain))
+}
+
+/// Splits an address the way the milter does, see normalize_address. `None`,
+/// with an error logged, if it's too long.
+fn split_address(address: &str) -> Option<(String, String)> {
+    let (local_part, domain) = address.rsplit_once('@').unwrap_or((address, ""));
+    let normalized = normalize_address(local_part, domain);
+    if normalized.is_none() {
+        error!(
+            recipient = address,
+            "Recipient too long to store, leaving it out"
+        );
+    }
+    normalized
+}
+
+/// Same rules as sql_greylist_milter::address::parse_ip: drop any zone ID,
+/// and IPv4-mapped IPv6 becomes plain IPv4. `None` if it doesn't parse.
+fn normalize_ip(address: &str) -> Option<String> {
+    let address = address
+        .split_once('%')
+        .map_or(address, |(address, _zone)| address);
+    let normalized = address.parse::<IpAddr>().ok()?.to_canonical().to_string();
+    Some(normalized)
+}
+
 #[async_trait::async_trait]
 impl MigratorTrait for Migrator {
     fn migrations() -> Vec<Box<dyn MigrationTrait>> {
-        vec![Box::new(m20220101_000001_create_tables::Migration)]
+        vec![
+            Box::new(m20220101_000001_create_tables::Migration),
+            Box::new(m20261018_000001_create_decision::Migration),
+            Box::new(m20261018_000002_create_delivery_attempt::Migration),
+            Box::new(m20261018_000003_add_session_metadata::Migration),
+            Box::new(m20261018_000004_native_types::Migration),
+            Box::new(m20261018_000005_normalize_ips::Migration),
+            Box::new(m20261018_000006_split_recipient::Migration),
+            Box::new(m20261018_000007_create_rollups::Migration),
+            Box::new(m20261018_000008_create_allowed_client::Migration),
+            Box::new(m20261018_000009_import_incoming_mail::Migration),
+            Box::new(m20261018_000010_create_mail_status_history::Migration),
+            Box::new(m20261018_000011_add_spf_result::Migration),
+            Box::new(m20261018_000012_create_allowed_address::Migration),
+            Box::new(m20261018_000013_index_change_times::Migration),
+            Box::new(m20261018_000014_add_mail_time_received::Migration),
+        ]
     }
 }
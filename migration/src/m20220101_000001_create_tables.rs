@@ -1,4 +1,4 @@
-use sea_orm_migration::{prelude::*, sea_orm::DatabaseBackend};
+use sea_orm_migration::prelude::*;
 
 #[derive(DeriveMigrationName)]
 pub struct Migration;
@@ -80,7 +80,7 @@ impl MigrationTrait for Migration {
                             .auto_increment(),
                     )
                     .col(
-                        ColumnDef::new(Recipient::Recipient)
+                        ColumnDef::new(Recipient::Address)
                             .string_len(100)
                             .not_null(),
                     )
@@ -94,7 +94,7 @@ impl MigrationTrait for Migration {
                     .name("idx_recipient_recipient")
                     .if_not_exists()
                     .table(Recipient::Table)
-                    .col(Recipient::Recipient)
+                    .col(Recipient::Address)
                     .unique()
                     .clone(),
             )
@@ -134,53 +134,8 @@ impl MigrationTrait for Migration {
             )
             .await?;
 
-        // Now copy any old data over
-        if Ok(true) == manager.has_table("incoming_mail").await
-            && manager.get_database_backend() == DatabaseBackend::Postgres
-        {
-            let db = manager.get_connection();
-            db.execute_unprepared(
-                "INSERT INTO recipient (recipient)
-                 SELECT DISTINCT LOWER(TRIM(UNNEST(STRING_TO_ARRAY(recipients, ', '))))
-                 FROM incoming_mail",
-            )
-            .await?;
-
-            db.execute_unprepared("ALTER TABLE mail ADD COLUMN old_id integer")
-                .await?;
-
-            db.execute_unprepared(
-                "INSERT INTO mail (sender_local_part, sender_domain, message_id, sending_host_name, sending_ip, time_received, time_accepted, status, old_id)
-                 SELECT sender_local_part, sender_domain, message_id, sending_host_name, sending_ip, time_received, time_accepted, status, incoming_mail_id
-                 FROM incoming_mail
-                 ON CONFLICT DO NOTHING",
-            )
-            .await?;
-
-            db.execute_unprepared(
-                "INSERT INTO mail_recipient (mail_id, recipient_id)
-                 SELECT
-                     a.id AS mail_id,
-                     r.id AS recipient_id
-                 FROM
-                     recipient r
-                 JOIN
-                 (
-                     SELECT
-                         id,
-                         incoming_mail_id,
-                         LOWER(TRIM(UNNEST(STRING_TO_ARRAY(recipients, ', ')))) AS recipients
-                     FROM
-                         incoming_mail im
-                     JOIN mail m ON
-                         im.incoming_mail_id = m.old_id) AS a ON
-                     r.recipient = a.recipients",
-            )
-            .await?;
-
-            db.execute_unprepared("ALTER TABLE mail DROP COLUMN old_id")
-                .await?;
-        }
+        // Any data in the schema before this one, incoming_mail, is copied
+        // over by m20261018_000009_import_incoming_mail
 
         Ok(())
     }
@@ -220,7 +175,9 @@ enum Mail {
 enum Recipient {
     Table,
     Id,
-    Recipient,
+    // The column is named after the table
+    #[sea_orm(iden = "recipient")]
+    Address,
 }
 
 #[derive(DeriveIden)]
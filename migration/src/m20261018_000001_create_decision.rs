@@ -0,0 +1,80 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Decision::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(Decision::Id)
+                            .integer()
+                            .not_null()
+                            .primary_key()
+                            .auto_increment(),
+                    )
+                    .col(ColumnDef::new(Decision::MailId).integer().not_null())
+                    .col(
+                        ColumnDef::new(Decision::TimeDecided)
+                            .timestamp_with_time_zone()
+                            .not_null()
+                            .default(Expr::current_timestamp()),
+                    )
+                    .col(ColumnDef::new(Decision::Status).tiny_integer().not_null())
+                    .col(ColumnDef::new(Decision::Rule).string_len(20).not_null())
+                    .col(ColumnDef::new(Decision::Detail).string_len(200))
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(Decision::Table, Decision::MailId)
+                            .to(Mail::Table, Mail::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .clone(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_decision_mailid")
+                    .if_not_exists()
+                    .table(Decision::Table)
+                    .col(Decision::MailId)
+                    .col(Decision::TimeDecided)
+                    .clone(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Decision::Table).clone())
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum Mail {
+    Table,
+    Id,
+}
+
+#[derive(DeriveIden)]
+enum Decision {
+    Table,
+    Id,
+    MailId,
+    TimeDecided,
+    Status,
+    Rule,
+    Detail,
+}
@@ -0,0 +1,88 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(DeliveryAttempt::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(DeliveryAttempt::Id)
+                            .integer()
+                            .not_null()
+                            .primary_key()
+                            .auto_increment(),
+                    )
+                    .col(ColumnDef::new(DeliveryAttempt::MailId).integer().not_null())
+                    .col(
+                        ColumnDef::new(DeliveryAttempt::TimeAttempted)
+                            .timestamp_with_time_zone()
+                            .not_null()
+                            .default(Expr::current_timestamp()),
+                    )
+                    .col(
+                        ColumnDef::new(DeliveryAttempt::SendingIp)
+                            .string_len(39)
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(DeliveryAttempt::SendingHostName).string_len(200))
+                    .col(
+                        ColumnDef::new(DeliveryAttempt::Status)
+                            .tiny_integer()
+                            .not_null(),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(DeliveryAttempt::Table, DeliveryAttempt::MailId)
+                            .to(Mail::Table, Mail::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .clone(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_deliveryattempt_mailid")
+                    .if_not_exists()
+                    .table(DeliveryAttempt::Table)
+                    .col(DeliveryAttempt::MailId)
+                    .col(DeliveryAttempt::TimeAttempted)
+                    .clone(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(DeliveryAttempt::Table).clone())
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum Mail {
+    Table,
+    Id,
+}
+
+#[derive(DeriveIden)]
+enum DeliveryAttempt {
+    Table,
+    Id,
+    MailId,
+    TimeAttempted,
+    SendingIp,
+    SendingHostName,
+    Status,
+}
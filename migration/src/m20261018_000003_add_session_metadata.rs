@@ -0,0 +1,66 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // SQLite can only add one column per ALTER TABLE
+        for mut column in [
+            ColumnDef::new(Mail::HeloName).string_len(255).clone(),
+            ColumnDef::new(Mail::QueueId).string_len(32).clone(),
+            ColumnDef::new(Mail::AuthUser).string_len(255).clone(),
+            ColumnDef::new(Mail::TlsVersion).string_len(20).clone(),
+            ColumnDef::new(Mail::TlsCipher).string_len(100).clone(),
+            ColumnDef::new(Mail::MessageSize).big_integer().clone(),
+            ColumnDef::new(Mail::DaemonName).string_len(100).clone(),
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Mail::Table)
+                        .add_column(&mut column)
+                        .clone(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for column in [
+            Mail::HeloName,
+            Mail::QueueId,
+            Mail::AuthUser,
+            Mail::TlsVersion,
+            Mail::TlsCipher,
+            Mail::MessageSize,
+            Mail::DaemonName,
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Mail::Table)
+                        .drop_column(column)
+                        .clone(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum Mail {
+    Table,
+    HeloName,
+    QueueId,
+    AuthUser,
+    TlsVersion,
+    TlsCipher,
+    MessageSize,
+    DaemonName,
+}
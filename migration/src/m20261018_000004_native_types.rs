@@ -0,0 +1,180 @@
+use sea_orm_migration::{prelude::*, sea_orm::DatabaseBackend};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+// Must match entity::email_status::EmailStatus
+const STATUSES: [(i16, &str); 8] = [
+    (1, "locally_accepted"),
+    (2, "ip_accepted"),
+    (3, "authenticated_accepted"),
+    (4, "passed_greylist_accepted"),
+    (5, "known_good_accepted"),
+    (6, "other_accepted"),
+    (10, "greylisted"),
+    (20, "denied"),
+];
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let backend = manager.get_database_backend();
+
+        // Give the status numbers a name everywhere; SQLite can't add foreign
+        // keys to existing tables, so there it's only for reference
+        manager
+            .create_table(
+                Table::create()
+                    .table(EmailStatus::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(EmailStatus::Id)
+                            .tiny_integer()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(EmailStatus::Name).string_len(30).not_null())
+                    .clone(),
+            )
+            .await?;
+
+        let mut insert = Query::insert()
+            .into_table(EmailStatus::Table)
+            .columns([EmailStatus::Id, EmailStatus::Name])
+            .clone();
+        for (id, name) in STATUSES {
+            insert.values_panic([id.into(), name.into()]);
+        }
+        manager.exec_stmt(insert).await?;
+
+        if backend != DatabaseBackend::Sqlite {
+            for (name, table) in [
+                ("fk_mail_status", Mail::Table.into_iden()),
+                ("fk_decision_status", Decision::Table.into_iden()),
+                (
+                    "fk_deliveryattempt_status",
+                    DeliveryAttempt::Table.into_iden(),
+                ),
+            ] {
+                manager
+                    .create_foreign_key(
+                        ForeignKey::create()
+                            .name(name)
+                            .from(table, Mail::Status)
+                            .to(EmailStatus::Table, EmailStatus::Id)
+                            .on_delete(ForeignKeyAction::Restrict)
+                            .clone(),
+                    )
+                    .await?;
+            }
+
+            // 39 characters doesn't fit an IPv4-mapped IPv6 address with a
+            // zone; SQLite doesn't enforce the length anyway
+            for table in [Mail::Table.into_iden(), DeliveryAttempt::Table.into_iden()] {
+                manager
+                    .alter_table(
+                        Table::alter()
+                            .table(table)
+                            .modify_column(
+                                ColumnDef::new(Mail::SendingIp).string_len(64).not_null(),
+                            )
+                            .clone(),
+                    )
+                    .await?;
+            }
+        }
+
+        // The address stays as text so every backend reads it the same way,
+        // but Postgres also keeps it as inet so it can be range-queried, e.g.
+        // WHERE sending_inet <<= '192.0.2.0/24'. Rows already stored get
+        // theirs as the column is added.
+        if backend == DatabaseBackend::Postgres {
+            let db = manager.get_connection();
+            for table in ["mail", "delivery_attempt"] {
+                db.execute_unprepared(&format!(
+                    "ALTER TABLE {table} ADD COLUMN sending_inet inet
+                     GENERATED ALWAYS AS (split_part(sending_ip, '%', 1)::inet) STORED"
+                ))
+                .await?;
+                db.execute_unprepared(&format!(
+                    "CREATE INDEX idx_{}_sendinginet ON {table} USING gist (sending_inet inet_ops)",
+                    table.replace('_', "")
+                ))
+                .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let backend = manager.get_database_backend();
+
+        if backend == DatabaseBackend::Postgres {
+            let db = manager.get_connection();
+            for table in ["mail", "delivery_attempt"] {
+                // Dropping the column drops its index too
+                db.execute_unprepared(&format!("ALTER TABLE {table} DROP COLUMN sending_inet"))
+                    .await?;
+            }
+        }
+
+        if backend != DatabaseBackend::Sqlite {
+            for table in [Mail::Table.into_iden(), DeliveryAttempt::Table.into_iden()] {
+                manager
+                    .alter_table(
+                        Table::alter()
+                            .table(table)
+                            .modify_column(
+                                ColumnDef::new(Mail::SendingIp).string_len(39).not_null(),
+                            )
+                            .clone(),
+                    )
+                    .await?;
+            }
+
+            for (name, table) in [
+                ("fk_mail_status", Mail::Table.into_iden()),
+                ("fk_decision_status", Decision::Table.into_iden()),
+                (
+                    "fk_deliveryattempt_status",
+                    DeliveryAttempt::Table.into_iden(),
+                ),
+            ] {
+                manager
+                    .drop_foreign_key(ForeignKey::drop().name(name).table(table).clone())
+                    .await?;
+            }
+        }
+
+        manager
+            .drop_table(Table::drop().table(EmailStatus::Table).clone())
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum EmailStatus {
+    Table,
+    Id,
+    Name,
+}
+
+#[derive(DeriveIden)]
+enum Mail {
+    Table,
+    SendingIp,
+    Status,
+}
+
+#[derive(DeriveIden)]
+enum Decision {
+    Table,
+}
+
+#[derive(DeriveIden)]
+enum DeliveryAttempt {
+    Table,
+}
@@ -0,0 +1,66 @@
+use sea_orm_migration::prelude::*;
+
+use crate::normalize_ip;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+        let builder = db.get_database_backend();
+
+        for table in [Mail::Table.into_iden(), DeliveryAttempt::Table.into_iden()] {
+            // Only IPv6 addresses can be mapped or have a zone
+            let rows = db
+                .query_all(
+                    builder.build(
+                        Query::select()
+                            .columns([Mail::Id, Mail::SendingIp])
+                            .from(table.clone())
+                            .and_where(Expr::col(Mail::SendingIp).like("%:%")),
+                    ),
+                )
+                .await?;
+
+            for row in rows {
+                let id: i32 = row.try_get("", "id")?;
+                let sending_ip: String = row.try_get("", "sending_ip")?;
+                match normalize_ip(&sending_ip) {
+                    Some(normalized) if normalized != sending_ip => {
+                        db.execute(
+                            builder.build(
+                                Query::update()
+                                    .table(table.clone())
+                                    .value(Mail::SendingIp, normalized)
+                                    .and_where(Expr::col(Mail::Id).eq(id)),
+                            ),
+                        )
+                        .await?;
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn down(&self, _manager: &SchemaManager) -> Result<(), DbErr> {
+        // The original forms aren't kept, and nothing depends on them
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum Mail {
+    Table,
+    Id,
+    SendingIp,
+}
+
+#[derive(DeriveIden)]
+enum DeliveryAttempt {
+    Table,
+}
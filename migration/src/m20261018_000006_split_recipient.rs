@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+
+use sea_orm_migration::{prelude::*, sea_orm::DatabaseBackend};
+
+use crate::{split_address, MAX_DOMAIN_LENGTH, MAX_LOCAL_PART_LENGTH};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+        let builder = db.get_database_backend();
+
+        // SQLite can only add one column per ALTER TABLE, and can't make them
+        // NOT NULL afterwards
+        for mut column in [
+            ColumnDef::new(Recipient::LocalPart)
+                .string_len(MAX_LOCAL_PART_LENGTH)
+                .clone(),
+            ColumnDef::new(Recipient::Domain)
+                .string_len(MAX_DOMAIN_LENGTH)
+                .clone(),
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Recipient::Table)
+                        .add_column(&mut column)
+                        .clone(),
+                )
+                .await?;
+        }
+
+        // Split the existing addresses the same way the milter does, and merge
+        // the ones that turn out to be the same
+        let rows = db
+            .query_all(
+                builder.build(
+                    Query::select()
+                        .columns([Recipient::Id, Recipient::Address])
+                        .from(Recipient::Table)
+                        .order_by(Recipient::Id, Order::Asc),
+                ),
+            )
+            .await?;
+
+        let mut kept: HashMap<(String, String), i32> = HashMap::new();
+        for row in rows {
+            let id: i32 = row.try_get("", "id")?;
+            let recipient: String = row.try_get("", "recipient")?;
+            // The milter refuses these now, so they're dropped along with
+            // the mail's link to them
+            let Some(address) = split_address(&recipient) else {
+                db.execute(
+                    builder.build(
+                        Query::delete()
+                            .from_table(MailRecipient::Table)
+                            .and_where(Expr::col(MailRecipient::RecipientId).eq(id)),
+                    ),
+                )
+                .await?;
+                db.execute(
+                    builder.build(
+                        Query::delete()
+                            .from_table(Recipient::Table)
+                            .and_where(Expr::col(Recipient::Id).eq(id)),
+                    ),
+                )
+                .await?;
+                continue;
+            };
+
+            if let Some(&kept_id) = kept.get(&address) {
+                db.execute(
+                    builder.build(
+                        Query::update()
+                            .table(MailRecipient::Table)
+                            .value(MailRecipient::RecipientId, kept_id)
+                            .and_where(Expr::col(MailRecipient::RecipientId).eq(id)),
+                    ),
+                )
+                .await?;
+                db.execute(
+                    builder.build(
+                        Query::delete()
+                            .from_table(Recipient::Table)
+                            .and_where(Expr::col(Recipient::Id).eq(id)),
+                    ),
+                )
+                .await?;
+            } else {
+                db.execute(
+                    builder.build(
+                        Query::update()
+                            .table(Recipient::Table)
+                            .value(Recipient::LocalPart, address.0.as_str())
+                            .value(Recipient::Domain, address.1.as_str())
+                            .and_where(Expr::col(Recipient::Id).eq(id)),
+                    ),
+                )
+                .await?;
+                kept.insert(address, id);
+            }
+        }
+
+        // A message sent to two spellings of one address now lists it twice.
+        // The derived table keeps MySQL happy about deleting from a table the
+        // subquery reads.
+        db.execute_unprepared(
+            "DELETE FROM mail_recipient WHERE id NOT IN (
+                 SELECT id FROM (
+                     SELECT MIN(id) AS id FROM mail_recipient GROUP BY mail_id, recipient_id
+                 ) AS kept
+             )",
+        )
+        .await?;
+
+        manager
+            .drop_index(
+                Index::drop()
+                    .name("idx_recipient_recipient")
+                    .table(Recipient::Table)
+                    .clone(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Recipient::Table)
+                    .drop_column(Recipient::Address)
+                    .clone(),
+            )
+            .await?;
+
+        if manager.get_database_backend() != DatabaseBackend::Sqlite {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Recipient::Table)
+                        .modify_column(
+                            ColumnDef::new(Recipient::LocalPart)
+                                .string_len(MAX_LOCAL_PART_LENGTH)
+                                .not_null(),
+                        )
+                        .modify_column(
+                            ColumnDef::new(Recipient::Domain)
+                                .string_len(MAX_DOMAIN_LENGTH)
+                                .not_null(),
+                        )
+                        .clone(),
+                )
+                .await?;
+        }
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_recipient_address")
+                    .table(Recipient::Table)
+                    .col(Recipient::LocalPart)
+                    .col(Recipient::Domain)
+                    .unique()
+                    .clone(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+        let builder = db.get_database_backend();
+
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Recipient::Table)
+                    .add_column(ColumnDef::new(Recipient::Address).string_len(100))
+                    .clone(),
+            )
+            .await?;
+
+        let rows = db
+            .query_all(
+                builder.build(
+                    Query::select()
+                        .columns([Recipient::Id, Recipient::LocalPart, Recipient::Domain])
+                        .from(Recipient::Table),
+                ),
+            )
+            .await?;
+        for row in rows {
+            let id: i32 = row.try_get("", "id")?;
+            let local_part: String = row.try_get("", "local_part")?;
+            let domain: String = row.try_get("", "domain")?;
+            let recipient = if domain.is_empty() {
+                local_part
+            } else {
+                format!("{}@{}", local_part, domain)
+            };
+            db.execute(
+                builder.build(
+                    Query::update()
+                        .table(Recipient::Table)
+                        .value(Recipient::Address, recipient)
+                        .and_where(Expr::col(Recipient::Id).eq(id)),
+                ),
+            )
+            .await?;
+        }
+
+        if manager.get_database_backend() != DatabaseBackend::Sqlite {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Recipient::Table)
+                        .modify_column(
+                            ColumnDef::new(Recipient::Address)
+                                .string_len(100)
+                                .not_null(),
+                        )
+                        .clone(),
+                )
+                .await?;
+        }
+
+        manager
+            .drop_index(
+                Index::drop()
+                    .name("idx_recipient_address")
+                    .table(Recipient::Table)
+                    .clone(),
+            )
+            .await?;
+        for column in [Recipient::LocalPart, Recipient::Domain] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Recipient::Table)
+                        .drop_column(column)
+                        .clone(),
+                )
+                .await?;
+        }
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_recipient_recipient")
+                    .table(Recipient::Table)
+                    .col(Recipient::Address)
+                    .unique()
+                    .clone(),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum Recipient {
+    Table,
+    Id,
+    // The column is named after the table
+    #[sea_orm(iden = "recipient")]
+    Address,
+    LocalPart,
+    Domain,
+}
+
+#[derive(DeriveIden)]
+enum MailRecipient {
+    Table,
+    RecipientId,
+}
@@ -0,0 +1,117 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // The rollups read mail a day at a time
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_mail_timereceived")
+                    .if_not_exists()
+                    .table(Mail::Table)
+                    .col(Mail::TimeReceived)
+                    .clone(),
+            )
+            .await?;
+
+        for (table, index) in [
+            (RollupHourly::Table.into_iden(), "idx_rolluphourly_period"),
+            (RollupDaily::Table.into_iden(), "idx_rollupdaily_period"),
+        ] {
+            manager
+                .create_table(
+                    Table::create()
+                        .table(table.clone())
+                        .if_not_exists()
+                        .col(
+                            ColumnDef::new(Rollup::Id)
+                                .integer()
+                                .not_null()
+                                .primary_key()
+                                .auto_increment(),
+                        )
+                        .col(
+                            ColumnDef::new(Rollup::PeriodStart)
+                                .timestamp_with_time_zone()
+                                .not_null(),
+                        )
+                        .col(ColumnDef::new(Rollup::Dimension).string_len(20).not_null())
+                        .col(ColumnDef::new(Rollup::Name).string_len(255).not_null())
+                        .col(ColumnDef::new(Rollup::Messages).integer().not_null())
+                        .col(ColumnDef::new(Rollup::Greylisted).integer().not_null())
+                        .col(ColumnDef::new(Rollup::NeverRetried).integer().not_null())
+                        .col(ColumnDef::new(Rollup::MedianRetrySeconds).big_integer())
+                        .clone(),
+                )
+                .await?;
+
+            manager
+                .create_index(
+                    Index::create()
+                        .name(index)
+                        .if_not_exists()
+                        .table(table)
+                        .col(Rollup::PeriodStart)
+                        .col(Rollup::Dimension)
+                        .col(Rollup::Name)
+                        .unique()
+                        .clone(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(RollupDaily::Table).clone())
+            .await?;
+        manager
+            .drop_table(Table::drop().table(RollupHourly::Table).clone())
+            .await?;
+        manager
+            .drop_index(
+                Index::drop()
+                    .name("idx_mail_timereceived")
+                    .table(Mail::Table)
+                    .clone(),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum Mail {
+    Table,
+    TimeReceived,
+}
+
+#[derive(DeriveIden)]
+enum RollupHourly {
+    Table,
+}
+
+#[derive(DeriveIden)]
+enum RollupDaily {
+    Table,
+}
+
+// Columns shared by both rollup tables
+#[derive(DeriveIden)]
+enum Rollup {
+    Id,
+    PeriodStart,
+    Dimension,
+    Name,
+    Messages,
+    Greylisted,
+    NeverRetried,
+    MedianRetrySeconds,
+}
@@ -0,0 +1,100 @@
+use sea_orm_migration::{prelude::*, sea_orm::DatabaseBackend};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(AllowedClient::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(AllowedClient::Id)
+                            .integer()
+                            .not_null()
+                            .primary_key()
+                            .auto_increment(),
+                    )
+                    // Canonical CIDR form, so lookups can match on the string
+                    .col(
+                        ColumnDef::new(AllowedClient::Network)
+                            .string_len(50)
+                            .not_null(),
+                    )
+                    // Empty if the network is allowed whoever the sender
+                    .col(
+                        ColumnDef::new(AllowedClient::SenderDomain)
+                            .string_len(255)
+                            .not_null()
+                            .default(""),
+                    )
+                    .col(
+                        ColumnDef::new(AllowedClient::Rule)
+                            .string_len(20)
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(AllowedClient::Source)
+                            .string_len(255)
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(AllowedClient::TimeAdded)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .clone(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_allowedclient_network_senderdomain")
+                    .table(AllowedClient::Table)
+                    .col(AllowedClient::Network)
+                    .col(AllowedClient::SenderDomain)
+                    .unique()
+                    .clone(),
+            )
+            .await?;
+
+        // Postgres also keeps the network as cidr, so the networks an address
+        // is in can be found with <<= through a GiST index
+        if manager.get_database_backend() == DatabaseBackend::Postgres {
+            let db = manager.get_connection();
+            db.execute_unprepared(
+                "ALTER TABLE allowed_client ADD COLUMN network_cidr cidr
+                 GENERATED ALWAYS AS (network::cidr) STORED",
+            )
+            .await?;
+            db.execute_unprepared(
+                "CREATE INDEX idx_allowedclient_networkcidr
+                 ON allowed_client USING gist (network_cidr inet_ops)",
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(AllowedClient::Table).clone())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum AllowedClient {
+    Table,
+    Id,
+    Network,
+    SenderDomain,
+    Rule,
+    Source,
+    TimeAdded,
+}
@@ -0,0 +1,321 @@
+use std::collections::HashSet;
+
+use sea_orm_migration::{
+    prelude::*,
+    sea_orm::{ConnectionTrait, DatabaseTransaction, TransactionTrait},
+};
+
+use tracing::error;
+
+use crate::{normalize_address, normalize_ip, split_address};
+
+/// Copies mail from the schema before this one, `incoming_mail`, if it's
+/// still there. This used to be part of the first migration, but only on
+/// Postgres. Senders are normalized like the recipients, and a message whose
+/// sender is too long to store is left out. Messages already copied are
+/// skipped, or only get their recipients if those are missing, so it's safe to
+/// run against a database where that import, or this one, only got part of
+/// the way.
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+// Messages copied per transaction
+const BATCH_SIZE: u64 = 1000;
+
+// The old schema kept the recipients as one comma separated string. They're
+// split the way the milter splits them, see split_address
+fn split_recipients(recipients: &str) -> Vec<(String, String)> {
+    let mut seen = HashSet::new();
+    recipients
+        .split(',')
+        .map(str::trim)
+        .filter(|recipient| !recipient.is_empty())
+        .filter_map(split_address)
+        .filter(|address| seen.insert(address.clone()))
+        .collect()
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Left behind by the old import if it failed after adding it
+        if manager.has_column("mail", "old_id").await? {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Mail::Table)
+                        .drop_column(Mail::OldId)
+                        .clone(),
+                )
+                .await?;
+        }
+
+        if !manager.has_table("incoming_mail").await? {
+            return Ok(());
+        }
+
+        let db = manager.get_connection();
+        let builder = db.get_database_backend();
+        let mut last_id = None;
+        loop {
+            let mut query = Query::select();
+            query
+                .columns([
+                    IncomingMail::IncomingMailId,
+                    IncomingMail::SenderLocalPart,
+                    IncomingMail::SenderDomain,
+                    IncomingMail::MessageId,
+                    IncomingMail::SendingIp,
+                    IncomingMail::Recipients,
+                ])
+                .from(IncomingMail::Table)
+                .order_by(IncomingMail::IncomingMailId, Order::Asc)
+                .limit(BATCH_SIZE);
+            if let Some(last_id) = last_id {
+                query.and_where(Expr::col(IncomingMail::IncomingMailId).gt(last_id));
+            }
+            let rows = db.query_all(builder.build(&query)).await?;
+            if rows.is_empty() {
+                break;
+            }
+
+            let txn = db.begin().await?;
+            for row in rows {
+                let incoming_mail_id: i32 = row.try_get("", "incoming_mail_id")?;
+                let sender_local_part: String = row.try_get("", "sender_local_part")?;
+                let sender_domain: String = row.try_get("", "sender_domain")?;
+                let message_id: String = row.try_get("", "message_id")?;
+                let sending_ip: String = row.try_get("", "sending_ip")?;
+                let recipients: Option<String> = row.try_get("", "recipients")?;
+                last_id = Some(incoming_mail_id);
+
+                let recipients = split_recipients(recipients.as_deref().unwrap_or_default());
+                match find_mail(&txn, &message_id).await? {
+                    None => {
+                        let Some(sender) = normalize_address(&sender_local_part, &sender_domain)
+                        else {
+                            error!(
+                                sender_local_part,
+                                sender_domain,
+                                message_id,
+                                "Sender too long to store, leaving out the message"
+                            );
+                            continue;
+                        };
+                        copy_mail(
+                            &txn,
+                            incoming_mail_id,
+                            &message_id,
+                            sender,
+                            normalize_ip(&sending_ip).unwrap_or(sending_ip),
+                            recipients,
+                        )
+                        .await?
+                    }
+                    // The old import could stop between copying a message and
+                    // its recipients
+                    Some(mail_id) if !has_recipients(&txn, mail_id).await? => {
+                        link_recipients(&txn, mail_id, recipients).await?
+                    }
+                    Some(_) => (),
+                }
+            }
+            txn.commit().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn down(&self, _manager: &SchemaManager) -> Result<(), DbErr> {
+        // incoming_mail is left alone, and the copies can't be told apart
+        // from mail received since
+        Ok(())
+    }
+}
+
+async fn find_mail(txn: &DatabaseTransaction, message_id: &str) -> Result<Option<i32>, DbErr> {
+    let builder = txn.get_database_backend();
+    txn.query_one(
+        builder.build(
+            Query::select()
+                .column(Mail::Id)
+                .from(Mail::Table)
+                .and_where(Expr::col(Mail::MessageId).eq(message_id)),
+        ),
+    )
+    .await?
+    .map(|row| row.try_get("", "id"))
+    .transpose()
+}
+
+// The times are copied in SQL, so whatever type the old columns had doesn't
+// need to be read here
+async fn copy_mail(
+    txn: &DatabaseTransaction,
+    incoming_mail_id: i32,
+    message_id: &str,
+    (sender_local_part, sender_domain): (String, String),
+    sending_ip: String,
+    recipients: Vec<(String, String)>,
+) -> Result<(), DbErr> {
+    let builder = txn.get_database_backend();
+    txn.execute(
+        builder.build(
+            Query::insert()
+                .into_table(Mail::Table)
+                .columns([
+                    Mail::SenderLocalPart,
+                    Mail::SenderDomain,
+                    Mail::MessageId,
+                    Mail::SendingHostName,
+                    Mail::SendingIp,
+                    Mail::TimeReceived,
+                    Mail::TimeAccepted,
+                    Mail::Status,
+                ])
+                .select_from(
+                    Query::select()
+                        .expr(Expr::val(sender_local_part))
+                        .expr(Expr::val(sender_domain))
+                        .columns([IncomingMail::MessageId, IncomingMail::SendingHostName])
+                        .expr(Expr::val(sending_ip))
+                        .columns([
+                            IncomingMail::TimeReceived,
+                            IncomingMail::TimeAccepted,
+                            IncomingMail::Status,
+                        ])
+                        .from(IncomingMail::Table)
+                        .and_where(Expr::col(IncomingMail::IncomingMailId).eq(incoming_mail_id))
+                        .to_owned(),
+                )
+                .map_err(|e| DbErr::Migration(e.to_string()))?,
+        ),
+    )
+    .await?;
+    let mail_id = find_mail(txn, message_id)
+        .await?
+        .ok_or_else(|| DbErr::RecordNotFound(format!("mail {}", message_id)))?;
+    link_recipients(txn, mail_id, recipients).await
+}
+
+async fn has_recipients(txn: &DatabaseTransaction, mail_id: i32) -> Result<bool, DbErr> {
+    let builder = txn.get_database_backend();
+    Ok(txn
+        .query_one(
+            builder.build(
+                Query::select()
+                    .column(MailRecipient::MailId)
+                    .from(MailRecipient::Table)
+                    .and_where(Expr::col(MailRecipient::MailId).eq(mail_id))
+                    .limit(1),
+            ),
+        )
+        .await?
+        .is_some())
+}
+
+async fn link_recipients(
+    txn: &DatabaseTransaction,
+    mail_id: i32,
+    recipients: Vec<(String, String)>,
+) -> Result<(), DbErr> {
+    let builder = txn.get_database_backend();
+    for (local_part, domain) in recipients {
+        let recipient_id = match find_recipient(txn, &local_part, &domain).await? {
+            Some(id) => id,
+            None => {
+                txn.execute(
+                    builder.build(
+                        Query::insert()
+                            .into_table(Recipient::Table)
+                            .columns([Recipient::LocalPart, Recipient::Domain])
+                            .values_panic([local_part.as_str().into(), domain.as_str().into()]),
+                    ),
+                )
+                .await?;
+                find_recipient(txn, &local_part, &domain)
+                    .await?
+                    .ok_or_else(|| {
+                        DbErr::RecordNotFound(format!("recipient {}@{}", local_part, domain))
+                    })?
+            }
+        };
+        txn.execute(
+            builder.build(
+                Query::insert()
+                    .into_table(MailRecipient::Table)
+                    .columns([MailRecipient::MailId, MailRecipient::RecipientId])
+                    .values_panic([mail_id.into(), recipient_id.into()]),
+            ),
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+async fn find_recipient(
+    txn: &DatabaseTransaction,
+    local_part: &str,
+    domain: &str,
+) -> Result<Option<i32>, DbErr> {
+    let builder = txn.get_database_backend();
+    txn.query_one(
+        builder.build(
+            Query::select()
+                .column(Recipient::Id)
+                .from(Recipient::Table)
+                .and_where(Expr::col(Recipient::LocalPart).eq(local_part))
+                .and_where(Expr::col(Recipient::Domain).eq(domain)),
+        ),
+    )
+    .await?
+    .map(|row| row.try_get("", "id"))
+    .transpose()
+}
+
+#[derive(DeriveIden)]
+#[allow(clippy::enum_variant_names)]
+enum IncomingMail {
+    Table,
+    IncomingMailId,
+    SenderLocalPart,
+    SenderDomain,
+    Recipients,
+    MessageId,
+    SendingHostName,
+    SendingIp,
+    TimeReceived,
+    TimeAccepted,
+    Status,
+}
+
+#[derive(DeriveIden)]
+enum Mail {
+    Table,
+    Id,
+    OldId,
+    SenderLocalPart,
+    SenderDomain,
+    MessageId,
+    SendingHostName,
+    SendingIp,
+    TimeReceived,
+    TimeAccepted,
+    Status,
+}
+
+#[derive(DeriveIden)]
+enum Recipient {
+    Table,
+    Id,
+    LocalPart,
+    Domain,
+}
+
+#[derive(DeriveIden)]
+enum MailRecipient {
+    Table,
+    MailId,
+    RecipientId,
+}
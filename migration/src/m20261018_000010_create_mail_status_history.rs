@@ -0,0 +1,134 @@
+use sea_orm_migration::{
+    prelude::*,
+    sea_orm::{ConnectionTrait, DatabaseBackend, Statement},
+};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let mut table = Table::create()
+            .table(MailStatusHistory::Table)
+            .if_not_exists()
+            .col(
+                ColumnDef::new(MailStatusHistory::Id)
+                    .integer()
+                    .not_null()
+                    .primary_key()
+                    .auto_increment(),
+            )
+            .col(
+                ColumnDef::new(MailStatusHistory::MailId)
+                    .integer()
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(MailStatusHistory::OldStatus)
+                    .tiny_integer()
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(MailStatusHistory::NewStatus)
+                    .tiny_integer()
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(MailStatusHistory::TimeChanged)
+                    .timestamp_with_time_zone()
+                    .not_null()
+                    .default(Expr::current_timestamp()),
+            )
+            .col(
+                ColumnDef::new(MailStatusHistory::Actor)
+                    .string_len(100)
+                    .not_null(),
+            )
+            .col(ColumnDef::new(MailStatusHistory::Note).string_len(255))
+            .foreign_key(
+                ForeignKey::create()
+                    .from(MailStatusHistory::Table, MailStatusHistory::OldStatus)
+                    .to(EmailStatus::Table, EmailStatus::Id)
+                    .on_delete(ForeignKeyAction::Restrict),
+            )
+            .foreign_key(
+                ForeignKey::create()
+                    .from(MailStatusHistory::Table, MailStatusHistory::NewStatus)
+                    .to(EmailStatus::Table, EmailStatus::Id)
+                    .on_delete(ForeignKeyAction::Restrict),
+            )
+            .clone();
+        // A partitioned mail.id isn't unique on its own, so there the rows
+        // are deleted along with their partition instead
+        if !mail_is_partitioned(manager).await? {
+            table.foreign_key(
+                ForeignKey::create()
+                    .from(MailStatusHistory::Table, MailStatusHistory::MailId)
+                    .to(Mail::Table, Mail::Id)
+                    .on_delete(ForeignKeyAction::Cascade),
+            );
+        }
+        manager.create_table(table).await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_mailstatushistory_mailid")
+                    .if_not_exists()
+                    .table(MailStatusHistory::Table)
+                    .col(MailStatusHistory::MailId)
+                    .col(MailStatusHistory::TimeChanged)
+                    .clone(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(MailStatusHistory::Table).clone())
+            .await?;
+
+        Ok(())
+    }
+}
+
+async fn mail_is_partitioned(manager: &SchemaManager<'_>) -> Result<bool, DbErr> {
+    if manager.get_database_backend() != DatabaseBackend::Postgres {
+        return Ok(false);
+    }
+    let partitioned = manager
+        .get_connection()
+        .query_one(Statement::from_string(
+            DatabaseBackend::Postgres,
+            "SELECT 1 FROM pg_partitioned_table WHERE partrelid = 'mail'::regclass",
+        ))
+        .await?;
+    Ok(partitioned.is_some())
+}
+
+#[derive(DeriveIden)]
+enum Mail {
+    Table,
+    Id,
+}
+
+#[derive(DeriveIden)]
+enum EmailStatus {
+    Table,
+    Id,
+}
+
+#[derive(DeriveIden)]
+enum MailStatusHistory {
+    Table,
+    Id,
+    MailId,
+    OldStatus,
+    NewStatus,
+    TimeChanged,
+    Actor,
+    Note,
+}
@@ -0,0 +1,96 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+// Must match entity::email_status::EmailStatus
+const SPF_ACCEPTED: (i16, &str) = (7, "spf_accepted");
+// For mail turned away by a DNS block list or SPF, which unlike mail denied
+// by hand is rejected again on every retry
+const REJECTED: (i16, &str) = (21, "rejected");
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Mail::Table)
+                    .add_column(ColumnDef::new(Mail::SpfResult).string_len(10))
+                    .clone(),
+            )
+            .await?;
+
+        // Finding earlier mail the sender domain's SPF record authorized
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_mail_senderdomain")
+                    .if_not_exists()
+                    .table(Mail::Table)
+                    .col(Mail::SenderDomain)
+                    .col(Mail::Status)
+                    .clone(),
+            )
+            .await?;
+
+        manager
+            .exec_stmt(
+                Query::insert()
+                    .into_table(EmailStatus::Table)
+                    .columns([EmailStatus::Id, EmailStatus::Name])
+                    .values_panic([SPF_ACCEPTED.0.into(), SPF_ACCEPTED.1.into()])
+                    .values_panic([REJECTED.0.into(), REJECTED.1.into()])
+                    .clone(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .exec_stmt(
+                Query::delete()
+                    .from_table(EmailStatus::Table)
+                    .and_where(Expr::col(EmailStatus::Id).is_in([SPF_ACCEPTED.0, REJECTED.0]))
+                    .clone(),
+            )
+            .await?;
+
+        manager
+            .drop_index(
+                Index::drop()
+                    .name("idx_mail_senderdomain")
+                    .table(Mail::Table)
+                    .clone(),
+            )
+            .await?;
+
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Mail::Table)
+                    .drop_column(Mail::SpfResult)
+                    .clone(),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum Mail {
+    Table,
+    SenderDomain,
+    Status,
+    SpfResult,
+}
+
+#[derive(DeriveIden)]
+enum EmailStatus {
+    Table,
+    Id,
+    Name,
+}
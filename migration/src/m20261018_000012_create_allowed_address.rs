@@ -0,0 +1,106 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+// Must match entity::email_status::EmailStatus::AllowlistAccepted
+const ALLOWLIST_ACCEPTED: (i16, &str) = (8, "allowlist_accepted");
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(AllowedAddress::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(AllowedAddress::Id)
+                            .integer()
+                            .not_null()
+                            .primary_key()
+                            .auto_increment(),
+                    )
+                    // Lowercase, so lookups can match on the string
+                    .col(
+                        ColumnDef::new(AllowedAddress::Address)
+                            .string_len(255)
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(AllowedAddress::Rule)
+                            .string_len(20)
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(AllowedAddress::Source)
+                            .string_len(255)
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(AllowedAddress::TimeAdded)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .clone(),
+            )
+            .await?;
+
+        // The same domain can be allowed both as sender and as recipient
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_allowed_address_rule")
+                    .table(AllowedAddress::Table)
+                    .col(AllowedAddress::Address)
+                    .col(AllowedAddress::Rule)
+                    .unique()
+                    .clone(),
+            )
+            .await?;
+
+        manager
+            .exec_stmt(
+                Query::insert()
+                    .into_table(EmailStatus::Table)
+                    .columns([EmailStatus::Id, EmailStatus::Name])
+                    .values_panic([ALLOWLIST_ACCEPTED.0.into(), ALLOWLIST_ACCEPTED.1.into()])
+                    .clone(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .exec_stmt(
+                Query::delete()
+                    .from_table(EmailStatus::Table)
+                    .and_where(Expr::col(EmailStatus::Id).eq(ALLOWLIST_ACCEPTED.0))
+                    .clone(),
+            )
+            .await?;
+
+        manager
+            .drop_table(Table::drop().table(AllowedAddress::Table).clone())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum AllowedAddress {
+    Table,
+    Id,
+    Address,
+    Rule,
+    Source,
+    TimeAdded,
+}
+
+#[derive(DeriveIden)]
+enum EmailStatus {
+    Table,
+    Id,
+    Name,
+}
@@ -0,0 +1,69 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // The rollups look for mail with attempts or status changes since
+        // their last update, so as to only work out those hours again
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_deliveryattempt_timeattempted")
+                    .if_not_exists()
+                    .table(DeliveryAttempt::Table)
+                    .col(DeliveryAttempt::TimeAttempted)
+                    .clone(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_mailstatushistory_timechanged")
+                    .if_not_exists()
+                    .table(MailStatusHistory::Table)
+                    .col(MailStatusHistory::TimeChanged)
+                    .clone(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_index(
+                Index::drop()
+                    .name("idx_mailstatushistory_timechanged")
+                    .table(MailStatusHistory::Table)
+                    .clone(),
+            )
+            .await?;
+
+        manager
+            .drop_index(
+                Index::drop()
+                    .name("idx_deliveryattempt_timeattempted")
+                    .table(DeliveryAttempt::Table)
+                    .clone(),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum DeliveryAttempt {
+    Table,
+    TimeAttempted,
+}
+
+#[derive(DeriveIden)]
+enum MailStatusHistory {
+    Table,
+    TimeChanged,
+}
@@ -0,0 +1,102 @@
+use sea_orm_migration::{
+    prelude::*,
+    sea_orm::{ConnectionTrait, DatabaseBackend, Statement},
+};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+// Each of these carries the time_received of its mail, so that once mail is
+// partitioned they can be partitioned on it too, and dropped along with it
+const TABLES: [&str; 4] = [
+    "mail_recipient",
+    "decision",
+    "delivery_attempt",
+    "mail_status_history",
+];
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+
+        for table in TABLES {
+            // Partitioning adds it itself if it comes first
+            if manager.has_column(table, "mail_time_received").await? {
+                continue;
+            }
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Alias::new(table))
+                        .add_column(
+                            ColumnDef::new(MailTimeReceived::MailTimeReceived)
+                                .timestamp_with_time_zone(),
+                        )
+                        .clone(),
+                )
+                .await?;
+            db.execute_unprepared(&format!(
+                "UPDATE {table} SET mail_time_received =
+                 (SELECT time_received FROM mail WHERE mail.id = {table}.mail_id)"
+            ))
+            .await?;
+            // SQLite can't make it NOT NULL afterwards
+            if manager.get_database_backend() != DatabaseBackend::Sqlite {
+                manager
+                    .alter_table(
+                        Table::alter()
+                            .table(Alias::new(table))
+                            .modify_column(
+                                ColumnDef::new(MailTimeReceived::MailTimeReceived)
+                                    .timestamp_with_time_zone()
+                                    .not_null(),
+                            )
+                            .clone(),
+                    )
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for table in TABLES {
+            // A partition key can't be dropped; the table goes with the
+            // migration that created it
+            if is_partitioned(manager, table).await? {
+                continue;
+            }
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Alias::new(table))
+                        .drop_column(MailTimeReceived::MailTimeReceived)
+                        .clone(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+async fn is_partitioned(manager: &SchemaManager<'_>, table: &str) -> Result<bool, DbErr> {
+    if manager.get_database_backend() != DatabaseBackend::Postgres {
+        return Ok(false);
+    }
+    let partitioned = manager
+        .get_connection()
+        .query_one(Statement::from_string(
+            DatabaseBackend::Postgres,
+            format!("SELECT 1 FROM pg_partitioned_table WHERE partrelid = '{table}'::regclass"),
+        ))
+        .await?;
+    Ok(partitioned.is_some())
+}
+
+#[derive(DeriveIden)]
+enum MailTimeReceived {
+    MailTimeReceived,
+}
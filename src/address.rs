@@ -0,0 +1,93 @@
+use std::{error, fmt, net::IpAddr};
+
+/// The one form client addresses are stored and compared in: IPv4-mapped IPv6
+/// addresses become plain IPv4, so `::ffff:192.0.2.1` and `192.0.2.1` match.
+#[must_use]
+pub fn normalize_ip(address: IpAddr) -> IpAddr {
+    address.to_canonical()
+}
+
+/// Parse an address as reported by the MTA or stored in the database,
+/// dropping any IPv6 zone ID (`fe80::1%eth0`) and normalizing the rest.
+#[must_use]
+pub fn parse_ip(address: &str) -> Option<IpAddr> {
+    let address = address
+        .split_once('%')
+        .map_or(address, |(address, _zone)| address);
+    address.parse().ok().map(normalize_ip)
+}
+
+/// RFC 5321 section 4.5.3.1 limits, which the recipient columns are sized to
+pub const MAX_LOCAL_PART_LENGTH: usize = 64;
+pub const MAX_DOMAIN_LENGTH: usize = 255;
+
+/// An address with a part over the RFC 5321 limits. It isn't stored, as
+/// cutting it short would make it the same as other addresses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TooLong {
+    LocalPart(usize),
+    Domain(usize),
+}
+
+impl fmt::Display for TooLong {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::LocalPart(length) => write!(
+                f,
+                "Local part is {} bytes long, over the limit of {}",
+                length, MAX_LOCAL_PART_LENGTH
+            ),
+            Self::Domain(length) => write!(
+                f,
+                "Domain is {} bytes long, over the limit of {}",
+                length, MAX_DOMAIN_LENGTH
+            ),
+        }
+    }
+}
+
+impl error::Error for TooLong {}
+
+/// Split an envelope address (without the angle brackets) into its local part
+/// and domain as stored, by `normalize_email`. Addresses without a domain,
+/// like `postmaster`, get an empty one.
+pub fn split_email(address: &str, lowercase_local_part: bool) -> Result<(String, String), TooLong> {
+    let (local_part, domain) = address.rsplit_once('@').unwrap_or((address, ""));
+    normalize_email(local_part, domain, lowercase_local_part)
+}
+
+/// An address's local part and domain as stored: the domain is always
+/// lowercased, the local part only if asked, as strictly it's
+/// case-sensitive. Parts over the RFC 5321 limits, which some senders
+/// ignore, are refused.
+pub fn normalize_email(
+    local_part: &str,
+    domain: &str,
+    lowercase_local_part: bool,
+) -> Result<(String, String), TooLong> {
+    let local_part = if lowercase_local_part {
+        local_part.to_lowercase()
+    } else {
+        local_part.to_string()
+    };
+    let domain = domain.to_lowercase();
+    if local_part.len() > MAX_LOCAL_PART_LENGTH {
+        return Err(TooLong::LocalPart(local_part.len()));
+    }
+    if domain.len() > MAX_DOMAIN_LENGTH {
+        return Err(TooLong::Domain(domain.len()));
+    }
+    Ok((local_part, domain))
+}
+
+/// Whether host name `name` is `domain` or somewhere under it, ignoring case
+/// and any trailing dot
+#[must_use]
+pub fn is_same_or_under(name: &str, domain: &str) -> bool {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    name == domain
+        || name
+            .strip_suffix(domain.as_str())
+            .is_some_and(|prefix| prefix.ends_with('.'))
+}
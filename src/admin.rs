@@ -0,0 +1,215 @@
+use chrono::{DateTime, Utc};
+use entity::{
+    decision, delivery_attempt, mail, mail_status_history,
+    prelude::{
+        DecisionEntity, DeliveryAttemptEntity, MailEntity, MailStatusHistoryEntity, RecipientEntity,
+    },
+    rollup_dimension::RollupDimension,
+};
+use sea_orm::{
+    ActiveEnum, ColumnTrait, DatabaseConnection, DbErr, EntityTrait, ModelTrait, QueryFilter,
+    QueryOrder,
+};
+
+use crate::rollup::{self, Period, Rollup};
+
+/// Print everything stored about a message, looked up by its Message-Id
+/// header (including the angle brackets).
+pub async fn show(db: &DatabaseConnection, message_id: &str) -> Result<(), DbErr> {
+    let Some(mail) = MailEntity::find()
+        .filter(mail::Column::MessageId.eq(message_id))
+        .one(db)
+        .await?
+    else {
+        println!("No message with Message-Id {}", message_id);
+        return Ok(());
+    };
+
+    println!("Message-Id:    {}", mail.message_id);
+    println!(
+        "Sender:        {}@{}",
+        mail.sender_local_part, mail.sender_domain
+    );
+    println!(
+        "Client:        {} [{}]",
+        display(&mail.sending_host_name),
+        mail.sending_ip
+    );
+    println!("HELO:          {}", display(&mail.helo_name));
+    println!("Daemon:        {}", display(&mail.daemon_name));
+    println!("Queue ID:      {}", display(&mail.queue_id));
+    println!("Auth user:     {}", display(&mail.auth_user));
+    println!(
+        "TLS:           {} {}",
+        display(&mail.tls_version),
+        display(&mail.tls_cipher)
+    );
+    println!("Size:          {}", display(&mail.message_size));
+    println!(
+        "SPF:           {}",
+        display(&mail.spf_result.as_ref().map(ActiveEnum::to_value))
+    );
+    println!("Received:      {}", mail.time_received);
+    println!("Accepted:      {}", display(&mail.time_accepted));
+    println!("Status:        {:?}", mail.status);
+
+    println!("Recipients:");
+    for recipient in mail.find_related(RecipientEntity).all(db).await? {
+        println!("  {}", recipient.address());
+    }
+
+    println!("Delivery attempts:");
+    for attempt in mail
+        .find_related(DeliveryAttemptEntity)
+        .order_by_asc(delivery_attempt::Column::TimeAttempted)
+        .all(db)
+        .await?
+    {
+        println!(
+            "  {}  {} [{}]  {:?}",
+            attempt.time_attempted,
+            display(&attempt.sending_host_name),
+            attempt.sending_ip,
+            attempt.status
+        );
+    }
+
+    println!("Decisions:");
+    for decision in mail
+        .find_related(DecisionEntity)
+        .order_by_asc(decision::Column::TimeDecided)
+        .all(db)
+        .await?
+    {
+        println!(
+            "  {}  {:?}  {:?}  {}",
+            decision.time_decided,
+            decision.status,
+            decision.rule,
+            display(&decision.detail)
+        );
+    }
+
+    println!("Status changes:");
+    for change in mail
+        .find_related(MailStatusHistoryEntity)
+        .order_by_asc(mail_status_history::Column::TimeChanged)
+        .order_by_asc(mail_status_history::Column::Id)
+        .all(db)
+        .await?
+    {
+        println!(
+            "  {}  {:?} -> {:?}  {}  {}",
+            change.time_changed,
+            change.old_status,
+            change.new_status,
+            change.actor,
+            display(&change.note)
+        );
+    }
+
+    Ok(())
+}
+
+/// Print the `period` rollups from `since` on, broken down by `dimension`,
+/// as an aligned table or as CSV
+pub async fn report(
+    db: &DatabaseConnection,
+    period: Period,
+    dimension: RollupDimension,
+    since: DateTime<Utc>,
+    csv: bool,
+) -> Result<(), DbErr> {
+    let rollups = rollup::load(db, period, dimension, since).await?;
+
+    if csv {
+        return write_csv(&rollups).map_err(|e| DbErr::Custom(format!("Writing CSV: {}", e)));
+    }
+
+    if rollups.is_empty() {
+        println!("No statistics for this period yet");
+        return Ok(());
+    }
+    let name_width = rollups
+        .iter()
+        .map(|rollup| rollup.name.len())
+        .max()
+        .unwrap_or(0)
+        .max("Name".len());
+    println!(
+        "{:<16}  {:<name_width$}  {:>8}  {:>10}  {:>13}  {:>12}",
+        "Period", "Name", "Messages", "Greylisted", "Never retried", "Median retry"
+    );
+    for rollup in &rollups {
+        let period_start = match period {
+            Period::Hourly => rollup.period_start.format("%Y-%m-%d %H:00"),
+            Period::Daily => rollup.period_start.format("%Y-%m-%d"),
+        };
+        let never_retried = match never_retried_percent(rollup) {
+            Some(percent) => format!("{} ({:.0}%)", rollup.never_retried, percent),
+            None => "-".to_string(),
+        };
+        println!(
+            "{:<16}  {:<name_width$}  {:>8}  {:>10}  {:>13}  {:>12}",
+            period_start.to_string(),
+            rollup.name,
+            rollup.messages,
+            rollup.greylisted,
+            never_retried,
+            display(&rollup.median_retry_seconds.map(duration)),
+        );
+    }
+
+    Ok(())
+}
+
+fn never_retried_percent(rollup: &Rollup) -> Option<f64> {
+    (rollup.greylisted > 0)
+        .then(|| f64::from(rollup.never_retried) * 100.0 / f64::from(rollup.greylisted))
+}
+
+fn duration(seconds: i64) -> String {
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m {:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {:02}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+fn write_csv(rollups: &[Rollup]) -> Result<(), csv::Error> {
+    let mut writer = csv::Writer::from_writer(std::io::stdout());
+    writer.write_record([
+        "period_start",
+        "dimension",
+        "name",
+        "messages",
+        "greylisted",
+        "never_retried",
+        "never_retried_percent",
+        "median_retry_seconds",
+    ])?;
+    for rollup in rollups {
+        writer.write_record([
+            rollup.period_start.to_rfc3339(),
+            rollup.dimension.to_value(),
+            rollup.name.clone(),
+            rollup.messages.to_string(),
+            rollup.greylisted.to_string(),
+            rollup.never_retried.to_string(),
+            never_retried_percent(rollup)
+                .map_or(String::new(), |percent| format!("{:.1}", percent)),
+            rollup
+                .median_retry_seconds
+                .map_or(String::new(), |seconds| seconds.to_string()),
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn display<T: ToString>(value: &Option<T>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => "-".to_string(),
+    }
+}
@@ -0,0 +1,104 @@
+//! Sender domains and recipients that are never greylisted, from
+//! `[greylist]` and the `allowed_address` table. Anyone can put any domain in
+//! MAIL FROM, so by default sender domains only count if the client also
+//! passes their SPF check. Recipients only count if every recipient of the
+//! message is allowed. Their local parts are compared the way recipients are
+//! stored, so only ignoring case with `lowercase_local_part`.
+
+use entity::spf_result::SpfResult;
+
+use crate::{
+    address::{self, TooLong},
+    settings::Settings,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Allowlist {
+    /// Lowercase, without leading or trailing dots
+    pub sender_domains: Vec<String>,
+    pub require_spf: bool,
+    /// In any of the forms `recipient_patterns` gives, normalized by
+    /// `normalize_recipient`
+    pub recipients: Vec<String>,
+}
+
+impl Allowlist {
+    #[must_use]
+    pub fn from_settings(config: &Settings) -> Self {
+        Self {
+            sender_domains: config.get_allow_sender_domains(),
+            require_spf: config.get_allow_sender_domains_require_spf(),
+            recipients: config.get_allow_recipients(),
+        }
+    }
+
+    /// Whether a sender domain on an allow list is to be believed
+    #[must_use]
+    pub fn sender_qualifies(&self, spf_result: Option<SpfResult>) -> bool {
+        !self.require_spf || spf_result == Some(SpfResult::Pass)
+    }
+
+    /// The configured entry the sender domain matches, if any
+    #[must_use]
+    pub fn sender(&self, domain: &str) -> Option<&str> {
+        first_listed(&self.sender_domains, domain_patterns(domain))
+    }
+
+    /// The configured entry the recipient matches, if any
+    #[must_use]
+    pub fn recipient(&self, local_part: &str, domain: &str) -> Option<&str> {
+        first_listed(&self.recipients, recipient_patterns(local_part, domain))
+    }
+}
+
+fn first_listed(entries: &[String], patterns: Vec<String>) -> Option<&str> {
+    patterns.into_iter().find_map(|pattern| {
+        entries
+            .iter()
+            .find(|entry| **entry == pattern)
+            .map(String::as_str)
+    })
+}
+
+/// The domain and every domain above it, most specific first
+#[must_use]
+pub fn domain_patterns(domain: &str) -> Vec<String> {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    let mut patterns = vec![];
+    let mut rest = domain.as_str();
+    while !rest.is_empty() {
+        patterns.push(rest.to_string());
+        rest = rest.split_once('.').map_or("", |(_label, parent)| parent);
+    }
+    patterns
+}
+
+/// Every allow list entry that would match the recipient, as stored, most
+/// specific first: the address, the local part at any domain
+/// (`postmaster@`), then the domain and those above it
+#[must_use]
+pub fn recipient_patterns(local_part: &str, domain: &str) -> Vec<String> {
+    let domains = domain_patterns(domain);
+    let mut patterns = vec![];
+    if let Some(domain) = domains.first() {
+        patterns.push(format!("{}@{}", local_part, domain));
+    }
+    patterns.push(format!("{}@", local_part));
+    patterns.extend(domains);
+    patterns
+}
+
+/// A recipient allow list entry, an address, a local part followed by `@`
+/// or a domain, normalized the way recipients are stored so that it matches
+/// the patterns `recipient_patterns` gives for them
+pub fn normalize_recipient(entry: &str, lowercase_local_part: bool) -> Result<String, TooLong> {
+    let entry = entry.trim_matches('.');
+    match entry.rsplit_once('@') {
+        Some((local_part, domain)) => {
+            let (local_part, domain) =
+                address::normalize_email(local_part, domain, lowercase_local_part)?;
+            Ok(format!("{}@{}", local_part, domain))
+        }
+        None => address::normalize_email("", entry, lowercase_local_part).map(|(_, domain)| domain),
+    }
+}
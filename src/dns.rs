@@ -0,0 +1,161 @@
+//! DNS lookups, behind a trait so the checks built on them can be tested
+//! against a stub instead of the network.
+
+use std::{
+    error, fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time,
+};
+
+use async_trait::async_trait;
+use hickory_resolver::{
+    config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
+    error::{ResolveError, ResolveErrorKind},
+    proto::rr::Name,
+    system_conf, TokioAsyncResolver,
+};
+
+use crate::settings::Settings;
+
+/// A lookup that got no usable answer; the name may or may not exist
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsError(pub String);
+
+impl fmt::Display for DnsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl error::Error for DnsError {}
+
+#[async_trait]
+pub trait Resolver: fmt::Debug + Send + Sync {
+    /// The A records of `name`, none if it doesn't exist or has none
+    async fn lookup_ipv4(&self, name: &str) -> Result<Vec<Ipv4Addr>, DnsError>;
+
+    /// The AAAA records of `name`
+    async fn lookup_ipv6(&self, name: &str) -> Result<Vec<Ipv6Addr>, DnsError>;
+
+    /// The TXT records of `name`, each with its strings joined together
+    async fn lookup_txt(&self, name: &str) -> Result<Vec<String>, DnsError>;
+
+    /// The exchanges of the MX records of `name`, most preferred first
+    async fn lookup_mx(&self, name: &str) -> Result<Vec<String>, DnsError>;
+
+    /// The names the PTR records for `address` point at
+    async fn lookup_ptr(&self, address: IpAddr) -> Result<Vec<String>, DnsError>;
+}
+
+/// Resolves through the configured name servers, or those in
+/// /etc/resolv.conf
+pub struct HickoryResolver {
+    resolver: TokioAsyncResolver,
+}
+
+impl fmt::Debug for HickoryResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HickoryResolver").finish_non_exhaustive()
+    }
+}
+
+impl HickoryResolver {
+    pub fn new(config: &Settings) -> Result<Self, DnsError> {
+        let nameservers = config.get_dns_nameservers();
+        let (resolver_config, mut options) = if nameservers.is_empty() {
+            system_conf::read_system_conf().map_err(|e| DnsError(e.to_string()))?
+        } else {
+            let mut group = NameServerConfigGroup::new();
+            for nameserver in nameservers {
+                group.merge(NameServerConfigGroup::from_ips_clear(
+                    &[nameserver.ip()],
+                    nameserver.port(),
+                    true,
+                ));
+            }
+            (
+                ResolverConfig::from_parts(None, vec![], group),
+                ResolverOpts::default(),
+            )
+        };
+        options.timeout = time::Duration::from_secs(config.get_dns_timeout_seconds());
+        options.attempts = 2;
+
+        Ok(Self {
+            resolver: TokioAsyncResolver::tokio(resolver_config, options),
+        })
+    }
+}
+
+// Names are always looked up as given, never under the search domains
+fn absolute(name: &str) -> String {
+    if name.ends_with('.') {
+        name.to_string()
+    } else {
+        format!("{}.", name)
+    }
+}
+
+// Without the trailing dot, as names are written everywhere else
+fn relative(name: &Name) -> String {
+    name.to_utf8().trim_end_matches('.').to_string()
+}
+
+fn no_records(e: &ResolveError) -> bool {
+    matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. })
+}
+
+#[async_trait]
+impl Resolver for HickoryResolver {
+    async fn lookup_ipv4(&self, name: &str) -> Result<Vec<Ipv4Addr>, DnsError> {
+        match self.resolver.ipv4_lookup(absolute(name)).await {
+            Ok(lookup) => Ok(lookup.iter().map(|a| a.0).collect()),
+            Err(e) if no_records(&e) => Ok(vec![]),
+            Err(e) => Err(DnsError(e.to_string())),
+        }
+    }
+
+    async fn lookup_ipv6(&self, name: &str) -> Result<Vec<Ipv6Addr>, DnsError> {
+        match self.resolver.ipv6_lookup(absolute(name)).await {
+            Ok(lookup) => Ok(lookup.iter().map(|aaaa| aaaa.0).collect()),
+            Err(e) if no_records(&e) => Ok(vec![]),
+            Err(e) => Err(DnsError(e.to_string())),
+        }
+    }
+
+    async fn lookup_txt(&self, name: &str) -> Result<Vec<String>, DnsError> {
+        match self.resolver.txt_lookup(absolute(name)).await {
+            Ok(lookup) => Ok(lookup
+                .iter()
+                .map(|txt| {
+                    txt.txt_data()
+                        .iter()
+                        .map(|data| String::from_utf8_lossy(data))
+                        .collect()
+                })
+                .collect()),
+            Err(e) if no_records(&e) => Ok(vec![]),
+            Err(e) => Err(DnsError(e.to_string())),
+        }
+    }
+
+    async fn lookup_mx(&self, name: &str) -> Result<Vec<String>, DnsError> {
+        match self.resolver.mx_lookup(absolute(name)).await {
+            Ok(lookup) => {
+                let mut exchanges = lookup.iter().collect::<Vec<_>>();
+                exchanges.sort_by_key(|mx| mx.preference());
+                Ok(exchanges.iter().map(|mx| relative(mx.exchange())).collect())
+            }
+            Err(e) if no_records(&e) => Ok(vec![]),
+            Err(e) => Err(DnsError(e.to_string())),
+        }
+    }
+
+    async fn lookup_ptr(&self, address: IpAddr) -> Result<Vec<String>, DnsError> {
+        match self.resolver.reverse_lookup(address).await {
+            Ok(lookup) => Ok(lookup.iter().map(|ptr| relative(&ptr.0)).collect()),
+            Err(e) if no_records(&e) => Ok(vec![]),
+            Err(e) => Err(DnsError(e.to_string())),
+        }
+    }
+}
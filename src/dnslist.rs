@@ -0,0 +1,191 @@
+//! DNS block and allow lists. The client address is looked up on every
+//! configured zone at once when it connects; the weights of the block lists
+//! it's on, less those of the allow lists, make up a score that decides
+//! whether it skips greylisting, waits longer, or is rejected.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+    sync::{Arc, Mutex},
+    time,
+};
+
+use futures::future::join_all;
+use tracing::{debug, warn};
+
+use crate::{
+    address,
+    dns::Resolver,
+    settings::{DnsListKind, DnsListZone, Settings},
+};
+
+// At most this many answers are remembered. Once there are, expired ones are
+// cleared out, and if that's not enough, the quarter closest to expiring
+const CACHE_SIZE: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Thresholds {
+    pub allow: i32,
+    pub delay: i32,
+    pub reject: Option<i32>,
+}
+
+impl Thresholds {
+    #[must_use]
+    pub fn from_settings(config: &Settings) -> Self {
+        Self {
+            allow: config.get_dns_list_allow_score(),
+            delay: config.get_dns_list_delay_score(),
+            reject: config.get_dns_list_reject_score(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// Listed well enough on allow lists to skip greylisting
+    Allow,
+    /// Not listed, or not enough to make a difference
+    Neutral,
+    /// Greylist for longer, even if known good
+    Delay,
+    Reject,
+}
+
+/// What the lists say about one client address
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Listings {
+    pub score: i32,
+    /// The zones the address is on, in the order configured
+    pub zones: Vec<String>,
+}
+
+impl Listings {
+    #[must_use]
+    pub fn verdict(&self, thresholds: &Thresholds) -> Verdict {
+        if thresholds.reject.is_some_and(|reject| self.score >= reject) {
+            Verdict::Reject
+        } else if self.score >= thresholds.delay {
+            Verdict::Delay
+        } else if self.score <= -thresholds.allow {
+            Verdict::Allow
+        } else {
+            Verdict::Neutral
+        }
+    }
+
+    /// For the decision detail, e.g. `score 2 on zen.spamhaus.org`
+    #[must_use]
+    pub fn describe(&self) -> String {
+        format!("score {} on {}", self.score, self.zones.join(", "))
+    }
+}
+
+#[derive(Debug)]
+pub struct DnsLists {
+    resolver: Arc<dyn Resolver>,
+    zones: Vec<DnsListZone>,
+    cache_time: time::Duration,
+    // Query name to whether it's listed, and until when that's remembered
+    cache: Mutex<HashMap<String, (bool, time::Instant)>>,
+}
+
+impl DnsLists {
+    #[must_use]
+    pub fn new(
+        resolver: Arc<dyn Resolver>,
+        zones: Vec<DnsListZone>,
+        cache_time: time::Duration,
+    ) -> Self {
+        Self {
+            resolver,
+            zones,
+            cache_time,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Look the address up on every zone concurrently. Zones that fail to
+    /// answer count as not listing it.
+    pub async fn check(&self, address: IpAddr) -> Listings {
+        let address = address::normalize_ip(address);
+        let lookups = self.zones.iter().map(|zone| async move {
+            let listed = self.is_listed(&query_name(address, &zone.zone)).await;
+            (zone, listed)
+        });
+
+        let mut listings = Listings::default();
+        for (zone, listed) in join_all(lookups).await {
+            if !listed {
+                continue;
+            }
+            let weight = zone.weight.unwrap_or(1);
+            listings.score += match zone.kind {
+                DnsListKind::Allow => -weight,
+                DnsListKind::Block => weight,
+            };
+            listings.zones.push(zone.zone.clone());
+        }
+        debug!(%address, ?listings, "DNS list lookups done");
+        listings
+    }
+
+    async fn is_listed(&self, name: &str) -> bool {
+        let now = time::Instant::now();
+        if let Some((listed, until)) = self.cache.lock().expect("Poisoned").get(name) {
+            if *until > now {
+                return *listed;
+            }
+        }
+
+        let listed = match self.resolver.lookup_ipv4(name).await {
+            Ok(addresses) => addresses.into_iter().any(is_listing),
+            Err(e) => {
+                // Not remembered, so the next connection asks again
+                warn!("Unable to look up {}: {}", name, e);
+                return false;
+            }
+        };
+
+        let mut cache = self.cache.lock().expect("Poisoned");
+        if cache.len() >= CACHE_SIZE {
+            cache.retain(|_, (_, until)| *until > now);
+        }
+        if cache.len() >= CACHE_SIZE {
+            // Everything is remembered for as long, so these are the oldest
+            let mut untils: Vec<_> = cache.values().map(|(_, until)| *until).collect();
+            let (_, &mut cutoff, _) = untils.select_nth_unstable(CACHE_SIZE / 4);
+            cache.retain(|_, (_, until)| *until > cutoff);
+        }
+        cache.insert(name.to_string(), (listed, now + self.cache_time));
+        listed
+    }
+}
+
+/// `2.0.0.127.zone` for 127.0.0.2, and the reversed nibbles for IPv6
+#[must_use]
+pub fn query_name(address: IpAddr, zone: &str) -> String {
+    let reversed = match address {
+        IpAddr::V4(address) => address
+            .octets()
+            .iter()
+            .rev()
+            .map(u8::to_string)
+            .collect::<Vec<_>>(),
+        IpAddr::V6(address) => address
+            .octets()
+            .iter()
+            .rev()
+            .flat_map(|octet| [octet & 0xf, octet >> 4])
+            .map(|nibble| format!("{:x}", nibble))
+            .collect(),
+    };
+    format!("{}.{}", reversed.join("."), zone.trim_end_matches('.'))
+}
+
+// Listings are answered with 127.0.0.0/8; some lists answer with
+// 127.255.255.0/24 to say the query was refused, which isn't a listing
+fn is_listing(answer: Ipv4Addr) -> bool {
+    let [first, second, third, _] = answer.octets();
+    first == 127 && !(second == 255 && third == 255)
+}
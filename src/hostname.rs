@@ -0,0 +1,162 @@
+//! Rules on the client's host name. Its reverse DNS is looked up when it
+//! connects and only trusted if the name resolves back to the client
+//! (forward-confirmed reverse DNS). Clients under trusted suffixes skip
+//! greylisting; those with no reverse DNS or a name that looks like a
+//! dynamically assigned address wait longer.
+
+use std::net::IpAddr;
+
+use tracing::{debug, warn};
+
+use crate::{address, dns::Resolver, settings::Settings};
+
+// Names beyond these aren't checked for pointing back at the client
+const MAX_PTR_RECORDS: usize = 10;
+
+/// What the client's reverse DNS says
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReverseDns {
+    /// Resolves back to the client
+    Verified(String),
+    /// The first name, which doesn't resolve back to the client
+    Unverified(String),
+    /// No PTR records at all
+    Missing,
+    /// The lookup failed, so nothing is known
+    Failed,
+}
+
+impl ReverseDns {
+    /// Look up the names of `address`, and which of them resolves back to it
+    pub async fn look_up(resolver: &dyn Resolver, address: IpAddr) -> Self {
+        let address = address::normalize_ip(address);
+        let names = match resolver.lookup_ptr(address).await {
+            Ok(names) => names,
+            Err(e) => {
+                warn!("Unable to look up the name of {}: {}", address, e);
+                return Self::Failed;
+            }
+        };
+
+        for name in names.iter().take(MAX_PTR_RECORDS) {
+            let addresses = match address {
+                IpAddr::V4(_) => resolver
+                    .lookup_ipv4(name)
+                    .await
+                    .map(|addresses| addresses.into_iter().map(IpAddr::V4).collect()),
+                IpAddr::V6(_) => resolver
+                    .lookup_ipv6(name)
+                    .await
+                    .map(|addresses| addresses.into_iter().map(IpAddr::V6).collect::<Vec<_>>()),
+            };
+            match addresses {
+                Ok(addresses) if addresses.contains(&address) => {
+                    debug!(%address, name, "Reverse DNS confirmed");
+                    return Self::Verified(name.clone());
+                }
+                Ok(_) => (),
+                Err(e) => debug!("Unable to look up {}: {}", name, e),
+            }
+        }
+        match names.into_iter().next() {
+            Some(name) => Self::Unverified(name),
+            None => Self::Missing,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// Under a trusted suffix, with the verified name
+    Trusted(String),
+    /// Greylist for longer, with the name that looks dynamic
+    Dynamic(String),
+    /// Greylist for longer
+    NoReverseDns,
+    Neutral,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rules {
+    /// Lowercase, without leading or trailing dots
+    pub trusted_suffixes: Vec<String>,
+    pub dynamic_keywords: Vec<String>,
+    pub delay_dynamic: bool,
+    pub delay_no_reverse_dns: bool,
+    pub delay_greylist_time_seconds: i64,
+}
+
+impl Rules {
+    #[must_use]
+    pub fn from_settings(config: &Settings) -> Self {
+        Self {
+            trusted_suffixes: config.get_hostname_trusted_suffixes(),
+            dynamic_keywords: config.get_hostname_dynamic_keywords(),
+            delay_dynamic: config.get_hostname_delay_dynamic(),
+            delay_no_reverse_dns: config.get_hostname_delay_no_reverse_dns(),
+            delay_greylist_time_seconds: config.get_hostname_delay_greylist_time_seconds(),
+        }
+    }
+
+    #[must_use]
+    pub fn verdict(&self, address: IpAddr, reverse_dns: &ReverseDns) -> Verdict {
+        match reverse_dns {
+            ReverseDns::Verified(name)
+                if self
+                    .trusted_suffixes
+                    .iter()
+                    .any(|suffix| address::is_same_or_under(name, suffix)) =>
+            {
+                Verdict::Trusted(name.clone())
+            }
+            // Anyone can make their name look dynamic, so it needn't be verified
+            ReverseDns::Verified(name) | ReverseDns::Unverified(name)
+                if self.delay_dynamic && self.looks_dynamic(name, address) =>
+            {
+                Verdict::Dynamic(name.clone())
+            }
+            ReverseDns::Missing if self.delay_no_reverse_dns => Verdict::NoReverseDns,
+            _ => Verdict::Neutral,
+        }
+    }
+
+    /// Whether `name` has the client's address in it, like
+    /// `host-192-0-2-1.isp.example` or `1.2.0.192.isp.example`, or one of the
+    /// keywords as a word of a label below the domain itself, like `pool-17`
+    /// or `dyn12`. Words are split at hyphens and digits, so `liverpool` or
+    /// `dynamo` don't count.
+    #[must_use]
+    pub fn looks_dynamic(&self, name: &str, address: IpAddr) -> bool {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let labels = name.split('.').collect::<Vec<_>>();
+        let keyword_found = labels
+            .iter()
+            .take(labels.len().saturating_sub(2))
+            .flat_map(|label| label.split(|c: char| !c.is_ascii_alphabetic()))
+            .any(|word| self.dynamic_keywords.iter().any(|keyword| keyword == word));
+        keyword_found || contains_address(&name, address::normalize_ip(address))
+    }
+}
+
+// The four octets in a row, either way round, in decimal with any
+// separators, or all together in hex
+fn contains_address(name: &str, address: IpAddr) -> bool {
+    let IpAddr::V4(address) = address else {
+        return false;
+    };
+    let octets = address.octets().map(|octet| Some(u16::from(octet)));
+    let numbers = name
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|number| !number.is_empty())
+        .map(|number| number.parse::<u16>().ok())
+        .collect::<Vec<_>>();
+    let reversed = {
+        let mut reversed = octets;
+        reversed.reverse();
+        reversed
+    };
+    numbers
+        .windows(4)
+        .any(|window| window == octets || window == reversed)
+        || name.contains(&format!("{:08x}", u32::from(address)))
+}
@@ -0,0 +1,252 @@
+//! Import of the state kept by postgrey and sqlgrey, so switching to this
+//! milter doesn't greylist every client again. Networks go into
+//! `allowed_client`, and host names and recipients into `allowed_address`;
+//! anything that can't be mapped onto them is reported back as skipped.
+
+use std::net::{IpAddr, Ipv4Addr};
+
+use entity::decision_rule::DecisionRule;
+use ipnet::IpNet;
+use sea_orm::{ConnectionTrait, DatabaseConnection, DbErr, Statement};
+
+use crate::{address, allowlist, store::GreylistStore};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PostgreyList {
+    /// `whitelist_clients`: client addresses, networks, hostnames and
+    /// hostname patterns
+    Clients,
+    /// `whitelist_recipients`: addresses, domains and patterns
+    Recipients,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct LegacySummary {
+    pub imported: usize,
+    /// Already allowed, from this or an earlier import
+    pub existing: usize,
+    pub skipped: Vec<Skipped>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Skipped {
+    pub entry: String,
+    pub reason: &'static str,
+}
+
+impl LegacySummary {
+    fn skip(&mut self, entry: impl Into<String>, reason: &'static str) {
+        self.skipped.push(Skipped {
+            entry: entry.into(),
+            reason,
+        });
+    }
+
+    async fn add(
+        &mut self,
+        store: &dyn GreylistStore,
+        network: IpNet,
+        sender_domain: &str,
+        rule: DecisionRule,
+        source: &str,
+    ) -> Result<(), DbErr> {
+        if store
+            .add_allowed_client(network, sender_domain.to_string(), rule, source.to_string())
+            .await?
+        {
+            self.imported += 1;
+        } else {
+            self.existing += 1;
+        }
+        Ok(())
+    }
+
+    async fn add_address(
+        &mut self,
+        store: &dyn GreylistStore,
+        address: &str,
+        rule: DecisionRule,
+        source: &str,
+    ) -> Result<(), DbErr> {
+        if store
+            .add_allowed_address(address.to_string(), rule, source.to_string())
+            .await?
+        {
+            self.imported += 1;
+        } else {
+            self.existing += 1;
+        }
+        Ok(())
+    }
+}
+
+/// An address, a CIDR network, or the leading octets of an IPv4 address as
+/// both postgrey and sqlgrey's class C mode write them (`192.0.2` or
+/// `192.0.2.`)
+#[must_use]
+pub fn parse_network(entry: &str) -> Option<IpNet> {
+    if let Some(address) = address::parse_ip(entry) {
+        return Some(IpNet::from(address));
+    }
+    if let Ok(network) = entry.parse::<IpNet>() {
+        return Some(network.trunc());
+    }
+
+    let octets = entry
+        .strip_suffix('.')
+        .unwrap_or(entry)
+        .split('.')
+        .map(|octet| octet.parse::<u8>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    if octets.len() > 4 {
+        return None;
+    }
+    let mut address = [0; 4];
+    address[..octets.len()].copy_from_slice(&octets);
+    IpNet::new(
+        IpAddr::V4(Ipv4Addr::from(address)),
+        u8::try_from(octets.len() * 8).expect("At most 32"),
+    )
+    .ok()
+}
+
+/// A host name or domain as postgrey lists clients, which also stands for
+/// the names under it: lowercase, without leading or trailing dots
+#[must_use]
+pub fn parse_hostname(entry: &str) -> Option<String> {
+    let name = entry.trim_matches('.').to_ascii_lowercase();
+    let valid = !name.is_empty()
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        });
+    valid.then_some(name)
+}
+
+/// Read one of postgrey's whitelist files. Client addresses and networks
+/// are carried over as whitelisted networks, client host names and domains
+/// as trusted host names, and recipient addresses, local parts and domains
+/// as allowed recipients, normalized as the milter stores recipients;
+/// `source` is recorded with each of them. Patterns can't be carried over.
+pub async fn import_postgrey(
+    store: &dyn GreylistStore,
+    list: PostgreyList,
+    contents: &str,
+    source: &str,
+    lowercase_local_part: bool,
+) -> Result<LegacySummary, DbErr> {
+    let mut summary = LegacySummary::default();
+    for line in contents.lines() {
+        let entry = line.split_once('#').map_or(line, |(entry, _comment)| entry);
+        let entry = entry.trim();
+        if entry.is_empty() {
+            continue;
+        }
+
+        let is_pattern = entry.len() > 1 && entry.starts_with('/') && entry.ends_with('/');
+        if list == PostgreyList::Recipients {
+            if is_pattern {
+                summary.skip(entry, "recipient patterns aren't supported");
+            } else if entry.starts_with('@') {
+                summary.skip(entry, "not an address, local part or domain");
+            } else if let Ok(recipient) =
+                allowlist::normalize_recipient(entry, lowercase_local_part)
+            {
+                summary
+                    .add_address(store, &recipient, DecisionRule::AllowedRecipient, source)
+                    .await?;
+            } else {
+                summary.skip(entry, "too long for any recipient");
+            }
+        } else if is_pattern {
+            summary.skip(entry, "hostname patterns aren't supported");
+        } else if let Some(network) = parse_network(entry) {
+            summary
+                .add(store, network, "", DecisionRule::AllowedNetwork, source)
+                .await?;
+        // Only trusted once the client's name resolves back to it, which
+        // postgrey doesn't check
+        } else if let Some(hostname) = parse_hostname(entry) {
+            summary
+                .add_address(store, &hostname, DecisionRule::TrustedHostname, source)
+                .await?;
+        } else {
+            summary.skip(entry, "not an address, network or host name");
+        }
+    }
+    Ok(summary)
+}
+
+/// Read sqlgrey's auto-whitelists, `from_awl` and `domain_awl`, whose
+/// clients become known good for the sender domains they sent from.
+/// `from_awl` has the whole sender address, but only its domain is kept, so
+/// other senders at that domain get through from the same client too.
+/// Triplets still waiting in `connect` are reported as skipped, since
+/// they'll be greylisted again either way.
+pub async fn import_sqlgrey(
+    store: &dyn GreylistStore,
+    sqlgrey: &DatabaseConnection,
+) -> Result<LegacySummary, DbErr> {
+    let backend = sqlgrey.get_database_backend();
+    let mut summary = LegacySummary::default();
+
+    for table in ["from_awl", "domain_awl"] {
+        let source = format!("sqlgrey {}", table);
+        let rows = sqlgrey
+            .query_all(Statement::from_string(
+                backend,
+                format!(
+                    "SELECT DISTINCT src, sender_domain FROM {} ORDER BY src, sender_domain",
+                    table
+                ),
+            ))
+            .await?;
+        for row in rows {
+            let src: String = row.try_get("", "src")?;
+            let sender_domain: String = row.try_get("", "sender_domain")?;
+            let entry = format!("{} {} {}", table, src, sender_domain);
+            // Stored the way the milter stores sender domains
+            let sender_domain = sender_domain.trim().to_lowercase();
+            let Some(network) = parse_network(src.trim()) else {
+                summary.skip(entry, "not an address or class C network");
+                continue;
+            };
+            // Would make the client known good for every sender
+            if sender_domain.is_empty() || sender_domain.len() > address::MAX_DOMAIN_LENGTH {
+                summary.skip(entry, "no sender domain that can be stored");
+                continue;
+            }
+            summary
+                .add(
+                    store,
+                    network,
+                    &sender_domain,
+                    DecisionRule::KnownGood,
+                    &source,
+                )
+                .await?;
+        }
+    }
+
+    let rows = sqlgrey
+        .query_all(Statement::from_string(
+            backend,
+            "SELECT sender_name, sender_domain, src, rcpt FROM connect ORDER BY src".to_string(),
+        ))
+        .await?;
+    for row in rows {
+        let sender_name: String = row.try_get("", "sender_name")?;
+        let sender_domain: String = row.try_get("", "sender_domain")?;
+        let src: String = row.try_get("", "src")?;
+        let rcpt: String = row.try_get("", "rcpt")?;
+        summary.skip(
+            format!("connect {} {}@{} {}", src, sender_name, sender_domain, rcpt),
+            "still greylisted, with no Message-Id to carry over",
+        );
+    }
+
+    Ok(summary)
+}
@@ -1,36 +1,138 @@
-use std::{ffi::CString, future::Future, net::IpAddr, str::FromStr, sync::Arc};
+use std::{ffi::CString, fmt, future::Future, net::IpAddr, sync::Arc, time};
 
+use allowlist::Allowlist;
 use chrono::{Duration, Utc};
+use dns::{HickoryResolver, Resolver};
+use dnslist::{DnsLists, Listings, Thresholds, Verdict};
 use entity::{
+    decision_rule::DecisionRule::{
+        self, AllowedNetwork, AllowedRecipient, AllowedSender, Authenticated, DnsAllowlisted,
+        DnsBlocklisted, DynamicHostname, ExistingMessage, GreylistingDisabled, KnownGood, Loopback,
+        NoReverseDns, SpfFail, SpfKnownGood, SpfTrusted, TrustedHostname,
+    },
     email_status::EmailStatus::{
-        AuthenticatedAccepted, Denied, Greylisted, IpAccepted, KnownGoodAccepted, LocallyAccepted,
-        OtherAccepted, PassedGreylistAccepted,
+        self, AllowlistAccepted, AuthenticatedAccepted, Denied, Greylisted, IpAccepted,
+        KnownGoodAccepted, LocallyAccepted, OtherAccepted, PassedGreylistAccepted, Rejected,
+        SpfAccepted,
     },
-    mail,
-    prelude::{MailActive, MailEntity, MailRecipientActive, RecipientActive, RecipientModel},
-    recipient,
+    prelude::{AllowedClientModel, DecisionActive, MailActive, MailModel, RecipientModel},
+    spf_result::SpfResult,
+};
+use futures::{
+    future::{BoxFuture, Shared},
+    FutureExt,
 };
+use hostname::ReverseDns;
 use indymilter::{
-    Callbacks, Config, Context, ContextActions, EomContext, MacroStage, NegotiateContext,
-    SocketInfo, Status,
+    Callbacks, Config, Context, ContextActions, EomContext, Listener, MacroStage, Macros,
+    NegotiateContext, SocketInfo, Status,
 };
 use ipnet::IpNet;
-use migration::{Migrator, MigratorTrait};
-use sea_orm::{
-    sea_query::OnConflict, ActiveModelTrait, ColumnTrait, ConnectOptions, Database,
-    DatabaseConnection, DbErr, EntityTrait, Insert, QueryFilter, Set, TransactionError,
-    TransactionTrait,
+use sea_orm::{ConnectOptions, Database, DatabaseConnection, DbErr, Set};
+use settings::{FailMode, Rewrite, Settings, SpfFailAction};
+use spf::{Policy, Spf};
+use stats::{ActiveSession, Stats};
+use store::{GreylistStore, InsertedMail, StatusChange};
+use tokio::{
+    net::TcpListener,
+    sync::{oneshot, OnceCell},
+    time::{sleep, timeout, timeout_at, Instant},
 };
-use settings::{Rewrite, Settings};
-use tokio::net::TcpListener;
-use tracing::{debug, error, info, warn};
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
 
+#[cfg(not(any(feature = "postgres", feature = "mysql", feature = "sqlite")))]
+compile_error!("At least one of the postgres, mysql or sqlite features must be enabled");
+#[cfg(not(any(feature = "rustls", feature = "native-tls")))]
+compile_error!("One of the rustls or native-tls features must be enabled");
+
+pub mod address;
+pub mod admin;
+pub mod allowlist;
+pub mod dns;
+pub mod dnslist;
+pub mod hostname;
+pub mod legacy;
+pub mod logging;
+pub mod partition;
+pub mod rollup;
 pub mod settings;
+pub mod spf;
+mod stats;
+pub mod store;
+mod systemd;
+pub mod transfer;
 
 #[derive(Clone, Debug)]
 struct SessionData {
     pub mail: MailActive,
-    pub recipients: Vec<(RecipientModel, RecipientStatus)>,
+    /// Each recipient as stored, as given in RCPT TO, and what to do with it
+    pub recipients: Vec<(RecipientModel, String, RecipientStatus)>,
+    pub session: Arc<ActiveSession>,
+    /// The database as it was when the session connected, `None` while
+    /// running degraded or once it failed the session with fail mode open
+    pub db: Option<Arc<dyn GreylistStore>>,
+    /// Lookups of the client on the DNS lists, if any are configured
+    pub dns_listings: Option<Pending<Listings>>,
+    /// The SPF check of the sender domain, if enabled
+    pub spf_result: Option<Pending<SpfResult>>,
+    /// The client's forward-confirmed reverse DNS, if checked
+    pub reverse_dns: Option<Pending<ReverseDns>>,
+}
+
+/// DNS lookups started as soon as what they need is known, to be awaited
+/// once the decision needs them
+#[derive(Clone)]
+struct Pending<T> {
+    answer: Shared<BoxFuture<'static, T>>,
+    /// What a lookup that panicked or took too long gives
+    failed: T,
+}
+
+impl<T: Clone + Send + Sync + 'static> Pending<T> {
+    fn spawn(lookup: impl Future<Output = T> + Send + 'static, failed: T) -> Self {
+        let panicked = failed.clone();
+        Self {
+            answer: tokio::spawn(lookup)
+                .map(move |result| result.unwrap_or(panicked))
+                .boxed()
+                .shared(),
+            failed,
+        }
+    }
+
+    /// The answer, or the failed one if there's none by `deadline`
+    async fn answer_by(self, deadline: Instant, check: &str) -> T {
+        match timeout_at(deadline, self.answer).await {
+            Ok(answer) => answer,
+            Err(_) => {
+                warn!("No answer to the {} lookups within the DNS timeout", check);
+                self.failed
+            }
+        }
+    }
+}
+
+impl<T: Clone + fmt::Debug> fmt::Debug for Pending<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Pending").field(&self.answer.peek()).finish()
+    }
+}
+
+/// How the checks beyond the client's network weigh in on the decision
+#[derive(Debug)]
+struct Policies {
+    dns_list_thresholds: Thresholds,
+    dns_list_delay_seconds: i64,
+    spf: Policy,
+    hostnames: hostname::Rules,
+    allowlist: Allowlist,
+    dns_timeout: time::Duration,
+}
+
+impl SessionData {
+    fn has_pending_lookups(&self) -> bool {
+        self.dns_listings.is_some() || self.spf_result.is_some() || self.reverse_dns.is_some()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -40,84 +142,398 @@ enum RecipientStatus {
     Keep,
 }
 
-pub async fn real_main(config_location: String, shutdown: impl Future) {
-    let config = Settings::new(&config_location).unwrap_or_else(|e| {
-        panic!(
-            "Unable to read configuration from {}: {}",
-            env!("CARGO_PKG_NAME"),
-            e
-        )
-    });
+/// The systemd `STATUS=` line while the database is available
+const READY_STATUS: &str = "Ready";
 
+pub async fn real_main(config: Settings, shutdown: impl Future) {
+    run(config, None, shutdown).await;
+}
+
+/// As `real_main`, but with DNS lookups going to `resolver` rather than the
+/// nameservers in `[dns]`, e.g. to run against a stub
+pub async fn real_main_with_resolver(
+    config: Settings,
+    resolver: Arc<dyn Resolver>,
+    shutdown: impl Future,
+) {
+    run(config, Some(resolver), shutdown).await;
+}
+
+async fn run(config: Settings, resolver: Option<Arc<dyn Resolver>>, shutdown: impl Future) {
     let allowed_networks = Arc::new(config.get_allow_from_networks());
     let rewrite_addresses = Arc::new(config.get_rewrites());
+    let shutdown_timeout = time::Duration::from_secs(config.get_shutdown_timeout_seconds());
+    let decision_timeout = time::Duration::from_secs(config.get_decision_timeout_seconds());
+    let fail_mode = config.get_fail_mode();
+    let lowercase_local_part = config.get_lowercase_local_part();
+    let stats = Arc::new(Stats::default());
+    let dns_list_zones = config.get_dns_list_zones();
+    let hostname_checks = config.get_hostname_checks_enabled();
+    let resolver = (!dns_list_zones.is_empty() || config.get_spf_enabled() || hostname_checks)
+        .then(|| {
+            resolver.unwrap_or_else(|| {
+                let resolver =
+                    HickoryResolver::new(&config).expect("Unable to set up DNS resolver");
+                Arc::new(resolver)
+            })
+        });
+    let dns_lists = resolver
+        .clone()
+        .filter(|_| !dns_list_zones.is_empty())
+        .map(|resolver| {
+            Arc::new(DnsLists::new(
+                resolver,
+                dns_list_zones,
+                time::Duration::from_secs(config.get_dns_list_cache_seconds()),
+            ))
+        });
+    let spf = resolver
+        .clone()
+        .filter(|_| config.get_spf_enabled())
+        .map(|resolver| Arc::new(Spf::new(resolver)));
+    let hostname_resolver = resolver.filter(|_| hostname_checks);
+    let policies = Arc::new(Policies {
+        dns_list_thresholds: Thresholds::from_settings(&config),
+        dns_list_delay_seconds: config.get_dns_list_delay_greylist_time_seconds(),
+        spf: Policy::from_settings(&config),
+        hostnames: hostname::Rules::from_settings(&config),
+        allowlist: Allowlist::from_settings(&config),
+        dns_timeout: time::Duration::from_secs(config.get_dns_check_timeout_seconds()),
+    });
 
     info!(
-        "Starting {} version {} on {}",
+        "Starting {} version {}",
         env!("CARGO_PKG_NAME"),
         env!("CARGO_PKG_VERSION"),
-        config.get_listen_address()
     );
-    let listener = TcpListener::bind(config.get_listen_address())
-        .await
-        .expect("Unable to open milter socket");
+    if !policies.allowlist.sender_domains.is_empty()
+        && policies.allowlist.require_spf
+        && spf.is_none()
+    {
+        warn!("Sender domains are only allowed if they pass SPF, but [spf] isn't enabled");
+    }
+    let listener = match systemd::inherited_listener()
+        .expect("Unable to use the socket passed in by systemd")
+    {
+        Some(listener) => {
+            info!("Listening on socket passed in by systemd");
+            listener
+        }
+        None => {
+            info!("Listening on {}", config.get_listen_address());
+            Listener::Tcp(
+                TcpListener::bind(config.get_listen_address())
+                    .await
+                    .expect("Unable to open milter socket"),
+            )
+        }
+    };
 
-    let mut db_options = ConnectOptions::new(config.get_db_url());
-    db_options
-        .max_connections(100)
-        .min_connections(1)
-        .connect_timeout(Duration::seconds(2).to_std().unwrap())
-        .idle_timeout(Duration::seconds(5).to_std().unwrap());
-    let db = Arc::new(
-        Database::connect(db_options)
+    let db = Arc::new(OnceCell::new());
+    let startup_max_wait = time::Duration::from_secs(config.get_startup_max_wait_seconds());
+    let connector = if config.get_degraded_start() {
+        // A degraded start is there to take mail while the database is down,
+        // so systemd is told the milter is ready regardless, with why it's
+        // degraded as its status until the database is up
+        warn!(
+            ?fail_mode,
+            "Starting in degraded mode until the database is available"
+        );
+        systemd::notify_ready(&format!(
+            "Degraded, failing {} until the database is available",
+            match fail_mode {
+                FailMode::Open => "open",
+                FailMode::Closed => "closed",
+            }
+        ));
+        let db = db.clone();
+        let config = config.clone();
+        Some(tokio::spawn(async move {
+            match connect_with_backoff(&config, None).await {
+                Ok(store) => {
+                    let _ = db.set(store);
+                    info!("Database available, leaving degraded mode");
+                    systemd::notify_status(READY_STATUS);
+                }
+                Err(e) => error!("Unable to connect to database, staying degraded: {}", e),
+            }
+        }))
+    } else {
+        let store = connect_with_backoff(&config, Some(startup_max_wait))
             .await
-            .expect("Unable to connect to database"),
-    );
-
-    Migrator::down(&*db, None)
-        .await
-        .expect("Unable to undo migrations");
-    Migrator::up(&*db, None)
-        .await
-        .expect("Unable to run migrations");
+            .expect("Unable to connect to database");
+        let _ = db.set(store);
+        systemd::notify_ready(READY_STATUS);
+        None
+    };
+    let watchdog = systemd::spawn_watchdog(db.clone());
+    let rollup_interval = config.get_rollup_interval_seconds();
+    let rollup_updater = (rollup_interval > 0)
+        .then(|| rollup::spawn_updater(db.clone(), time::Duration::from_secs(rollup_interval)));
+    let partition_interval = config.get_partition_maintenance_interval_seconds();
+    let partition_maintainer = (partition_interval > 0).then(|| {
+        partition::spawn_maintainer(
+            db.clone(),
+            time::Duration::from_secs(partition_interval),
+            config.get_partition_months_ahead(),
+            config.get_partition_retention_months(),
+        )
+    });
 
     let db_1 = db.clone();
-    let db_2 = db.clone();
+    let stats_1 = stats.clone();
+    let stats_2 = stats.clone();
 
     let callbacks = Callbacks::new()
         .on_negotiate(|context, _, _| Box::pin(negotiate(context)))
-        .on_connect(|context, hostname, socket_info| {
-            Box::pin(handle_connect(context, hostname, socket_info))
+        .on_connect(move |context, hostname, socket_info| {
+            let session = stats_1.session_started();
+            let span = info_span!("session", id = session.id());
+            let db = db_1.get().cloned();
+            Box::pin(
+                handle_connect(
+                    context,
+                    hostname,
+                    socket_info,
+                    session,
+                    db,
+                    dns_lists.clone(),
+                    hostname_resolver.clone(),
+                )
+                .instrument(span),
+            )
+        })
+        .on_helo(|context, helo_host| {
+            let span = session_span(&context.data);
+            Box::pin(handle_helo(context, helo_host).instrument(span))
+        })
+        .on_mail(move |context, args| {
+            let span = session_span(&context.data);
+            Box::pin(handle_mail(context, args, spf.clone(), lowercase_local_part).instrument(span))
         })
-        .on_mail(|context, args| Box::pin(handle_mail(context, args)))
         .on_rcpt(move |context, args| {
-            Box::pin(handle_rcpt(
-                context,
-                args,
-                db_1.clone(),
-                rewrite_addresses.clone(),
-            ))
+            let span = session_span(&context.data);
+            Box::pin(
+                handle_rcpt(
+                    context,
+                    args,
+                    rewrite_addresses.clone(),
+                    lowercase_local_part,
+                    fail_mode,
+                    decision_timeout,
+                )
+                .instrument(span),
+            )
+        })
+        .on_header(|context, name, value| {
+            let span = session_span(&context.data);
+            Box::pin(handle_header(context, name, value).instrument(span))
         })
-        .on_header(|context, name, value| Box::pin(handle_header(context, name, value)))
         .on_eoh(move |context| {
-            Box::pin(handle_eoh(
-                context,
-                allowed_networks.clone(),
-                db_2.clone(),
-                config.get_greylist_time_seconds(),
-            ))
+            let span = session_span(&context.data);
+            let allowed_networks = allowed_networks.clone();
+            let stats = stats_2.clone();
+            let greylist_time_seconds = config.get_greylist_time_seconds();
+            let policies = policies.clone();
+            Box::pin(
+                async move {
+                    let Some(db) = context.data.as_ref().and_then(|data| data.db.clone()) else {
+                        let queue_id = get_macro(&context.macros, "{i}");
+                        warn!(?queue_id, ?fail_mode, "Database not available");
+                        let status = fail_status(fail_mode);
+                        stats.record_decision(status);
+                        return status;
+                    };
+                    // Waiting for DNS answers doesn't count against the
+                    // database, which only gets the decision timeout
+                    let deadline = if context
+                        .data
+                        .as_ref()
+                        .is_some_and(SessionData::has_pending_lookups)
+                    {
+                        decision_timeout + policies.dns_timeout
+                    } else {
+                        decision_timeout
+                    };
+                    // Dropping the decision part way through rolls back its
+                    // transaction, so nothing is half-recorded
+                    let status = match timeout(
+                        deadline,
+                        handle_eoh(
+                            context,
+                            allowed_networks,
+                            db,
+                            greylist_time_seconds,
+                            policies,
+                        ),
+                    )
+                    .await
+                    {
+                        Ok(Ok(status)) => status,
+                        Ok(Err(e)) => {
+                            let queue_id = get_macro(&context.macros, "{i}");
+                            error!(?queue_id, ?fail_mode, "Unable to record decision: {}", e);
+                            fail_status(fail_mode)
+                        }
+                        Err(_) => {
+                            let queue_id = get_macro(&context.macros, "{i}");
+                            warn!(
+                                ?queue_id,
+                                ?fail_mode,
+                                "No answer from the database within {} seconds",
+                                decision_timeout.as_secs()
+                            );
+                            fail_status(fail_mode)
+                        }
+                    };
+                    stats.record_decision(status);
+                    status
+                }
+                .instrument(span),
+            )
         })
-        .on_eom(move |context| Box::pin(handle_eom(context)));
+        .on_eom(move |context| {
+            let span = session_span(&context.data);
+            Box::pin(handle_eom(context).instrument(span))
+        });
+
+    let (shutdown_started_sender, shutdown_started) = oneshot::channel();
+    let shutdown_stats = stats.clone();
+    let shutdown = async move {
+        shutdown.await;
+        info!(
+            "Shutting down, waiting up to {} seconds for {} open sessions",
+            shutdown_timeout.as_secs(),
+            shutdown_stats.active_sessions()
+        );
+        systemd::notify_stopping();
+        let _ = shutdown_started_sender.send(());
+    };
+    let drain_expired = async {
+        match shutdown_started.await {
+            Ok(()) => sleep(shutdown_timeout).await,
+            // The milter stopped by itself, so leave it to report why
+            Err(_) => futures::future::pending().await,
+        }
+    };
+
+    let mut forcibly_closed = 0;
+    tokio::select! {
+        result = indymilter::run(listener, callbacks, Config::default(), shutdown) => {
+            result.expect("milter execution failed");
+        }
+        () = drain_expired => {
+            // The remaining sessions are dropped along with the runtime; any
+            // transaction they have open is rolled back, not committed
+            forcibly_closed = stats.active_sessions();
+            warn!(
+                "{} sessions still open after {} seconds, closing them",
+                forcibly_closed,
+                shutdown_timeout.as_secs()
+            );
+        }
+    }
+
+    if let Some(watchdog) = watchdog {
+        watchdog.abort();
+    }
+    if let Some(connector) = connector {
+        connector.abort();
+    }
+    if let Some(rollup_updater) = rollup_updater {
+        rollup_updater.abort();
+    }
+    if let Some(partition_maintainer) = partition_maintainer {
+        partition_maintainer.abort();
+    }
+
+    if let (0, Some(db)) = (forcibly_closed, db.get()) {
+        if let Err(e) = db.close().await {
+            warn!("Unable to close database connections: {}", e);
+        }
+    }
 
-    indymilter::run(listener, callbacks, Config::default(), shutdown)
-        .await
-        .expect("milter execution failed");
+    stats.log_summary(forcibly_closed);
+}
+
+fn session_span(data: &Option<SessionData>) -> Span {
+    match data {
+        Some(session_data) => info_span!("session", id = session_data.session.id()),
+        None => Span::none(),
+    }
+}
+
+fn get_macro(macros: &Macros, name: &str) -> Option<String> {
+    macros
+        .get(&CString::new(name).unwrap())
+        .map(|value| value.to_string_lossy().into_owned())
+}
+
+/// Keep trying to connect, doubling the delay between attempts up to 30
+/// seconds, until connected or `max_wait` has passed. Without `max_wait`
+/// every error is retried, as nothing is waiting to report it.
+async fn connect_with_backoff(
+    config: &Settings,
+    max_wait: Option<time::Duration>,
+) -> Result<Arc<dyn GreylistStore>, DbErr> {
+    let started = time::Instant::now();
+    let mut delay = time::Duration::from_millis(500);
+    loop {
+        match store::open(config).await {
+            Ok(db) => return Ok(db),
+            // Retrying won't help if the configuration itself is wrong
+            Err(e @ DbErr::Custom(_)) if max_wait.is_some() => return Err(e),
+            Err(e) => {
+                if max_wait.is_some_and(|max_wait| started.elapsed() + delay > max_wait) {
+                    return Err(e);
+                }
+                warn!(
+                    "Unable to connect to database, retrying in {:?}: {}",
+                    delay, e
+                );
+                sleep(delay).await;
+                delay = (delay * 2).min(time::Duration::from_secs(30));
+            }
+        }
+    }
+}
+
+fn fail_status(fail_mode: FailMode) -> Status {
+    match fail_mode {
+        FailMode::Open => Status::Continue,
+        FailMode::Closed => Status::Tempfail,
+    }
+}
+
+pub async fn connect_database(config: &Settings) -> Result<DatabaseConnection, DbErr> {
+    let db_url = config
+        .get_db_url()
+        .map_err(|e| DbErr::Custom(e.to_string()))?;
+    let mut db_options = ConnectOptions::new(db_url);
+    db_options
+        .max_connections(config.get_max_connections())
+        .min_connections(config.get_min_connections())
+        .connect_timeout(time::Duration::from_secs(
+            config.get_connect_timeout_seconds(),
+        ))
+        .acquire_timeout(time::Duration::from_secs(
+            config.get_acquire_timeout_seconds(),
+        ))
+        .idle_timeout(time::Duration::from_secs(config.get_idle_timeout_seconds()));
+    Database::connect(db_options).await
 }
 
 async fn negotiate(context: &mut NegotiateContext<SessionData>) -> Status {
-    context
-        .requested_macros
-        .insert(MacroStage::Eoh, CString::new("{auth_type}").unwrap());
+    for (stage, macros) in [
+        (MacroStage::Connect, "{daemon_name}"),
+        (MacroStage::Helo, "{tls_version} {cipher}"),
+        (MacroStage::Mail, "{auth_authen} {i}"),
+        (MacroStage::Eoh, "{auth_type} {i}"),
+    ] {
+        context
+            .requested_macros
+            .insert(stage, CString::new(macros).unwrap());
+    }
 
     Status::Continue
 }
@@ -126,15 +542,34 @@ async fn handle_connect(
     session: &mut Context<SessionData>,
     hostname: CString,
     socket_info: SocketInfo,
+    active_session: Arc<ActiveSession>,
+    db: Option<Arc<dyn GreylistStore>>,
+    dns_lists: Option<Arc<DnsLists>>,
+    hostname_resolver: Option<Arc<dyn Resolver>>,
 ) -> Status {
     let mut session_data = MailActive {
         time_received: Set(Utc::now().into()),
         ..Default::default()
     };
+    let mut dns_listings = None;
+    let mut reverse_dns = None;
 
     if let SocketInfo::Inet(addr) = socket_info {
         debug!("Connect from {}", addr.ip());
-        session_data.sending_ip = Set(addr.ip().to_string());
+        // Started now so the answers are likely in by the end of headers
+        if let Some(dns_lists) = dns_lists.filter(|_| !addr.ip().is_loopback()) {
+            dns_listings = Some(Pending::spawn(
+                async move { dns_lists.check(addr.ip()).await },
+                Listings::default(),
+            ));
+        }
+        if let Some(resolver) = hostname_resolver.filter(|_| !addr.ip().is_loopback()) {
+            reverse_dns = Some(Pending::spawn(
+                async move { ReverseDns::look_up(resolver.as_ref(), addr.ip()).await },
+                ReverseDns::Failed,
+            ));
+        }
+        session_data.sending_ip = Set(address::normalize_ip(addr.ip()).to_string());
         if hostname.is_empty() {
             session_data.sending_host_name = Set(None);
         } else {
@@ -151,15 +586,52 @@ async fn handle_connect(
     session.data = Some(SessionData {
         mail: session_data,
         recipients: vec![],
+        session: active_session,
+        db,
+        dns_listings,
+        spf_result: None,
+        reverse_dns,
     });
 
     Status::Continue
 }
 
-async fn handle_mail(session: &mut Context<SessionData>, args: Vec<CString>) -> Status {
+async fn handle_helo(session: &mut Context<SessionData>, helo_host: CString) -> Status {
+    debug!("HELO {:?}", helo_host);
+    let session_data = session.data.as_mut().expect("No session?");
+
+    session_data.mail.helo_name = match helo_host.into_string() {
+        Ok(helo_host) if !helo_host.is_empty() => Set(Some(helo_host)),
+        Ok(_) => Set(None),
+        Err(err) => {
+            warn!("Unable to read HELO name: {}", err);
+            Set(None)
+        }
+    };
+
+    Status::Continue
+}
+
+async fn handle_mail(
+    session: &mut Context<SessionData>,
+    args: Vec<CString>,
+    spf: Option<Arc<Spf>>,
+    lowercase_local_part: bool,
+) -> Status {
     debug!("MAIL FROM {:?}", args);
+    let authenticated = get_macro(&session.macros, "{auth_authen}").is_some();
     let session_data = session.data.as_mut().expect("No session?");
 
+    // ESMTP parameters follow the address, e.g. SIZE=12345
+    session_data.mail.message_size = Set(args.iter().skip(1).find_map(|arg| {
+        let (keyword, value) = arg.to_str().ok()?.split_once('=')?;
+        if keyword.eq_ignore_ascii_case("SIZE") {
+            value.parse().ok()
+        } else {
+            None
+        }
+    }));
+
     if args.is_empty() {
         warn!("Null sender? (args from MAIL FROM: {:?})", args);
         Status::Reject
@@ -167,20 +639,48 @@ async fn handle_mail(session: &mut Context<SessionData>, args: Vec<CString>) ->
         if sender.len() > 2 {
             // Assume the first and last characters are < and >
             let sender = &sender[1..sender.len() - 1];
-            let mut sender_parts = sender.split('@');
-            if let Some(sender_local_part) = sender_parts.next() {
-                session_data.mail.sender_local_part = Set(sender_local_part.to_string());
-            } else {
-                warn!("No sender_local_part? (args from MAIL FROM: {:?})", args);
-                return Status::Reject;
-            }
-            if let Some(sender_domain) = sender_parts.next() {
-                session_data.mail.sender_domain = Set(sender_domain.to_string());
-                Status::Continue
-            } else {
+            let Some((local_part, _)) = sender.rsplit_once('@') else {
                 warn!("No sender_domain? (args from MAIL FROM: {:?})", args);
-                Status::Reject
-            }
+                return Status::Reject;
+            };
+            // Stored mail is looked up by domain, e.g. for known good mail
+            // that passed SPF, so it's kept in one case
+            let (sender_local_part, sender_domain) =
+                match address::split_email(sender, lowercase_local_part) {
+                    Ok(sender) => sender,
+                    Err(e) => {
+                        error!("{} (args from MAIL FROM: {:?})", e, args);
+                        return Status::Reject;
+                    }
+                };
+            session_data.mail.sender_local_part = Set(sender_local_part);
+            session_data.mail.sender_domain = Set(sender_domain.clone());
+            // Local and authenticated mail is accepted without it
+            let client = session_data
+                .mail
+                .sending_ip
+                .is_set()
+                .then(|| address::parse_ip(session_data.mail.sending_ip.as_ref()))
+                .flatten()
+                .filter(|client| !client.is_loopback() && !authenticated);
+            session_data.spf_result = spf.zip(client).map(|(spf, client)| {
+                // Macros expand the local part as sent
+                let local_part = local_part.to_string();
+                let helo = session_data
+                    .mail
+                    .helo_name
+                    .is_set()
+                    .then(|| session_data.mail.helo_name.as_ref().clone())
+                    .flatten();
+                Pending::spawn(
+                    async move {
+                        spf.check(client, &local_part, &sender_domain, helo.as_deref())
+                            .await
+                    },
+                    SpfResult::TempError,
+                )
+            });
+            Status::Continue
         } else {
             warn!("Sender length is < 2? (args from MAIL FROM: {:?})", args);
             Status::Reject
@@ -197,8 +697,10 @@ async fn handle_mail(session: &mut Context<SessionData>, args: Vec<CString>) ->
 async fn handle_rcpt(
     session: &mut Context<SessionData>,
     args: Vec<CString>,
-    db: Arc<DatabaseConnection>,
     rewrite_addresses: Arc<Vec<Rewrite>>,
+    lowercase_local_part: bool,
+    fail_mode: FailMode,
+    decision_timeout: time::Duration,
 ) -> Status {
     debug!("RCPT TO {:?}", args);
     let session_data = session.data.as_mut().expect("No session?");
@@ -210,31 +712,56 @@ async fn handle_rcpt(
         if recipient.len() > 2 {
             // Assume the first and last characters are < and >
             let recipient = &recipient[1..recipient.len() - 1];
-            let recipient_active = RecipientActive {
-                recipient: Set(recipient.to_owned()),
-                ..Default::default()
+            let (local_part, domain) = match address::split_email(recipient, lowercase_local_part) {
+                Ok(recipient) => recipient,
+                Err(e) => {
+                    error!("{} (args from RCPT TO: {:?})", e, args);
+                    return Status::Reject;
+                }
             };
-
-            session_data.recipients.push(
-                match Insert::one(recipient_active)
-                    .on_conflict(
-                        OnConflict::column(recipient::Column::Recipient)
-                            .update_column(recipient::Column::Recipient)
-                            .clone(),
-                    )
-                    .exec_with_returning(db.as_ref())
-                    .await
+            let stored = match session_data.db.clone() {
+                Some(db) => match timeout(
+                    decision_timeout,
+                    db.upsert_recipient(local_part.clone(), domain.clone()),
+                )
+                .await
                 {
-                    Ok(model) => (
-                        model,
-                        change_address((*rewrite_addresses).clone(), recipient),
-                    ),
-                    Err(e) => {
-                        error!("Unable to insert recipient: {}", e);
-                        return Status::Tempfail;
+                    Ok(Ok(model)) => Some(model),
+                    Ok(Err(e)) => {
+                        error!(?fail_mode, "Unable to insert recipient: {}", e);
+                        None
+                    }
+                    Err(_) => {
+                        error!(
+                            ?fail_mode,
+                            "No answer from the database within {} seconds",
+                            decision_timeout.as_secs()
+                        );
+                        None
                     }
                 },
-            );
+                None => None,
+            };
+            let model = match (stored, fail_mode) {
+                (Some(model), _) => model,
+                // Never written anywhere, only kept for rewriting at EOM. The
+                // rest of the session goes without the database, so the
+                // message isn't recorded without this recipient
+                (None, FailMode::Open) => {
+                    session_data.db = None;
+                    RecipientModel {
+                        id: 0,
+                        local_part,
+                        domain,
+                    }
+                }
+                (None, FailMode::Closed) => return Status::Tempfail,
+            };
+            session_data.recipients.push((
+                model,
+                recipient.to_owned(),
+                change_address((*rewrite_addresses).clone(), recipient),
+            ));
             Status::Continue
         } else {
             warn!("Recipient length is < 2? (args from RCPT TO: {:?})", args);
@@ -278,14 +805,16 @@ async fn handle_header(
 async fn handle_eoh(
     session: &mut Context<SessionData>,
     allowed_networks: Arc<Vec<IpNet>>,
-    db: Arc<DatabaseConnection>,
-    greylist_time_seconds: i64,
-) -> Status {
+    db: Arc<dyn GreylistStore>,
+    mut greylist_time_seconds: i64,
+    policies: Arc<Policies>,
+) -> Result<Status, DbErr> {
     debug!(
         "EOH, {{auth_type}}: {:?}",
         session.macros.get(&CString::new("{auth_type}").unwrap())
     );
     debug!("{:?}", session.macros);
+    let queue_id = get_macro(&session.macros, "{i}");
     let session_data = session.data.as_mut().expect("No session?");
 
     // Check we have enough information in the session now
@@ -299,131 +828,200 @@ async fn handle_eoh(
             ?session_data,
             "End of headers but we don't have all the information we need?"
         );
-        return Status::Tempfail;
+        return Ok(Status::Tempfail);
     }
 
-    if let Ok(from_ip) = IpAddr::from_str(session_data.mail.sending_ip.clone().unwrap().as_str()) {
-        // Locally-generated email
-        if from_ip.is_loopback() {
-            session_data.mail.status = Set(LocallyAccepted);
-            session_data.mail.time_accepted = Set(Some(Utc::now().into()));
-            insert_mail(session_data.clone(), db)
-                .await
-                .expect("Unable to connect to database");
-            debug!(?session_data.mail.sending_ip, "Locally accepted");
-            Status::Continue
-        // Authenticated users
-        } else if let Some(auth_type) = session.macros.get(&CString::new("{auth_type}").unwrap()) {
-            session_data.mail.status = Set(AuthenticatedAccepted);
-            session_data.mail.time_accepted = Set(Some(Utc::now().into()));
-            insert_mail(session_data.clone(), db)
-                .await
-                .expect("Unable to connect to database");
-            debug!(?auth_type, "Authenticated accepted");
-            Status::Continue
-        // Whitelisted networks
-        } else if is_allowed_ip(&allowed_networks, from_ip) {
-            session_data.mail.status = Set(IpAccepted);
-            session_data.mail.time_accepted = Set(Some(Utc::now().into()));
-            insert_mail(session_data.clone(), db)
-                .await
-                .expect("Unable to connect to database");
-            debug!(?from_ip, "IP accepted");
-            Status::Continue
-        } else {
-            // Does the message already exist in the database?
-            if let Ok(Some(existing_message)) = MailEntity::find()
-                .filter(mail::Column::MessageId.eq(session_data.mail.message_id.clone().unwrap()))
-                .one(db.as_ref())
-                .await
-            {
-                match existing_message.status {
-                    Greylisted => {
-                        let previously_received = existing_message.time_received;
-                        // If the message was greylisted but we've waited long enough
-                        if previously_received
-                            .checked_add_signed(Duration::seconds(greylist_time_seconds))
-                            .unwrap()
-                            < Utc::now()
-                        {
-                            let mut active_existing_message: mail::ActiveModel =
-                                existing_message.into();
-                            active_existing_message.status = Set(PassedGreylistAccepted);
-                            active_existing_message.time_accepted = Set(Some(Utc::now().into()));
-                            active_existing_message
-                                .update(db.as_ref())
-                                .await
-                                .expect("Unable to connect to database");
-                            debug!(?previously_received, "Greylisted accepted");
-                            Status::Continue
-                        } else {
-                            // We know there's already a record for this message in the database; reject this one
-                            debug!(?previously_received, "Still greylisted");
-                            Status::Tempfail
-                        }
-                    }
-                    Denied => Status::Discard,
-                    AuthenticatedAccepted
-                    | IpAccepted
-                    | KnownGoodAccepted
-                    | LocallyAccepted
-                    | OtherAccepted
-                    | PassedGreylistAccepted => Status::Accept,
-                }
-            } else {
-                // Ok, no existing message, what about previous ones from the same server?
-                if let Ok(Some(_)) = MailEntity::find()
-                    .filter(
-                        mail::Column::SendingIp
-                            .eq(session_data.mail.sending_ip.clone().unwrap())
-                            .and(mail::Column::Status.is_in([
-                                PassedGreylistAccepted,
-                                KnownGoodAccepted,
-                                OtherAccepted,
-                            ])),
-                    )
-                    .one(db.as_ref())
-                    .await
-                {
-                    session_data.mail.status = Set(KnownGoodAccepted);
-                    session_data.mail.time_accepted = Set(Some(Utc::now().into()));
-                    insert_mail(session_data.clone(), db)
-                        .await
-                        .expect("Unable to connect to database");
-                    debug!("Known good - accepted");
-                    Status::Continue
-                // Nope? Ok, then we'll have to greylist
-                } else if greylist_time_seconds > 0 {
-                    session_data.mail.status = Set(Greylisted);
-                    insert_mail(session_data.clone(), db)
-                        .await
-                        .expect("Unable to connect to database");
-                    debug!("Greylist");
-                    Status::Tempfail
-                // Greylisting is disabled
-                } else {
-                    session_data.mail.status = Set(OtherAccepted);
-                    session_data.mail.time_accepted = Set(Some(Utc::now().into()));
-                    insert_mail(session_data.clone(), db)
-                        .await
-                        .expect("Unable to connect to database");
-                    debug!("Greylisting disabled - accepted");
-                    Status::Continue
-                }
-            }
-        }
-    } else {
+    session_data.mail.queue_id = Set(queue_id.clone());
+    session_data.mail.auth_user = Set(get_macro(&session.macros, "{auth_authen}"));
+    session_data.mail.tls_version = Set(get_macro(&session.macros, "{tls_version}"));
+    session_data.mail.tls_cipher = Set(get_macro(&session.macros, "{cipher}"));
+    session_data.mail.daemon_name = Set(get_macro(&session.macros, "{daemon_name}"));
+
+    let Some(from_ip) = address::parse_ip(session_data.mail.sending_ip.as_ref().as_str()) else {
         warn!(
             "Unable to parse IP address {}",
-            session_data.mail.sending_ip.clone().unwrap()
+            session_data.mail.sending_ip.as_ref()
         );
-        Status::Tempfail
-    }
+        return Ok(Status::Tempfail);
+    };
+
+    // Locally-generated email
+    let (status, rule, detail) = if from_ip.is_loopback() {
+        (LocallyAccepted, Loopback, Some(from_ip.to_string()))
+    // Authenticated users
+    } else if let Some(auth_type) = get_macro(&session.macros, "{auth_type}") {
+        (AuthenticatedAccepted, Authenticated, Some(auth_type))
+    // Whitelisted networks
+    } else if let Some(allowed_network) = find_allowed_network(&allowed_networks, from_ip) {
+        (
+            IpAccepted,
+            AllowedNetwork,
+            Some(allowed_network.to_string()),
+        )
+    // Whitelisted networks imported from other greylisters
+    } else if let Some(allowed_client) = db
+        .find_allowed_client(
+            from_ip,
+            session_data.mail.sender_domain.as_ref(),
+            AllowedNetwork,
+        )
+        .await?
+    {
+        (
+            IpAccepted,
+            AllowedNetwork,
+            Some(describe_allowed_client(&allowed_client)),
+        )
+    } else {
+        // Lookups still outstanding get the same deadline, so between them
+        // they hold up the decision for no longer than the DNS timeout
+        let dns_deadline = Instant::now() + policies.dns_timeout;
+        let (listings, verdict) = match session_data.dns_listings.clone() {
+            Some(pending) => {
+                let listings = pending.answer_by(dns_deadline, "DNS list").await;
+                let verdict = listings.verdict(&policies.dns_list_thresholds);
+                (listings, verdict)
+            }
+            None => (Listings::default(), Verdict::Neutral),
+        };
+        // Clients on block lists wait longer, retries included
+        if matches!(verdict, Verdict::Delay | Verdict::Reject) {
+            greylist_time_seconds = greylist_time_seconds.max(policies.dns_list_delay_seconds);
+        }
+        let spf_result = match session_data.spf_result.clone() {
+            Some(pending) => Some(pending.answer_by(dns_deadline, "SPF").await),
+            None => None,
+        };
+        session_data.mail.spf_result = Set(spf_result);
+        let spf_failed = spf_result == Some(SpfResult::Fail);
+        // As do clients the sender domain doesn't allow
+        if spf_failed && policies.spf.on_fail == SpfFailAction::Delay {
+            greylist_time_seconds =
+                greylist_time_seconds.max(policies.spf.fail_greylist_time_seconds);
+        }
+        let reverse_dns = match session_data.reverse_dns.clone() {
+            Some(pending) => Some(pending.answer_by(dns_deadline, "reverse DNS").await),
+            None => None,
+        };
+        let hostname_verdict = match &reverse_dns {
+            Some(reverse_dns) => policies.hostnames.verdict(from_ip, reverse_dns),
+            None => hostname::Verdict::Neutral,
+        };
+        // And clients that look like they're on a dynamic address
+        if matches!(
+            hostname_verdict,
+            hostname::Verdict::Dynamic(_) | hostname::Verdict::NoReverseDns
+        ) {
+            greylist_time_seconds =
+                greylist_time_seconds.max(policies.hostnames.delay_greylist_time_seconds);
+        }
+        let sender_domain = session_data.mail.sender_domain.clone().unwrap();
+
+        // Does the message already exist in the database?
+        if let Some(existing_message) = db
+            .find_mail(session_data.mail.message_id.as_ref().as_str())
+            .await?
+        {
+            return handle_existing(
+                session_data,
+                existing_message,
+                &db,
+                greylist_time_seconds,
+                &queue_id,
+            )
+            .await;
+        }
+
+        // Ok, no existing message, is the server on a block list?
+        if verdict == Verdict::Reject {
+            (Rejected, DnsBlocklisted, Some(listings.describe()))
+        // Or not allowed to send for the sender domain?
+        } else if spf_failed && policies.spf.on_fail == SpfFailAction::Reject {
+            (Rejected, SpfFail, Some(sender_domain))
+        // Or only to recipients who always get their mail straight away?
+        } else if let Some(allowed) =
+            find_allowed_recipients(&db, &policies.allowlist, &session_data.recipients).await?
+        {
+            (AllowlistAccepted, AllowedRecipient, Some(allowed))
+        // Or from an allowed sender domain?
+        } else if let Some(allowed) =
+            find_allowed_sender(&db, &policies.allowlist, spf_result, &sender_domain).await?
+        {
+            (AllowlistAccepted, AllowedSender, Some(allowed))
+        } else if verdict == Verdict::Delay {
+            (Greylisted, DnsBlocklisted, Some(listings.describe()))
+        } else if spf_failed && policies.spf.on_fail == SpfFailAction::Delay {
+            (Greylisted, SpfFail, Some(sender_domain))
+        // Or an allow list?
+        } else if verdict == Verdict::Allow {
+            (IpAccepted, DnsAllowlisted, Some(listings.describe()))
+        // Or a domain we trust, and allowed to send for it?
+        } else if spf_result == Some(SpfResult::Pass) && policies.spf.is_trusted(&sender_domain) {
+            (SpfAccepted, SpfTrusted, Some(sender_domain))
+        // Or a server under a name we trust?
+        } else if let Some(trusted) =
+            find_trusted_hostname(&db, &hostname_verdict, reverse_dns.as_ref()).await?
+        {
+            (IpAccepted, TrustedHostname, Some(trusted))
+        // What about previous messages from the same server?
+        } else if let Some(known_good) = db
+            .find_known_good(session_data.mail.sending_ip.as_ref().as_str())
+            .await?
+        {
+            (
+                KnownGoodAccepted,
+                KnownGood,
+                Some(format!("mail {}", known_good.id)),
+            )
+        // Or from another server the sender domain allows?
+        } else if let Some(known_good) =
+            find_known_good_spf(&db, &policies.spf, spf_result, &sender_domain).await?
+        {
+            (
+                KnownGoodAccepted,
+                SpfKnownGood,
+                Some(format!("mail {} from {}", known_good.id, sender_domain)),
+            )
+        // Or known good to another greylister before this one
+        } else if let Some(allowed_client) = db
+            .find_allowed_client(from_ip, &sender_domain, KnownGood)
+            .await?
+        {
+            (
+                KnownGoodAccepted,
+                KnownGood,
+                Some(describe_allowed_client(&allowed_client)),
+            )
+        // Nope? Ok, then we'll have to greylist, recording why it's for
+        // longer if it is
+        } else if greylist_time_seconds > 0 {
+            match hostname_verdict {
+                hostname::Verdict::Dynamic(name) => (Greylisted, DynamicHostname, Some(name)),
+                hostname::Verdict::NoReverseDns => (Greylisted, NoReverseDns, None),
+                hostname::Verdict::Trusted(_) | hostname::Verdict::Neutral => {
+                    (Greylisted, DecisionRule::Greylisted, None)
+                }
+            }
+        // Greylisting is disabled
+        } else {
+            (OtherAccepted, GreylistingDisabled, None)
+        }
+    };
+
+    store_decision(
+        session_data,
+        &db,
+        greylist_time_seconds,
+        status,
+        rule,
+        detail,
+    )
+    .await
 }
 
 async fn handle_eom(context: &mut EomContext<SessionData>) -> Status {
     if let Some(data) = &context.data {
-        for (model, recipient_status) in &data.recipients {
+        for (_model, address, recipient_status) in &data.recipients {
             match recipient_status {
                 RecipientStatus::Add(additions) => {
                     for recipient in additions {
@@ -437,11 +1035,7 @@ async fn handle_eom(context: &mut EomContext<SessionData>) -> Status {
                     }
                 }
                 RecipientStatus::Change(additions) => {
-                    match context
-                        .actions
-                        .delete_recipient(model.recipient.clone())
-                        .await
-                    {
+                    match context.actions.delete_recipient(address.clone()).await {
                         Ok(_) => (),
                         Err(e) => {
                             warn!("Unable to remove recipient: {}", e);
@@ -465,13 +1059,120 @@ async fn handle_eom(context: &mut EomContext<SessionData>) -> Status {
     Status::Continue
 }
 
-fn is_allowed_ip(allowed_networks: &Arc<Vec<IpNet>>, address: IpAddr) -> bool {
-    for allowed_network in allowed_networks.as_ref() {
-        if allowed_network.contains(&address) {
-            return true;
+fn find_allowed_network(allowed_networks: &Arc<Vec<IpNet>>, address: IpAddr) -> Option<&IpNet> {
+    let address = address::normalize_ip(address);
+    allowed_networks
+        .iter()
+        .find(|allowed_network| allowed_network.contains(&address))
+}
+
+/// Earlier known good mail from the sender domain that passed SPF, if this
+/// one passed too
+async fn find_known_good_spf(
+    db: &Arc<dyn GreylistStore>,
+    spf_policy: &Policy,
+    spf_result: Option<SpfResult>,
+    sender_domain: &str,
+) -> Result<Option<MailModel>, DbErr> {
+    if spf_policy.known_good_by_domain && spf_result == Some(SpfResult::Pass) {
+        db.find_known_good_spf(sender_domain).await
+    } else {
+        Ok(None)
+    }
+}
+
+/// What allows every recipient, if they all are. The decision is one for the
+/// whole message, so a message to allowed and other recipients alike is
+/// greylisted: accepting it would let anyone skip greylisting by adding an
+/// allowed recipient, and it comes back for all of them when retried.
+async fn find_allowed_recipients(
+    db: &Arc<dyn GreylistStore>,
+    allowlist: &Allowlist,
+    recipients: &[(RecipientModel, String, RecipientStatus)],
+) -> Result<Option<String>, DbErr> {
+    let mut allowed = vec![];
+    for (recipient, _, _) in recipients {
+        let entry =
+            if let Some(entry) = allowlist.recipient(&recipient.local_part, &recipient.domain) {
+                entry.to_string()
+            } else if let Some(allowed_address) = db
+                .find_allowed_address(
+                    allowlist::recipient_patterns(&recipient.local_part, &recipient.domain),
+                    AllowedRecipient,
+                )
+                .await?
+            {
+                format!(
+                    "{} from {}",
+                    allowed_address.address, allowed_address.source
+                )
+            } else {
+                return Ok(None);
+            };
+        if !allowed.contains(&entry) {
+            allowed.push(entry);
         }
     }
-    false
+    Ok((!allowed.is_empty()).then(|| allowed.join(", ")))
+}
+
+/// What allows the sender domain, if it's allowed and qualifies
+async fn find_allowed_sender(
+    db: &Arc<dyn GreylistStore>,
+    allowlist: &Allowlist,
+    spf_result: Option<SpfResult>,
+    sender_domain: &str,
+) -> Result<Option<String>, DbErr> {
+    if !allowlist.sender_qualifies(spf_result) {
+        return Ok(None);
+    }
+    if let Some(entry) = allowlist.sender(sender_domain) {
+        return Ok(Some(entry.to_string()));
+    }
+    Ok(db
+        .find_allowed_address(allowlist::domain_patterns(sender_domain), AllowedSender)
+        .await?
+        .map(|allowed_address| {
+            format!(
+                "{} from {}",
+                allowed_address.address, allowed_address.source
+            )
+        }))
+}
+
+fn describe_allowed_client(allowed_client: &AllowedClientModel) -> String {
+    if allowed_client.sender_domain.is_empty() {
+        format!("{} from {}", allowed_client.network, allowed_client.source)
+    } else {
+        format!(
+            "{} for {} from {}",
+            allowed_client.network, allowed_client.sender_domain, allowed_client.source
+        )
+    }
+}
+
+/// The client's verified name, if it's under a trusted suffix from
+/// `[hostname]` or one imported into the `allowed_address` table
+async fn find_trusted_hostname(
+    db: &Arc<dyn GreylistStore>,
+    hostname_verdict: &hostname::Verdict,
+    reverse_dns: Option<&ReverseDns>,
+) -> Result<Option<String>, DbErr> {
+    if let hostname::Verdict::Trusted(name) = hostname_verdict {
+        return Ok(Some(name.clone()));
+    }
+    let Some(ReverseDns::Verified(name)) = reverse_dns else {
+        return Ok(None);
+    };
+    Ok(db
+        .find_allowed_address(allowlist::domain_patterns(name), TrustedHostname)
+        .await?
+        .map(|allowed_address| {
+            format!(
+                "{} under {} from {}",
+                name, allowed_address.address, allowed_address.source
+            )
+        }))
 }
 
 fn change_address(rewrite_addresses: Vec<Rewrite>, address: &str) -> RecipientStatus {
@@ -490,27 +1191,163 @@ fn change_address(rewrite_addresses: Vec<Rewrite>, address: &str) -> RecipientSt
     RecipientStatus::Keep
 }
 
-async fn insert_mail(
-    session: SessionData,
-    db: Arc<DatabaseConnection>,
-) -> Result<(), TransactionError<DbErr>> {
-    db.transaction::<_, (), DbErr>(|txn| {
-        Box::pin(async move {
-            let mail = session.mail.save(txn).await?;
-            let mail_id = mail.id.unwrap();
-
-            for recipient in session.recipients {
-                MailRecipientActive {
-                    mail_id: Set(mail_id),
-                    recipient_id: Set(recipient.0.id),
-                    ..Default::default()
-                }
-                .save(txn)
+fn new_decision(status: EmailStatus, rule: DecisionRule, detail: Option<String>) -> DecisionActive {
+    DecisionActive {
+        time_decided: Set(Utc::now().into()),
+        status: Set(status),
+        rule: Set(rule),
+        detail: Set(detail),
+        ..Default::default()
+    }
+}
+
+/// A retry of a message already recorded, decided by what happened to it
+/// before
+async fn handle_existing(
+    session_data: &SessionData,
+    existing_message: MailModel,
+    db: &Arc<dyn GreylistStore>,
+    greylist_time_seconds: i64,
+    queue_id: &Option<String>,
+) -> Result<Status, DbErr> {
+    let mail_id = existing_message.id;
+    let previous_status = Some(format!("{:?}", existing_message.status));
+    Ok(match existing_message.status {
+        Greylisted => {
+            let previously_received = existing_message.time_received;
+            // If the message was greylisted but we've waited long enough
+            let now = Utc::now();
+            if previously_received
+                .checked_add_signed(Duration::seconds(greylist_time_seconds))
+                .unwrap()
+                < now
+            {
+                let waited = now.signed_duration_since(previously_received);
+                db.update_status(
+                    mail_id,
+                    PassedGreylistAccepted,
+                    Some(now.into()),
+                    StatusChange::milter(format!("Retried after {} seconds", waited.num_seconds())),
+                    session_data.mail.clone(),
+                    new_decision(PassedGreylistAccepted, ExistingMessage, previous_status),
+                )
                 .await?;
+                info!(?queue_id, ?previously_received, "Greylisted accepted");
+                Status::Continue
+            } else {
+                // We know there's already a record for this message in the database; reject this one
+                db.insert_attempt(
+                    mail_id,
+                    session_data.mail.clone(),
+                    new_decision(Greylisted, ExistingMessage, previous_status),
+                )
+                .await?;
+                info!(?queue_id, ?previously_received, "Still greylisted");
+                Status::Tempfail
             }
-
-            Ok(())
-        })
+        }
+        Denied => {
+            db.insert_attempt(
+                mail_id,
+                session_data.mail.clone(),
+                new_decision(Denied, ExistingMessage, previous_status),
+            )
+            .await?;
+            info!(?queue_id, "Previously denied");
+            Status::Discard
+        }
+        // Turned away again, as the client retrying doesn't change why
+        Rejected => {
+            db.insert_attempt(
+                mail_id,
+                session_data.mail.clone(),
+                new_decision(Rejected, ExistingMessage, previous_status),
+            )
+            .await?;
+            info!(?queue_id, "Previously rejected");
+            Status::Reject
+        }
+        status @ (AuthenticatedAccepted
+        | IpAccepted
+        | KnownGoodAccepted
+        | LocallyAccepted
+        | OtherAccepted
+        | PassedGreylistAccepted
+        | SpfAccepted
+        | AllowlistAccepted) => {
+            db.insert_attempt(
+                mail_id,
+                session_data.mail.clone(),
+                new_decision(status.clone(), ExistingMessage, previous_status),
+            )
+            .await?;
+            info!(?queue_id, ?status, "Previously accepted");
+            Status::Accept
+        }
     })
-    .await
+}
+
+/// Records a message seen for the first time along with the decision made
+/// about it, and returns what to tell the MTA. If another session, here or
+/// in another milter instance sharing the database, recorded the same
+/// Message-Id first, this attempt is handled as a retry of that message
+/// instead.
+async fn store_decision(
+    session: &mut SessionData,
+    db: &Arc<dyn GreylistStore>,
+    greylist_time_seconds: i64,
+    status: EmailStatus,
+    rule: DecisionRule,
+    detail: Option<String>,
+) -> Result<Status, DbErr> {
+    let reply = reply_for(&status);
+    if reply == Status::Continue {
+        session.mail.time_accepted = Set(Some(Utc::now().into()));
+    }
+    session.mail.status = Set(status.clone());
+    let queue_id = session.mail.queue_id.clone().unwrap();
+    let recipient_ids = session.recipients.iter().map(|recipient| recipient.0.id);
+    match db
+        .insert_mail(
+            session.mail.clone(),
+            recipient_ids.collect(),
+            new_decision(status.clone(), rule.clone(), detail.clone()),
+        )
+        .await?
+    {
+        InsertedMail::New(_) => {
+            info!(?queue_id, ?rule, ?detail, "{:?}", status);
+            Ok(reply)
+        }
+        InsertedMail::Existing(existing_message) => {
+            info!(
+                ?queue_id,
+                existing_message.id, "Recorded at the same time by another session"
+            );
+            handle_existing(
+                session,
+                *existing_message,
+                db,
+                greylist_time_seconds,
+                &queue_id,
+            )
+            .await
+        }
+    }
+}
+
+/// What to tell the MTA about a message seen for the first time
+fn reply_for(status: &EmailStatus) -> Status {
+    match status {
+        Greylisted => Status::Tempfail,
+        Denied | Rejected => Status::Reject,
+        AuthenticatedAccepted
+        | IpAccepted
+        | KnownGoodAccepted
+        | LocallyAccepted
+        | OtherAccepted
+        | PassedGreylistAccepted
+        | SpfAccepted
+        | AllowlistAccepted => Status::Continue,
+    }
 }
@@ -0,0 +1,35 @@
+use std::io;
+
+use tracing::warn;
+use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+
+use crate::settings::{LogFormat, Settings};
+
+/// Set up the global subscriber from the `[logging]` section. `RUST_LOG`, if
+/// set, takes precedence over the configured filter. Logs go to standard
+/// error, leaving standard output to what the admin commands print.
+pub fn init(config: &Settings) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        EnvFilter::try_new(config.get_log_filter()).expect("Unable to parse logging filter")
+    });
+    let registry = tracing_subscriber::registry().with(filter);
+
+    match config.get_log_format() {
+        LogFormat::Text => registry.with(fmt::layer().with_writer(io::stderr)).init(),
+        LogFormat::Json => registry
+            .with(
+                fmt::layer()
+                    .json()
+                    .with_current_span(true)
+                    .with_writer(io::stderr),
+            )
+            .init(),
+        LogFormat::Journald => match tracing_journald::layer() {
+            Ok(layer) => registry.with(layer).init(),
+            Err(e) => {
+                registry.with(fmt::layer().with_writer(io::stderr)).init();
+                warn!("Unable to connect to journald, logging to stderr: {}", e);
+            }
+        },
+    }
+}
@@ -1,23 +1,429 @@
-use std::io;
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter},
+    path::PathBuf,
+    process,
+};
 
-use sql_greylist_milter::real_main;
-use tokio::signal::unix::SignalKind;
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use clap::{Parser, Subcommand, ValueEnum};
+use entity::{email_status::EmailStatus, rollup_dimension::RollupDimension};
+use sea_orm::{Database, DatabaseConnection};
+use sql_greylist_milter::{
+    admin, connect_database,
+    legacy::{self, LegacySummary, PostgreyList},
+    logging, partition, real_main,
+    rollup::{self, Period},
+    settings::Settings,
+    store::{self, StatusChange},
+    transfer::{self, ExportFilter, Format},
+};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{debug, error, info, warn};
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Configuration file
+    #[arg(short, long, default_value = concat!("/etc/", env!("CARGO_PKG_NAME"), ".toml"))]
+    config: String,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the milter (the default)
+    Run,
+    /// Show what is stored about a message, from an SQL database
+    Show {
+        /// Message-Id header, including the angle brackets
+        message_id: String,
+    },
+    /// Change the status of a message by hand, e.g. to Denied, recording
+    /// who did it in its status history
+    SetStatus {
+        /// Message-Id header, including the angle brackets
+        message_id: String,
+        /// The new status, e.g. Denied or OtherAccepted
+        #[arg(value_parser = transfer::parse_status)]
+        status: EmailStatus,
+        /// Why, kept with the change
+        #[arg(long)]
+        note: Option<String>,
+        /// Who to record the change as, instead of $USER
+        #[arg(long)]
+        user: Option<String>,
+    },
+    /// Print statistics from the hourly and daily rollups, which are only
+    /// kept in an SQL database
+    Report {
+        #[arg(long, value_enum, default_value_t = ReportPeriod::Daily)]
+        period: ReportPeriod,
+        /// What to break the figures down by
+        #[arg(long, value_enum, default_value_t = ReportBy::Status)]
+        by: ReportBy,
+        /// How many days to cover, including today
+        #[arg(long, default_value_t = 7)]
+        days: u32,
+        #[arg(long, value_enum, default_value_t = ReportFormat::Table)]
+        format: ReportFormat,
+        /// Bring the rollups up to date first, for when the milter isn't
+        /// updating them
+        #[arg(long)]
+        update: bool,
+    },
+    /// Write messages and their recipients out of an SQL database, for
+    /// another environment or for analysis
+    Export {
+        #[arg(long, value_enum, default_value_t = DataFormat::Ndjson)]
+        format: DataFormat,
+        /// Only messages received at or after this RFC 3339 time or date
+        #[arg(long, value_parser = transfer::parse_time)]
+        since: Option<DateTime<Utc>>,
+        /// Only messages received before this RFC 3339 time or date
+        #[arg(long, value_parser = transfer::parse_time)]
+        until: Option<DateTime<Utc>>,
+        /// Only messages with this status, e.g. Greylisted; may be repeated
+        #[arg(long = "status", value_parser = transfer::parse_status)]
+        statuses: Vec<EmailStatus>,
+        /// File to write to instead of standard output
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Load messages written by export, skipping those already stored
+    Import {
+        /// File to read, or - for standard input
+        input: PathBuf,
+        #[arg(long, value_enum, default_value_t = DataFormat::Ndjson)]
+        format: DataFormat,
+    },
+    /// Load whitelisted client networks, host names and recipients from
+    /// postgrey's whitelist files
+    ImportPostgrey {
+        /// A whitelist_clients file; may be repeated
+        #[arg(long = "clients")]
+        clients: Vec<PathBuf>,
+        /// A whitelist_recipients file; may be repeated
+        #[arg(long = "recipients")]
+        recipients: Vec<PathBuf>,
+    },
+    /// Partition mail, and the tables pointing at it, by month on Postgres if
+    /// it isn't already, then create and drop partitions as configured in
+    /// [partitioning]. Partitioning can't be undone; restart the milter
+    /// afterwards. With the embedded store, only deletes mail past retention
+    Partition,
+    /// Load clients that passed greylisting from sqlgrey's auto-whitelists,
+    /// for the sender domains they sent from
+    ImportSqlgrey {
+        /// sqlgrey's database, e.g. postgres://sqlgrey@localhost/sqlgrey
+        url: String,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ReportPeriod {
+    Hourly,
+    Daily,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ReportBy {
+    Status,
+    SenderDomain,
+    RecipientDomain,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ReportFormat {
+    Table,
+    Csv,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum DataFormat {
+    Csv,
+    Ndjson,
+}
+
+impl From<DataFormat> for Format {
+    fn from(format: DataFormat) -> Self {
+        match format {
+            DataFormat::Csv => Format::Csv,
+            DataFormat::Ndjson => Format::Ndjson,
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() {
+    let cli = Cli::parse();
+    let config = Settings::new(&cli.config)
+        .unwrap_or_else(|e| panic!("Unable to read configuration from {}: {}", cli.config, e));
+
     // Set up logging
-    tracing_subscriber::fmt::init();
+    logging::init(&config);
 
-    real_main(
-        format!("/etc/{}.toml", env!("CARGO_PKG_NAME")),
-        await_sigint(),
-    )
-    .await;
+    match cli.command.unwrap_or(Command::Run) {
+        Command::Run => real_main(config, await_shutdown_signal()).await,
+        Command::Show { message_id } => {
+            let db = connect_sql(&config, "show").await;
+            admin::show(&db, &message_id)
+                .await
+                .expect("Unable to read from database");
+        }
+        Command::SetStatus {
+            message_id,
+            status,
+            note,
+            user,
+        } => {
+            let db = store::open(&config)
+                .await
+                .expect("Unable to connect to database");
+            match db
+                .find_mail(&message_id)
+                .await
+                .expect("Unable to read from database")
+            {
+                Some(mail) => {
+                    let user = user
+                        .or_else(|| env::var("USER").ok())
+                        .unwrap_or_else(|| "unknown".to_string());
+                    let change = StatusChange {
+                        actor: format!("cli:{}", user),
+                        note,
+                    };
+                    let old_status = db
+                        .set_status(mail.id, status.clone(), change)
+                        .await
+                        .expect("Unable to update status");
+                    info!(
+                        "Changed {} from {:?} to {:?}",
+                        message_id, old_status, status
+                    );
+                }
+                None => warn!("No message with Message-Id {}", message_id),
+            }
+            db.close().await.expect("Unable to close database");
+        }
+        Command::Report {
+            period,
+            by,
+            days,
+            format,
+            update,
+        } => {
+            let db = connect_sql(&config, "report").await;
+            if update {
+                rollup::update(&db)
+                    .await
+                    .expect("Unable to update statistics rollups");
+            }
+            let since = Utc::now()
+                .duration_trunc(Duration::days(1))
+                .expect("Days always fit")
+                - Duration::days(i64::from(days.max(1)) - 1);
+            let period = match period {
+                ReportPeriod::Hourly => Period::Hourly,
+                ReportPeriod::Daily => Period::Daily,
+            };
+            let dimension = match by {
+                ReportBy::Status => RollupDimension::Status,
+                ReportBy::SenderDomain => RollupDimension::SenderDomain,
+                ReportBy::RecipientDomain => RollupDimension::RecipientDomain,
+            };
+            admin::report(
+                &db,
+                period,
+                dimension,
+                since,
+                matches!(format, ReportFormat::Csv),
+            )
+            .await
+            .expect("Unable to read from database");
+        }
+        Command::Export {
+            format,
+            since,
+            until,
+            statuses,
+            output,
+        } => {
+            let db = connect_sql(&config, "export").await;
+            let filter = ExportFilter {
+                since,
+                until,
+                statuses,
+            };
+            let exported = match output {
+                Some(path) => {
+                    let file = File::create(&path)
+                        .unwrap_or_else(|e| panic!("Unable to create {}: {}", path.display(), e));
+                    transfer::export(&db, &filter, format.into(), BufWriter::new(file)).await
+                }
+                None => transfer::export(&db, &filter, format.into(), io::stdout().lock()).await,
+            }
+            .expect("Unable to export");
+            info!("Exported {} messages", exported);
+        }
+        Command::Import { input, format } => {
+            let db = store::open(&config)
+                .await
+                .expect("Unable to connect to database");
+            let lowercase_local_part = config.get_lowercase_local_part();
+            let summary = if input.as_os_str() == "-" {
+                transfer::import(
+                    db.as_ref(),
+                    io::stdin().lock(),
+                    format.into(),
+                    lowercase_local_part,
+                )
+                .await
+            } else {
+                let file = File::open(&input)
+                    .unwrap_or_else(|e| panic!("Unable to open {}: {}", input.display(), e));
+                transfer::import(
+                    db.as_ref(),
+                    BufReader::new(file),
+                    format.into(),
+                    lowercase_local_part,
+                )
+                .await
+            }
+            .expect("Unable to import");
+            info!(
+                "Imported {} messages, skipped {} already stored",
+                summary.imported, summary.skipped
+            );
+            db.close().await.expect("Unable to close database");
+        }
+        Command::Partition => {
+            // The conversion expects the schema to be up to date
+            let store = store::open(&config)
+                .await
+                .expect("Unable to connect to database");
+            let Some(db) = store.sql_connection() else {
+                // The embedded store has no partitions, only mail to expire
+                if let Some(retention_months) = config.get_partition_retention_months() {
+                    let expired = partition::expire(store.as_ref(), retention_months, Utc::now())
+                        .await
+                        .expect("Unable to delete mail past retention");
+                    info!("Deleted {} mails past retention", expired);
+                }
+                store.close().await.expect("Unable to close database");
+                return;
+            };
+            if partition::convert(db, Utc::now())
+                .await
+                .expect("Unable to partition mail")
+            {
+                info!("Partitioned mail by month");
+            }
+            let maintenance = partition::maintain(
+                db,
+                config.get_partition_months_ahead(),
+                config.get_partition_retention_months(),
+                Utc::now(),
+            )
+            .await
+            .expect("Unable to maintain mail partitions");
+            info!(
+                "Created {} partitions, dropped {}",
+                maintenance.created.len(),
+                maintenance.dropped.len()
+            );
+            store.close().await.expect("Unable to close database");
+        }
+        Command::ImportPostgrey {
+            clients,
+            recipients,
+        } => {
+            let db = store::open(&config)
+                .await
+                .expect("Unable to connect to database");
+            let lists = clients
+                .into_iter()
+                .map(|path| (PostgreyList::Clients, path))
+                .chain(
+                    recipients
+                        .into_iter()
+                        .map(|path| (PostgreyList::Recipients, path)),
+                );
+            for (list, path) in lists {
+                let contents = fs::read_to_string(&path)
+                    .unwrap_or_else(|e| panic!("Unable to read {}: {}", path.display(), e));
+                let source = format!("postgrey {}", path.display());
+                let summary = legacy::import_postgrey(
+                    db.as_ref(),
+                    list,
+                    &contents,
+                    &source,
+                    config.get_lowercase_local_part(),
+                )
+                .await
+                .expect("Unable to import");
+                report_legacy(&source, &summary);
+            }
+            db.close().await.expect("Unable to close database");
+        }
+        Command::ImportSqlgrey { url } => {
+            let db = store::open(&config)
+                .await
+                .expect("Unable to connect to database");
+            let sqlgrey = Database::connect(url)
+                .await
+                .expect("Unable to connect to sqlgrey database");
+            let summary = legacy::import_sqlgrey(db.as_ref(), &sqlgrey)
+                .await
+                .expect("Unable to import");
+            report_legacy("sqlgrey", &summary);
+            sqlgrey.close().await.expect("Unable to close database");
+            db.close().await.expect("Unable to close database");
+        }
+    }
 }
 
-async fn await_sigint() -> io::Result<()> {
-    tokio::signal::unix::signal(SignalKind::terminate())?
-        .recv()
-        .await;
+// For the commands that read the SQL schema directly, which the embedded
+// store doesn't have
+async fn connect_sql(config: &Settings, command: &str) -> DatabaseConnection {
+    if config.get_embedded_db_path().is_some() {
+        error!("{} is not supported with the embedded store", command);
+        process::exit(1);
+    }
+    connect_database(config)
+        .await
+        .expect("Unable to connect to database")
+}
+
+fn report_legacy(source: &str, summary: &LegacySummary) {
+    info!(
+        "{}: imported {} networks, {} already allowed, skipped {} entries",
+        source,
+        summary.imported,
+        summary.existing,
+        summary.skipped.len()
+    );
+    let mut reasons = BTreeMap::new();
+    for skipped in &summary.skipped {
+        debug!(entry = skipped.entry, reason = skipped.reason, "Skipped");
+        *reasons.entry(skipped.reason).or_insert(0) += 1;
+    }
+    for (reason, count) in reasons {
+        info!("{}: skipped {} entries, {}", source, count, reason);
+    }
+}
+
+async fn await_shutdown_signal() -> io::Result<()> {
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+
+    tokio::select! {
+        _ = sigint.recv() => info!("Received SIGINT"),
+        _ = sigterm.recv() => info!("Received SIGTERM"),
+    }
     Ok(())
 }
@@ -0,0 +1,628 @@
+//! Optional range partitioning of `mail` by month of `time_received`, on
+//! Postgres only. The tables pointing at `mail.id` carry the time_received of
+//! their mail in `mail_time_received` and are partitioned the same way, so
+//! each month of mail and everything about it sits in partitions of the
+//! same name. `convert` turns the existing tables into the first partitions;
+//! after that `maintain` keeps partitions created ahead of time, and detaches
+//! and drops those older than the retention period.
+//!
+//! Postgres can only enforce uniqueness on a partitioned table across the
+//! partition key, so `mail.message_id` is no longer unique once partitioned.
+//! The store makes up for that by serializing inserts of the same Message-Id
+//! itself. The foreign keys onto `mail.id` go too, as a partition of `mail`
+//! can't be detached while they point at it. Nothing enforces that every row
+//! pointing at `mail.id` has its mail any more: the store writes those rows
+//! in the same transaction as their mail, with its time_received, and
+//! retention takes away a month of mail and of what points at it together.
+
+use std::{iter, sync::Arc, time};
+
+use chrono::{DateTime, Datelike, Months, NaiveDate, Utc};
+use sea_orm::{
+    ConnectionTrait, DatabaseBackend, DatabaseConnection, DatabaseTransaction, DbErr, Statement,
+    TransactionTrait,
+};
+use tokio::{
+    sync::OnceCell,
+    task::JoinHandle,
+    time::{interval, MissedTickBehavior},
+};
+use tracing::{debug, info, warn};
+
+use crate::store::GreylistStore;
+
+/// Catches anything outside the monthly partitions, so mail is still stored
+/// if maintenance falls behind
+const DEFAULT_PARTITION: &str = "mail_default";
+
+/// The indexes of the partitioned `mail`
+const MAIL_INDEXES: [&str; 5] = [
+    "CREATE INDEX idx_mail_messageid ON mail (message_id)",
+    "CREATE INDEX idx_mail_senderip ON mail (sending_ip, status)",
+    "CREATE INDEX idx_mail_senderdomain ON mail (sender_domain, status)",
+    "CREATE INDEX idx_mail_timereceived ON mail (time_received)",
+    "CREATE INDEX idx_mail_sendinginet ON mail USING gist (sending_inet inet_ops)",
+];
+
+/// What points at `mail.id`, partitioned on `mail_time_received` along with
+/// it, and the indexes of each
+const REFERENCING_TABLES: [(&str, &[&str]); 4] = [
+    (
+        "mail_recipient",
+        &["CREATE INDEX idx_mailrecipient_mailid ON mail_recipient (mail_id)"],
+    ),
+    (
+        "decision",
+        &["CREATE INDEX idx_decision_mailid ON decision (mail_id, time_decided)"],
+    ),
+    (
+        "delivery_attempt",
+        &[
+            "CREATE INDEX idx_deliveryattempt_mailid ON delivery_attempt (mail_id, time_attempted)",
+            "CREATE INDEX idx_deliveryattempt_sendinginet
+             ON delivery_attempt USING gist (sending_inet inet_ops)",
+            "CREATE INDEX idx_deliveryattempt_timeattempted ON delivery_attempt (time_attempted)",
+        ],
+    ),
+    (
+        "mail_status_history",
+        &[
+            "CREATE INDEX idx_mailstatushistory_mailid ON mail_status_history (mail_id, time_changed)",
+            "CREATE INDEX idx_mailstatushistory_timechanged ON mail_status_history (time_changed)",
+        ],
+    ),
+];
+
+/// The partition key of the tables pointing at `mail.id`
+const MAIL_TIME_RECEIVED: &str = "mail_time_received";
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Maintenance {
+    pub created: Vec<String>,
+    pub dropped: Vec<String>,
+    /// Mail moved out of the default partition into those created
+    pub moved: u64,
+    /// Mail in the default partition deleted for being past retention
+    pub expired: u64,
+}
+
+fn month_start(time: DateTime<Utc>) -> NaiveDate {
+    NaiveDate::from_ymd_opt(time.year(), time.month(), 1).expect("The first is always valid")
+}
+
+fn month_name(month: NaiveDate) -> String {
+    format!("y{:04}m{:02}", month.year(), month.month())
+}
+
+/// `mail_y2026m10` holds October 2026
+fn partition_name(month: NaiveDate) -> String {
+    format!("mail_{}", month_name(month))
+}
+
+/// `mail_before_y2026m11` is the table from before partitioning, holding
+/// everything up to November 2026
+fn legacy_partition_name(table: &str, end: NaiveDate) -> String {
+    format!("{}_before_{}", table, month_name(end))
+}
+
+/// The partition of `table` that goes with the partition of `mail` named
+/// `mail_partition`, e.g. `decision_y2026m10` with `mail_y2026m10`
+fn table_partition_name(table: &str, mail_partition: &str) -> String {
+    let suffix = mail_partition
+        .strip_prefix("mail")
+        .expect("Partitions of mail start with its name");
+    format!("{}{}", table, suffix)
+}
+
+// Where a partition's range ends, going by its name
+fn partition_end(name: &str) -> Option<NaiveDate> {
+    let (legacy, month) = match name.strip_prefix("mail_before_y") {
+        Some(month) => (true, month),
+        None => (false, name.strip_prefix("mail_y")?),
+    };
+    let (year, month) = month.split_once('m')?;
+    let start = NaiveDate::from_ymd_opt(year.parse().ok()?, month.parse().ok()?, 1)?;
+    if legacy {
+        Some(start)
+    } else {
+        start.checked_add_months(Months::new(1))
+    }
+}
+
+fn statement(sql: impl Into<String>) -> Statement {
+    Statement::from_string(DatabaseBackend::Postgres, sql)
+}
+
+async fn query_count(db: &impl ConnectionTrait, sql: &str) -> Result<i64, DbErr> {
+    db.query_one(statement(sql))
+        .await?
+        .ok_or_else(|| DbErr::Custom(format!("No rows from {}", sql)))?
+        .try_get_by_index(0)
+}
+
+async fn query_strings(db: &impl ConnectionTrait, sql: &str) -> Result<Vec<String>, DbErr> {
+    db.query_all(statement(sql))
+        .await?
+        .iter()
+        .map(|row| row.try_get_by_index(0))
+        .collect()
+}
+
+/// Whether `mail` has been converted; always false other than on Postgres
+pub async fn is_partitioned(db: &DatabaseConnection) -> Result<bool, DbErr> {
+    if db.get_database_backend() != DatabaseBackend::Postgres {
+        return Ok(false);
+    }
+    let partitioned = query_strings(
+        db,
+        "SELECT relname::text FROM pg_partitioned_table JOIN pg_class ON oid = partrelid
+         WHERE oid = 'mail'::regclass",
+    )
+    .await?;
+    Ok(!partitioned.is_empty())
+}
+
+/// The partitions of `mail`, by name
+pub async fn partitions(db: &impl ConnectionTrait) -> Result<Vec<String>, DbErr> {
+    query_strings(
+        db,
+        "SELECT relname::text FROM pg_inherits JOIN pg_class ON oid = inhrelid
+         WHERE inhparent = 'mail'::regclass ORDER BY relname",
+    )
+    .await
+}
+
+/// Turn `mail`, and the tables pointing at it, into partitioned tables,
+/// keeping the existing tables, as they are, as the partitions for
+/// everything before next month. Returns false if it was already
+/// partitioned. This can't be undone other than by copying the rows back
+/// out.
+pub async fn convert(db: &DatabaseConnection, now: DateTime<Utc>) -> Result<bool, DbErr> {
+    if db.get_database_backend() != DatabaseBackend::Postgres {
+        return Err(DbErr::Custom(
+            "Partitioning is only supported on Postgres".to_string(),
+        ));
+    }
+    if is_partitioned(db).await? {
+        return Ok(false);
+    }
+
+    for (table, _) in REFERENCING_TABLES {
+        let missing = query_strings(
+            db,
+            &format!("SELECT '{}' WHERE to_regclass('{}') IS NULL", table, table),
+        )
+        .await?;
+        if !missing.is_empty() {
+            return Err(DbErr::Custom(format!(
+                "Partitioning needs {} to be there, run the migrations first",
+                table
+            )));
+        }
+    }
+
+    let end = month_start(now)
+        .checked_add_months(Months::new(1))
+        .expect("Months are in range");
+    let txn = db.begin().await?;
+    txn.execute(statement(format!(
+        "LOCK TABLE mail, {} IN ACCESS EXCLUSIVE MODE",
+        REFERENCING_TABLES.map(|(table, _)| table).join(", ")
+    )))
+    .await?;
+
+    // See the module documentation for what replaces them
+    let foreign_keys = txn
+        .query_all(statement(
+            "SELECT conrelid::regclass::text AS table_name, conname::text AS constraint_name
+             FROM pg_constraint WHERE contype = 'f' AND confrelid = 'mail'::regclass",
+        ))
+        .await?;
+    for foreign_key in foreign_keys {
+        let table: String = foreign_key.try_get("", "table_name")?;
+        let constraint: String = foreign_key.try_get("", "constraint_name")?;
+        txn.execute(statement(format!(
+            "ALTER TABLE {} DROP CONSTRAINT \"{}\"",
+            table, constraint
+        )))
+        .await?;
+    }
+
+    partition_table(&txn, "mail", "time_received", end, &MAIL_INDEXES).await?;
+    for (table, indexes) in REFERENCING_TABLES {
+        // Only there already if the migration adding it came first
+        for sql in [
+            format!(
+                "ALTER TABLE {} ADD COLUMN IF NOT EXISTS {} timestamptz",
+                table, MAIL_TIME_RECEIVED
+            ),
+            format!(
+                "UPDATE {table} SET {key} = mail.time_received FROM mail
+                 WHERE mail.id = {table}.mail_id AND {table}.{key} IS NULL",
+                table = table,
+                key = MAIL_TIME_RECEIVED
+            ),
+            format!(
+                "ALTER TABLE {} ALTER COLUMN {} SET NOT NULL",
+                table, MAIL_TIME_RECEIVED
+            ),
+        ] {
+            txn.execute(statement(sql)).await?;
+        }
+        partition_table(&txn, table, MAIL_TIME_RECEIVED, end, indexes).await?;
+    }
+
+    txn.commit().await?;
+    Ok(true)
+}
+
+// Turns `table` into one partitioned by range of `key`, keeping the table as
+// the partition for everything before `end`, next to a default partition.
+// The partitioned table gets `indexes` and the table's foreign keys; the
+// table keeps its own indexes, renamed to make way.
+async fn partition_table(
+    txn: &DatabaseTransaction,
+    table: &str,
+    key: &str,
+    end: NaiveDate,
+    indexes: &[&str],
+) -> Result<(), DbErr> {
+    let legacy = legacy_partition_name(table, end);
+
+    let foreign_keys = txn
+        .query_all(statement(format!(
+            "SELECT conname::text AS constraint_name,
+                    pg_get_constraintdef(oid) AS definition
+             FROM pg_constraint WHERE contype = 'f' AND conrelid = '{}'::regclass",
+            table
+        )))
+        .await?;
+    // Replaced by one across id and the partition key
+    for primary_key in query_strings(
+        txn,
+        &format!(
+            "SELECT conname::text FROM pg_constraint
+             WHERE contype = 'p' AND conrelid = '{}'::regclass",
+            table
+        ),
+    )
+    .await?
+    {
+        txn.execute(statement(format!(
+            "ALTER TABLE {} DROP CONSTRAINT \"{}\"",
+            table, primary_key
+        )))
+        .await?;
+    }
+
+    // Index names are per schema, so the old ones make way for the new
+    txn.execute(statement(format!(
+        "ALTER TABLE {} RENAME TO {}",
+        table, legacy
+    )))
+    .await?;
+    for index in query_strings(
+        txn,
+        &format!(
+            "SELECT indexname::text FROM pg_indexes
+             WHERE schemaname = current_schema() AND tablename = '{}'",
+            legacy
+        ),
+    )
+    .await?
+    {
+        txn.execute(statement(format!(
+            "ALTER INDEX \"{}\" RENAME TO \"{}_{}\"",
+            index,
+            index,
+            month_name(end)
+        )))
+        .await?;
+    }
+
+    let sequence = query_strings(
+        txn,
+        &format!("SELECT pg_get_serial_sequence('{}', 'id')", legacy),
+    )
+    .await?;
+    let mut statements = vec![
+        format!(
+            "CREATE TABLE {} (LIKE {} INCLUDING DEFAULTS INCLUDING GENERATED)
+             PARTITION BY RANGE ({})",
+            table, legacy, key
+        ),
+        format!("ALTER SEQUENCE {} OWNED BY {}.id", sequence[0], table),
+        format!(
+            "ALTER TABLE {table} ADD CONSTRAINT {table}_pkey PRIMARY KEY (id, {key})",
+            table = table,
+            key = key
+        ),
+    ];
+    statements.extend(indexes.iter().map(|index| index.to_string()));
+    for foreign_key in foreign_keys {
+        let constraint: String = foreign_key.try_get("", "constraint_name")?;
+        let definition: String = foreign_key.try_get("", "definition")?;
+        statements.push(format!(
+            "ALTER TABLE {} ADD CONSTRAINT \"{}\" {}",
+            table, constraint, definition
+        ));
+    }
+    statements.push(format!(
+        "ALTER TABLE {} ATTACH PARTITION {} FOR VALUES FROM (MINVALUE) TO ('{}')",
+        table, legacy, end
+    ));
+    statements.push(format!(
+        "CREATE TABLE {} PARTITION OF {} DEFAULT",
+        table_partition_name(table, DEFAULT_PARTITION),
+        table
+    ));
+    for sql in statements {
+        txn.execute(statement(sql)).await?;
+    }
+    Ok(())
+}
+
+/// Mail received before the start of the month `retention_months` whole
+/// months ago is past retention
+#[must_use]
+pub fn retention_cutoff(retention_months: u32, now: DateTime<Utc>) -> NaiveDate {
+    month_start(now)
+        .checked_sub_months(Months::new(retention_months))
+        .expect("Months are in range")
+}
+
+/// Delete the mail past retention from a store without partitions, the
+/// embedded one. Returns how many were.
+pub async fn expire(
+    store: &dyn GreylistStore,
+    retention_months: u32,
+    now: DateTime<Utc>,
+) -> Result<u64, DbErr> {
+    let cutoff = retention_cutoff(retention_months, now)
+        .and_hms_opt(0, 0, 0)
+        .expect("Midnight is valid")
+        .and_utc();
+    store.expire_mail(cutoff.into()).await
+}
+
+/// Create the partitions for this month and `months_ahead` after it, and
+/// drop any that ended more than `retention_months` whole months ago
+pub async fn maintain(
+    db: &DatabaseConnection,
+    months_ahead: u32,
+    retention_months: Option<u32>,
+    now: DateTime<Utc>,
+) -> Result<Maintenance, DbErr> {
+    let mut maintenance = Maintenance::default();
+    let existing = partitions(db).await?;
+    // Nothing can be created inside the range of the partition from before
+    let covered_until = existing
+        .iter()
+        .filter(|name| name.starts_with("mail_before_"))
+        .filter_map(|name| partition_end(name))
+        .max();
+
+    let this_month = month_start(now);
+    for ahead in 0..=months_ahead {
+        let month = this_month
+            .checked_add_months(Months::new(ahead))
+            .expect("Months are in range");
+        let name = partition_name(month);
+        if covered_until.is_some_and(|end| month < end) || existing.contains(&name) {
+            continue;
+        }
+        let end = month
+            .checked_add_months(Months::new(1))
+            .expect("Months are in range");
+        let txn = db.begin().await?;
+        let mut moved = 0;
+        for (table, key) in partitioned_tables() {
+            let partition = table_partition_name(table, &name);
+            let moved_rows = if existing.iter().any(|name| name == DEFAULT_PARTITION) {
+                create_partition_from_default(&txn, table, key, &partition, month, end).await?
+            } else {
+                create_partition(&txn, table, &partition, month, end).await?;
+                0
+            };
+            if table == "mail" {
+                moved = moved_rows;
+            }
+        }
+        txn.commit().await?;
+        info!(partition = name, "Created mail partition");
+        if moved > 0 {
+            warn!(
+                partition = name,
+                moved, "Moved mail out of the default partition, maintenance fell behind"
+            );
+        }
+        maintenance.created.push(name);
+        maintenance.moved += moved;
+    }
+
+    if let Some(retention_months) = retention_months {
+        let cutoff = retention_cutoff(retention_months, now);
+        if existing.iter().any(|name| name == DEFAULT_PARTITION) {
+            let txn = db.begin().await?;
+            let expired = expire_default(&txn, cutoff).await?;
+            txn.commit().await?;
+            if expired > 0 {
+                info!(expired, "Deleted old mail from the default partition");
+            }
+            maintenance.expired = expired;
+        }
+        for name in existing {
+            if partition_end(&name).is_some_and(|end| end <= cutoff) {
+                let txn = db.begin().await?;
+                drop_partition(&txn, &name).await?;
+                txn.commit().await?;
+                info!(partition = name, "Dropped mail partition");
+                maintenance.dropped.push(name);
+            }
+        }
+    }
+
+    Ok(maintenance)
+}
+
+// `mail` and each of the tables pointing at it, with their partition keys
+fn partitioned_tables() -> impl DoubleEndedIterator<Item = (&'static str, &'static str)> {
+    iter::once(("mail", "time_received")).chain(
+        REFERENCING_TABLES
+            .into_iter()
+            .map(|(table, _)| (table, MAIL_TIME_RECEIVED)),
+    )
+}
+
+async fn create_partition(
+    txn: &DatabaseTransaction,
+    table: &str,
+    name: &str,
+    start: NaiveDate,
+    end: NaiveDate,
+) -> Result<(), DbErr> {
+    txn.execute(statement(format!(
+        "CREATE TABLE {} PARTITION OF {} FOR VALUES FROM ('{}') TO ('{}')",
+        name, table, start, end
+    )))
+    .await?;
+    Ok(())
+}
+
+// Postgres won't create a partition while the default one holds rows in its
+// range, so the default is detached while they're moved across. Returns how
+// many were.
+async fn create_partition_from_default(
+    txn: &DatabaseTransaction,
+    table: &str,
+    key: &str,
+    name: &str,
+    start: NaiveDate,
+    end: NaiveDate,
+) -> Result<u64, DbErr> {
+    let default = table_partition_name(table, DEFAULT_PARTITION);
+    let range = format!("{key} >= '{start}' AND {key} < '{end}'");
+    let stranded = query_count(
+        txn,
+        &format!("SELECT COUNT(*) FROM {} WHERE {}", default, range),
+    )
+    .await?;
+    if stranded == 0 {
+        create_partition(txn, table, name, start, end).await?;
+        return Ok(0);
+    }
+
+    txn.execute(statement(format!(
+        "ALTER TABLE {} DETACH PARTITION {}",
+        table, default
+    )))
+    .await?;
+    create_partition(txn, table, name, start, end).await?;
+    // Generated columns can't be inserted into
+    let columns = query_strings(
+        txn,
+        &format!(
+            "SELECT column_name::text FROM information_schema.columns
+             WHERE table_schema = current_schema() AND table_name = '{}'
+             AND is_generated = 'NEVER' ORDER BY ordinal_position",
+            table
+        ),
+    )
+    .await?
+    .join(", ");
+    let moved = txn
+        .execute(statement(format!(
+            "WITH moved AS (DELETE FROM {} WHERE {} RETURNING {})
+             INSERT INTO {} ({}) SELECT {} FROM moved",
+            default, range, columns, name, columns, columns
+        )))
+        .await?
+        .rows_affected();
+    txn.execute(statement(format!(
+        "ALTER TABLE {} ATTACH PARTITION {} DEFAULT",
+        table, default
+    )))
+    .await?;
+    Ok(moved)
+}
+
+// Mail that landed in the default partitions has no partition to be dropped
+// with, so it's deleted once older than the cutoff. Returns how much mail was.
+async fn expire_default(txn: &DatabaseTransaction, cutoff: NaiveDate) -> Result<u64, DbErr> {
+    let mut expired = 0;
+    for (table, key) in partitioned_tables().rev() {
+        let deleted = txn
+            .execute(statement(format!(
+                "DELETE FROM {} WHERE {} < '{}'",
+                table_partition_name(table, DEFAULT_PARTITION),
+                key,
+                cutoff
+            )))
+            .await?
+            .rows_affected();
+        if table == "mail" {
+            expired = deleted;
+        }
+    }
+    Ok(expired)
+}
+
+// What points at the partition's mail is in the partitions of the same name,
+// which go first
+async fn drop_partition(txn: &DatabaseTransaction, name: &str) -> Result<(), DbErr> {
+    for (table, _) in partitioned_tables().rev() {
+        let partition = table_partition_name(table, name);
+        txn.execute(statement(format!(
+            "ALTER TABLE {} DETACH PARTITION {}",
+            table, partition
+        )))
+        .await?;
+        txn.execute(statement(format!("DROP TABLE {}", partition)))
+            .await?;
+    }
+    Ok(())
+}
+
+pub fn spawn_maintainer(
+    db: Arc<OnceCell<Arc<dyn GreylistStore>>>,
+    every: time::Duration,
+    months_ahead: u32,
+    retention_months: Option<u32>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = interval(every);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let Some(store) = db.get() else {
+                continue;
+            };
+            let Some(connection) = store.sql_connection() else {
+                // Nothing to partition, but old mail still has to go
+                let Some(retention_months) = retention_months else {
+                    return;
+                };
+                match expire(store.as_ref(), retention_months, Utc::now()).await {
+                    Ok(0) => (),
+                    Ok(expired) => info!(expired, "Deleted mail past retention"),
+                    Err(e) => warn!("Unable to delete mail past retention: {}", e),
+                }
+                continue;
+            };
+            match is_partitioned(connection).await {
+                Ok(true) => (),
+                Ok(false) => {
+                    debug!("Mail isn't partitioned, not maintaining partitions");
+                    return;
+                }
+                Err(e) => {
+                    warn!("Unable to check mail partitioning: {}", e);
+                    continue;
+                }
+            }
+            if let Err(e) = maintain(connection, months_ahead, retention_months, Utc::now()).await {
+                warn!("Unable to maintain mail partitions: {}", e);
+            }
+        }
+    })
+}
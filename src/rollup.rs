@@ -0,0 +1,555 @@
+//! Hourly and daily figures per status, sender domain and recipient domain,
+//! kept in their own tables so reports don't have to scan `mail`.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    iter,
+    sync::Arc,
+    time,
+};
+
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use entity::{
+    delivery_attempt,
+    email_status::EmailStatus::{self, Greylisted, PassedGreylistAccepted},
+    mail, mail_recipient, mail_status_history,
+    prelude::{
+        DeliveryAttemptEntity, MailEntity, MailRecipientEntity, MailStatusHistoryEntity,
+        RollupDailyActive, RollupDailyEntity, RollupDailyModel, RollupHourlyActive,
+        RollupHourlyEntity, RollupHourlyModel,
+    },
+    recipient, rollup_daily,
+    rollup_dimension::RollupDimension,
+    rollup_hourly,
+};
+use sea_orm::{
+    prelude::DateTimeWithTimeZone, ColumnTrait, DatabaseConnection, DbErr, EntityTrait, JoinType,
+    QueryFilter, QueryOrder, QuerySelect, RelationTrait, Set, TransactionTrait,
+};
+use tokio::{
+    sync::OnceCell,
+    task::JoinHandle,
+    time::{interval, MissedTickBehavior},
+};
+use tracing::{debug, info, warn};
+
+use crate::store::GreylistStore;
+
+/// Senders keep retrying for a while, so the most recent days are worked out
+/// again on every update
+const RECOMPUTE_DAYS: i64 = 2;
+
+/// Rows are written a moment after the time they carry, so changes are looked
+/// for from this long before the previous update started
+const CHANGE_SLACK_MINUTES: i64 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Period {
+    Hourly,
+    Daily,
+}
+
+/// One row of either rollup table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rollup {
+    pub period_start: DateTimeWithTimeZone,
+    pub dimension: RollupDimension,
+    /// Status name or domain
+    pub name: String,
+    pub messages: i32,
+    /// Messages whose first attempt was greylisted
+    pub greylisted: i32,
+    /// Greylisted messages with no attempt after the first
+    pub never_retried: i32,
+    /// Between the first and second attempts of greylisted messages
+    pub median_retry_seconds: Option<i64>,
+}
+
+impl From<RollupHourlyModel> for Rollup {
+    fn from(row: RollupHourlyModel) -> Self {
+        Self {
+            period_start: row.period_start,
+            dimension: row.dimension,
+            name: row.name,
+            messages: row.messages,
+            greylisted: row.greylisted,
+            never_retried: row.never_retried,
+            median_retry_seconds: row.median_retry_seconds,
+        }
+    }
+}
+
+impl From<RollupDailyModel> for Rollup {
+    fn from(row: RollupDailyModel) -> Self {
+        Self {
+            period_start: row.period_start,
+            dimension: row.dimension,
+            name: row.name,
+            messages: row.messages,
+            greylisted: row.greylisted,
+            never_retried: row.never_retried,
+            median_retry_seconds: row.median_retry_seconds,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct MailFacts {
+    time_received: DateTime<Utc>,
+    status: EmailStatus,
+    sender_domain: String,
+    recipient_domains: Vec<String>,
+    greylisted: bool,
+    never_retried: bool,
+    retry_seconds: Option<i64>,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    messages: i32,
+    greylisted: i32,
+    never_retried: i32,
+    retry_seconds: Vec<i64>,
+}
+
+impl Counts {
+    fn add(&mut self, other: &Self) {
+        self.messages += other.messages;
+        self.greylisted += other.greylisted;
+        self.never_retried += other.never_retried;
+        self.retry_seconds.extend(&other.retry_seconds);
+    }
+}
+
+/// The counts of one hour, by dimension and name
+type HourCounts = BTreeMap<(RollupDimension, String), Counts>;
+
+/// Keeps the counts of the hours it may still have to work out again, so that
+/// after the first update it only reads the mail of the hours that changed
+/// since the one before. The daily rows are added up from those counts.
+#[derive(Debug, Default)]
+pub struct Updater {
+    last_started: Option<DateTime<Utc>>,
+    /// Every hour from `RECOMPUTE_DAYS` before today on that had mail
+    hours: BTreeMap<DateTime<Utc>, HourCounts>,
+}
+
+/// Update the rollups every `every` while the milter runs. Until the
+/// database is available after a degraded start there is nothing to do.
+pub fn spawn_updater(
+    db: Arc<OnceCell<Arc<dyn GreylistStore>>>,
+    every: time::Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = interval(every);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut updater = Updater::default();
+        loop {
+            ticker.tick().await;
+            let Some(store) = db.get() else {
+                continue;
+            };
+            let Some(connection) = store.sql_connection() else {
+                info!("Statistics rollups need an SQL database, not updating them");
+                return;
+            };
+            if let Err(e) = updater.update(connection).await {
+                warn!("Unable to update statistics rollups: {}", e);
+            }
+        }
+    })
+}
+
+/// Work out the rollups for every day since the last update, and the
+/// `RECOMPUTE_DAYS` before it. The first update goes back to the oldest mail.
+pub async fn update(db: &DatabaseConnection) -> Result<(), DbErr> {
+    Updater::default().update(db).await
+}
+
+impl Updater {
+    /// The first time, the same as [`update`]. After that, only the hours
+    /// with mail received, attempted or changed since the previous update
+    /// are read again, as long as they're within `RECOMPUTE_DAYS`.
+    pub async fn update(&mut self, db: &DatabaseConnection) -> Result<(), DbErr> {
+        let started = Utc::now();
+        let today = start_of_day(started);
+        let recent = today - Duration::days(RECOMPUTE_DAYS);
+        self.hours = self.hours.split_off(&recent);
+
+        match self.last_started {
+            Some(last_started) => {
+                let since = last_started - Duration::minutes(CHANGE_SLACK_MINUTES);
+                self.update_changed(db, recent, since).await?;
+            }
+            None => self.update_all(db, today, recent).await?,
+        }
+        self.last_started = Some(started);
+        Ok(())
+    }
+
+    async fn update_all(
+        &mut self,
+        db: &DatabaseConnection,
+        today: DateTime<Utc>,
+        recent: DateTime<Utc>,
+    ) -> Result<(), DbErr> {
+        let last_daily = RollupDailyEntity::find()
+            .order_by_desc(rollup_daily::Column::PeriodStart)
+            .one(db)
+            .await?;
+        let mut day = match last_daily {
+            Some(last) => {
+                start_of_day(last.period_start.with_timezone(&Utc)).min(today)
+                    - Duration::days(RECOMPUTE_DAYS)
+            }
+            None => match MailEntity::find()
+                .order_by_asc(mail::Column::TimeReceived)
+                .one(db)
+                .await?
+            {
+                Some(first) => start_of_day(first.time_received.with_timezone(&Utc)),
+                None => return Ok(()),
+            },
+        };
+
+        while day <= today {
+            let end = day + Duration::days(1);
+            let mails = load_mails(db, day, end).await?;
+            debug!(
+                "Rolling up {} messages received on {}",
+                mails.len(),
+                day.date_naive()
+            );
+            let hours = count(&mails);
+            let all_hours: Vec<_> = (0..24).map(|i| day + Duration::hours(i)).collect();
+            write_day(db, day, &all_hours, &hours).await?;
+            if day >= recent {
+                self.hours.retain(|hour, _| *hour < day || *hour >= end);
+                self.hours.extend(hours);
+            }
+            day = end;
+        }
+        Ok(())
+    }
+
+    async fn update_changed(
+        &mut self,
+        db: &DatabaseConnection,
+        recent: DateTime<Utc>,
+        since: DateTime<Utc>,
+    ) -> Result<(), DbErr> {
+        let changed = changed_hours(db, recent, since).await?;
+        debug!("Rolling up {} hours with changed mail", changed.len());
+
+        let mut days: BTreeMap<DateTime<Utc>, Vec<DateTime<Utc>>> = BTreeMap::new();
+        for hour in changed {
+            let mails = load_mails(db, hour, hour + Duration::hours(1)).await?;
+            match count(&mails).remove(&hour) {
+                Some(counts) => self.hours.insert(hour, counts),
+                None => self.hours.remove(&hour),
+            };
+            days.entry(start_of_day(hour)).or_default().push(hour);
+        }
+        for (day, hours) in days {
+            write_day(db, day, &hours, &self.hours).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Rows of the `period` table from `since` on, busiest first within each period
+pub async fn load(
+    db: &DatabaseConnection,
+    period: Period,
+    dimension: RollupDimension,
+    since: DateTime<Utc>,
+) -> Result<Vec<Rollup>, DbErr> {
+    let since = since.fixed_offset();
+    Ok(match period {
+        Period::Hourly => RollupHourlyEntity::find()
+            .filter(rollup_hourly::Column::PeriodStart.gte(since))
+            .filter(rollup_hourly::Column::Dimension.eq(dimension))
+            .order_by_asc(rollup_hourly::Column::PeriodStart)
+            .order_by_desc(rollup_hourly::Column::Messages)
+            .order_by_asc(rollup_hourly::Column::Name)
+            .all(db)
+            .await?
+            .into_iter()
+            .map(Rollup::from)
+            .collect(),
+        Period::Daily => RollupDailyEntity::find()
+            .filter(rollup_daily::Column::PeriodStart.gte(since))
+            .filter(rollup_daily::Column::Dimension.eq(dimension))
+            .order_by_asc(rollup_daily::Column::PeriodStart)
+            .order_by_desc(rollup_daily::Column::Messages)
+            .order_by_asc(rollup_daily::Column::Name)
+            .all(db)
+            .await?
+            .into_iter()
+            .map(Rollup::from)
+            .collect(),
+    })
+}
+
+// Replaces the rows of `changed`, hours within `day`, and the day's rows,
+// adding up all of its hours. `hours` has the counts of every hour of the day.
+async fn write_day(
+    db: &DatabaseConnection,
+    day: DateTime<Utc>,
+    changed: &[DateTime<Utc>],
+    hours: &BTreeMap<DateTime<Utc>, HourCounts>,
+) -> Result<(), DbErr> {
+    let end = day + Duration::days(1);
+    let hourly: Vec<_> = changed
+        .iter()
+        .filter_map(|hour| Some((*hour, hours.get(hour)?)))
+        .flat_map(|(hour, counts)| rollups(hour, counts))
+        .collect();
+    let mut day_counts = HourCounts::new();
+    for (_, counts) in hours.range(day..end) {
+        for (key, counts) in counts {
+            day_counts.entry(key.clone()).or_default().add(counts);
+        }
+    }
+    let daily = rollups(day, &day_counts);
+
+    let txn = db.begin().await?;
+    RollupHourlyEntity::delete_many()
+        .filter(
+            rollup_hourly::Column::PeriodStart
+                .is_in(changed.iter().map(|hour| hour.fixed_offset())),
+        )
+        .exec(&txn)
+        .await?;
+    RollupDailyEntity::delete_many()
+        .filter(rollup_daily::Column::PeriodStart.eq(day.fixed_offset()))
+        .exec(&txn)
+        .await?;
+    if !hourly.is_empty() {
+        RollupHourlyEntity::insert_many(hourly.into_iter().map(|rollup| RollupHourlyActive {
+            period_start: Set(rollup.period_start),
+            dimension: Set(rollup.dimension),
+            name: Set(rollup.name),
+            messages: Set(rollup.messages),
+            greylisted: Set(rollup.greylisted),
+            never_retried: Set(rollup.never_retried),
+            median_retry_seconds: Set(rollup.median_retry_seconds),
+            ..Default::default()
+        }))
+        .exec(&txn)
+        .await?;
+    }
+    if !daily.is_empty() {
+        RollupDailyEntity::insert_many(daily.into_iter().map(|rollup| RollupDailyActive {
+            period_start: Set(rollup.period_start),
+            dimension: Set(rollup.dimension),
+            name: Set(rollup.name),
+            messages: Set(rollup.messages),
+            greylisted: Set(rollup.greylisted),
+            never_retried: Set(rollup.never_retried),
+            median_retry_seconds: Set(rollup.median_retry_seconds),
+            ..Default::default()
+        }))
+        .exec(&txn)
+        .await?;
+    }
+    txn.commit().await
+}
+
+/// The hours from `recent` on with mail received, attempted or changed
+/// from `since` on
+async fn changed_hours(
+    db: &DatabaseConnection,
+    recent: DateTime<Utc>,
+    since: DateTime<Utc>,
+) -> Result<BTreeSet<DateTime<Utc>>, DbErr> {
+    let (recent, since) = (recent.fixed_offset(), since.fixed_offset());
+
+    let received: Vec<DateTimeWithTimeZone> = MailEntity::find()
+        .select_only()
+        .column(mail::Column::TimeReceived)
+        .filter(mail::Column::TimeReceived.gte(recent.max(since)))
+        .into_tuple()
+        .all(db)
+        .await?;
+    let attempted: Vec<DateTimeWithTimeZone> = DeliveryAttemptEntity::find()
+        .select_only()
+        .column(mail::Column::TimeReceived)
+        .inner_join(MailEntity)
+        .filter(delivery_attempt::Column::TimeAttempted.gte(since))
+        .filter(mail::Column::TimeReceived.gte(recent))
+        .into_tuple()
+        .all(db)
+        .await?;
+    let changed: Vec<DateTimeWithTimeZone> = MailStatusHistoryEntity::find()
+        .select_only()
+        .column(mail::Column::TimeReceived)
+        .inner_join(MailEntity)
+        .filter(mail_status_history::Column::TimeChanged.gte(since))
+        .filter(mail::Column::TimeReceived.gte(recent))
+        .into_tuple()
+        .all(db)
+        .await?;
+
+    Ok(received
+        .into_iter()
+        .chain(attempted)
+        .chain(changed)
+        .map(|time_received| start_of_hour(time_received.with_timezone(&Utc)))
+        .collect())
+}
+
+async fn load_mails(
+    db: &DatabaseConnection,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<Vec<MailFacts>, DbErr> {
+    let (start, end) = (start.fixed_offset(), end.fixed_offset());
+
+    let mails: Vec<(i32, DateTimeWithTimeZone, EmailStatus, String)> = MailEntity::find()
+        .select_only()
+        .columns([
+            mail::Column::Id,
+            mail::Column::TimeReceived,
+            mail::Column::Status,
+            mail::Column::SenderDomain,
+        ])
+        .filter(mail::Column::TimeReceived.gte(start))
+        .filter(mail::Column::TimeReceived.lt(end))
+        .into_tuple()
+        .all(db)
+        .await?;
+
+    let mut attempts: HashMap<i32, Vec<(DateTimeWithTimeZone, EmailStatus)>> = HashMap::new();
+    for (mail_id, time_attempted, status) in DeliveryAttemptEntity::find()
+        .select_only()
+        .columns([
+            delivery_attempt::Column::MailId,
+            delivery_attempt::Column::TimeAttempted,
+            delivery_attempt::Column::Status,
+        ])
+        .inner_join(MailEntity)
+        .filter(mail::Column::TimeReceived.gte(start))
+        .filter(mail::Column::TimeReceived.lt(end))
+        .order_by_asc(delivery_attempt::Column::TimeAttempted)
+        .into_tuple::<(i32, DateTimeWithTimeZone, EmailStatus)>()
+        .all(db)
+        .await?
+    {
+        attempts
+            .entry(mail_id)
+            .or_default()
+            .push((time_attempted, status));
+    }
+
+    let mut recipient_domains: HashMap<i32, Vec<String>> = HashMap::new();
+    for (mail_id, domain) in MailRecipientEntity::find()
+        .select_only()
+        .column(mail_recipient::Column::MailId)
+        .column(recipient::Column::Domain)
+        .distinct()
+        .join(
+            JoinType::InnerJoin,
+            mail_recipient::Relation::Recipient.def(),
+        )
+        .join(JoinType::InnerJoin, mail_recipient::Relation::Mail.def())
+        .filter(mail::Column::TimeReceived.gte(start))
+        .filter(mail::Column::TimeReceived.lt(end))
+        .into_tuple::<(i32, String)>()
+        .all(db)
+        .await?
+    {
+        recipient_domains.entry(mail_id).or_default().push(domain);
+    }
+
+    Ok(mails
+        .into_iter()
+        .map(|(id, time_received, status, sender_domain)| {
+            let attempts = attempts.remove(&id).unwrap_or_default();
+            // Messages from before attempts were recorded only have their status
+            let greylisted = match attempts.first() {
+                Some((_, first_status)) => *first_status == Greylisted,
+                None => status == Greylisted || status == PassedGreylistAccepted,
+            };
+            let retry_seconds = match attempts.as_slice() {
+                [(first, _), (second, _), ..] if greylisted => {
+                    Some((*second - *first).num_seconds())
+                }
+                _ => None,
+            };
+            MailFacts {
+                time_received: time_received.with_timezone(&Utc),
+                never_retried: greylisted && attempts.len() <= 1 && status == Greylisted,
+                status,
+                sender_domain,
+                recipient_domains: recipient_domains.remove(&id).unwrap_or_default(),
+                greylisted,
+                retry_seconds,
+            }
+        })
+        .collect())
+}
+
+// By the hour they were received in
+fn count(mails: &[MailFacts]) -> BTreeMap<DateTime<Utc>, HourCounts> {
+    let mut hours: BTreeMap<DateTime<Utc>, HourCounts> = BTreeMap::new();
+    for mail in mails {
+        let hour = hours.entry(start_of_hour(mail.time_received)).or_default();
+        let keys = iter::once((RollupDimension::Status, format!("{:?}", mail.status)))
+            .chain(iter::once((
+                RollupDimension::SenderDomain,
+                mail.sender_domain.clone(),
+            )))
+            .chain(
+                mail.recipient_domains
+                    .iter()
+                    .map(|domain| (RollupDimension::RecipientDomain, domain.clone())),
+            );
+        for key in keys {
+            let counts = hour.entry(key).or_default();
+            counts.messages += 1;
+            counts.greylisted += i32::from(mail.greylisted);
+            counts.never_retried += i32::from(mail.never_retried);
+            counts.retry_seconds.extend(mail.retry_seconds);
+        }
+    }
+    hours
+}
+
+fn rollups(period_start: DateTime<Utc>, counts: &HourCounts) -> Vec<Rollup> {
+    counts
+        .iter()
+        .map(|((dimension, name), counts)| Rollup {
+            period_start: period_start.fixed_offset(),
+            dimension: *dimension,
+            name: name.clone(),
+            messages: counts.messages,
+            greylisted: counts.greylisted,
+            never_retried: counts.never_retried,
+            median_retry_seconds: median(&mut counts.retry_seconds.clone()),
+        })
+        .collect()
+}
+
+fn median(values: &mut [i64]) -> Option<i64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_unstable();
+    let middle = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        Some((values[middle - 1] + values[middle]) / 2)
+    } else {
+        Some(values[middle])
+    }
+}
+
+fn start_of_hour(time: DateTime<Utc>) -> DateTime<Utc> {
+    time.duration_trunc(Duration::hours(1))
+        .expect("Hours always fit")
+}
+
+fn start_of_day(time: DateTime<Utc>) -> DateTime<Utc> {
+    time.duration_trunc(Duration::days(1))
+        .expect("Days always fit")
+}
@@ -1,39 +1,80 @@
-use std::str::FromStr;
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
 
 use config::{Config, ConfigError, File};
 use ipnet::IpNet;
 use serde::Deserialize;
 
-#[derive(Debug, Deserialize)]
+use crate::allowlist;
+
+#[derive(Debug, Deserialize, Clone)]
 pub struct Settings {
     milter: Milter,
     database: Database,
     greylist: Option<Greylist>,
     recipient_rewriting: Option<RecipientRewriting>,
+    logging: Option<Logging>,
+    statistics: Option<Statistics>,
+    partitioning: Option<Partitioning>,
+    dns: Option<Dns>,
+    dns_lists: Option<DnsLists>,
+    spf: Option<Spf>,
+    hostnames: Option<Hostnames>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub struct Milter {
     listen_address: String,
+    shutdown_timeout_seconds: Option<u64>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 struct Database {
     r#type: String,
+    #[serde(default)]
     user: String,
+    #[serde(default)]
     pass: String,
+    #[serde(default)]
     host: String,
+    #[serde(default)]
     port: u16,
+    /// Database name, or file name for SQLite and the embedded store
     db_name: String,
+    max_connections: Option<u32>,
+    min_connections: Option<u32>,
+    connect_timeout_seconds: Option<u64>,
+    acquire_timeout_seconds: Option<u64>,
+    idle_timeout_seconds: Option<u64>,
+    decision_timeout_seconds: Option<u64>,
+    fail_mode: Option<FailMode>,
+    startup_max_wait_seconds: Option<u64>,
+    degraded_start: Option<bool>,
+}
+
+/// What to do with a message when the database doesn't answer in time, or
+/// isn't reachable yet after a degraded start
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub enum FailMode {
+    /// Let the message through
+    Open,
+    /// Tell the sender to try again later
+    Closed,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 struct Greylist {
     allow_from_ranges: Vec<String>,
     greylist_time_seconds: i64,
+    lowercase_local_part: Option<bool>,
+    allow_sender_domains: Option<Vec<String>>,
+    allow_sender_domains_require_spf: Option<bool>,
+    allow_recipients: Option<Vec<String>>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 struct RecipientRewriting {
     rewrites: Vec<Rewrite>,
 }
@@ -51,6 +92,94 @@ pub enum ChangeRecipientAction {
     Replace,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+struct Logging {
+    format: Option<LogFormat>,
+    filter: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct Statistics {
+    rollup_interval_seconds: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct Partitioning {
+    months_ahead: Option<u32>,
+    retention_months: Option<u32>,
+    maintenance_interval_seconds: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct Dns {
+    /// `address` or `address:port`, instead of those in /etc/resolv.conf
+    nameservers: Option<Vec<String>>,
+    timeout_seconds: Option<u64>,
+    /// How long the end of headers waits for lookups still outstanding
+    check_timeout_seconds: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct DnsLists {
+    zones: Vec<DnsListZone>,
+    allow_score: Option<i32>,
+    delay_score: Option<i32>,
+    reject_score: Option<i32>,
+    delay_greylist_time_seconds: Option<i64>,
+    cache_seconds: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct DnsListZone {
+    /// e.g. `zen.spamhaus.org` or `list.dnswl.org`
+    pub zone: String,
+    pub kind: DnsListKind,
+    /// Added to the score for a block list, taken off it for an allow
+    /// list; 1 if not given
+    pub weight: Option<i32>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum DnsListKind {
+    Allow,
+    Block,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct Spf {
+    enabled: Option<bool>,
+    trusted_domains: Option<Vec<String>>,
+    known_good_by_domain: Option<bool>,
+    on_fail: Option<SpfFailAction>,
+    fail_greylist_time_seconds: Option<i64>,
+}
+
+/// What to do with new mail from a client the sender domain's SPF record
+/// says may not send for it
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum SpfFailAction {
+    Ignore,
+    /// Greylist for `fail_greylist_time_seconds`, even if known good
+    Delay,
+    Reject,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct Hostnames {
+    trusted_suffixes: Option<Vec<String>>,
+    dynamic_keywords: Option<Vec<String>>,
+    delay_dynamic: Option<bool>,
+    delay_no_reverse_dns: Option<bool>,
+    delay_greylist_time_seconds: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub enum LogFormat {
+    Text,
+    Json,
+    Journald,
+}
+
 impl Settings {
     pub fn new(path: &str) -> Result<Self, ConfigError> {
         let s = Config::builder()
@@ -60,12 +189,36 @@ impl Settings {
         s.try_deserialize()
     }
 
-    #[must_use]
-    pub fn get_db_url(&self) -> String {
+    /// Builds the connection URL, failing if the configured database type is
+    /// unknown or its driver wasn't compiled in.
+    pub fn get_db_url(&self) -> Result<String, ConfigError> {
+        let (feature, compiled_in) = match self.database.r#type.as_str() {
+            "postgres" | "postgresql" => ("postgres", cfg!(feature = "postgres")),
+            "mysql" => ("mysql", cfg!(feature = "mysql")),
+            "sqlite" => ("sqlite", cfg!(feature = "sqlite")),
+            "embedded" => {
+                return Err(ConfigError::Message(
+                    "Database type embedded is not an SQL database".to_string(),
+                ))
+            }
+            other => {
+                return Err(ConfigError::Message(format!(
+                    "Unknown database type {}, expected postgres, mysql, sqlite or embedded",
+                    other
+                )))
+            }
+        };
+        if !compiled_in {
+            return Err(ConfigError::Message(format!(
+                "Database type {} is not supported by this build, rebuild with the {} feature",
+                self.database.r#type, feature
+            )));
+        }
+
         if self.database.r#type.eq("sqlite") {
-            format!("sqlite:{}", self.database.db_name)
+            Ok(format!("sqlite:{}", self.database.db_name))
         } else {
-            format!(
+            Ok(format!(
                 "{}://{}:{}@{}:{}/{}",
                 self.database.r#type,
                 self.database.user,
@@ -73,15 +226,77 @@ impl Settings {
                 self.database.host,
                 self.database.port,
                 self.database.db_name,
-            )
+            ))
         }
     }
 
+    /// The file to keep the embedded key-value store in, if that's the
+    /// configured database type
+    #[must_use]
+    pub fn get_embedded_db_path(&self) -> Option<&str> {
+        (self.database.r#type == "embedded").then_some(self.database.db_name.as_str())
+    }
+
+    #[must_use]
+    pub fn get_max_connections(&self) -> u32 {
+        self.database.max_connections.unwrap_or(100)
+    }
+
+    #[must_use]
+    pub fn get_min_connections(&self) -> u32 {
+        self.database.min_connections.unwrap_or(1)
+    }
+
+    #[must_use]
+    pub fn get_connect_timeout_seconds(&self) -> u64 {
+        self.database.connect_timeout_seconds.unwrap_or(2)
+    }
+
+    /// How long to wait for a free connection from the pool
+    #[must_use]
+    pub fn get_acquire_timeout_seconds(&self) -> u64 {
+        self.database.acquire_timeout_seconds.unwrap_or(5)
+    }
+
+    #[must_use]
+    pub fn get_idle_timeout_seconds(&self) -> u64 {
+        self.database.idle_timeout_seconds.unwrap_or(5)
+    }
+
+    /// Time budget for all the queries behind one greylisting decision
+    #[must_use]
+    pub fn get_decision_timeout_seconds(&self) -> u64 {
+        self.database.decision_timeout_seconds.unwrap_or(10)
+    }
+
+    #[must_use]
+    pub fn get_fail_mode(&self) -> FailMode {
+        self.database.fail_mode.unwrap_or(FailMode::Closed)
+    }
+
+    /// How long to keep retrying the database at startup before giving up
+    #[must_use]
+    pub fn get_startup_max_wait_seconds(&self) -> u64 {
+        self.database.startup_max_wait_seconds.unwrap_or(60)
+    }
+
+    /// Start answering straight away with the fail mode's status and keep
+    /// retrying the database in the background, rather than waiting for it
+    #[must_use]
+    pub fn get_degraded_start(&self) -> bool {
+        self.database.degraded_start.unwrap_or(false)
+    }
+
     #[must_use]
     pub fn get_listen_address(&self) -> &String {
         &self.milter.listen_address
     }
 
+    #[must_use]
+    pub fn get_shutdown_timeout_seconds(&self) -> u64 {
+        self.milter.shutdown_timeout_seconds.unwrap_or(30)
+    }
+
     #[must_use]
     pub fn get_allow_from_networks(&self) -> Vec<IpNet> {
         if let Some(greylist) = &self.greylist {
@@ -103,6 +318,65 @@ impl Settings {
         }
     }
 
+    /// Store the local parts of senders and recipients lowercased, so
+    /// recipients differing only in their case are the same; domains are
+    /// always lowercased
+    #[must_use]
+    pub fn get_lowercase_local_part(&self) -> bool {
+        self.greylist
+            .as_ref()
+            .and_then(|greylist| greylist.lowercase_local_part)
+            .unwrap_or(false)
+    }
+
+    /// Sender domains, and the domains under them, whose mail isn't
+    /// greylisted
+    #[must_use]
+    pub fn get_allow_sender_domains(&self) -> Vec<String> {
+        self.greylist
+            .as_ref()
+            .and_then(|greylist| greylist.allow_sender_domains.as_ref())
+            .map(|domains| {
+                domains
+                    .iter()
+                    .map(|domain| domain.trim_matches('.').to_ascii_lowercase())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Only take the sender domain's word for it if the client passes its
+    /// SPF check, which needs `[spf]`
+    #[must_use]
+    pub fn get_allow_sender_domains_require_spf(&self) -> bool {
+        self.greylist
+            .as_ref()
+            .and_then(|greylist| greylist.allow_sender_domains_require_spf)
+            .unwrap_or(true)
+    }
+
+    /// Recipients whose mail isn't greylisted: addresses, local parts
+    /// followed by `@` for any domain, or domains, as in postgrey's
+    /// whitelist_recipients. Only for messages to no other recipients. Local
+    /// parts only ignore case with `lowercase_local_part`, like recipients
+    #[must_use]
+    pub fn get_allow_recipients(&self) -> Vec<String> {
+        self.greylist
+            .as_ref()
+            .and_then(|greylist| greylist.allow_recipients.as_ref())
+            .map(|recipients| {
+                recipients
+                    .iter()
+                    // Too long to match any recipient stored
+                    .filter_map(|recipient| {
+                        allowlist::normalize_recipient(recipient, self.get_lowercase_local_part())
+                            .ok()
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
     #[must_use]
     pub fn get_rewrites(&self) -> Vec<Rewrite> {
         match &self.recipient_rewriting {
@@ -110,4 +384,273 @@ impl Settings {
             None => vec![],
         }
     }
+
+    #[must_use]
+    pub fn get_log_format(&self) -> LogFormat {
+        self.logging
+            .as_ref()
+            .and_then(|logging| logging.format)
+            .unwrap_or(LogFormat::Text)
+    }
+
+    /// Filter directives in `RUST_LOG` syntax, e.g. `warn,sql_greylist_milter=debug`
+    #[must_use]
+    pub fn get_log_filter(&self) -> &str {
+        self.logging
+            .as_ref()
+            .and_then(|logging| logging.filter.as_deref())
+            .unwrap_or("info")
+    }
+
+    /// How often the milter updates the statistics rollups, 0 to leave that
+    /// to `report --update`
+    #[must_use]
+    pub fn get_rollup_interval_seconds(&self) -> u64 {
+        self.statistics
+            .as_ref()
+            .and_then(|statistics| statistics.rollup_interval_seconds)
+            .unwrap_or(900)
+    }
+
+    /// How many months of `mail` partitions to keep created past the current
+    /// one, on Postgres once `partition` has been run
+    #[must_use]
+    pub fn get_partition_months_ahead(&self) -> u32 {
+        self.partitioning
+            .as_ref()
+            .and_then(|partitioning| partitioning.months_ahead)
+            .unwrap_or(3)
+    }
+
+    /// Whole months of mail to keep before dropping their partitions, and
+    /// those of its recipients, decisions, attempts and status changes, or
+    /// deleting it from the embedded store, `None` to keep everything
+    #[must_use]
+    pub fn get_partition_retention_months(&self) -> Option<u32> {
+        self.partitioning
+            .as_ref()
+            .and_then(|partitioning| partitioning.retention_months)
+    }
+
+    /// How often the milter creates and drops partitions, or expires mail
+    /// from the embedded store, 0 to leave that to `partition`
+    #[must_use]
+    pub fn get_partition_maintenance_interval_seconds(&self) -> u64 {
+        self.partitioning
+            .as_ref()
+            .and_then(|partitioning| partitioning.maintenance_interval_seconds)
+            .unwrap_or(86400)
+    }
+
+    /// Name servers to send queries to, empty to use the system's
+    #[must_use]
+    pub fn get_dns_nameservers(&self) -> Vec<SocketAddr> {
+        let Some(nameservers) = self.dns.as_ref().and_then(|dns| dns.nameservers.as_ref()) else {
+            return vec![];
+        };
+        nameservers
+            .iter()
+            .map(|nameserver| {
+                nameserver
+                    .parse::<SocketAddr>()
+                    .or_else(|_| {
+                        nameserver
+                            .parse::<IpAddr>()
+                            .map(|address| SocketAddr::new(address, 53))
+                    })
+                    .expect("Unable to parse name server address")
+            })
+            .collect()
+    }
+
+    #[must_use]
+    pub fn get_dns_timeout_seconds(&self) -> u64 {
+        self.dns
+            .as_ref()
+            .and_then(|dns| dns.timeout_seconds)
+            .unwrap_or(2)
+    }
+
+    /// Checks with no answer by then are taken as having failed, e.g. SPF as
+    /// a temporary error, rather than holding up the decision
+    #[must_use]
+    pub fn get_dns_check_timeout_seconds(&self) -> u64 {
+        self.dns
+            .as_ref()
+            .and_then(|dns| dns.check_timeout_seconds)
+            .unwrap_or(5)
+    }
+
+    #[must_use]
+    pub fn get_dns_list_zones(&self) -> Vec<DnsListZone> {
+        match &self.dns_lists {
+            Some(dns_lists) => dns_lists.zones.clone(),
+            None => vec![],
+        }
+    }
+
+    /// Clients scoring this far below zero skip greylisting
+    #[must_use]
+    pub fn get_dns_list_allow_score(&self) -> i32 {
+        self.dns_lists
+            .as_ref()
+            .and_then(|dns_lists| dns_lists.allow_score)
+            .unwrap_or(1)
+    }
+
+    /// Clients scoring at least this are greylisted for
+    /// `delay_greylist_time_seconds`, even if they're known good
+    #[must_use]
+    pub fn get_dns_list_delay_score(&self) -> i32 {
+        self.dns_lists
+            .as_ref()
+            .and_then(|dns_lists| dns_lists.delay_score)
+            .unwrap_or(1)
+    }
+
+    /// Clients scoring at least this are rejected, `None` to never reject
+    #[must_use]
+    pub fn get_dns_list_reject_score(&self) -> Option<i32> {
+        self.dns_lists
+            .as_ref()
+            .and_then(|dns_lists| dns_lists.reject_score)
+    }
+
+    #[must_use]
+    pub fn get_dns_list_delay_greylist_time_seconds(&self) -> i64 {
+        self.dns_lists
+            .as_ref()
+            .and_then(|dns_lists| dns_lists.delay_greylist_time_seconds)
+            .unwrap_or(3600)
+    }
+
+    /// How long to remember whether an address is listed on a zone
+    #[must_use]
+    pub fn get_dns_list_cache_seconds(&self) -> u64 {
+        self.dns_lists
+            .as_ref()
+            .and_then(|dns_lists| dns_lists.cache_seconds)
+            .unwrap_or(300)
+    }
+
+    /// Whether the sender domain's SPF record is checked, on by default once
+    /// there's an `[spf]` section
+    #[must_use]
+    pub fn get_spf_enabled(&self) -> bool {
+        self.spf
+            .as_ref()
+            .is_some_and(|spf| spf.enabled.unwrap_or(true))
+    }
+
+    /// Sender domains, and those under them, whose mail skips greylisting
+    /// when it passes SPF
+    #[must_use]
+    pub fn get_spf_trusted_domains(&self) -> Vec<String> {
+        self.spf
+            .as_ref()
+            .and_then(|spf| spf.trusted_domains.as_ref())
+            .map(|domains| {
+                domains
+                    .iter()
+                    .map(|domain| domain.trim_end_matches('.').to_ascii_lowercase())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Accept mail that passes SPF from a domain with earlier known good
+    /// mail that also passed, whichever of its addresses it came from
+    #[must_use]
+    pub fn get_spf_known_good_by_domain(&self) -> bool {
+        self.spf
+            .as_ref()
+            .and_then(|spf| spf.known_good_by_domain)
+            .unwrap_or(true)
+    }
+
+    #[must_use]
+    pub fn get_spf_on_fail(&self) -> SpfFailAction {
+        self.spf
+            .as_ref()
+            .and_then(|spf| spf.on_fail)
+            .unwrap_or(SpfFailAction::Delay)
+    }
+
+    #[must_use]
+    pub fn get_spf_fail_greylist_time_seconds(&self) -> i64 {
+        self.spf
+            .as_ref()
+            .and_then(|spf| spf.fail_greylist_time_seconds)
+            .unwrap_or(3600)
+    }
+
+    /// Whether the client's host name is looked up, once there's a
+    /// `[hostnames]` section
+    #[must_use]
+    pub fn get_hostname_checks_enabled(&self) -> bool {
+        self.hostnames.is_some()
+    }
+
+    /// Clients whose forward-confirmed host name is under one of these skip
+    /// greylisting, e.g. `outbound.protection.outlook.com`
+    #[must_use]
+    pub fn get_hostname_trusted_suffixes(&self) -> Vec<String> {
+        self.hostnames
+            .as_ref()
+            .and_then(|hostnames| hostnames.trusted_suffixes.as_ref())
+            .map(|suffixes| {
+                suffixes
+                    .iter()
+                    .map(|suffix| suffix.trim_matches('.').to_ascii_lowercase())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Host names with one of these as a word of a label, between the ends,
+    /// hyphens or digits, look dynamic
+    #[must_use]
+    pub fn get_hostname_dynamic_keywords(&self) -> Vec<String> {
+        self.hostnames
+            .as_ref()
+            .and_then(|hostnames| hostnames.dynamic_keywords.as_ref())
+            .map(|keywords| {
+                keywords
+                    .iter()
+                    .map(|keyword| keyword.to_ascii_lowercase())
+                    .collect()
+            })
+            .unwrap_or_else(|| {
+                [
+                    "dyn", "dynamic", "pool", "dhcp", "dsl", "adsl", "vdsl", "ppp", "pppoe",
+                    "cable", "dialup",
+                ]
+                .map(str::to_string)
+                .to_vec()
+            })
+    }
+
+    #[must_use]
+    pub fn get_hostname_delay_dynamic(&self) -> bool {
+        self.hostnames
+            .as_ref()
+            .and_then(|hostnames| hostnames.delay_dynamic)
+            .unwrap_or(true)
+    }
+
+    #[must_use]
+    pub fn get_hostname_delay_no_reverse_dns(&self) -> bool {
+        self.hostnames
+            .as_ref()
+            .and_then(|hostnames| hostnames.delay_no_reverse_dns)
+            .unwrap_or(true)
+    }
+
+    #[must_use]
+    pub fn get_hostname_delay_greylist_time_seconds(&self) -> i64 {
+        self.hostnames
+            .as_ref()
+            .and_then(|hostnames| hostnames.delay_greylist_time_seconds)
+            .unwrap_or(3600)
+    }
 }
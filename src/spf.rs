@@ -0,0 +1,602 @@
+//! SPF (RFC 7208) for the sender domain. A provider retrying from another of
+//! its addresses doesn't match anything known by address, but its SPF record
+//! says both are allowed to send for the domain, so a pass can stand in for
+//! the address when looking for earlier good mail.
+//!
+//! `check_host()` is implemented here rather than taken from a crate such as
+//! viaspf so it runs on the same `dns::Resolver` as the DNS lists and host
+//! name checks, with their nameservers and timeouts, and can be tested
+//! against the same stub. Only the result is used, so explanations (`exp=`)
+//! are parsed but never looked up, and no Received-SPF header is written.
+
+use std::{
+    fmt::Write,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+};
+
+use entity::spf_result::SpfResult;
+use futures::{future::BoxFuture, FutureExt};
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use tracing::debug;
+
+use crate::{
+    address,
+    dns::Resolver,
+    settings::{Settings, SpfFailAction},
+};
+
+// Terms that look something up, includes and redirects among them
+const MAX_LOOKUPS: usize = 10;
+// Lookups that found nothing
+const MAX_VOID_LOOKUPS: usize = 2;
+const MAX_MX_RECORDS: usize = 10;
+const MAX_PTR_RECORDS: usize = 10;
+const MAX_DOMAIN_LENGTH: usize = 253;
+
+/// What to do with a message depending on its SPF result
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Policy {
+    /// Domains, lowercase, whose mail skips greylisting on a pass
+    pub trusted_domains: Vec<String>,
+    /// Whether a pass from a sender domain that passed before counts as
+    /// known good, wherever in the domain's SPF record the client is
+    pub known_good_by_domain: bool,
+    pub on_fail: SpfFailAction,
+    pub fail_greylist_time_seconds: i64,
+}
+
+impl Policy {
+    #[must_use]
+    pub fn from_settings(config: &Settings) -> Self {
+        Self {
+            trusted_domains: config.get_spf_trusted_domains(),
+            known_good_by_domain: config.get_spf_known_good_by_domain(),
+            on_fail: config.get_spf_on_fail(),
+            fail_greylist_time_seconds: config.get_spf_fail_greylist_time_seconds(),
+        }
+    }
+
+    /// The trusted domain itself or any domain under it
+    #[must_use]
+    pub fn is_trusted(&self, domain: &str) -> bool {
+        self.trusted_domains
+            .iter()
+            .any(|trusted| address::is_same_or_under(domain, trusted))
+    }
+}
+
+#[derive(Debug)]
+pub struct Spf {
+    resolver: Arc<dyn Resolver>,
+}
+
+impl Spf {
+    #[must_use]
+    pub fn new(resolver: Arc<dyn Resolver>) -> Self {
+        Self { resolver }
+    }
+
+    /// Whether `address` may send mail from `local_part@domain`
+    pub async fn check(
+        &self,
+        address: IpAddr,
+        local_part: &str,
+        domain: &str,
+        helo: Option<&str>,
+    ) -> SpfResult {
+        let mut evaluation = Evaluation {
+            resolver: self.resolver.as_ref(),
+            address: address::normalize_ip(address),
+            local_part: if local_part.is_empty() {
+                "postmaster".to_string()
+            } else {
+                local_part.to_string()
+            },
+            sender_domain: domain.to_string(),
+            helo: helo.unwrap_or(domain).to_string(),
+            lookups: 0,
+            void_lookups: 0,
+        };
+        let result = match evaluation.check_host(domain.to_string()).await {
+            Ok(result) => result,
+            Err(Failure::Temporary(reason)) => {
+                debug!(domain, "SPF check failed for now: {}", reason);
+                SpfResult::TempError
+            }
+            Err(Failure::Permanent(reason)) => {
+                debug!(domain, "SPF record unusable: {}", reason);
+                SpfResult::PermError
+            }
+        };
+        debug!(%address, domain, ?result, "SPF check done");
+        result
+    }
+}
+
+enum Failure {
+    /// A lookup failed, so the same check may work later
+    Temporary(String),
+    /// The record is broken or too expensive to evaluate
+    Permanent(String),
+}
+
+#[derive(Debug)]
+enum Mechanism {
+    All,
+    Include(String),
+    A(Option<String>, Cidr),
+    Mx(Option<String>, Cidr),
+    Ptr(Option<String>),
+    Ip(IpNet),
+    Exists(String),
+}
+
+/// The prefix lengths for addresses found by `a` and `mx`
+#[derive(Debug, Clone, Copy)]
+struct Cidr {
+    v4: u8,
+    v6: u8,
+}
+
+#[derive(Debug)]
+struct Record {
+    directives: Vec<(SpfResult, Mechanism)>,
+    redirect: Option<String>,
+}
+
+struct Evaluation<'a> {
+    resolver: &'a dyn Resolver,
+    address: IpAddr,
+    local_part: String,
+    sender_domain: String,
+    helo: String,
+    lookups: usize,
+    void_lookups: usize,
+}
+
+impl Evaluation<'_> {
+    fn check_host(&mut self, domain: String) -> BoxFuture<'_, Result<SpfResult, Failure>> {
+        async move {
+            if !is_valid_domain(&domain) {
+                return Ok(SpfResult::None);
+            }
+            let records = self
+                .resolver
+                .lookup_txt(&domain)
+                .await
+                .map_err(|e| Failure::Temporary(format!("TXT lookup of {}: {}", domain, e)))?;
+            let mut records = records.iter().filter(|record| is_spf_record(record));
+            let Some(record) = records.next() else {
+                return Ok(SpfResult::None);
+            };
+            if records.next().is_some() {
+                return Err(Failure::Permanent(format!(
+                    "{} has more than one SPF record",
+                    domain
+                )));
+            }
+
+            let record = parse_record(record)?;
+            for (qualifier, mechanism) in &record.directives {
+                if self.matches(mechanism, &domain).await? {
+                    debug!(domain, ?mechanism, "SPF mechanism matched");
+                    return Ok(*qualifier);
+                }
+            }
+            match record.redirect {
+                Some(domain_spec) => {
+                    self.count_lookup()?;
+                    let target = self.expand(&domain_spec, &domain)?;
+                    match self.check_host(target.clone()).await? {
+                        SpfResult::None => Err(Failure::Permanent(format!(
+                            "redirect to {} without an SPF record",
+                            target
+                        ))),
+                        result => Ok(result),
+                    }
+                }
+                None => Ok(SpfResult::Neutral),
+            }
+        }
+        .boxed()
+    }
+
+    async fn matches(&mut self, mechanism: &Mechanism, domain: &str) -> Result<bool, Failure> {
+        match mechanism {
+            Mechanism::All => Ok(true),
+            Mechanism::Ip(network) => Ok(network.contains(&self.address)),
+            Mechanism::Include(domain_spec) => {
+                self.count_lookup()?;
+                let target = self.expand(domain_spec, domain)?;
+                match self.check_host(target.clone()).await? {
+                    SpfResult::Pass => Ok(true),
+                    SpfResult::Fail | SpfResult::SoftFail | SpfResult::Neutral => Ok(false),
+                    SpfResult::TempError => Err(Failure::Temporary(format!(
+                        "include of {} failed for now",
+                        target
+                    ))),
+                    SpfResult::PermError | SpfResult::None => Err(Failure::Permanent(format!(
+                        "include of {} without a usable SPF record",
+                        target
+                    ))),
+                }
+            }
+            Mechanism::A(domain_spec, cidr) => {
+                self.count_lookup()?;
+                let target = self.target(domain_spec.as_deref(), domain)?;
+                let addresses = self.addresses(&target).await?;
+                self.count_void(addresses.is_empty())?;
+                Ok(self.in_any(&addresses, *cidr))
+            }
+            Mechanism::Mx(domain_spec, cidr) => {
+                self.count_lookup()?;
+                let target = self.target(domain_spec.as_deref(), domain)?;
+                let exchanges =
+                    self.resolver.lookup_mx(&target).await.map_err(|e| {
+                        Failure::Temporary(format!("MX lookup of {}: {}", target, e))
+                    })?;
+                if exchanges.len() > MAX_MX_RECORDS {
+                    return Err(Failure::Permanent(format!(
+                        "{} has more than {} MX records",
+                        target, MAX_MX_RECORDS
+                    )));
+                }
+                self.count_void(exchanges.is_empty())?;
+                // The address lookups of each exchange count as void too
+                for exchange in exchanges {
+                    let addresses = self.addresses(&exchange).await?;
+                    self.count_void(addresses.is_empty())?;
+                    if self.in_any(&addresses, *cidr) {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            Mechanism::Ptr(domain_spec) => {
+                self.count_lookup()?;
+                let target = self.target(domain_spec.as_deref(), domain)?;
+                // Failing to look the names up only means no match
+                let Ok(names) = self.resolver.lookup_ptr(self.address).await else {
+                    return Ok(false);
+                };
+                for name in names.into_iter().take(MAX_PTR_RECORDS) {
+                    if !address::is_same_or_under(&name, &target) {
+                        continue;
+                    }
+                    if let Ok(addresses) = self.addresses(&name).await {
+                        if addresses.contains(&self.address) {
+                            return Ok(true);
+                        }
+                    }
+                }
+                Ok(false)
+            }
+            Mechanism::Exists(domain_spec) => {
+                self.count_lookup()?;
+                let target = self.expand(domain_spec, domain)?;
+                // Always A, whatever the client's address
+                let addresses =
+                    self.resolver.lookup_ipv4(&target).await.map_err(|e| {
+                        Failure::Temporary(format!("A lookup of {}: {}", target, e))
+                    })?;
+                self.count_void(addresses.is_empty())?;
+                Ok(!addresses.is_empty())
+            }
+        }
+    }
+
+    fn count_lookup(&mut self) -> Result<(), Failure> {
+        self.lookups += 1;
+        if self.lookups > MAX_LOOKUPS {
+            return Err(Failure::Permanent(format!(
+                "more than {} DNS lookups",
+                MAX_LOOKUPS
+            )));
+        }
+        Ok(())
+    }
+
+    fn count_void(&mut self, void: bool) -> Result<(), Failure> {
+        if void {
+            self.void_lookups += 1;
+            if self.void_lookups > MAX_VOID_LOOKUPS {
+                return Err(Failure::Permanent(format!(
+                    "more than {} lookups found nothing",
+                    MAX_VOID_LOOKUPS
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn target(&self, domain_spec: Option<&str>, domain: &str) -> Result<String, Failure> {
+        match domain_spec {
+            Some(domain_spec) => self.expand(domain_spec, domain),
+            None => Ok(domain.to_string()),
+        }
+    }
+
+    /// The addresses of `name` in the same family as the client's
+    async fn addresses(&self, name: &str) -> Result<Vec<IpAddr>, Failure> {
+        let addresses = match self.address {
+            IpAddr::V4(_) => self
+                .resolver
+                .lookup_ipv4(name)
+                .await
+                .map(|addresses| addresses.into_iter().map(IpAddr::V4).collect()),
+            IpAddr::V6(_) => self
+                .resolver
+                .lookup_ipv6(name)
+                .await
+                .map(|addresses| addresses.into_iter().map(IpAddr::V6).collect()),
+        };
+        addresses.map_err(|e| Failure::Temporary(format!("address lookup of {}: {}", name, e)))
+    }
+
+    fn in_any(&self, addresses: &[IpAddr], cidr: Cidr) -> bool {
+        addresses.iter().any(|address| {
+            let prefix = match address {
+                IpAddr::V4(_) => cidr.v4,
+                IpAddr::V6(_) => cidr.v6,
+            };
+            IpNet::new(*address, prefix)
+                .is_ok_and(|network| network.trunc().contains(&self.address))
+        })
+    }
+
+    /// Expand the macros in a domain-spec, dropping labels from the left of
+    /// names that end up too long
+    fn expand(&self, domain_spec: &str, domain: &str) -> Result<String, Failure> {
+        let malformed = || Failure::Permanent(format!("malformed macro in {}", domain_spec));
+        let mut expanded = String::new();
+        let mut chars = domain_spec.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                expanded.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('%') => expanded.push('%'),
+                Some('_') => expanded.push(' '),
+                Some('-') => expanded.push_str("%20"),
+                Some('{') => {
+                    let rest = chars.as_str();
+                    let (body, after) = rest.split_once('}').ok_or_else(malformed)?;
+                    expanded.push_str(&self.expand_macro(body, domain).ok_or_else(malformed)?);
+                    chars = after.chars();
+                }
+                _ => return Err(malformed()),
+            }
+        }
+
+        let mut expanded = expanded.trim_end_matches('.');
+        while expanded.len() > MAX_DOMAIN_LENGTH {
+            match expanded.split_once('.') {
+                Some((_, rest)) => expanded = rest,
+                None => break,
+            }
+        }
+        Ok(expanded.to_string())
+    }
+
+    // e.g. `ir` or `d2` or `l-`, without the braces
+    fn expand_macro(&self, body: &str, domain: &str) -> Option<String> {
+        let mut chars = body.chars();
+        let letter = chars.next()?;
+        let value = match letter.to_ascii_lowercase() {
+            's' => format!("{}@{}", self.local_part, self.sender_domain),
+            'l' => self.local_part.clone(),
+            'o' => self.sender_domain.clone(),
+            'd' => domain.to_string(),
+            'i' => address_labels(self.address),
+            // Validating the client's name for this isn't worth the lookups
+            'p' => "unknown".to_string(),
+            'v' => match self.address {
+                IpAddr::V4(_) => "in-addr".to_string(),
+                IpAddr::V6(_) => "ip6".to_string(),
+            },
+            'h' => self.helo.clone(),
+            _ => return None,
+        };
+
+        let rest = chars.as_str();
+        let (digits, rest) = rest.split_at(
+            rest.find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len()),
+        );
+        let (reverse, delimiters) = match rest.strip_prefix(['r', 'R']) {
+            Some(delimiters) => (true, delimiters),
+            None => (false, rest),
+        };
+        if !delimiters.chars().all(|c| ".-+,/_=".contains(c)) {
+            return None;
+        }
+        let delimiters = if delimiters.is_empty() {
+            "."
+        } else {
+            delimiters
+        };
+
+        let mut parts = value.split(|c| delimiters.contains(c)).collect::<Vec<_>>();
+        if reverse {
+            parts.reverse();
+        }
+        if !digits.is_empty() {
+            let keep = digits.parse::<usize>().ok().filter(|keep| *keep > 0)?;
+            parts.drain(..parts.len().saturating_sub(keep));
+        }
+        let value = parts.join(".");
+
+        // Upper case letters URL-escape the value
+        if letter.is_ascii_uppercase() {
+            let mut escaped = String::new();
+            for byte in value.bytes() {
+                if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+                    escaped.push(char::from(byte));
+                } else {
+                    let _ = write!(escaped, "%{:02X}", byte);
+                }
+            }
+            Some(escaped)
+        } else {
+            Some(value)
+        }
+    }
+}
+
+fn parse_record(record: &str) -> Result<Record, Failure> {
+    let mut directives = vec![];
+    let mut redirect = None;
+    let mut explanation = None;
+    for term in record.split(' ').skip(1).filter(|term| !term.is_empty()) {
+        let invalid = || Failure::Permanent(format!("invalid term {}", term));
+        let (qualifier, rest) = match term.as_bytes()[0] {
+            b'+' => (Some(SpfResult::Pass), &term[1..]),
+            b'-' => (Some(SpfResult::Fail), &term[1..]),
+            b'~' => (Some(SpfResult::SoftFail), &term[1..]),
+            b'?' => (Some(SpfResult::Neutral), &term[1..]),
+            _ => (None, term),
+        };
+        let (name, argument) = rest.split_at(rest.find([':', '/', '=']).unwrap_or(rest.len()));
+
+        if let Some(value) = argument.strip_prefix('=') {
+            let is_name = name.starts_with(|c: char| c.is_ascii_alphabetic())
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+            if qualifier.is_some() || !is_name {
+                return Err(invalid());
+            }
+            let modifier = match name.to_ascii_lowercase().as_str() {
+                "redirect" => &mut redirect,
+                "exp" => &mut explanation,
+                // Unknown modifiers are ignored
+                _ => continue,
+            };
+            if value.is_empty() || modifier.replace(value.to_string()).is_some() {
+                return Err(invalid());
+            }
+            continue;
+        }
+
+        let mechanism = match name.to_ascii_lowercase().as_str() {
+            "all" if argument.is_empty() => Mechanism::All,
+            "include" => Mechanism::Include(required(argument).ok_or_else(invalid)?),
+            "a" => {
+                let (domain_spec, cidr) = domain_and_cidr(argument).ok_or_else(invalid)?;
+                Mechanism::A(domain_spec, cidr)
+            }
+            "mx" => {
+                let (domain_spec, cidr) = domain_and_cidr(argument).ok_or_else(invalid)?;
+                Mechanism::Mx(domain_spec, cidr)
+            }
+            "ptr" => Mechanism::Ptr(optional(argument).ok_or_else(invalid)?),
+            "ip4" => Mechanism::Ip(
+                parse_network::<Ipv4Addr>(argument, 32)
+                    .and_then(|(address, prefix)| Ipv4Net::new(address, prefix).ok())
+                    .ok_or_else(invalid)?
+                    .into(),
+            ),
+            "ip6" => Mechanism::Ip(
+                parse_network::<Ipv6Addr>(argument, 128)
+                    .and_then(|(address, prefix)| Ipv6Net::new(address, prefix).ok())
+                    .ok_or_else(invalid)?
+                    .into(),
+            ),
+            "exists" => Mechanism::Exists(required(argument).ok_or_else(invalid)?),
+            _ => return Err(invalid()),
+        };
+        directives.push((qualifier.unwrap_or(SpfResult::Pass), mechanism));
+    }
+
+    Ok(Record {
+        directives,
+        redirect,
+    })
+}
+
+// `:domain-spec`
+fn required(argument: &str) -> Option<String> {
+    argument
+        .strip_prefix(':')
+        .filter(|domain_spec| !domain_spec.is_empty())
+        .map(str::to_string)
+}
+
+// Nothing, or `:domain-spec`
+fn optional(argument: &str) -> Option<Option<String>> {
+    if argument.is_empty() {
+        Some(None)
+    } else {
+        required(argument).map(Some)
+    }
+}
+
+// e.g. `:example.com/24//64`, `/24` or nothing
+fn domain_and_cidr(argument: &str) -> Option<(Option<String>, Cidr)> {
+    let (domain_spec, cidr) = match argument.split_once('/') {
+        Some((domain_spec, cidr)) => (domain_spec, Some(cidr)),
+        None => (argument, None),
+    };
+    let (v4, v6) = match cidr {
+        None => (None, None),
+        Some(cidr) => match cidr.strip_prefix('/') {
+            Some(v6) => (None, Some(v6)),
+            None => match cidr.split_once("//") {
+                Some((v4, v6)) => (Some(v4), Some(v6)),
+                None => (Some(cidr), None),
+            },
+        },
+    };
+    let prefix = |prefix: Option<&str>, max| match prefix {
+        Some(prefix) => prefix.parse().ok().filter(|prefix| *prefix <= max),
+        None => Some(max),
+    };
+    Some((
+        optional(domain_spec)?,
+        Cidr {
+            v4: prefix(v4, 32)?,
+            v6: prefix(v6, 128)?,
+        },
+    ))
+}
+
+// `:address` or `:address/prefix`
+fn parse_network<A: std::str::FromStr>(argument: &str, max_prefix: u8) -> Option<(A, u8)> {
+    let network = argument.strip_prefix(':')?;
+    let (address, prefix) = match network.split_once('/') {
+        Some((address, prefix)) => (address, prefix.parse().ok()?),
+        None => (network, max_prefix),
+    };
+    Some((address.parse().ok()?, prefix))
+}
+
+fn is_spf_record(record: &str) -> bool {
+    let version = record.get(..6).unwrap_or_default();
+    version.eq_ignore_ascii_case("v=spf1") && matches!(record.as_bytes().get(6), None | Some(b' '))
+}
+
+// Anything else, including names without a dot, has no SPF record to find
+fn is_valid_domain(domain: &str) -> bool {
+    let domain = domain.trim_end_matches('.');
+    domain.len() <= MAX_DOMAIN_LENGTH
+        && domain.contains('.')
+        && domain
+            .split('.')
+            .all(|label| !label.is_empty() && label.len() <= 63)
+}
+
+// The `i` macro: dotted quad, or dotted nibbles for IPv6
+fn address_labels(address: IpAddr) -> String {
+    match address {
+        IpAddr::V4(address) => address.to_string(),
+        IpAddr::V6(address) => address
+            .octets()
+            .iter()
+            .flat_map(|octet| [octet >> 4, octet & 0xf])
+            .map(|nibble| format!("{:x}", nibble))
+            .collect::<Vec<_>>()
+            .join("."),
+    }
+}
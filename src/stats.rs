@@ -0,0 +1,77 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use indymilter::Status;
+use tracing::info;
+
+/// Counters for the lifetime of the milter, logged as a summary on shutdown.
+#[derive(Debug, Default)]
+pub struct Stats {
+    sessions: AtomicUsize,
+    active_sessions: AtomicUsize,
+    accepted: AtomicUsize,
+    greylisted: AtomicUsize,
+    discarded: AtomicUsize,
+    rejected: AtomicUsize,
+}
+
+impl Stats {
+    pub fn session_started(self: &Arc<Self>) -> Arc<ActiveSession> {
+        let id = self.sessions.fetch_add(1, Ordering::Relaxed) + 1;
+        self.active_sessions.fetch_add(1, Ordering::Relaxed);
+        Arc::new(ActiveSession {
+            id,
+            stats: self.clone(),
+        })
+    }
+
+    pub fn active_sessions(&self) -> usize {
+        self.active_sessions.load(Ordering::Relaxed)
+    }
+
+    pub fn record_decision(&self, status: Status) {
+        match status {
+            Status::Accept | Status::Continue => &self.accepted,
+            Status::Tempfail => &self.greylisted,
+            Status::Discard => &self.discarded,
+            Status::Reject => &self.rejected,
+            _ => return,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn log_summary(&self, forcibly_closed: usize) {
+        info!(
+            sessions = self.sessions.load(Ordering::Relaxed),
+            accepted = self.accepted.load(Ordering::Relaxed),
+            greylisted = self.greylisted.load(Ordering::Relaxed),
+            discarded = self.discarded.load(Ordering::Relaxed),
+            rejected = self.rejected.load(Ordering::Relaxed),
+            forcibly_closed,
+            "Shutdown complete"
+        );
+    }
+}
+
+/// Held in the session data so a session counts as active until its data is
+/// dropped, whether it finished normally or was cut off at shutdown. The ID
+/// ties together the log lines of one session.
+#[derive(Debug)]
+pub struct ActiveSession {
+    id: usize,
+    stats: Arc<Stats>,
+}
+
+impl ActiveSession {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+}
+
+impl Drop for ActiveSession {
+    fn drop(&mut self) {
+        self.stats.active_sessions.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,222 @@
+use std::{fmt::Debug, net::IpAddr, sync::Arc};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use entity::{
+    decision_rule::DecisionRule,
+    email_status::EmailStatus::{self, KnownGoodAccepted, OtherAccepted, PassedGreylistAccepted},
+    prelude::{
+        AllowedAddressModel, AllowedClientModel, DecisionActive, MailActive, MailModel,
+        MailStatusHistoryModel, RecipientModel,
+    },
+};
+use ipnet::IpNet;
+use sea_orm::{prelude::DateTimeWithTimeZone, ActiveEnum, DatabaseConnection, DbErr};
+
+use crate::{address, settings::Settings};
+
+#[cfg(feature = "embedded")]
+mod embedded;
+mod sql;
+
+/// Statuses that make the sending address known good
+pub const KNOWN_GOOD_STATUSES: [EmailStatus; 3] =
+    [PassedGreylistAccepted, KnownGoodAccepted, OtherAccepted];
+
+/// The actor recorded for status changes the milter makes itself
+pub const MILTER_ACTOR: &str = "milter";
+
+/// Who changed a mail's status and why, kept in `mail_status_history`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusChange {
+    pub actor: String,
+    pub note: Option<String>,
+}
+
+impl StatusChange {
+    #[must_use]
+    pub fn milter(note: impl Into<String>) -> Self {
+        Self {
+            actor: MILTER_ACTOR.to_string(),
+            note: Some(note.into()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum InsertedMail {
+    New(i32),
+    /// Another session recorded the same Message-Id first
+    Existing(Box<MailModel>),
+}
+
+/// The storage operations behind greylisting decisions. Every attempt is
+/// recorded along with the decision made about it.
+#[async_trait]
+pub trait GreylistStore: Debug + Send + Sync {
+    async fn upsert_recipient(
+        &self,
+        local_part: String,
+        domain: String,
+    ) -> Result<RecipientModel, DbErr>;
+
+    async fn find_mail(&self, message_id: &str) -> Result<Option<MailModel>, DbErr>;
+
+    /// Any message from this address with one of the `KNOWN_GOOD_STATUSES`
+    async fn find_known_good(&self, sending_ip: &str) -> Result<Option<MailModel>, DbErr>;
+
+    /// Any message from this sender domain with one of the
+    /// `KNOWN_GOOD_STATUSES` that passed SPF, so came from an address the
+    /// domain authorizes
+    async fn find_known_good_spf(&self, sender_domain: &str) -> Result<Option<MailModel>, DbErr>;
+
+    /// The most specific allowed client network with this rule that the
+    /// address is in, allowed for any sender or for `sender_domain`
+    async fn find_allowed_client(
+        &self,
+        address: IpAddr,
+        sender_domain: &str,
+        rule: DecisionRule,
+    ) -> Result<Option<AllowedClientModel>, DbErr>;
+
+    /// Returns false if the network was already allowed for the sender
+    /// domain, under any rule. An empty `sender_domain` allows it for any
+    /// sender.
+    async fn add_allowed_client(
+        &self,
+        network: IpNet,
+        sender_domain: String,
+        rule: DecisionRule,
+        source: String,
+    ) -> Result<bool, DbErr>;
+
+    /// The first of `patterns` allowed with this rule, as given by
+    /// `allowlist::domain_patterns` or `allowlist::recipient_patterns`
+    async fn find_allowed_address(
+        &self,
+        patterns: Vec<String>,
+        rule: DecisionRule,
+    ) -> Result<Option<AllowedAddressModel>, DbErr>;
+
+    /// Stores the address as given, already normalized by the caller.
+    /// Returns false if it was already allowed with this rule
+    async fn add_allowed_address(
+        &self,
+        address: String,
+        rule: DecisionRule,
+        source: String,
+    ) -> Result<bool, DbErr>;
+
+    async fn insert_mail(
+        &self,
+        mail: MailActive,
+        recipient_ids: Vec<i32>,
+        decision: DecisionActive,
+    ) -> Result<InsertedMail, DbErr>;
+
+    /// Store mail copied from elsewhere, which comes without an attempt or
+    /// decision, the same way `insert_mail` does otherwise. The recipients,
+    /// as (local part, domain), are added in the same transaction
+    async fn import_mail(
+        &self,
+        mail: MailActive,
+        recipients: Vec<(String, String)>,
+    ) -> Result<InsertedMail, DbErr> {
+        let _ = (mail, recipients);
+        Err(DbErr::Custom(
+            "Importing mail needs an SQL database".to_string(),
+        ))
+    }
+
+    async fn update_status(
+        &self,
+        mail_id: i32,
+        status: EmailStatus,
+        time_accepted: Option<DateTimeWithTimeZone>,
+        change: StatusChange,
+        session_mail: MailActive,
+        decision: DecisionActive,
+    ) -> Result<(), DbErr>;
+
+    /// Change the status by hand, outside of any delivery attempt. Accepted
+    /// statuses keep or set `time_accepted`, others clear it. Returns the
+    /// status from before.
+    async fn set_status(
+        &self,
+        mail_id: i32,
+        status: EmailStatus,
+        change: StatusChange,
+    ) -> Result<EmailStatus, DbErr>;
+
+    /// Every status change of a mail, oldest first
+    async fn status_history(&self, mail_id: i32) -> Result<Vec<MailStatusHistoryModel>, DbErr>;
+
+    async fn insert_attempt(
+        &self,
+        mail_id: i32,
+        session_mail: MailActive,
+        decision: DecisionActive,
+    ) -> Result<(), DbErr>;
+
+    /// Delete mail received before `received_before` along with its
+    /// attempts and history, for stores without partitions to drop. Returns
+    /// how many were.
+    async fn expire_mail(&self, received_before: DateTimeWithTimeZone) -> Result<u64, DbErr> {
+        let _ = received_before;
+        Err(DbErr::Custom(
+            "Expiring mail is done by dropping partitions on an SQL database".to_string(),
+        ))
+    }
+
+    async fn ping(&self) -> Result<(), DbErr>;
+
+    async fn close(&self) -> Result<(), DbErr>;
+
+    /// For the jobs that only work on an SQL database
+    fn sql_connection(&self) -> Option<&DatabaseConnection> {
+        None
+    }
+}
+
+/// Open the store configured in `[database]`, running any migrations it needs
+pub async fn open(config: &Settings) -> Result<Arc<dyn GreylistStore>, DbErr> {
+    match config.get_embedded_db_path() {
+        #[cfg(feature = "embedded")]
+        Some(path) => Ok(Arc::new(embedded::EmbeddedStore::open(path).await?)),
+        #[cfg(not(feature = "embedded"))]
+        Some(_) => Err(DbErr::Custom(
+            "Database type embedded is not supported by this build, rebuild with the embedded feature"
+                .to_string(),
+        )),
+        None => Ok(Arc::new(sql::SqlStore::connect(config).await?)),
+    }
+}
+
+/// `time_accepted` for a mail set to `status` by hand: kept for the accepted
+/// statuses, or now if there wasn't one, and cleared for the others
+fn time_accepted(
+    status: &EmailStatus,
+    previous: Option<DateTimeWithTimeZone>,
+) -> Option<DateTimeWithTimeZone> {
+    (status.to_value() < EmailStatus::Greylisted.to_value())
+        .then(|| previous.unwrap_or_else(|| Utc::now().into()))
+}
+
+/// Every network the address is in, most specific first, in the form
+/// `allowed_client` networks are stored
+fn containing_networks(address: IpAddr) -> Vec<String> {
+    let address = address::normalize_ip(address);
+    let max_prefix_len = match address {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    };
+    (0..=max_prefix_len)
+        .rev()
+        .map(|prefix_len| {
+            IpNet::new(address, prefix_len)
+                .expect("Prefix length is in range")
+                .trunc()
+                .to_string()
+        })
+        .collect()
+}
@@ -0,0 +1,745 @@
+use std::{fmt, net::IpAddr, path::PathBuf, sync::Arc};
+
+use async_trait::async_trait;
+use chrono::{DateTime, FixedOffset, Utc};
+use entity::{
+    decision_rule::DecisionRule,
+    email_status::EmailStatus,
+    prelude::{
+        AllowedAddressModel, AllowedClientModel, DecisionActive, MailActive, MailModel,
+        MailStatusHistoryModel, RecipientModel,
+    },
+    spf_result::SpfResult,
+};
+use ipnet::IpNet;
+use redb::{
+    Database, MultimapTableDefinition, ReadTransaction, ReadableTable, TableDefinition,
+    WriteTransaction,
+};
+use sea_orm::{prelude::DateTimeWithTimeZone, ActiveEnum, ActiveValue, DbErr};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    containing_networks, time_accepted, GreylistStore, InsertedMail, StatusChange,
+    KNOWN_GOOD_STATUSES,
+};
+
+// Mail ID to StoredMail as JSON
+const MAIL: TableDefinition<i32, &str> = TableDefinition::new("mail");
+const MAIL_BY_MESSAGE_ID: TableDefinition<&str, i32> = TableDefinition::new("mail_by_message_id");
+// Sending IP to the IDs of every mail that makes it known good
+const KNOWN_GOOD: MultimapTableDefinition<&str, i32> =
+    MultimapTableDefinition::new("known_good_mail");
+// Sender domain to the IDs of every known good mail that passed its SPF check
+const KNOWN_GOOD_SPF: MultimapTableDefinition<&str, i32> =
+    MultimapTableDefinition::new("known_good_spf_mail");
+const RECIPIENT: TableDefinition<(&str, &str), i32> = TableDefinition::new("recipient");
+// Network and sender domain, empty for any, to StoredAllowedClient as JSON
+const ALLOWED_CLIENT: TableDefinition<(&str, &str), &str> = TableDefinition::new("allowed_client");
+// Rule and address to StoredAllowedAddress as JSON
+const ALLOWED_ADDRESS: TableDefinition<(&str, &str), &str> =
+    TableDefinition::new("allowed_address");
+// The last ID handed out for each of mail, recipient, allowed_client,
+// allowed_address and mail_status_history
+const LAST_ID: TableDefinition<&str, i32> = TableDefinition::new("last_id");
+
+/// A single on-disk file through redb, for installs that don't want to run a
+/// database server. Each mail is kept as one record, with its recipients and
+/// every attempt and decision.
+pub struct EmbeddedStore {
+    path: PathBuf,
+    db: Arc<Database>,
+}
+
+impl fmt::Debug for EmbeddedStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EmbeddedStore")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredMail {
+    sender_local_part: String,
+    sender_domain: String,
+    message_id: String,
+    sending_host_name: Option<String>,
+    sending_ip: String,
+    time_received: DateTime<FixedOffset>,
+    time_accepted: Option<DateTime<FixedOffset>>,
+    status: i16,
+    helo_name: Option<String>,
+    queue_id: Option<String>,
+    auth_user: Option<String>,
+    tls_version: Option<String>,
+    tls_cipher: Option<String>,
+    message_size: Option<i64>,
+    daemon_name: Option<String>,
+    spf_result: Option<String>,
+    recipient_ids: Vec<i32>,
+    attempts: Vec<StoredAttempt>,
+    history: Vec<StoredStatusChange>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredAttempt {
+    time_attempted: DateTime<FixedOffset>,
+    sending_ip: String,
+    sending_host_name: Option<String>,
+    status: i16,
+    rule: String,
+    detail: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredStatusChange {
+    id: i32,
+    old_status: i16,
+    new_status: i16,
+    time_changed: DateTime<FixedOffset>,
+    actor: String,
+    note: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredAllowedClient {
+    id: i32,
+    rule: String,
+    source: String,
+    time_added: DateTime<FixedOffset>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredAllowedAddress {
+    id: i32,
+    source: String,
+    time_added: DateTime<FixedOffset>,
+}
+
+impl StoredMail {
+    fn into_model(self, id: i32) -> Result<MailModel, DbErr> {
+        Ok(MailModel {
+            id,
+            sender_local_part: self.sender_local_part,
+            sender_domain: self.sender_domain,
+            message_id: self.message_id,
+            sending_host_name: self.sending_host_name,
+            sending_ip: self.sending_ip,
+            time_received: self.time_received,
+            time_accepted: self.time_accepted,
+            status: EmailStatus::try_from_value(&self.status)?,
+            helo_name: self.helo_name,
+            queue_id: self.queue_id,
+            auth_user: self.auth_user,
+            tls_version: self.tls_version,
+            tls_cipher: self.tls_cipher,
+            message_size: self.message_size,
+            daemon_name: self.daemon_name,
+            spf_result: self
+                .spf_result
+                .as_ref()
+                .map(SpfResult::try_from_value)
+                .transpose()?,
+        })
+    }
+}
+
+fn value<T: Into<sea_orm::Value>>(value: ActiveValue<T>) -> Option<T> {
+    match value {
+        ActiveValue::Set(value) | ActiveValue::Unchanged(value) => Some(value),
+        ActiveValue::NotSet => None,
+    }
+}
+
+fn required<T: Into<sea_orm::Value>>(field: &str, active: ActiveValue<T>) -> Result<T, DbErr> {
+    value(active).ok_or_else(|| DbErr::Custom(format!("{} not set", field)))
+}
+
+fn new_attempt(
+    session_mail: &MailActive,
+    decision: DecisionActive,
+) -> Result<StoredAttempt, DbErr> {
+    Ok(StoredAttempt {
+        time_attempted: required("time_decided", decision.time_decided)?,
+        sending_ip: required("sending_ip", session_mail.sending_ip.clone())?,
+        sending_host_name: value(session_mail.sending_host_name.clone()).flatten(),
+        status: required("status", decision.status)?.to_value(),
+        rule: required::<DecisionRule>("rule", decision.rule)?.to_value(),
+        detail: value(decision.detail).flatten(),
+    })
+}
+
+fn to_db_err(e: impl fmt::Display) -> DbErr {
+    DbErr::Custom(format!("Embedded database error: {}", e))
+}
+
+fn next_id(table: &mut redb::Table<&str, i32>, name: &str) -> Result<i32, DbErr> {
+    let id = table
+        .get(name)
+        .map_err(to_db_err)?
+        .map_or(0, |id| id.value())
+        + 1;
+    table.insert(name, id).map_err(to_db_err)?;
+    Ok(id)
+}
+
+fn read_mail(table: &impl ReadableTable<i32, &'static str>, id: i32) -> Result<StoredMail, DbErr> {
+    let json = table
+        .get(id)
+        .map_err(to_db_err)?
+        .ok_or_else(|| DbErr::RecordNotFound(format!("mail {}", id)))?;
+    serde_json::from_str(json.value()).map_err(to_db_err)
+}
+
+fn write_mail(table: &mut redb::Table<i32, &str>, id: i32, mail: &StoredMail) -> Result<(), DbErr> {
+    let json = serde_json::to_string(mail).map_err(to_db_err)?;
+    table.insert(id, json.as_str()).map_err(to_db_err)?;
+    Ok(())
+}
+
+fn update_known_good(txn: &WriteTransaction, id: i32, mail: &StoredMail) -> Result<(), DbErr> {
+    let known_good = KNOWN_GOOD_STATUSES.contains(&EmailStatus::try_from_value(&mail.status)?);
+    update_index(
+        &mut txn.open_multimap_table(KNOWN_GOOD).map_err(to_db_err)?,
+        &mail.sending_ip,
+        id,
+        known_good,
+    )?;
+    if mail.spf_result.as_deref() == Some(SpfResult::Pass.to_value().as_str()) {
+        update_index(
+            &mut txn.open_multimap_table(KNOWN_GOOD_SPF).map_err(to_db_err)?,
+            &mail.sender_domain,
+            id,
+            known_good,
+        )?;
+    }
+    Ok(())
+}
+
+// Index this mail under the key while it's known good, so the key stays
+// known good for as long as any of its mail is
+fn update_index(
+    index: &mut redb::MultimapTable<&str, i32>,
+    key: &str,
+    id: i32,
+    known_good: bool,
+) -> Result<(), DbErr> {
+    if known_good {
+        index.insert(key, id).map_err(to_db_err)?;
+    } else {
+        index.remove(key, id).map_err(to_db_err)?;
+    }
+    Ok(())
+}
+
+// Drops the indexes of mail that's gone
+fn remove_from_indexes(txn: &WriteTransaction, id: i32, mail: &StoredMail) -> Result<(), DbErr> {
+    txn.open_multimap_table(KNOWN_GOOD)
+        .map_err(to_db_err)?
+        .remove(mail.sending_ip.as_str(), id)
+        .map_err(to_db_err)?;
+    txn.open_multimap_table(KNOWN_GOOD_SPF)
+        .map_err(to_db_err)?
+        .remove(mail.sender_domain.as_str(), id)
+        .map_err(to_db_err)?;
+    let mut by_message_id = txn.open_table(MAIL_BY_MESSAGE_ID).map_err(to_db_err)?;
+    let indexed = by_message_id
+        .get(mail.message_id.as_str())
+        .map_err(to_db_err)?
+        .map(|indexed| indexed.value());
+    if indexed == Some(id) {
+        by_message_id
+            .remove(mail.message_id.as_str())
+            .map_err(to_db_err)?;
+    }
+    Ok(())
+}
+
+impl EmbeddedStore {
+    pub async fn open(path: &str) -> Result<Self, DbErr> {
+        let path = PathBuf::from(path);
+        let db_path = path.clone();
+        let db = tokio::task::spawn_blocking(move || -> Result<Database, DbErr> {
+            let db = Database::create(db_path).map_err(to_db_err)?;
+            // Create the tables up front so readers can always open them
+            let txn = db.begin_write().map_err(to_db_err)?;
+            txn.open_table(MAIL).map_err(to_db_err)?;
+            txn.open_table(MAIL_BY_MESSAGE_ID).map_err(to_db_err)?;
+            txn.open_multimap_table(KNOWN_GOOD).map_err(to_db_err)?;
+            txn.open_multimap_table(KNOWN_GOOD_SPF).map_err(to_db_err)?;
+            txn.open_table(RECIPIENT).map_err(to_db_err)?;
+            txn.open_table(ALLOWED_CLIENT).map_err(to_db_err)?;
+            txn.open_table(ALLOWED_ADDRESS).map_err(to_db_err)?;
+            txn.open_table(LAST_ID).map_err(to_db_err)?;
+            txn.commit().map_err(to_db_err)?;
+            Ok(db)
+        })
+        .await
+        .map_err(to_db_err)??;
+
+        Ok(Self {
+            path,
+            db: Arc::new(db),
+        })
+    }
+
+    /// redb blocks, so run `f` off the async runtime
+    async fn blocking<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Database) -> Result<T, DbErr> + Send + 'static,
+    ) -> Result<T, DbErr> {
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || f(&db))
+            .await
+            .map_err(to_db_err)?
+    }
+
+    // Look a mail up through one of the indexes onto it
+    async fn find_by(
+        &self,
+        index: impl FnOnce(&ReadTransaction) -> Result<Option<i32>, DbErr> + Send + 'static,
+    ) -> Result<Option<MailModel>, DbErr> {
+        self.blocking(move |db| {
+            let txn = db.begin_read().map_err(to_db_err)?;
+            let Some(id) = index(&txn)? else {
+                return Ok(None);
+            };
+            let mail = read_mail(&txn.open_table(MAIL).map_err(to_db_err)?, id)?;
+            mail.into_model(id).map(Some)
+        })
+        .await
+    }
+
+    // Any of the mail that makes the key known good
+    async fn find_known_good_by(
+        &self,
+        index: MultimapTableDefinition<'static, &'static str, i32>,
+        key: String,
+    ) -> Result<Option<MailModel>, DbErr> {
+        self.find_by(move |txn| {
+            let index = txn.open_multimap_table(index).map_err(to_db_err)?;
+            let mut ids = index.get(key.as_str()).map_err(to_db_err)?;
+            ids.next()
+                .transpose()
+                .map(|id| id.map(|id| id.value()))
+                .map_err(to_db_err)
+        })
+        .await
+    }
+
+    // Adds an attempt to a mail, optionally changing its status as well
+    async fn add_attempt(
+        &self,
+        mail_id: i32,
+        status: Option<(EmailStatus, Option<DateTimeWithTimeZone>, StatusChange)>,
+        attempt: StoredAttempt,
+    ) -> Result<(), DbErr> {
+        self.blocking(move |db| {
+            let txn = db.begin_write().map_err(to_db_err)?;
+            {
+                let mut mails = txn.open_table(MAIL).map_err(to_db_err)?;
+                let mut mail = read_mail(&mails, mail_id)?;
+                if let Some((status, time_accepted, change)) = status {
+                    change_status(&txn, mail_id, &mut mail, &status, time_accepted, change)?;
+                }
+                mail.attempts.push(attempt);
+                write_mail(&mut mails, mail_id, &mail)?;
+            }
+            txn.commit().map_err(to_db_err)
+        })
+        .await
+    }
+}
+
+fn change_status(
+    txn: &WriteTransaction,
+    mail_id: i32,
+    mail: &mut StoredMail,
+    status: &EmailStatus,
+    time_accepted: Option<DateTimeWithTimeZone>,
+    change: StatusChange,
+) -> Result<(), DbErr> {
+    let old_status = mail.status;
+    mail.status = status.to_value();
+    mail.time_accepted = time_accepted;
+    update_known_good(txn, mail_id, mail)?;
+    if old_status != mail.status {
+        let id = next_id(
+            &mut txn.open_table(LAST_ID).map_err(to_db_err)?,
+            "mail_status_history",
+        )?;
+        mail.history.push(StoredStatusChange {
+            id,
+            old_status,
+            new_status: mail.status,
+            time_changed: Utc::now().into(),
+            actor: change.actor,
+            note: change.note,
+        });
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl GreylistStore for EmbeddedStore {
+    async fn upsert_recipient(
+        &self,
+        local_part: String,
+        domain: String,
+    ) -> Result<RecipientModel, DbErr> {
+        self.blocking(move |db| {
+            let txn = db.begin_write().map_err(to_db_err)?;
+            let id = {
+                let mut recipients = txn.open_table(RECIPIENT).map_err(to_db_err)?;
+                let existing = recipients
+                    .get((local_part.as_str(), domain.as_str()))
+                    .map_err(to_db_err)?
+                    .map(|id| id.value());
+                match existing {
+                    Some(id) => id,
+                    None => {
+                        let id = next_id(
+                            &mut txn.open_table(LAST_ID).map_err(to_db_err)?,
+                            "recipient",
+                        )?;
+                        recipients
+                            .insert((local_part.as_str(), domain.as_str()), id)
+                            .map_err(to_db_err)?;
+                        id
+                    }
+                }
+            };
+            txn.commit().map_err(to_db_err)?;
+
+            Ok(RecipientModel {
+                id,
+                local_part,
+                domain,
+            })
+        })
+        .await
+    }
+
+    async fn find_mail(&self, message_id: &str) -> Result<Option<MailModel>, DbErr> {
+        let message_id = message_id.to_string();
+        self.find_by(move |txn| {
+            let by_message_id = txn.open_table(MAIL_BY_MESSAGE_ID).map_err(to_db_err)?;
+            let id = by_message_id
+                .get(message_id.as_str())
+                .map_err(to_db_err)?
+                .map(|id| id.value());
+            Ok(id)
+        })
+        .await
+    }
+
+    async fn find_known_good(&self, sending_ip: &str) -> Result<Option<MailModel>, DbErr> {
+        self.find_known_good_by(KNOWN_GOOD, sending_ip.to_string())
+            .await
+    }
+
+    async fn find_known_good_spf(&self, sender_domain: &str) -> Result<Option<MailModel>, DbErr> {
+        self.find_known_good_by(KNOWN_GOOD_SPF, sender_domain.to_string())
+            .await
+    }
+
+    async fn find_allowed_client(
+        &self,
+        address: IpAddr,
+        sender_domain: &str,
+        rule: DecisionRule,
+    ) -> Result<Option<AllowedClientModel>, DbErr> {
+        let networks = containing_networks(address);
+        let sender_domain = sender_domain.to_string();
+        self.blocking(move |db| {
+            let txn = db.begin_read().map_err(to_db_err)?;
+            let clients = txn.open_table(ALLOWED_CLIENT).map_err(to_db_err)?;
+            for network in networks {
+                for sender_domain in ["", sender_domain.as_str()] {
+                    let Some(json) = clients
+                        .get((network.as_str(), sender_domain))
+                        .map_err(to_db_err)?
+                    else {
+                        continue;
+                    };
+                    let client: StoredAllowedClient =
+                        serde_json::from_str(json.value()).map_err(to_db_err)?;
+                    if DecisionRule::try_from_value(&client.rule)? == rule {
+                        return Ok(Some(AllowedClientModel {
+                            id: client.id,
+                            network,
+                            sender_domain: sender_domain.to_string(),
+                            rule,
+                            source: client.source,
+                            time_added: client.time_added,
+                        }));
+                    }
+                }
+            }
+            Ok(None)
+        })
+        .await
+    }
+
+    async fn add_allowed_client(
+        &self,
+        network: IpNet,
+        sender_domain: String,
+        rule: DecisionRule,
+        source: String,
+    ) -> Result<bool, DbErr> {
+        let network = network.trunc().to_string();
+        self.blocking(move |db| {
+            let txn = db.begin_write().map_err(to_db_err)?;
+            {
+                let mut clients = txn.open_table(ALLOWED_CLIENT).map_err(to_db_err)?;
+                let key = (network.as_str(), sender_domain.as_str());
+                if clients.get(key).map_err(to_db_err)?.is_some() {
+                    return Ok(false);
+                }
+                let client = StoredAllowedClient {
+                    id: next_id(
+                        &mut txn.open_table(LAST_ID).map_err(to_db_err)?,
+                        "allowed_client",
+                    )?,
+                    rule: rule.to_value(),
+                    source,
+                    time_added: Utc::now().into(),
+                };
+                let json = serde_json::to_string(&client).map_err(to_db_err)?;
+                clients.insert(key, json.as_str()).map_err(to_db_err)?;
+            }
+            txn.commit().map_err(to_db_err)?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn find_allowed_address(
+        &self,
+        patterns: Vec<String>,
+        rule: DecisionRule,
+    ) -> Result<Option<AllowedAddressModel>, DbErr> {
+        self.blocking(move |db| {
+            let txn = db.begin_read().map_err(to_db_err)?;
+            let addresses = txn.open_table(ALLOWED_ADDRESS).map_err(to_db_err)?;
+            let rule_value = rule.to_value();
+            for address in patterns {
+                let Some(json) = addresses
+                    .get((rule_value.as_str(), address.as_str()))
+                    .map_err(to_db_err)?
+                else {
+                    continue;
+                };
+                let allowed: StoredAllowedAddress =
+                    serde_json::from_str(json.value()).map_err(to_db_err)?;
+                return Ok(Some(AllowedAddressModel {
+                    id: allowed.id,
+                    address,
+                    rule,
+                    source: allowed.source,
+                    time_added: allowed.time_added,
+                }));
+            }
+            Ok(None)
+        })
+        .await
+    }
+
+    async fn add_allowed_address(
+        &self,
+        address: String,
+        rule: DecisionRule,
+        source: String,
+    ) -> Result<bool, DbErr> {
+        self.blocking(move |db| {
+            let txn = db.begin_write().map_err(to_db_err)?;
+            {
+                let mut addresses = txn.open_table(ALLOWED_ADDRESS).map_err(to_db_err)?;
+                let key = (rule.to_value(), address);
+                let key = (key.0.as_str(), key.1.as_str());
+                if addresses.get(key).map_err(to_db_err)?.is_some() {
+                    return Ok(false);
+                }
+                let allowed = StoredAllowedAddress {
+                    id: next_id(
+                        &mut txn.open_table(LAST_ID).map_err(to_db_err)?,
+                        "allowed_address",
+                    )?,
+                    source,
+                    time_added: Utc::now().into(),
+                };
+                let json = serde_json::to_string(&allowed).map_err(to_db_err)?;
+                addresses.insert(key, json.as_str()).map_err(to_db_err)?;
+            }
+            txn.commit().map_err(to_db_err)?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn insert_mail(
+        &self,
+        mail: MailActive,
+        recipient_ids: Vec<i32>,
+        decision: DecisionActive,
+    ) -> Result<InsertedMail, DbErr> {
+        let attempt = new_attempt(&mail, decision)?;
+        let stored = StoredMail {
+            sender_local_part: required("sender_local_part", mail.sender_local_part)?,
+            sender_domain: required("sender_domain", mail.sender_domain)?,
+            message_id: required("message_id", mail.message_id)?,
+            sending_host_name: value(mail.sending_host_name).flatten(),
+            sending_ip: required("sending_ip", mail.sending_ip)?,
+            time_received: required("time_received", mail.time_received)?,
+            time_accepted: value(mail.time_accepted).flatten(),
+            status: required("status", mail.status)?.to_value(),
+            helo_name: value(mail.helo_name).flatten(),
+            queue_id: value(mail.queue_id).flatten(),
+            auth_user: value(mail.auth_user).flatten(),
+            tls_version: value(mail.tls_version).flatten(),
+            tls_cipher: value(mail.tls_cipher).flatten(),
+            message_size: value(mail.message_size).flatten(),
+            daemon_name: value(mail.daemon_name).flatten(),
+            spf_result: value(mail.spf_result)
+                .flatten()
+                .map(|spf_result| spf_result.to_value()),
+            recipient_ids,
+            attempts: vec![attempt],
+            history: vec![],
+        };
+
+        self.blocking(move |db| {
+            let txn = db.begin_write().map_err(to_db_err)?;
+            let inserted = {
+                let mut by_message_id = txn.open_table(MAIL_BY_MESSAGE_ID).map_err(to_db_err)?;
+                let mut mails = txn.open_table(MAIL).map_err(to_db_err)?;
+                let existing = by_message_id
+                    .get(stored.message_id.as_str())
+                    .map_err(to_db_err)?
+                    .map(|id| id.value());
+                match existing {
+                    Some(id) => {
+                        InsertedMail::Existing(Box::new(read_mail(&mails, id)?.into_model(id)?))
+                    }
+                    None => {
+                        let id = next_id(&mut txn.open_table(LAST_ID).map_err(to_db_err)?, "mail")?;
+                        by_message_id
+                            .insert(stored.message_id.as_str(), id)
+                            .map_err(to_db_err)?;
+                        write_mail(&mut mails, id, &stored)?;
+                        update_known_good(&txn, id, &stored)?;
+                        InsertedMail::New(id)
+                    }
+                }
+            };
+            txn.commit().map_err(to_db_err)?;
+            Ok(inserted)
+        })
+        .await
+    }
+
+    async fn update_status(
+        &self,
+        mail_id: i32,
+        status: EmailStatus,
+        time_accepted: Option<DateTimeWithTimeZone>,
+        change: StatusChange,
+        session_mail: MailActive,
+        decision: DecisionActive,
+    ) -> Result<(), DbErr> {
+        let attempt = new_attempt(&session_mail, decision)?;
+        self.add_attempt(mail_id, Some((status, time_accepted, change)), attempt)
+            .await
+    }
+
+    async fn set_status(
+        &self,
+        mail_id: i32,
+        status: EmailStatus,
+        change: StatusChange,
+    ) -> Result<EmailStatus, DbErr> {
+        self.blocking(move |db| {
+            let txn = db.begin_write().map_err(to_db_err)?;
+            let old_status = {
+                let mut mails = txn.open_table(MAIL).map_err(to_db_err)?;
+                let mut mail = read_mail(&mails, mail_id)?;
+                let old_status = EmailStatus::try_from_value(&mail.status)?;
+                let time_accepted = time_accepted(&status, mail.time_accepted);
+                change_status(&txn, mail_id, &mut mail, &status, time_accepted, change)?;
+                write_mail(&mut mails, mail_id, &mail)?;
+                old_status
+            };
+            txn.commit().map_err(to_db_err)?;
+            Ok(old_status)
+        })
+        .await
+    }
+
+    async fn status_history(&self, mail_id: i32) -> Result<Vec<MailStatusHistoryModel>, DbErr> {
+        self.blocking(move |db| {
+            let txn = db.begin_read().map_err(to_db_err)?;
+            let mail = read_mail(&txn.open_table(MAIL).map_err(to_db_err)?, mail_id)?;
+            mail.history
+                .into_iter()
+                .map(|change| {
+                    Ok(MailStatusHistoryModel {
+                        id: change.id,
+                        mail_id,
+                        mail_time_received: mail.time_received,
+                        old_status: EmailStatus::try_from_value(&change.old_status)?,
+                        new_status: EmailStatus::try_from_value(&change.new_status)?,
+                        time_changed: change.time_changed,
+                        actor: change.actor,
+                        note: change.note,
+                    })
+                })
+                .collect()
+        })
+        .await
+    }
+
+    async fn insert_attempt(
+        &self,
+        mail_id: i32,
+        session_mail: MailActive,
+        decision: DecisionActive,
+    ) -> Result<(), DbErr> {
+        let attempt = new_attempt(&session_mail, decision)?;
+        self.add_attempt(mail_id, None, attempt).await
+    }
+
+    async fn expire_mail(&self, received_before: DateTimeWithTimeZone) -> Result<u64, DbErr> {
+        self.blocking(move |db| {
+            let txn = db.begin_write().map_err(to_db_err)?;
+            let expired = {
+                let mut mails = txn.open_table(MAIL).map_err(to_db_err)?;
+                let mut expired = vec![];
+                for entry in mails.iter().map_err(to_db_err)? {
+                    let (id, json) = entry.map_err(to_db_err)?;
+                    let mail: StoredMail = serde_json::from_str(json.value()).map_err(to_db_err)?;
+                    if mail.time_received < received_before {
+                        expired.push((id.value(), mail));
+                    }
+                }
+                for (id, mail) in &expired {
+                    remove_from_indexes(&txn, *id, mail)?;
+                    mails.remove(*id).map_err(to_db_err)?;
+                }
+                expired.len()
+            };
+            txn.commit().map_err(to_db_err)?;
+            Ok(expired as u64)
+        })
+        .await
+    }
+
+    async fn ping(&self) -> Result<(), DbErr> {
+        Ok(())
+    }
+
+    async fn close(&self) -> Result<(), DbErr> {
+        Ok(())
+    }
+}
@@ -0,0 +1,634 @@
+use std::net::IpAddr;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use entity::{
+    allowed_address, allowed_client,
+    decision_rule::DecisionRule,
+    email_status::EmailStatus,
+    mail, mail_recipient, mail_status_history,
+    prelude::{
+        AllowedAddressActive, AllowedAddressEntity, AllowedAddressModel, AllowedClientActive,
+        AllowedClientEntity, AllowedClientModel, DecisionActive, DeliveryAttemptActive, MailActive,
+        MailEntity, MailModel, MailRecipientActive, MailRecipientEntity, MailStatusHistoryActive,
+        MailStatusHistoryEntity, MailStatusHistoryModel, RecipientActive, RecipientEntity,
+        RecipientModel,
+    },
+    recipient,
+    spf_result::SpfResult,
+};
+use ipnet::IpNet;
+use migration::{Migrator, MigratorTrait};
+use sea_orm::{
+    prelude::DateTimeWithTimeZone,
+    sea_query::{Expr, Func, OnConflict},
+    ActiveModelTrait, ColumnTrait, ConnectOptions, ConnectionTrait, Database, DatabaseConnection,
+    DatabaseTransaction, DbBackend, DbErr, EntityTrait, Insert, QueryFilter, QueryOrder,
+    QuerySelect, Set, SqlErr, Statement, TransactionError, TransactionTrait,
+};
+use tracing::{error, info};
+
+use super::{
+    containing_networks, time_accepted, GreylistStore, InsertedMail, StatusChange,
+    KNOWN_GOOD_STATUSES,
+};
+use crate::{address, settings::Settings};
+
+/// Postgres, MySQL or SQLite through sea-orm
+#[derive(Debug)]
+pub struct SqlStore {
+    db: DatabaseConnection,
+}
+
+impl SqlStore {
+    pub async fn connect(config: &Settings) -> Result<Self, DbErr> {
+        let db_url = config
+            .get_db_url()
+            .map_err(|e| DbErr::Custom(e.to_string()))?;
+
+        // Other SQLite connections in the pool could be reading from before the
+        // migrations, and fail to prepare statements that need the new schema,
+        // so those run on a connection of their own first. Not in memory though,
+        // where every pool is a database of its own.
+        let lowercase_local_part = config.get_lowercase_local_part();
+        if db_url.starts_with("sqlite:") && !is_in_memory(&db_url) {
+            let mut options = ConnectOptions::new(db_url);
+            options.max_connections(1);
+            let db = Database::connect(options).await?;
+            migrate(&db, lowercase_local_part).await?;
+            db.close().await?;
+            return Ok(Self {
+                db: crate::connect_database(config).await?,
+            });
+        }
+
+        let db = crate::connect_database(config).await?;
+        migrate(&db, lowercase_local_part).await?;
+        Ok(Self { db })
+    }
+
+    // Where the unique index on Message-Ids turned the insert down, the
+    // transaction has been rolled back, so the mail is looked up outside it
+    async fn inserted_or_existing(
+        &self,
+        result: Result<InsertedMail, TransactionError<DbErr>>,
+        message_id: &str,
+    ) -> Result<InsertedMail, DbErr> {
+        match result {
+            Ok(inserted) => Ok(inserted),
+            Err(TransactionError::Transaction(e))
+                if matches!(e.sql_err(), Some(SqlErr::UniqueConstraintViolation(_))) =>
+            {
+                self.find_mail(message_id)
+                    .await?
+                    .map(|existing| InsertedMail::Existing(Box::new(existing)))
+                    .ok_or_else(|| DbErr::RecordNotFound(format!("mail {}", message_id)))
+            }
+            Err(e) => Err(flatten(e)),
+        }
+    }
+}
+
+// Only the migrations not yet applied are run. Undoing them all first, as
+// startup once did, dropped every table and with it all stored mail.
+async fn migrate(db: &DatabaseConnection, lowercase_local_part: bool) -> Result<(), DbErr> {
+    Migrator::up(db, None).await?;
+    if lowercase_local_part {
+        lowercase_local_parts(db).await?;
+    }
+    Ok(())
+}
+
+// Recipients stored before lowercase_local_part was set, which migrations
+// can't see, are merged into the lowercase address the milter now looks up.
+// Where the collation ignores case nothing matches, but there the unique
+// index already treats both spellings as one.
+async fn lowercase_local_parts(db: &DatabaseConnection) -> Result<(), DbErr> {
+    let local_part = Expr::col(recipient::Column::LocalPart);
+    let mixed_case = RecipientEntity::find()
+        .filter(local_part.clone().ne(Func::lower(local_part)))
+        .order_by_asc(recipient::Column::Id)
+        .all(db)
+        .await?;
+    if mixed_case.is_empty() {
+        return Ok(());
+    }
+
+    let txn = db.begin().await?;
+    for recipient in &mixed_case {
+        let (local_part, _) =
+            match address::normalize_email(&recipient.local_part, &recipient.domain, true) {
+                Ok(address) => address,
+                // Some characters take more bytes in lowercase
+                Err(e) => {
+                    error!(
+                        local_part = recipient.local_part,
+                        domain = recipient.domain,
+                        "Leaving recipient in mixed case: {}",
+                        e
+                    );
+                    continue;
+                }
+            };
+        let existing = RecipientEntity::find()
+            .filter(recipient::Column::LocalPart.eq(local_part.as_str()))
+            .filter(recipient::Column::Domain.eq(recipient.domain.as_str()))
+            .one(&txn)
+            .await?;
+        if let Some(existing) = existing {
+            MailRecipientEntity::update_many()
+                .col_expr(
+                    mail_recipient::Column::RecipientId,
+                    Expr::value(existing.id),
+                )
+                .filter(mail_recipient::Column::RecipientId.eq(recipient.id))
+                .exec(&txn)
+                .await?;
+            RecipientEntity::delete_by_id(recipient.id)
+                .exec(&txn)
+                .await?;
+        } else {
+            RecipientActive {
+                id: Set(recipient.id),
+                local_part: Set(local_part),
+                ..Default::default()
+            }
+            .update(&txn)
+            .await?;
+        }
+    }
+    // A message sent to two spellings of one address now lists it twice
+    txn.execute_unprepared(
+        "DELETE FROM mail_recipient WHERE id NOT IN (
+             SELECT id FROM (
+                 SELECT MIN(id) AS id FROM mail_recipient GROUP BY mail_id, recipient_id
+             ) AS kept
+         )",
+    )
+    .await?;
+    txn.commit().await?;
+
+    info!(
+        recipients = mixed_case.len(),
+        "Lowercased the local parts of stored recipients"
+    );
+    Ok(())
+}
+
+fn is_in_memory(db_url: &str) -> bool {
+    db_url.contains(":memory:") || db_url.contains("mode=memory")
+}
+
+#[async_trait]
+impl GreylistStore for SqlStore {
+    // RETURNING can't be relied on here: MySQL doesn't support it and SQLite falls
+    // back to last_insert_rowid(), which isn't updated when the row already exists.
+    // DO NOTHING isn't portable either, so "update" the address to itself.
+    async fn upsert_recipient(
+        &self,
+        local_part: String,
+        domain: String,
+    ) -> Result<RecipientModel, DbErr> {
+        upsert_recipient(&self.db, local_part, domain).await
+    }
+
+    async fn find_mail(&self, message_id: &str) -> Result<Option<MailModel>, DbErr> {
+        MailEntity::find()
+            .filter(mail::Column::MessageId.eq(message_id))
+            .one(&self.db)
+            .await
+    }
+
+    async fn find_known_good(&self, sending_ip: &str) -> Result<Option<MailModel>, DbErr> {
+        MailEntity::find()
+            .filter(
+                mail::Column::SendingIp
+                    .eq(sending_ip)
+                    .and(mail::Column::Status.is_in(KNOWN_GOOD_STATUSES)),
+            )
+            .one(&self.db)
+            .await
+    }
+
+    async fn find_known_good_spf(&self, sender_domain: &str) -> Result<Option<MailModel>, DbErr> {
+        MailEntity::find()
+            .filter(
+                mail::Column::SenderDomain
+                    .eq(sender_domain)
+                    .and(mail::Column::SpfResult.eq(SpfResult::Pass))
+                    .and(mail::Column::Status.is_in(KNOWN_GOOD_STATUSES)),
+            )
+            .one(&self.db)
+            .await
+    }
+
+    async fn find_allowed_client(
+        &self,
+        address: IpAddr,
+        sender_domain: &str,
+        rule: DecisionRule,
+    ) -> Result<Option<AllowedClientModel>, DbErr> {
+        let sender_domains = ["", sender_domain];
+        // Through the GiST index on the cidr copy of the network
+        if self.db.get_database_backend() == DbBackend::Postgres {
+            return AllowedClientEntity::find()
+                .filter(Expr::cust_with_values(
+                    "$1::inet <<= network_cidr",
+                    [address::normalize_ip(address).to_string()],
+                ))
+                .filter(allowed_client::Column::SenderDomain.is_in(sender_domains))
+                .filter(allowed_client::Column::Rule.eq(rule))
+                .order_by_desc(Expr::cust("masklen(network_cidr)"))
+                .one(&self.db)
+                .await;
+        }
+
+        // Elsewhere the network is only text, so look for each one the
+        // address could be in
+        let networks = containing_networks(address);
+        let mut found = AllowedClientEntity::find()
+            .filter(
+                allowed_client::Column::Network
+                    .is_in(networks.clone())
+                    .and(allowed_client::Column::SenderDomain.is_in(sender_domains))
+                    .and(allowed_client::Column::Rule.eq(rule)),
+            )
+            .all(&self.db)
+            .await?;
+        Ok(networks.iter().find_map(|network| {
+            found
+                .iter()
+                .position(|client| client.network == *network)
+                .map(|index| found.swap_remove(index))
+        }))
+    }
+
+    async fn add_allowed_client(
+        &self,
+        network: IpNet,
+        sender_domain: String,
+        rule: DecisionRule,
+        source: String,
+    ) -> Result<bool, DbErr> {
+        let network = network.trunc().to_string();
+        let existing = AllowedClientEntity::find()
+            .filter(allowed_client::Column::Network.eq(network.as_str()))
+            .filter(allowed_client::Column::SenderDomain.eq(sender_domain.as_str()))
+            .one(&self.db)
+            .await?;
+        if existing.is_some() {
+            return Ok(false);
+        }
+
+        let result = AllowedClientActive {
+            network: Set(network),
+            sender_domain: Set(sender_domain),
+            rule: Set(rule),
+            source: Set(source),
+            time_added: Set(Utc::now().into()),
+            ..Default::default()
+        }
+        .insert(&self.db)
+        .await;
+        match result {
+            Ok(_) => Ok(true),
+            Err(e) if matches!(e.sql_err(), Some(SqlErr::UniqueConstraintViolation(_))) => {
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn find_allowed_address(
+        &self,
+        patterns: Vec<String>,
+        rule: DecisionRule,
+    ) -> Result<Option<AllowedAddressModel>, DbErr> {
+        let mut found = AllowedAddressEntity::find()
+            .filter(
+                allowed_address::Column::Address
+                    .is_in(patterns.clone())
+                    .and(allowed_address::Column::Rule.eq(rule)),
+            )
+            .all(&self.db)
+            .await?;
+        Ok(patterns.iter().find_map(|pattern| {
+            found
+                .iter()
+                .position(|allowed| allowed.address == *pattern)
+                .map(|index| found.swap_remove(index))
+        }))
+    }
+
+    async fn add_allowed_address(
+        &self,
+        address: String,
+        rule: DecisionRule,
+        source: String,
+    ) -> Result<bool, DbErr> {
+        let existing = AllowedAddressEntity::find()
+            .filter(
+                allowed_address::Column::Address
+                    .eq(address.as_str())
+                    .and(allowed_address::Column::Rule.eq(rule.clone())),
+            )
+            .one(&self.db)
+            .await?;
+        if existing.is_some() {
+            return Ok(false);
+        }
+
+        let result = AllowedAddressActive {
+            address: Set(address),
+            rule: Set(rule),
+            source: Set(source),
+            time_added: Set(Utc::now().into()),
+            ..Default::default()
+        }
+        .insert(&self.db)
+        .await;
+        match result {
+            Ok(_) => Ok(true),
+            Err(e) if matches!(e.sql_err(), Some(SqlErr::UniqueConstraintViolation(_))) => {
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn insert_mail(
+        &self,
+        mail: MailActive,
+        recipient_ids: Vec<i32>,
+        decision: DecisionActive,
+    ) -> Result<InsertedMail, DbErr> {
+        let message_id = mail.message_id.clone().unwrap();
+        let result = self
+            .db
+            .transaction::<_, InsertedMail, DbErr>(|txn| {
+                Box::pin(async move {
+                    let inserted = match insert_new_mail(txn, &mail, recipient_ids).await? {
+                        Ok(inserted) => inserted,
+                        Err(existing) => return Ok(InsertedMail::Existing(existing)),
+                    };
+                    record_attempt(txn, &inserted, &mail, decision).await?;
+                    Ok(InsertedMail::New(inserted.id))
+                })
+            })
+            .await;
+        self.inserted_or_existing(result, &message_id).await
+    }
+
+    async fn import_mail(
+        &self,
+        mail: MailActive,
+        recipients: Vec<(String, String)>,
+    ) -> Result<InsertedMail, DbErr> {
+        let message_id = mail.message_id.clone().unwrap();
+        let result = self
+            .db
+            .transaction::<_, InsertedMail, DbErr>(|txn| {
+                Box::pin(async move {
+                    let mut recipient_ids = vec![];
+                    for (local_part, domain) in recipients {
+                        let id = upsert_recipient(txn, local_part, domain).await?.id;
+                        // Two spellings of one address end up as the same
+                        // recipient
+                        if !recipient_ids.contains(&id) {
+                            recipient_ids.push(id);
+                        }
+                    }
+                    Ok(match insert_new_mail(txn, &mail, recipient_ids).await? {
+                        Ok(inserted) => InsertedMail::New(inserted.id),
+                        Err(existing) => InsertedMail::Existing(existing),
+                    })
+                })
+            })
+            .await;
+        self.inserted_or_existing(result, &message_id).await
+    }
+
+    async fn update_status(
+        &self,
+        mail_id: i32,
+        status: EmailStatus,
+        time_accepted: Option<DateTimeWithTimeZone>,
+        change: StatusChange,
+        session_mail: MailActive,
+        decision: DecisionActive,
+    ) -> Result<(), DbErr> {
+        self.db
+            .transaction::<_, (), DbErr>(|txn| {
+                Box::pin(async move {
+                    let mail = lock_mail(txn, mail_id).await?;
+                    change_status(txn, &mail, status, time_accepted, change).await?;
+                    record_attempt(txn, &mail, &session_mail, decision).await
+                })
+            })
+            .await
+            .map_err(flatten)
+    }
+
+    async fn set_status(
+        &self,
+        mail_id: i32,
+        status: EmailStatus,
+        change: StatusChange,
+    ) -> Result<EmailStatus, DbErr> {
+        self.db
+            .transaction::<_, EmailStatus, DbErr>(|txn| {
+                Box::pin(async move {
+                    let mail = lock_mail(txn, mail_id).await?;
+                    let time_accepted = time_accepted(&status, mail.time_accepted);
+                    change_status(txn, &mail, status, time_accepted, change).await?;
+                    Ok(mail.status)
+                })
+            })
+            .await
+            .map_err(flatten)
+    }
+
+    async fn status_history(&self, mail_id: i32) -> Result<Vec<MailStatusHistoryModel>, DbErr> {
+        MailStatusHistoryEntity::find()
+            .filter(mail_status_history::Column::MailId.eq(mail_id))
+            .order_by_asc(mail_status_history::Column::TimeChanged)
+            .order_by_asc(mail_status_history::Column::Id)
+            .all(&self.db)
+            .await
+    }
+
+    async fn insert_attempt(
+        &self,
+        mail_id: i32,
+        session_mail: MailActive,
+        decision: DecisionActive,
+    ) -> Result<(), DbErr> {
+        self.db
+            .transaction::<_, (), DbErr>(|txn| {
+                Box::pin(async move {
+                    let mail = MailEntity::find_by_id(mail_id)
+                        .one(txn)
+                        .await?
+                        .ok_or_else(|| DbErr::RecordNotFound(format!("mail {}", mail_id)))?;
+                    record_attempt(txn, &mail, &session_mail, decision).await
+                })
+            })
+            .await
+            .map_err(flatten)
+    }
+
+    async fn ping(&self) -> Result<(), DbErr> {
+        self.db.ping().await
+    }
+
+    async fn close(&self) -> Result<(), DbErr> {
+        self.db.clone().close().await
+    }
+
+    fn sql_connection(&self) -> Option<&DatabaseConnection> {
+        Some(&self.db)
+    }
+}
+
+// Shared by the store and the import, which adds recipients inside its own
+// transaction
+async fn upsert_recipient<C: ConnectionTrait>(
+    db: &C,
+    local_part: String,
+    domain: String,
+) -> Result<RecipientModel, DbErr> {
+    Insert::one(RecipientActive {
+        local_part: Set(local_part.clone()),
+        domain: Set(domain.clone()),
+        ..Default::default()
+    })
+    .on_conflict(
+        OnConflict::columns([recipient::Column::LocalPart, recipient::Column::Domain])
+            .update_column(recipient::Column::LocalPart)
+            .clone(),
+    )
+    .exec_without_returning(db)
+    .await?;
+
+    RecipientEntity::find()
+        .filter(recipient::Column::LocalPart.eq(local_part.as_str()))
+        .filter(recipient::Column::Domain.eq(domain.as_str()))
+        .one(db)
+        .await?
+        .ok_or_else(|| DbErr::RecordNotFound(format!("recipient {}@{}", local_part, domain)))
+}
+
+// Inserts the mail and its recipients, or returns the mail already stored
+// under its Message-Id. A partitioned mail table can't keep Message-Ids
+// unique, so on Postgres everything inserting mail with the same one takes
+// turns here.
+async fn insert_new_mail(
+    txn: &DatabaseTransaction,
+    mail: &MailActive,
+    recipient_ids: Vec<i32>,
+) -> Result<Result<MailModel, Box<MailModel>>, DbErr> {
+    if txn.get_database_backend() == DbBackend::Postgres {
+        let message_id = mail.message_id.clone().unwrap();
+        txn.execute(Statement::from_sql_and_values(
+            DbBackend::Postgres,
+            "SELECT pg_advisory_xact_lock(hashtext($1))",
+            [message_id.as_str().into()],
+        ))
+        .await?;
+        if let Some(existing) = MailEntity::find()
+            .filter(mail::Column::MessageId.eq(message_id.as_str()))
+            .one(txn)
+            .await?
+        {
+            return Ok(Err(Box::new(existing)));
+        }
+    }
+
+    let inserted = mail.clone().insert(txn).await?;
+    for recipient_id in recipient_ids {
+        MailRecipientActive {
+            mail_id: Set(inserted.id),
+            mail_time_received: Set(inserted.time_received),
+            recipient_id: Set(recipient_id),
+            ..Default::default()
+        }
+        .insert(txn)
+        .await?;
+    }
+    Ok(Ok(inserted))
+}
+
+// Held until the transaction ends, so the status read is the one replaced
+async fn lock_mail(txn: &DatabaseTransaction, mail_id: i32) -> Result<MailModel, DbErr> {
+    MailEntity::find_by_id(mail_id)
+        .lock_exclusive()
+        .one(txn)
+        .await?
+        .ok_or_else(|| DbErr::RecordNotFound(format!("mail {}", mail_id)))
+}
+
+async fn change_status(
+    txn: &DatabaseTransaction,
+    mail: &MailModel,
+    status: EmailStatus,
+    time_accepted: Option<DateTimeWithTimeZone>,
+    change: StatusChange,
+) -> Result<(), DbErr> {
+    MailActive {
+        id: Set(mail.id),
+        status: Set(status.clone()),
+        time_accepted: Set(time_accepted),
+        ..Default::default()
+    }
+    .update(txn)
+    .await?;
+
+    if mail.status != status {
+        MailStatusHistoryActive {
+            mail_id: Set(mail.id),
+            mail_time_received: Set(mail.time_received),
+            old_status: Set(mail.status.clone()),
+            new_status: Set(status),
+            time_changed: Set(Utc::now().into()),
+            actor: Set(change.actor),
+            note: Set(change.note),
+            ..Default::default()
+        }
+        .insert(txn)
+        .await?;
+    }
+    Ok(())
+}
+
+// Every attempt gets a delivery_attempt row with the connection it came in on
+// and a decision row with the reason for the outcome
+async fn record_attempt(
+    txn: &DatabaseTransaction,
+    mail: &MailModel,
+    session_mail: &MailActive,
+    mut decision: DecisionActive,
+) -> Result<(), DbErr> {
+    DeliveryAttemptActive {
+        mail_id: Set(mail.id),
+        mail_time_received: Set(mail.time_received),
+        time_attempted: decision.time_decided.clone(),
+        sending_ip: session_mail.sending_ip.clone(),
+        sending_host_name: session_mail.sending_host_name.clone(),
+        status: decision.status.clone(),
+        ..Default::default()
+    }
+    .insert(txn)
+    .await?;
+
+    decision.mail_id = Set(mail.id);
+    decision.mail_time_received = Set(mail.time_received);
+    decision.insert(txn).await?;
+
+    Ok(())
+}
+
+fn flatten(e: TransactionError<DbErr>) -> DbErr {
+    match e {
+        TransactionError::Connection(e) | TransactionError::Transaction(e) => e,
+    }
+}
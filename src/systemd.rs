@@ -0,0 +1,104 @@
+use std::{
+    io,
+    os::unix::{
+        io::{FromRawFd, IntoRawFd},
+        net::UnixListener,
+    },
+    sync::Arc,
+    time::Duration,
+};
+
+use indymilter::Listener;
+use sd_notify::NotifyState;
+use tokio::{sync::OnceCell, task::JoinHandle, time::timeout};
+use tracing::{debug, info, warn};
+
+use crate::store::GreylistStore;
+
+/// Returns the listening socket passed in by systemd socket activation
+/// (`LISTEN_FDS`), if there is one.
+pub fn inherited_listener() -> io::Result<Option<Listener>> {
+    let mut fds = sd_notify::listen_fds()?;
+
+    let Some(fd) = fds.next() else {
+        return Ok(None);
+    };
+    if fds.next().is_some() {
+        warn!("systemd passed more than one socket, only the first will be used");
+    }
+
+    // Safety: systemd hands over ownership of the descriptors in LISTEN_FDS
+    // and they are not used anywhere else in this process.
+    let unix_listener = unsafe { UnixListener::from_raw_fd(fd) };
+    if unix_listener.local_addr().is_ok() {
+        debug!("Inherited UNIX socket from systemd");
+        unix_listener.set_nonblocking(true)?;
+        return Ok(Some(Listener::Unix(tokio::net::UnixListener::from_std(
+            unix_listener,
+        )?)));
+    }
+
+    // Not a UNIX socket, so it has to be a TCP one
+    let tcp_listener = unsafe { std::net::TcpListener::from_raw_fd(unix_listener.into_raw_fd()) };
+    debug!(
+        "Inherited TCP socket {:?} from systemd",
+        tcp_listener.local_addr()?
+    );
+    tcp_listener.set_nonblocking(true)?;
+    Ok(Some(Listener::Tcp(tokio::net::TcpListener::from_std(
+        tcp_listener,
+    )?)))
+}
+
+/// `READY=1`, once the milter is taking connections, with `status` as the
+/// `STATUS=` line shown by `systemctl status`
+pub fn notify_ready(status: &str) {
+    notify(&[NotifyState::Ready, NotifyState::Status(status)]);
+}
+
+/// Change the `STATUS=` line, e.g. when a degraded start is over
+pub fn notify_status(status: &str) {
+    notify(&[NotifyState::Status(status)]);
+}
+
+pub fn notify_stopping() {
+    notify(&[NotifyState::Stopping]);
+}
+
+/// If systemd expects watchdog keep-alives, check the database at half the
+/// watchdog interval and only send `WATCHDOG=1` while it is answering. Until
+/// the first connection is made after a degraded start there is nothing to
+/// check, and the keep-alives carry on regardless.
+pub fn spawn_watchdog(db: Arc<OnceCell<Arc<dyn GreylistStore>>>) -> Option<JoinHandle<()>> {
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) {
+        return None;
+    }
+
+    let interval = Duration::from_micros(usec / 2);
+    info!(
+        "systemd watchdog enabled, checking database every {:?}",
+        interval
+    );
+
+    Some(tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            let Some(db) = db.get() else {
+                notify(&[NotifyState::Watchdog]);
+                continue;
+            };
+            match timeout(interval, db.ping()).await {
+                Ok(Ok(())) => notify(&[NotifyState::Watchdog]),
+                Ok(Err(e)) => warn!("Database health check failed: {}", e),
+                Err(_) => warn!("Database health check timed out"),
+            }
+        }
+    }))
+}
+
+fn notify(state: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, state) {
+        warn!("Unable to notify systemd: {}", e);
+    }
+}
@@ -0,0 +1,456 @@
+//! Export of messages with their recipients as CSV or NDJSON, and import of
+//! the same. Imports go through the same normalization as the milter, and
+//! skip messages already stored, so a partial import can be run again.
+
+use std::{
+    collections::HashMap,
+    error, fmt,
+    io::{self, BufRead, Write},
+};
+
+use chrono::{DateTime, FixedOffset, NaiveDate, Utc};
+use entity::{
+    email_status::EmailStatus,
+    mail, mail_recipient,
+    prelude::{MailActive, MailEntity, MailModel, MailRecipientEntity},
+    recipient,
+    spf_result::SpfResult,
+};
+use sea_orm::{
+    ActiveEnum, ColumnTrait, Condition, DatabaseConnection, DbErr, EntityTrait, Iterable, JoinType,
+    PaginatorTrait, QueryFilter, QueryOrder, QuerySelect, RelationTrait, Set,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    address,
+    store::{GreylistStore, InsertedMail},
+};
+
+/// Written into every record. Bump it whenever the fields change, and teach
+/// `upgrade` how to read the previous version.
+pub const SCHEMA_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    /// One JSON object per line
+    Ndjson,
+}
+
+/// One message. NDJSON lists the recipients, CSV puts them in one field
+/// separated by spaces.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record<R> {
+    pub schema_version: u32,
+    pub message_id: String,
+    pub sender_local_part: String,
+    pub sender_domain: String,
+    pub recipients: R,
+    pub sending_ip: String,
+    #[serde(default)]
+    pub sending_host_name: Option<String>,
+    #[serde(default)]
+    pub helo_name: Option<String>,
+    pub time_received: DateTime<FixedOffset>,
+    #[serde(default)]
+    pub time_accepted: Option<DateTime<FixedOffset>>,
+    /// `EmailStatus` by name
+    pub status: String,
+    #[serde(default)]
+    pub queue_id: Option<String>,
+    #[serde(default)]
+    pub auth_user: Option<String>,
+    #[serde(default)]
+    pub tls_version: Option<String>,
+    #[serde(default)]
+    pub tls_cipher: Option<String>,
+    #[serde(default)]
+    pub message_size: Option<i64>,
+    #[serde(default)]
+    pub daemon_name: Option<String>,
+    /// `SpfResult` as stored, e.g. `softfail`
+    #[serde(default)]
+    pub spf_result: Option<String>,
+}
+
+pub type MailRecord = Record<Vec<String>>;
+
+impl<R> Record<R> {
+    fn map_recipients<S>(self, f: impl FnOnce(R) -> S) -> Record<S> {
+        Record {
+            schema_version: self.schema_version,
+            message_id: self.message_id,
+            sender_local_part: self.sender_local_part,
+            sender_domain: self.sender_domain,
+            recipients: f(self.recipients),
+            sending_ip: self.sending_ip,
+            sending_host_name: self.sending_host_name,
+            helo_name: self.helo_name,
+            time_received: self.time_received,
+            time_accepted: self.time_accepted,
+            status: self.status,
+            queue_id: self.queue_id,
+            auth_user: self.auth_user,
+            tls_version: self.tls_version,
+            tls_cipher: self.tls_cipher,
+            message_size: self.message_size,
+            daemon_name: self.daemon_name,
+            spf_result: self.spf_result,
+        }
+    }
+}
+
+impl MailRecord {
+    fn new(mail: MailModel, recipients: Vec<String>) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            message_id: mail.message_id,
+            sender_local_part: mail.sender_local_part,
+            sender_domain: mail.sender_domain,
+            recipients,
+            sending_ip: mail.sending_ip,
+            sending_host_name: mail.sending_host_name,
+            helo_name: mail.helo_name,
+            time_received: mail.time_received,
+            time_accepted: mail.time_accepted,
+            status: format!("{:?}", mail.status),
+            queue_id: mail.queue_id,
+            auth_user: mail.auth_user,
+            tls_version: mail.tls_version,
+            tls_cipher: mail.tls_cipher,
+            message_size: mail.message_size,
+            daemon_name: mail.daemon_name,
+            spf_result: mail.spf_result.map(|spf_result| spf_result.to_value()),
+        }
+    }
+}
+
+/// Which messages to export; empty `statuses` means all of them
+#[derive(Debug, Clone, Default)]
+pub struct ExportFilter {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub statuses: Vec<EmailStatus>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    pub imported: usize,
+    /// Already stored, going by Message-Id
+    pub skipped: usize,
+}
+
+#[derive(Debug)]
+pub enum TransferError {
+    Io(io::Error),
+    Csv(csv::Error),
+    Json(serde_json::Error),
+    Db(DbErr),
+    /// A record that can't be imported, counting from 1
+    Invalid {
+        record: usize,
+        reason: String,
+    },
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Csv(e) => write!(f, "{}", e),
+            Self::Json(e) => write!(f, "{}", e),
+            Self::Db(e) => write!(f, "{}", e),
+            Self::Invalid { record, reason } => write!(f, "Record {}: {}", record, reason),
+        }
+    }
+}
+
+impl error::Error for TransferError {}
+
+impl From<io::Error> for TransferError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<csv::Error> for TransferError {
+    fn from(e: csv::Error) -> Self {
+        Self::Csv(e)
+    }
+}
+
+impl From<serde_json::Error> for TransferError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Json(e)
+    }
+}
+
+impl From<DbErr> for TransferError {
+    fn from(e: DbErr) -> Self {
+        Self::Db(e)
+    }
+}
+
+/// An RFC 3339 time, or a date taken as midnight UTC
+pub fn parse_time(value: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(|date| date.and_time(Default::default()).and_utc())
+        .map_err(|_| format!("{} is neither an RFC 3339 time nor a date", value))
+}
+
+/// An `EmailStatus` by name, as shown by `show` and `report`
+pub fn parse_status(name: &str) -> Result<EmailStatus, String> {
+    EmailStatus::iter()
+        .find(|status| format!("{:?}", status) == name)
+        .ok_or_else(|| format!("Unknown status {}", name))
+}
+
+/// Write the messages matching `filter` to `output`, oldest first, and return
+/// how many there were
+pub async fn export(
+    db: &DatabaseConnection,
+    filter: &ExportFilter,
+    format: Format,
+    output: impl Write,
+) -> Result<usize, TransferError> {
+    let mut condition = Condition::all();
+    if let Some(since) = filter.since {
+        condition = condition.add(mail::Column::TimeReceived.gte(since.fixed_offset()));
+    }
+    if let Some(until) = filter.until {
+        condition = condition.add(mail::Column::TimeReceived.lt(until.fixed_offset()));
+    }
+    if !filter.statuses.is_empty() {
+        condition = condition.add(mail::Column::Status.is_in(filter.statuses.clone()));
+    }
+
+    let mut writer = match format {
+        Format::Csv => Writer::Csv(Box::new(csv::Writer::from_writer(output))),
+        Format::Ndjson => Writer::Ndjson(output),
+    };
+    let mut exported = 0;
+    let mut pages = MailEntity::find()
+        .filter(condition)
+        .order_by_asc(mail::Column::TimeReceived)
+        .order_by_asc(mail::Column::Id)
+        .paginate(db, 500);
+    while let Some(mails) = pages.fetch_and_next().await? {
+        let mut recipients = recipients_of(db, &mails).await?;
+        for mail in mails {
+            let addresses = recipients.remove(&mail.id).unwrap_or_default();
+            writer.write(MailRecord::new(mail, addresses))?;
+            exported += 1;
+        }
+    }
+    writer.flush()?;
+
+    Ok(exported)
+}
+
+/// Read records from `input` and store those not already there. Stops at the
+/// first record that can't be read or stored; those before it stay imported.
+pub async fn import(
+    store: &dyn GreylistStore,
+    input: impl BufRead,
+    format: Format,
+    lowercase_local_part: bool,
+) -> Result<ImportSummary, TransferError> {
+    if store.sql_connection().is_none() {
+        return Err(TransferError::Db(DbErr::Custom(
+            "Import needs an SQL database".to_string(),
+        )));
+    }
+
+    let mut summary = ImportSummary::default();
+    let records: Box<dyn Iterator<Item = Result<MailRecord, TransferError>>> = match format {
+        Format::Csv => Box::new(
+            csv::Reader::from_reader(input)
+                .into_deserialize::<Record<String>>()
+                .map(|record| {
+                    Ok(record?.map_recipients(|recipients| {
+                        recipients.split_whitespace().map(str::to_string).collect()
+                    }))
+                }),
+        ),
+        Format::Ndjson => Box::new(
+            input
+                .lines()
+                .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+                .map(|line| read_json(&line?)),
+        ),
+    };
+
+    for (index, record) in records.enumerate() {
+        let invalid = |reason: String| TransferError::Invalid {
+            record: index + 1,
+            reason,
+        };
+        let record = record.map_err(|e| match e {
+            TransferError::Io(e) => TransferError::Io(e),
+            TransferError::Invalid { reason, .. } => invalid(reason),
+            e => invalid(e.to_string()),
+        })?;
+        let record = upgrade(record).map_err(invalid)?;
+        let mail = normalize(&record, lowercase_local_part).map_err(invalid)?;
+        if store.find_mail(&record.message_id).await?.is_some() {
+            summary.skipped += 1;
+            continue;
+        }
+
+        let recipients = record
+            .recipients
+            .iter()
+            .map(|address| {
+                let address = address.trim_start_matches('<').trim_end_matches('>');
+                address::split_email(address, lowercase_local_part)
+                    .map_err(|e| invalid(format!("Recipient {}: {}", address, e)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Mail the milter stored since it was looked for above is skipped too
+        match store.import_mail(mail, recipients).await? {
+            InsertedMail::New(_) => summary.imported += 1,
+            InsertedMail::Existing(_) => summary.skipped += 1,
+        }
+    }
+
+    Ok(summary)
+}
+
+enum Writer<W: Write> {
+    Csv(Box<csv::Writer<W>>),
+    Ndjson(W),
+}
+
+impl<W: Write> Writer<W> {
+    fn write(&mut self, record: MailRecord) -> Result<(), TransferError> {
+        match self {
+            Self::Csv(writer) => {
+                writer.serialize(record.map_recipients(|recipients| recipients.join(" ")))?
+            }
+            Self::Ndjson(writer) => {
+                serde_json::to_writer(&mut *writer, &record)?;
+                writer.write_all(b"\n")?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), TransferError> {
+        match self {
+            Self::Csv(writer) => writer.flush()?,
+            Self::Ndjson(writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+}
+
+// Recipient addresses of each of the mails, in the order they were stored
+async fn recipients_of(
+    db: &DatabaseConnection,
+    mails: &[MailModel],
+) -> Result<HashMap<i32, Vec<String>>, DbErr> {
+    let mut recipients: HashMap<i32, Vec<String>> = HashMap::new();
+    for (mail_id, id, local_part, domain) in MailRecipientEntity::find()
+        .select_only()
+        .column(mail_recipient::Column::MailId)
+        .columns([
+            recipient::Column::Id,
+            recipient::Column::LocalPart,
+            recipient::Column::Domain,
+        ])
+        .join(
+            JoinType::InnerJoin,
+            mail_recipient::Relation::Recipient.def(),
+        )
+        .filter(mail_recipient::Column::MailId.is_in(mails.iter().map(|mail| mail.id)))
+        .order_by_asc(mail_recipient::Column::Id)
+        .into_tuple::<(i32, i32, String, String)>()
+        .all(db)
+        .await?
+    {
+        let recipient = recipient::Model {
+            id,
+            local_part,
+            domain,
+        };
+        recipients
+            .entry(mail_id)
+            .or_default()
+            .push(recipient.address());
+    }
+    Ok(recipients)
+}
+
+// Checks the version first, as a newer record may not parse as the current
+// version; the record number is filled in by the caller
+fn read_json(line: &str) -> Result<MailRecord, TransferError> {
+    let value: serde_json::Value = serde_json::from_str(line)?;
+    if let Some(version) = value.get("schema_version").and_then(|v| v.as_u64()) {
+        if version > u64::from(SCHEMA_VERSION) {
+            return Err(TransferError::Invalid {
+                record: 0,
+                reason: format!("Schema version {} is newer than this build", version),
+            });
+        }
+    }
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Bring a record from an older export up to `SCHEMA_VERSION`
+fn upgrade(record: MailRecord) -> Result<MailRecord, String> {
+    match record.schema_version {
+        SCHEMA_VERSION => Ok(record),
+        // Version 1 had no SPF result, which reads in as none
+        1 => Ok(MailRecord {
+            schema_version: SCHEMA_VERSION,
+            ..record
+        }),
+        version if version > SCHEMA_VERSION => Err(format!(
+            "Schema version {} is newer than this build",
+            version
+        )),
+        version => Err(format!("Unknown schema version {}", version)),
+    }
+}
+
+// Stored the way the milter would have stored it
+fn normalize(record: &MailRecord, lowercase_local_part: bool) -> Result<MailActive, String> {
+    let sending_ip = address::parse_ip(&record.sending_ip)
+        .ok_or_else(|| format!("Invalid client address {}", record.sending_ip))?;
+    let (sender_local_part, sender_domain) = address::normalize_email(
+        &record.sender_local_part,
+        &record.sender_domain,
+        lowercase_local_part,
+    )
+    .map_err(|e| format!("Sender: {}", e))?;
+
+    Ok(MailActive {
+        sender_local_part: Set(sender_local_part),
+        sender_domain: Set(sender_domain),
+        message_id: Set(record.message_id.clone()),
+        sending_host_name: Set(record.sending_host_name.clone()),
+        sending_ip: Set(sending_ip.to_string()),
+        time_received: Set(record.time_received),
+        time_accepted: Set(record.time_accepted),
+        status: Set(parse_status(&record.status)?),
+        helo_name: Set(record.helo_name.clone()),
+        queue_id: Set(record.queue_id.clone()),
+        auth_user: Set(record.auth_user.clone()),
+        tls_version: Set(record.tls_version.clone()),
+        tls_cipher: Set(record.tls_cipher.clone()),
+        message_size: Set(record.message_size),
+        daemon_name: Set(record.daemon_name.clone()),
+        spf_result: Set(record
+            .spf_result
+            .as_ref()
+            .map(SpfResult::try_from_value)
+            .transpose()
+            .map_err(|_| format!("Invalid SPF result {:?}", record.spf_result))?),
+        ..Default::default()
+    })
+}
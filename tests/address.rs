@@ -0,0 +1,99 @@
+use std::net::IpAddr;
+
+use sql_greylist_milter::address::{
+    is_same_or_under, normalize_ip, parse_ip, split_email, TooLong,
+};
+
+fn ip(address: &str) -> IpAddr {
+    address.parse().unwrap()
+}
+
+#[test]
+fn ipv4_unchanged() {
+    assert_eq!(parse_ip("192.0.2.1"), Some(ip("192.0.2.1")));
+    assert_eq!(normalize_ip(ip("192.0.2.1")), ip("192.0.2.1"));
+}
+
+#[test]
+fn ipv4_mapped_becomes_ipv4() {
+    assert_eq!(parse_ip("::ffff:192.0.2.1"), Some(ip("192.0.2.1")));
+    assert_eq!(parse_ip("::FFFF:c000:201"), Some(ip("192.0.2.1")));
+    assert_eq!(normalize_ip(ip("::ffff:192.0.2.1")), ip("192.0.2.1"));
+}
+
+#[test]
+fn ipv6_unchanged() {
+    assert_eq!(parse_ip("2001:db8::1"), Some(ip("2001:db8::1")));
+    assert_eq!(parse_ip("2001:DB8:0::1"), Some(ip("2001:db8::1")));
+    // IPv4-compatible (deprecated) addresses are not IPv4-mapped
+    assert_eq!(parse_ip("::192.0.2.1"), Some(ip("::c000:201")));
+}
+
+#[test]
+fn zone_dropped() {
+    assert_eq!(parse_ip("fe80::1%eth0"), Some(ip("fe80::1")));
+    assert_eq!(parse_ip("::ffff:192.0.2.1%2"), Some(ip("192.0.2.1")));
+}
+
+#[test]
+fn invalid_rejected() {
+    assert_eq!(parse_ip(""), None);
+    assert_eq!(parse_ip("unknown"), None);
+    assert_eq!(parse_ip("192.0.2.256"), None);
+}
+
+#[test]
+fn email_domain_lowercased() {
+    assert_eq!(
+        split_email("User@Example.COM", false),
+        Ok(("User".to_string(), "example.com".to_string()))
+    );
+    assert_eq!(
+        split_email("User@Example.COM", true),
+        Ok(("user".to_string(), "example.com".to_string()))
+    );
+}
+
+#[test]
+fn email_split_at_last_at() {
+    assert_eq!(
+        split_email("\"a@b\"@example.com", false),
+        Ok(("\"a@b\"".to_string(), "example.com".to_string()))
+    );
+}
+
+#[test]
+fn email_without_domain() {
+    assert_eq!(
+        split_email("Postmaster", false),
+        Ok(("Postmaster".to_string(), String::new()))
+    );
+}
+
+#[test]
+fn email_length_limits() {
+    let local_part = "a".repeat(64);
+    let domain = format!("{}.example", "b".repeat(247));
+    assert_eq!(
+        split_email(&format!("{}@{}", local_part, domain), false),
+        Ok((local_part.clone(), domain.clone()))
+    );
+    // Too long parts are refused rather than cut short, where they'd be the
+    // same as other addresses
+    assert_eq!(
+        split_email(&format!("{}bounce-1234@example.com", local_part), false),
+        Err(TooLong::LocalPart(75))
+    );
+    assert_eq!(
+        split_email(&format!("a@{}.b", domain), false),
+        Err(TooLong::Domain(257))
+    );
+}
+
+#[test]
+fn names_under_domains() {
+    assert!(is_same_or_under("mail.Example.com.", "example.com"));
+    assert!(is_same_or_under("example.com", "EXAMPLE.com."));
+    assert!(!is_same_or_under("badexample.com", "example.com"));
+    assert!(!is_same_or_under("example.com", "mail.example.com"));
+}
@@ -0,0 +1,146 @@
+mod common;
+
+use entity::spf_result::SpfResult;
+use sql_greylist_milter::allowlist::{self, Allowlist};
+
+fn allowlist(require_spf: bool) -> Allowlist {
+    Allowlist {
+        sender_domains: vec!["partner.example".to_string()],
+        require_spf,
+        recipients: ["postmaster@", "optout@example.com", "lists.example.com"]
+            .map(String::from)
+            .to_vec(),
+    }
+}
+
+#[test]
+fn patterns() {
+    assert_eq!(
+        allowlist::domain_patterns("Mail.Example.COM."),
+        ["mail.example.com", "example.com", "com"]
+    );
+    assert!(allowlist::domain_patterns("").is_empty());
+    // Local parts as stored, which only ignore case if they're lowercased
+    assert_eq!(
+        allowlist::recipient_patterns("Abuse", "Example.com"),
+        ["Abuse@example.com", "Abuse@", "example.com", "com"]
+    );
+    // Recipients without a domain, like postmaster
+    assert_eq!(
+        allowlist::recipient_patterns("postmaster", ""),
+        ["postmaster@"]
+    );
+}
+
+#[test]
+fn senders() {
+    let allowlist = allowlist(true);
+    assert_eq!(allowlist.sender("partner.example"), Some("partner.example"));
+    assert_eq!(
+        allowlist.sender("mail.Partner.example"),
+        Some("partner.example")
+    );
+    assert_eq!(allowlist.sender("otherpartner.example"), None);
+    assert_eq!(allowlist.sender("example"), None);
+
+    assert!(allowlist.sender_qualifies(Some(SpfResult::Pass)));
+    assert!(!allowlist.sender_qualifies(Some(SpfResult::SoftFail)));
+    assert!(!allowlist.sender_qualifies(None));
+    let unqualified = Allowlist {
+        require_spf: false,
+        ..allowlist
+    };
+    assert!(unqualified.sender_qualifies(None));
+}
+
+#[test]
+fn recipients() {
+    let allowlist = allowlist(true);
+    assert_eq!(
+        allowlist.recipient("postmaster", "example.org"),
+        Some("postmaster@")
+    );
+    assert_eq!(allowlist.recipient("PostMaster", "example.org"), None);
+    assert_eq!(allowlist.recipient("postmaster", ""), Some("postmaster@"));
+    assert_eq!(
+        allowlist.recipient("optout", "Example.com"),
+        Some("optout@example.com")
+    );
+    assert_eq!(
+        allowlist.recipient("anyone", "lists.example.com"),
+        Some("lists.example.com")
+    );
+    assert_eq!(
+        allowlist.recipient("anyone", "dev.lists.example.com"),
+        Some("lists.example.com")
+    );
+    assert_eq!(allowlist.recipient("optout", "example.org"), None);
+    assert_eq!(allowlist.recipient("user", "example.com"), None);
+}
+
+#[test]
+fn normalize_recipient() {
+    for (entry, lowercase_local_part, normalized) in [
+        ("Abuse@Example.COM", false, "Abuse@example.com"),
+        ("Abuse@Example.COM", true, "abuse@example.com"),
+        ("PostMaster@", false, "PostMaster@"),
+        ("PostMaster@", true, "postmaster@"),
+        (".Lists.Example.com.", false, "lists.example.com"),
+    ] {
+        assert_eq!(
+            allowlist::normalize_recipient(entry, lowercase_local_part).as_deref(),
+            Ok(normalized),
+            "{}",
+            entry
+        );
+    }
+    assert!(allowlist::normalize_recipient(&format!("{}@", "a".repeat(65)), false).is_err());
+}
+
+#[test]
+fn settings() {
+    let settings = common::settings_with("allowlist", |config| {
+        config.replace(
+            "greylist_time_seconds = 300",
+            r#"greylist_time_seconds = 300
+allow_sender_domains = [ "Partner.Example." ]
+allow_recipients = [ "postmaster@", "optout@Example.com", ".lists.example.com" ]"#,
+        )
+    });
+    assert_eq!(Allowlist::from_settings(&settings), allowlist(true));
+
+    // Normalized like the recipients they're compared with
+    for (lowercase_local_part, postmaster) in [(false, "PostMaster@"), (true, "postmaster@")] {
+        let settings = common::settings_with("allowlist", |config| {
+            config.replace(
+                "greylist_time_seconds = 300",
+                &format!(
+                    r#"greylist_time_seconds = 300
+lowercase_local_part = {}
+allow_recipients = [ "PostMaster@", "OptOut@Example.COM", "{}@example.com" ]"#,
+                    lowercase_local_part,
+                    "a".repeat(65)
+                ),
+            )
+        });
+        let expected = if lowercase_local_part {
+            "optout@example.com"
+        } else {
+            "OptOut@example.com"
+        };
+        assert_eq!(
+            Allowlist::from_settings(&settings).recipients,
+            [postmaster, expected]
+        );
+    }
+
+    let settings = common::settings_with("allowlist", |config| config);
+    assert_eq!(
+        Allowlist::from_settings(&settings),
+        Allowlist {
+            sender_domains: vec![],
+            require_spf: true,
+            recipients: vec![],
+        }
+    );
+}
@@ -0,0 +1,128 @@
+//! A resolver answering from a fixed table, shared by the tests of the
+//! checks built on DNS. Not every test uses every kind of record.
+#![allow(dead_code)]
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use sql_greylist_milter::dns::{DnsError, Resolver};
+use tokio::time::sleep;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+    A,
+    Aaaa,
+    Txt,
+    Mx,
+    Ptr,
+}
+
+#[derive(Debug, Default)]
+pub struct StubResolver {
+    answers: HashMap<(Kind, String), Vec<String>>,
+    failing: Vec<String>,
+    delay: Duration,
+    pub lookups: AtomicUsize,
+}
+
+impl StubResolver {
+    /// Every answer takes this long, so lookups done one after the other
+    /// would show
+    pub fn delayed(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    fn add(mut self, kind: Kind, name: &str, value: &str) -> Self {
+        self.answers
+            .entry((kind, name.to_ascii_lowercase()))
+            .or_default()
+            .push(value.to_string());
+        self
+    }
+
+    pub fn listed(self, name: &str, answer: [u8; 4]) -> Self {
+        let answer = IpAddr::from(answer).to_string();
+        self.add(Kind::A, name, &answer)
+    }
+
+    /// An A or AAAA record, depending on the address
+    pub fn address(self, name: &str, address: &str) -> Self {
+        let kind = match address.parse::<IpAddr>().unwrap() {
+            IpAddr::V4(_) => Kind::A,
+            IpAddr::V6(_) => Kind::Aaaa,
+        };
+        self.add(kind, name, address)
+    }
+
+    pub fn txt(self, name: &str, text: &str) -> Self {
+        self.add(Kind::Txt, name, text)
+    }
+
+    /// In order of preference
+    pub fn mx(self, name: &str, exchange: &str) -> Self {
+        self.add(Kind::Mx, name, exchange)
+    }
+
+    pub fn ptr(self, address: &str, name: &str) -> Self {
+        self.add(Kind::Ptr, address, name)
+    }
+
+    /// Any lookup of `name` fails, as if the server didn't answer
+    pub fn failing(mut self, name: &str) -> Self {
+        self.failing.push(name.to_ascii_lowercase());
+        self
+    }
+
+    pub fn lookups(&self) -> usize {
+        self.lookups.load(Ordering::SeqCst)
+    }
+
+    async fn answer(&self, kind: Kind, name: &str) -> Result<Vec<String>, DnsError> {
+        self.lookups.fetch_add(1, Ordering::SeqCst);
+        if !self.delay.is_zero() {
+            sleep(self.delay).await;
+        }
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if self.failing.contains(&name) {
+            return Err(DnsError("SERVFAIL".to_string()));
+        }
+        Ok(self.answers.get(&(kind, name)).cloned().unwrap_or_default())
+    }
+}
+
+#[async_trait]
+impl Resolver for StubResolver {
+    async fn lookup_ipv4(&self, name: &str) -> Result<Vec<Ipv4Addr>, DnsError> {
+        let answers = self.answer(Kind::A, name).await?;
+        Ok(answers
+            .iter()
+            .map(|answer| answer.parse().unwrap())
+            .collect())
+    }
+
+    async fn lookup_ipv6(&self, name: &str) -> Result<Vec<Ipv6Addr>, DnsError> {
+        let answers = self.answer(Kind::Aaaa, name).await?;
+        Ok(answers
+            .iter()
+            .map(|answer| answer.parse().unwrap())
+            .collect())
+    }
+
+    async fn lookup_txt(&self, name: &str) -> Result<Vec<String>, DnsError> {
+        self.answer(Kind::Txt, name).await
+    }
+
+    async fn lookup_mx(&self, name: &str) -> Result<Vec<String>, DnsError> {
+        self.answer(Kind::Mx, name).await
+    }
+
+    async fn lookup_ptr(&self, address: IpAddr) -> Result<Vec<String>, DnsError> {
+        self.answer(Kind::Ptr, &address.to_string()).await
+    }
+}
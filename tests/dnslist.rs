@@ -0,0 +1,204 @@
+mod common;
+mod dns_stub;
+
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+use dns_stub::StubResolver;
+use sql_greylist_milter::{
+    dnslist::{query_name, DnsLists, Listings, Thresholds, Verdict},
+    settings::{DnsListKind, DnsListZone},
+};
+use tokio::time::sleep;
+
+fn stub() -> StubResolver {
+    StubResolver::default().delayed(Duration::from_millis(200))
+}
+
+fn zone(zone: &str, kind: DnsListKind, weight: Option<i32>) -> DnsListZone {
+    DnsListZone {
+        zone: zone.to_string(),
+        kind,
+        weight,
+    }
+}
+
+fn zones() -> Vec<DnsListZone> {
+    vec![
+        zone("block.test", DnsListKind::Block, Some(2)),
+        zone("other-block.test", DnsListKind::Block, None),
+        zone("allow.test", DnsListKind::Allow, Some(3)),
+    ]
+}
+
+#[test]
+fn query_names() {
+    assert_eq!(
+        query_name("192.0.2.1".parse().unwrap(), "block.test"),
+        "1.2.0.192.block.test"
+    );
+    assert_eq!(
+        query_name("203.0.113.45".parse().unwrap(), "block.test."),
+        "45.113.0.203.block.test"
+    );
+    assert_eq!(
+        query_name("2001:db8::1".parse().unwrap(), "block.test"),
+        "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.block.test"
+    );
+}
+
+#[test]
+fn verdicts() {
+    let thresholds = Thresholds {
+        allow: 2,
+        delay: 1,
+        reject: Some(3),
+    };
+    let verdict = |score| {
+        Listings {
+            score,
+            zones: vec![],
+        }
+        .verdict(&thresholds)
+    };
+    assert_eq!(verdict(-3), Verdict::Allow);
+    assert_eq!(verdict(-2), Verdict::Allow);
+    assert_eq!(verdict(-1), Verdict::Neutral);
+    assert_eq!(verdict(0), Verdict::Neutral);
+    assert_eq!(verdict(1), Verdict::Delay);
+    assert_eq!(verdict(2), Verdict::Delay);
+    assert_eq!(verdict(3), Verdict::Reject);
+
+    let never_reject = Thresholds {
+        reject: None,
+        ..thresholds
+    };
+    assert_eq!(
+        Listings {
+            score: 100,
+            zones: vec![],
+        }
+        .verdict(&never_reject),
+        Verdict::Delay
+    );
+}
+
+#[tokio::test]
+async fn scores_listings() {
+    let resolver = Arc::new(
+        stub()
+            .listed("1.2.0.192.block.test", [127, 0, 0, 2])
+            .listed("1.2.0.192.other-block.test", [127, 0, 0, 10])
+            .listed("2.2.0.192.allow.test", [127, 0, 15, 3])
+            .listed("2.2.0.192.block.test", [127, 0, 0, 2])
+            // Refused queries and answers outside 127/8 aren't listings
+            .listed("3.2.0.192.block.test", [127, 255, 255, 254])
+            .listed("3.2.0.192.other-block.test", [192, 0, 2, 1])
+            .failing("3.2.0.192.allow.test"),
+    );
+    let lists = DnsLists::new(resolver.clone(), zones(), Duration::from_secs(60));
+
+    let started = tokio::time::Instant::now();
+    let listings = lists.check("192.0.2.1".parse().unwrap()).await;
+    // All three zones were asked at once
+    assert!(started.elapsed() < Duration::from_millis(500));
+    assert_eq!(
+        listings,
+        Listings {
+            score: 3,
+            zones: vec!["block.test".to_string(), "other-block.test".to_string()],
+        }
+    );
+    assert_eq!(
+        listings.describe(),
+        "score 3 on block.test, other-block.test"
+    );
+
+    let address: IpAddr = "::ffff:192.0.2.2".parse().unwrap();
+    assert_eq!(lists.check(address).await.score, -1);
+    assert_eq!(
+        lists.check("192.0.2.3".parse().unwrap()).await,
+        Listings::default()
+    );
+    assert_eq!(resolver.lookups(), 9);
+}
+
+#[tokio::test]
+async fn caches_answers() {
+    let resolver = Arc::new(
+        stub()
+            .listed("1.2.0.192.block.test", [127, 0, 0, 2])
+            .failing("1.2.0.192.allow.test"),
+    );
+    let lists = DnsLists::new(resolver.clone(), zones(), Duration::from_millis(500));
+    let address: IpAddr = "192.0.2.1".parse().unwrap();
+
+    assert_eq!(lists.check(address).await.score, 2);
+    assert_eq!(resolver.lookups(), 3);
+    // Failures are asked again, the rest come from the cache
+    assert_eq!(lists.check(address).await.score, 2);
+    assert_eq!(resolver.lookups(), 4);
+
+    sleep(Duration::from_millis(600)).await;
+    assert_eq!(lists.check(address).await.score, 2);
+    assert_eq!(resolver.lookups(), 7);
+}
+
+// Answers still good are dropped, oldest first, once too many are remembered
+#[tokio::test]
+async fn caps_cache() {
+    let resolver = Arc::new(StubResolver::default());
+    let zones = vec![zone("block.test", DnsListKind::Block, None)];
+    let lists = DnsLists::new(resolver.clone(), zones, Duration::from_secs(3600));
+    let addresses: Vec<IpAddr> = (0..=10_000u16)
+        .map(|i| IpAddr::from([10, 0, (i >> 8) as u8, i as u8]))
+        .collect();
+
+    for address in &addresses {
+        lists.check(*address).await;
+    }
+    assert_eq!(resolver.lookups(), 10_001);
+
+    lists.check(addresses[10_000]).await;
+    assert_eq!(resolver.lookups(), 10_001);
+    lists.check(addresses[0]).await;
+    assert_eq!(resolver.lookups(), 10_002);
+}
+
+#[test]
+fn settings() {
+    let settings = common::settings_with("dnslist", |config| {
+        config
+            + r#"
+[dns]
+nameservers = [ "192.0.2.53", "[2001:db8::53]:5353" ]
+
+[dns_lists]
+zones = [ { zone = "zen.spamhaus.org", kind = "Block", weight = 2 },
+          { zone = "list.dnswl.org", kind = "Allow" } ]
+reject_score = 4
+"#
+    });
+
+    assert_eq!(
+        settings.get_dns_nameservers(),
+        [
+            "192.0.2.53:53".parse().unwrap(),
+            "[2001:db8::53]:5353".parse().unwrap()
+        ]
+    );
+    let zones = settings.get_dns_list_zones();
+    assert_eq!(zones.len(), 2);
+    assert_eq!(zones[0].kind, DnsListKind::Block);
+    assert_eq!(zones[0].weight, Some(2));
+    assert_eq!(zones[1].zone, "list.dnswl.org");
+    assert_eq!(zones[1].weight, None);
+    assert_eq!(
+        Thresholds::from_settings(&settings),
+        Thresholds {
+            allow: 1,
+            delay: 1,
+            reject: Some(4),
+        }
+    );
+    assert_eq!(settings.get_dns_list_delay_greylist_time_seconds(), 3600);
+}
@@ -0,0 +1,46 @@
+//! Checks that only the database types compiled into this build are accepted.
+//! CI runs every test against each combination of features with cargo hack;
+//! one combination on its own, for example:
+//!
+//! cargo test --no-default-features --features sqlite,rustls
+//! cargo test --no-default-features --features postgres,mysql,native-tls
+
+mod common;
+
+use sql_greylist_milter::settings::Settings;
+
+fn settings_for(db_type: &str) -> Settings {
+    common::settings_with(&format!("features-{}", db_type), |config| {
+        config.replace("type = \"sqlite\"", &format!("type = \"{}\"", db_type))
+    })
+}
+
+#[test]
+fn postgres_url() {
+    let result = settings_for("postgres").get_db_url();
+    assert_eq!(result.is_ok(), cfg!(feature = "postgres"), "{:?}", result);
+}
+
+#[test]
+fn mysql_url() {
+    let result = settings_for("mysql").get_db_url();
+    assert_eq!(result.is_ok(), cfg!(feature = "mysql"), "{:?}", result);
+}
+
+#[test]
+fn sqlite_url() {
+    let result = settings_for("sqlite").get_db_url();
+    assert_eq!(result.is_ok(), cfg!(feature = "sqlite"), "{:?}", result);
+}
+
+#[test]
+fn unknown_type_rejected() {
+    assert!(settings_for("oracle").get_db_url().is_err());
+}
+
+#[test]
+fn embedded_is_not_sql() {
+    let settings = settings_for("embedded");
+    assert!(settings.get_db_url().is_err());
+    assert_eq!(settings.get_embedded_db_path(), Some(":memory:"));
+}
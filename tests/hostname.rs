@@ -0,0 +1,174 @@
+mod common;
+mod dns_stub;
+
+use dns_stub::StubResolver;
+use sql_greylist_milter::hostname::{ReverseDns, Rules, Verdict};
+
+fn rules() -> Rules {
+    Rules {
+        trusted_suffixes: vec!["outbound.protection.outlook.com".to_string()],
+        dynamic_keywords: [
+            "dyn", "dynamic", "pool", "dhcp", "dsl", "adsl", "vdsl", "ppp", "pppoe", "cable",
+            "dialup",
+        ]
+        .map(String::from)
+        .to_vec(),
+        delay_dynamic: true,
+        delay_no_reverse_dns: true,
+        delay_greylist_time_seconds: 3600,
+    }
+}
+
+#[tokio::test]
+async fn look_up() {
+    let resolver = StubResolver::default()
+        .ptr("192.0.2.1", "mail.example.org")
+        .address("mail.example.org", "192.0.2.1")
+        .ptr("192.0.2.2", "forged.example.org")
+        .address("forged.example.org", "198.51.100.1")
+        // Only the second name points back
+        .ptr("192.0.2.3", "old.example.org")
+        .ptr("192.0.2.3", "new.example.org")
+        .address("new.example.org", "192.0.2.3")
+        .ptr("2001:db8::25", "mail6.example.org")
+        .address("mail6.example.org", "2001:db8::25")
+        .failing("192.0.2.5");
+    let look_up = |address: &str| ReverseDns::look_up(&resolver, address.parse().unwrap());
+
+    assert_eq!(
+        look_up("192.0.2.1").await,
+        ReverseDns::Verified("mail.example.org".to_string())
+    );
+    assert_eq!(
+        look_up("::ffff:192.0.2.1").await,
+        ReverseDns::Verified("mail.example.org".to_string())
+    );
+    assert_eq!(
+        look_up("192.0.2.2").await,
+        ReverseDns::Unverified("forged.example.org".to_string())
+    );
+    assert_eq!(
+        look_up("192.0.2.3").await,
+        ReverseDns::Verified("new.example.org".to_string())
+    );
+    assert_eq!(
+        look_up("2001:db8::25").await,
+        ReverseDns::Verified("mail6.example.org".to_string())
+    );
+    assert_eq!(look_up("192.0.2.4").await, ReverseDns::Missing);
+    assert_eq!(look_up("192.0.2.5").await, ReverseDns::Failed);
+}
+
+#[test]
+fn verdicts() {
+    let rules = rules();
+    let address = "192.0.2.1".parse().unwrap();
+    let verified = |name: &str| ReverseDns::Verified(name.to_string());
+    let unverified = |name: &str| ReverseDns::Unverified(name.to_string());
+
+    assert_eq!(
+        rules.verdict(
+            address,
+            &verified("mail-db5eur01on2101.outbound.protection.outlook.com")
+        ),
+        Verdict::Trusted("mail-db5eur01on2101.outbound.protection.outlook.com".to_string())
+    );
+    // The name has to resolve back to be trusted
+    assert_eq!(
+        rules.verdict(
+            address,
+            &unverified("mail-db5eur01on2101.outbound.protection.outlook.com")
+        ),
+        Verdict::Neutral
+    );
+    assert_eq!(
+        rules.verdict(
+            address,
+            &verified("notoutbound.protection.outlook.com.example")
+        ),
+        Verdict::Neutral
+    );
+    assert_eq!(
+        rules.verdict(address, &unverified("dyn-1.isp.example")),
+        Verdict::Dynamic("dyn-1.isp.example".to_string())
+    );
+    assert_eq!(
+        rules.verdict(address, &verified("mail.example.org")),
+        Verdict::Neutral
+    );
+    assert_eq!(
+        rules.verdict(address, &ReverseDns::Missing),
+        Verdict::NoReverseDns
+    );
+    // Nothing is known if the lookup failed
+    assert_eq!(
+        rules.verdict(address, &ReverseDns::Failed),
+        Verdict::Neutral
+    );
+
+    let lenient = Rules {
+        delay_dynamic: false,
+        delay_no_reverse_dns: false,
+        ..rules
+    };
+    assert_eq!(
+        lenient.verdict(address, &unverified("dyn-1.isp.example")),
+        Verdict::Neutral
+    );
+    assert_eq!(
+        lenient.verdict(address, &ReverseDns::Missing),
+        Verdict::Neutral
+    );
+}
+
+#[test]
+fn dynamic_names() {
+    let rules = rules();
+    let address = "192.0.2.1".parse().unwrap();
+
+    assert!(rules.looks_dynamic("host-192-0-2-1.isp.example", address));
+    assert!(rules.looks_dynamic("1.2.0.192.broadband.isp.example", address));
+    assert!(rules.looks_dynamic("c0000201.isp.example", address));
+    assert!(rules.looks_dynamic("x.dynamic.isp.example", address));
+    assert!(rules.looks_dynamic("POOL-17.Isp.Example.", address));
+    assert!(rules.looks_dynamic("adsl-17.isp.example", address));
+    assert!(rules.looks_dynamic("dsl17.isp.example", address));
+    assert!(rules.looks_dynamic("cpe-dynamic-17.isp.example", address));
+    assert!(rules.looks_dynamic("ppp_17.isp.example", address));
+
+    // Keywords in the domain itself don't count
+    assert!(!rules.looks_dynamic("mail.dyn.example", address));
+    assert!(!rules.looks_dynamic("mail.poolside.example", address));
+    // Nor do keywords inside longer words
+    assert!(!rules.looks_dynamic("mail.liverpool.ac.uk", address));
+    assert!(!rules.looks_dynamic("dynamo.example.co.uk", address));
+    assert!(!rules.looks_dynamic("mx.cablevision.isp.example", address));
+    // Nor do other addresses
+    assert!(!rules.looks_dynamic("host-192-0-2-10.isp.example", address));
+    assert!(!rules.looks_dynamic("mx1.example.org", address));
+    assert!(!rules.looks_dynamic("mail.example.org", "2001:db8::1".parse().unwrap()));
+}
+
+#[test]
+fn settings() {
+    let settings = common::settings_with("hostname", |config| {
+        config
+            + r#"
+[hostnames]
+trusted_suffixes = [ ".Outbound.Protection.Outlook.com" ]
+delay_no_reverse_dns = false
+"#
+    });
+
+    assert!(settings.get_hostname_checks_enabled());
+    assert_eq!(
+        Rules::from_settings(&settings),
+        Rules {
+            delay_no_reverse_dns: false,
+            ..rules()
+        }
+    );
+
+    let settings = common::settings_with("hostname", |config| config);
+    assert!(!settings.get_hostname_checks_enabled());
+}
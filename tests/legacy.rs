@@ -0,0 +1,350 @@
+//! Runs against SQLite, like the stores in `common`
+
+#![cfg(feature = "sqlite")]
+
+mod common;
+
+use std::net::IpAddr;
+
+use common::TestStore;
+use entity::decision_rule::DecisionRule;
+use sea_orm::{ConnectionTrait, Database};
+use sql_greylist_milter::{
+    allowlist,
+    legacy::{self, LegacySummary, PostgreyList},
+};
+
+async fn allowed(
+    store: &TestStore,
+    address: &str,
+    sender_domain: &str,
+    rule: DecisionRule,
+) -> Option<String> {
+    let address: IpAddr = address.parse().unwrap();
+    store
+        .db
+        .find_allowed_client(address, sender_domain, rule)
+        .await
+        .unwrap()
+        .map(|client| client.network)
+}
+
+fn reasons(summary: &LegacySummary) -> Vec<(&str, &str)> {
+    summary
+        .skipped
+        .iter()
+        .map(|skipped| (skipped.entry.as_str(), skipped.reason))
+        .collect()
+}
+
+#[test]
+fn parse_network() {
+    for (entry, network) in [
+        ("192.0.2.1", Some("192.0.2.1/32")),
+        ("::ffff:192.0.2.1", Some("192.0.2.1/32")),
+        ("2001:db8::1", Some("2001:db8::1/128")),
+        ("192.0.2.0/24", Some("192.0.2.0/24")),
+        ("192.0.2.9/24", Some("192.0.2.0/24")),
+        ("2001:db8::/32", Some("2001:db8::/32")),
+        ("192.0.2", Some("192.0.2.0/24")),
+        ("192.0.2.", Some("192.0.2.0/24")),
+        ("10", Some("10.0.0.0/8")),
+        ("192.0.256", None),
+        ("1.2.3.4.5", None),
+        ("mx.example.com", None),
+        ("", None),
+    ] {
+        assert_eq!(
+            legacy::parse_network(entry).map(|network| network.to_string()),
+            network.map(str::to_string),
+            "{}",
+            entry
+        );
+    }
+}
+
+#[tokio::test]
+async fn import_postgrey() {
+    let target = TestStore::new("legacy-postgrey").await;
+    let clients = "\
+# postgrey whitelist for mail client hostnames
+# --------------------------------------------
+
+# Example
+gmail.com
+.Mail.Example.NET.
+/^mail\\d+\\.example\\.com$/
+*.example.org
+192.0.2      # a partner
+198.51.100.0/24
+  2001:db8::/32
+203.0.113.5
+";
+    let summary = legacy::import_postgrey(
+        target.db.as_ref(),
+        PostgreyList::Clients,
+        clients,
+        "postgrey whitelist_clients",
+        false,
+    )
+    .await
+    .unwrap();
+    assert_eq!(summary.imported, 6);
+    assert_eq!(summary.existing, 0);
+    assert_eq!(
+        reasons(&summary),
+        [
+            (
+                "/^mail\\d+\\.example\\.com$/",
+                "hostname patterns aren't supported"
+            ),
+            ("*.example.org", "not an address, network or host name"),
+        ]
+    );
+
+    // Host names cover the names under them
+    for (name, trusted) in [
+        ("gmail.com", Some("gmail.com")),
+        ("mail-wm1-f54.google.GMAIL.com", Some("gmail.com")),
+        ("mx.mail.example.net", Some("mail.example.net")),
+        ("example.net", None),
+        ("notgmail.com", None),
+    ] {
+        let found = target
+            .db
+            .find_allowed_address(
+                allowlist::domain_patterns(name),
+                DecisionRule::TrustedHostname,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            found.as_ref().map(|found| found.address.as_str()),
+            trusted,
+            "{}",
+            name
+        );
+    }
+
+    assert_eq!(
+        allowed(
+            &target,
+            "192.0.2.77",
+            "a.example",
+            DecisionRule::AllowedNetwork
+        )
+        .await
+        .as_deref(),
+        Some("192.0.2.0/24")
+    );
+    assert_eq!(
+        allowed(
+            &target,
+            "2001:db8:1::1",
+            "a.example",
+            DecisionRule::AllowedNetwork
+        )
+        .await
+        .as_deref(),
+        Some("2001:db8::/32")
+    );
+    assert_eq!(
+        allowed(
+            &target,
+            "203.0.113.5",
+            "a.example",
+            DecisionRule::AllowedNetwork
+        )
+        .await
+        .as_deref(),
+        Some("203.0.113.5/32")
+    );
+    assert!(allowed(
+        &target,
+        "203.0.113.6",
+        "a.example",
+        DecisionRule::AllowedNetwork
+    )
+    .await
+    .is_none());
+    assert!(
+        allowed(&target, "192.0.2.77", "a.example", DecisionRule::KnownGood)
+            .await
+            .is_none()
+    );
+
+    // Running it again adds nothing
+    let again = legacy::import_postgrey(
+        target.db.as_ref(),
+        PostgreyList::Clients,
+        clients,
+        "postgrey whitelist_clients",
+        false,
+    )
+    .await
+    .unwrap();
+    assert_eq!(again.imported, 0);
+    assert_eq!(again.existing, 6);
+
+    let long = format!("{}@example.com", "a".repeat(65));
+    let recipients_file = format!(
+        "postmaster@\nAbuse@Example.com\nexample.net\n/^spamtrap@/\n@example.org\n{}\n",
+        long
+    );
+    let recipients = legacy::import_postgrey(
+        target.db.as_ref(),
+        PostgreyList::Recipients,
+        &recipients_file,
+        "postgrey whitelist_recipients",
+        false,
+    )
+    .await
+    .unwrap();
+    assert_eq!(recipients.imported, 3);
+    assert_eq!(
+        reasons(&recipients),
+        [
+            ("/^spamtrap@/", "recipient patterns aren't supported"),
+            ("@example.org", "not an address, local part or domain"),
+            (long.as_str(), "too long for any recipient"),
+        ]
+    );
+    // Local parts are stored the way the milter stores recipients, so only
+    // lowercased if configured to be
+    let lowercased = legacy::import_postgrey(
+        target.db.as_ref(),
+        PostgreyList::Recipients,
+        &recipients_file,
+        "postgrey whitelist_recipients",
+        true,
+    )
+    .await
+    .unwrap();
+    assert_eq!(lowercased.imported, 1);
+    assert_eq!(lowercased.existing, 2);
+    for (local_part, domain, allowed) in [
+        ("postmaster", "example.org", "postmaster@"),
+        ("Abuse", "example.com", "Abuse@example.com"),
+        ("abuse", "example.com", "abuse@example.com"),
+        ("user", "mail.example.net", "example.net"),
+    ] {
+        let found = target
+            .db
+            .find_allowed_address(
+                allowlist::recipient_patterns(local_part, domain),
+                DecisionRule::AllowedRecipient,
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.address, allowed);
+        assert_eq!(found.source, "postgrey whitelist_recipients");
+    }
+    assert!(target
+        .db
+        .find_allowed_address(
+            allowlist::recipient_patterns("user", "example.com"),
+            DecisionRule::AllowedRecipient,
+        )
+        .await
+        .unwrap()
+        .is_none());
+
+    target.close().await;
+}
+
+#[tokio::test]
+async fn import_sqlgrey() {
+    let target = TestStore::new("legacy-sqlgrey").await;
+    let sqlgrey = Database::connect("sqlite::memory:").await.unwrap();
+    sqlgrey
+        .execute_unprepared(
+            "CREATE TABLE from_awl (sender_name varchar(64) NOT NULL, sender_domain varchar(255) NOT NULL,
+                src varchar(39) NOT NULL, first_seen timestamp NOT NULL, last_seen timestamp NOT NULL,
+                PRIMARY KEY (src, sender_domain, sender_name));
+             CREATE TABLE domain_awl (sender_domain varchar(255) NOT NULL, src varchar(39) NOT NULL,
+                first_seen timestamp NOT NULL, last_seen timestamp NOT NULL,
+                PRIMARY KEY (src, sender_domain));
+             CREATE TABLE connect (sender_name varchar(64) NOT NULL, sender_domain varchar(255) NOT NULL,
+                src varchar(39) NOT NULL, rcpt varchar(255) NOT NULL, first_seen timestamp NOT NULL);
+             INSERT INTO from_awl VALUES
+                ('alice', 'a.example', '192.0.2.1', '2020-01-01 00:00:00', '2026-01-01 00:00:00'),
+                ('bob', 'a.example', '192.0.2.1', '2020-01-01 00:00:00', '2026-01-01 00:00:00'),
+                ('carol', 'b.example', '198.51.100', '2020-01-01 00:00:00', '2026-01-01 00:00:00'),
+                ('dave', 'c.example', 'unknown', '2020-01-01 00:00:00', '2026-01-01 00:00:00');
+             INSERT INTO domain_awl VALUES
+                ('a.example', '192.0.2.1', '2020-01-01 00:00:00', '2026-01-01 00:00:00'),
+                ('d.example', '2001:db8::25', '2020-01-01 00:00:00', '2026-01-01 00:00:00');
+             INSERT INTO connect VALUES
+                ('erin', 'e.example', '203.0.113.9', 'user@example.com', '2026-10-18 00:00:00');",
+        )
+        .await
+        .unwrap();
+
+    let summary = legacy::import_sqlgrey(target.db.as_ref(), &sqlgrey)
+        .await
+        .unwrap();
+    // alice and bob only count once, and so does the same pair in domain_awl
+    assert_eq!(summary.imported, 3);
+    assert_eq!(summary.existing, 1);
+    assert_eq!(
+        reasons(&summary),
+        [
+            (
+                "from_awl unknown c.example",
+                "not an address or class C network"
+            ),
+            (
+                "connect 203.0.113.9 erin@e.example user@example.com",
+                "still greylisted, with no Message-Id to carry over"
+            ),
+        ]
+    );
+
+    let known_good = target
+        .db
+        .find_allowed_client(
+            "198.51.100.20".parse().unwrap(),
+            "b.example",
+            DecisionRule::KnownGood,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(known_good.network, "198.51.100.0/24");
+    assert_eq!(known_good.sender_domain, "b.example");
+    assert_eq!(known_good.source, "sqlgrey from_awl");
+    // Only for the sender domains they sent from
+    for (address, sender_domain, network) in [
+        ("192.0.2.1", "a.example", Some("192.0.2.1/32")),
+        ("192.0.2.1", "b.example", None),
+        ("198.51.100.20", "a.example", None),
+        ("2001:db8::25", "d.example", Some("2001:db8::25/128")),
+        ("2001:db8::25", "sub.d.example", None),
+    ] {
+        assert_eq!(
+            allowed(&target, address, sender_domain, DecisionRule::KnownGood)
+                .await
+                .as_deref(),
+            network,
+            "{} {}",
+            address,
+            sender_domain
+        );
+    }
+    assert!(allowed(
+        &target,
+        "192.0.2.1",
+        "a.example",
+        DecisionRule::AllowedNetwork
+    )
+    .await
+    .is_none());
+    assert!(
+        allowed(&target, "203.0.113.9", "e.example", DecisionRule::KnownGood)
+            .await
+            .is_none()
+    );
+
+    target.close().await;
+}
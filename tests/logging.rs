@@ -0,0 +1,89 @@
+//! Log lines carry what's needed to join them up with the MTA's. Logging is
+//! set up once per process, so this has a test crate of its own.
+
+#![cfg(feature = "sqlite")]
+
+mod common;
+
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+use indymilter::MacroStage;
+use indymilter_test::Status;
+use sql_greylist_milter::settings::LogFormat;
+use tracing::Level;
+
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn settings() {
+    let settings = common::settings_with("logging", |config| {
+        config
+            + r#"
+[logging]
+format = "Json"
+filter = "warn,sql_greylist_milter=debug"
+"#
+    });
+    assert!(matches!(settings.get_log_format(), LogFormat::Json));
+    assert_eq!(settings.get_log_filter(), "warn,sql_greylist_milter=debug");
+
+    let settings = common::settings_with("logging-default", |config| config);
+    assert!(matches!(settings.get_log_format(), LogFormat::Text));
+    assert_eq!(settings.get_log_filter(), "info");
+}
+
+// Decisions are logged within the session's span, with the queue ID
+#[tokio::test]
+async fn session_and_queue_ids() {
+    let captured = Captured::default();
+    let writer = captured.clone();
+    tracing_subscriber::fmt()
+        .with_max_level(Level::INFO)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .init();
+
+    let (mut conn, milter) = common::setup().await;
+    conn.connect("client.test.example", [123, 123, 123, 123])
+        .await
+        .unwrap();
+    conn.macros(MacroStage::Mail, [("{i}", "4ABCD1234")])
+        .await
+        .unwrap();
+    conn.mail(["<from@test.example>"]).await.unwrap();
+    conn.rcpt(["<to@test.example>"]).await.unwrap();
+    conn.header("Message-Id", "<test_logging@example.org>")
+        .await
+        .unwrap();
+    let status = conn.eoh().await.unwrap();
+    assert_eq!(status, Status::Tempfail { message: None });
+    conn.close().await.unwrap();
+    milter.shutdown().await;
+
+    let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+    let decision = output
+        .lines()
+        .find(|line| line.contains(" Greylisted "))
+        .unwrap_or_else(|| panic!("No decision logged in:\n{}", output));
+    assert!(decision.contains("session{id="), "{}", decision);
+    assert!(
+        decision.contains("queue_id=Some(\"4ABCD1234\")"),
+        "{}",
+        decision
+    );
+}
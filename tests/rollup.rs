@@ -0,0 +1,315 @@
+//! Runs against SQLite, like the stores in `common`
+
+#![cfg(feature = "sqlite")]
+
+mod common;
+
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use common::TestStore;
+use entity::{
+    decision_rule::DecisionRule,
+    email_status::EmailStatus,
+    prelude::{DecisionActive, MailActive},
+    rollup_dimension::RollupDimension,
+};
+use sea_orm::{DatabaseConnection, Set};
+use sql_greylist_milter::{
+    rollup::{self, Period, Rollup, Updater},
+    store::{GreylistStore, InsertedMail, StatusChange},
+};
+
+fn decision(status: EmailStatus, time: DateTime<Utc>) -> DecisionActive {
+    DecisionActive {
+        time_decided: Set(time.into()),
+        status: Set(status),
+        rule: Set(DecisionRule::Greylisted),
+        detail: Set(None),
+        ..Default::default()
+    }
+}
+
+/// Records a message first greylisted at `received`, retried after each of
+/// `retries` and accepted on the last one if `accepted`. Returns its ID and
+/// what was stored.
+async fn add_mail(
+    db: &dyn GreylistStore,
+    message_id: &str,
+    sender_domain: &str,
+    recipients: &[(&str, &str)],
+    received: DateTime<Utc>,
+    retries: &[i64],
+    accepted: bool,
+) -> (i32, MailActive) {
+    let mut recipient_ids = vec![];
+    for (local_part, domain) in recipients {
+        let recipient = db
+            .upsert_recipient(local_part.to_string(), domain.to_string())
+            .await
+            .unwrap();
+        recipient_ids.push(recipient.id);
+    }
+    let mail = MailActive {
+        sender_local_part: Set("sender".to_string()),
+        sender_domain: Set(sender_domain.to_string()),
+        message_id: Set(message_id.to_string()),
+        sending_ip: Set("192.0.2.1".to_string()),
+        time_received: Set(received.into()),
+        status: Set(EmailStatus::Greylisted),
+        ..Default::default()
+    };
+    let InsertedMail::New(mail_id) = db
+        .insert_mail(
+            mail.clone(),
+            recipient_ids,
+            decision(EmailStatus::Greylisted, received),
+        )
+        .await
+        .unwrap()
+    else {
+        panic!("{} already recorded", message_id);
+    };
+
+    for (i, retry) in retries.iter().enumerate() {
+        let time = received + Duration::seconds(*retry);
+        if accepted && i == retries.len() - 1 {
+            db.update_status(
+                mail_id,
+                EmailStatus::PassedGreylistAccepted,
+                Some(time.into()),
+                StatusChange::milter("retried"),
+                mail.clone(),
+                decision(EmailStatus::PassedGreylistAccepted, time),
+            )
+            .await
+            .unwrap();
+        } else {
+            db.insert_attempt(
+                mail_id,
+                mail.clone(),
+                decision(EmailStatus::Greylisted, time),
+            )
+            .await
+            .unwrap();
+        }
+    }
+    (mail_id, mail)
+}
+
+fn find<'a>(rollups: &'a [Rollup], name: &str) -> &'a Rollup {
+    rollups
+        .iter()
+        .find(|rollup| rollup.name == name)
+        .unwrap_or_else(|| panic!("No rollup for {} in {:?}", name, rollups))
+}
+
+#[tokio::test]
+async fn rollups() {
+    let store = TestStore::new("rollup").await;
+    let db = &store.db;
+    let connection = db.sql_connection().unwrap();
+
+    let yesterday = Utc::now().duration_trunc(Duration::days(1)).unwrap() - Duration::days(1);
+    let ten = yesterday + Duration::hours(10);
+    let eleven = yesterday + Duration::hours(11);
+    add_mail(
+        db.as_ref(),
+        "<1@a.example>",
+        "a.example",
+        &[("one", "x.example"), ("two", "x.example")],
+        ten,
+        &[300],
+        true,
+    )
+    .await;
+    add_mail(
+        db.as_ref(),
+        "<2@a.example>",
+        "a.example",
+        &[("one", "x.example"), ("three", "y.example")],
+        ten + Duration::minutes(5),
+        &[60, 900],
+        true,
+    )
+    .await;
+    add_mail(
+        db.as_ref(),
+        "<3@b.example>",
+        "b.example",
+        &[("one", "x.example")],
+        eleven,
+        &[],
+        false,
+    )
+    .await;
+
+    rollup::update(connection).await.unwrap();
+    // Updating again replaces the rows rather than adding to them
+    rollup::update(connection).await.unwrap();
+
+    let daily = rollup::load(
+        connection,
+        Period::Daily,
+        RollupDimension::SenderDomain,
+        yesterday,
+    )
+    .await
+    .unwrap();
+    assert_eq!(daily.len(), 2, "{:?}", daily);
+    let a = find(&daily, "a.example");
+    assert_eq!(a.period_start, yesterday.fixed_offset());
+    assert_eq!(
+        (a.messages, a.greylisted, a.never_retried),
+        (2, 2, 0),
+        "{:?}",
+        a
+    );
+    // Only the first retry counts
+    assert_eq!(a.median_retry_seconds, Some(180));
+    let b = find(&daily, "b.example");
+    assert_eq!((b.messages, b.greylisted, b.never_retried), (1, 1, 1));
+    assert_eq!(b.median_retry_seconds, None);
+
+    let statuses = rollup::load(
+        connection,
+        Period::Daily,
+        RollupDimension::Status,
+        yesterday,
+    )
+    .await
+    .unwrap();
+    assert_eq!(find(&statuses, "PassedGreylistAccepted").messages, 2);
+    assert_eq!(find(&statuses, "Greylisted").messages, 1);
+
+    // Messages count once per recipient domain, not once per recipient
+    let recipients = rollup::load(
+        connection,
+        Period::Daily,
+        RollupDimension::RecipientDomain,
+        yesterday,
+    )
+    .await
+    .unwrap();
+    assert_eq!(find(&recipients, "x.example").messages, 3);
+    assert_eq!(find(&recipients, "y.example").messages, 1);
+
+    let hourly = rollup::load(
+        connection,
+        Period::Hourly,
+        RollupDimension::SenderDomain,
+        yesterday,
+    )
+    .await
+    .unwrap();
+    let periods: Vec<_> = hourly
+        .iter()
+        .map(|rollup| (rollup.period_start, rollup.name.as_str(), rollup.messages))
+        .collect();
+    assert_eq!(
+        periods,
+        [
+            (ten.fixed_offset(), "a.example", 2),
+            (eleven.fixed_offset(), "b.example", 1)
+        ]
+    );
+
+    store.close().await;
+}
+
+async fn load_all(connection: &DatabaseConnection, since: DateTime<Utc>) -> Vec<Rollup> {
+    let mut rollups = vec![];
+    for period in [Period::Hourly, Period::Daily] {
+        for dimension in [
+            RollupDimension::Status,
+            RollupDimension::SenderDomain,
+            RollupDimension::RecipientDomain,
+        ] {
+            rollups.extend(
+                rollup::load(connection, period, dimension, since)
+                    .await
+                    .unwrap(),
+            );
+        }
+    }
+    rollups
+}
+
+// After the first update, only the hours that changed are read again, and
+// the rows come out as if everything had been
+#[tokio::test]
+async fn incremental_updates() {
+    let store = TestStore::new("rollup-incremental").await;
+    let db = &store.db;
+    let connection = db.sql_connection().unwrap();
+
+    let now = Utc::now();
+    let earlier = now - Duration::hours(2);
+    let since = earlier.duration_trunc(Duration::days(1)).unwrap();
+    let (mail_id, mail) = add_mail(
+        db.as_ref(),
+        "<1@a.example>",
+        "a.example",
+        &[("one", "x.example")],
+        earlier,
+        &[],
+        false,
+    )
+    .await;
+    add_mail(
+        db.as_ref(),
+        "<2@b.example>",
+        "b.example",
+        &[("one", "x.example")],
+        earlier,
+        &[],
+        false,
+    )
+    .await;
+
+    let mut updater = Updater::default();
+    updater.update(connection).await.unwrap();
+
+    // The first message is retried now, and another one comes in
+    db.update_status(
+        mail_id,
+        EmailStatus::PassedGreylistAccepted,
+        Some(now.into()),
+        StatusChange::milter("retried"),
+        mail,
+        decision(EmailStatus::PassedGreylistAccepted, now),
+    )
+    .await
+    .unwrap();
+    add_mail(
+        db.as_ref(),
+        "<3@a.example>",
+        "a.example",
+        &[("two", "y.example")],
+        now,
+        &[],
+        false,
+    )
+    .await;
+    updater.update(connection).await.unwrap();
+
+    let incremental = load_all(connection, since).await;
+    let statuses = rollup::load(connection, Period::Daily, RollupDimension::Status, since)
+        .await
+        .unwrap();
+    // The two hours can fall on different days
+    let messages = |name: &str| -> i32 {
+        statuses
+            .iter()
+            .filter(|rollup| rollup.name == name)
+            .map(|rollup| rollup.messages)
+            .sum()
+    };
+    assert_eq!(messages("PassedGreylistAccepted"), 1);
+    assert_eq!(messages("Greylisted"), 2);
+    let a = find(&incremental, "a.example");
+    assert_eq!(a.median_retry_seconds, Some(7200));
+
+    rollup::update(connection).await.unwrap();
+    assert_eq!(load_all(connection, since).await, incremental);
+
+    store.close().await;
+}
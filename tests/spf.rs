@@ -0,0 +1,391 @@
+mod common;
+mod dns_stub;
+
+use std::sync::Arc;
+
+use dns_stub::StubResolver;
+use entity::spf_result::SpfResult;
+use sql_greylist_milter::{
+    settings::SpfFailAction,
+    spf::{Policy, Spf},
+};
+
+async fn check(resolver: StubResolver, address: &str, sender: &str) -> SpfResult {
+    let (local_part, domain) = sender.split_once('@').unwrap();
+    Spf::new(Arc::new(resolver))
+        .check(address.parse().unwrap(), local_part, domain, None)
+        .await
+}
+
+#[tokio::test]
+async fn qualifiers() {
+    let resolver = || {
+        StubResolver::default()
+            .txt("pass.example", "v=spf1 ip4:192.0.2.0/24 -all")
+            .txt("soft.example", "v=spf1 ip4:192.0.2.0/24 ~all")
+            .txt("neutral.example", "v=spf1 ?all")
+            .txt("open.example", "v=spf1 ip4:198.51.100.1")
+            .txt("none.example", "google-site-verification=abc")
+    };
+    let from = |domain: &str| format!("sender@{}", domain);
+
+    assert_eq!(
+        check(resolver(), "192.0.2.9", &from("pass.example")).await,
+        SpfResult::Pass
+    );
+    assert_eq!(
+        check(resolver(), "::ffff:192.0.2.9", &from("pass.example")).await,
+        SpfResult::Pass
+    );
+    assert_eq!(
+        check(resolver(), "198.51.100.1", &from("pass.example")).await,
+        SpfResult::Fail
+    );
+    assert_eq!(
+        check(resolver(), "198.51.100.1", &from("soft.example")).await,
+        SpfResult::SoftFail
+    );
+    assert_eq!(
+        check(resolver(), "198.51.100.1", &from("neutral.example")).await,
+        SpfResult::Neutral
+    );
+    // Nothing matched and no all
+    assert_eq!(
+        check(resolver(), "192.0.2.9", &from("open.example")).await,
+        SpfResult::Neutral
+    );
+    assert_eq!(
+        check(resolver(), "192.0.2.9", &from("none.example")).await,
+        SpfResult::None
+    );
+    assert_eq!(
+        check(resolver(), "192.0.2.9", "sender@localhost").await,
+        SpfResult::None
+    );
+}
+
+#[tokio::test]
+async fn mechanisms() {
+    let resolver = || {
+        StubResolver::default()
+            .txt(
+                "sender.example",
+                "v=spf1 a/28 mx:mail.example//64 ptr include:_spf.provider.example -all",
+            )
+            .address("sender.example", "192.0.2.1")
+            .mx("mail.example", "mx1.mail.example")
+            .mx("mail.example", "mx2.mail.example")
+            .address("mx2.mail.example", "2001:db8:1::25")
+            .ptr("203.0.113.5", "out.sender.example")
+            .address("out.sender.example", "203.0.113.5")
+            .ptr("203.0.113.6", "forged.sender.example")
+            .txt(
+                "_spf.provider.example",
+                "v=spf1 ip6:2001:db8:2::/48 ip4:198.51.100.0/24",
+            )
+    };
+    let sender = "sender@sender.example";
+
+    // a with a prefix length
+    assert_eq!(
+        check(resolver(), "192.0.2.14", sender).await,
+        SpfResult::Pass
+    );
+    assert_eq!(
+        check(resolver(), "192.0.2.17", sender).await,
+        SpfResult::Fail
+    );
+    // mx, with the IPv6 prefix length
+    assert_eq!(
+        check(resolver(), "2001:db8:1::99", sender).await,
+        SpfResult::Pass
+    );
+    // ptr, only if the name resolves back to the client
+    assert_eq!(
+        check(resolver(), "203.0.113.5", sender).await,
+        SpfResult::Pass
+    );
+    assert_eq!(
+        check(resolver(), "203.0.113.6", sender).await,
+        SpfResult::Fail
+    );
+    // include
+    assert_eq!(
+        check(resolver(), "2001:db8:2::1", sender).await,
+        SpfResult::Pass
+    );
+    assert_eq!(
+        check(resolver(), "198.51.100.20", sender).await,
+        SpfResult::Pass
+    );
+}
+
+#[tokio::test]
+async fn includes_and_redirects() {
+    let resolver = || {
+        StubResolver::default()
+            .txt("sender.example", "v=spf1 include:failing.example ~all")
+            .txt("failing.example", "v=spf1 -all")
+            .txt("redirected.example", "v=spf1 redirect=sender.example")
+            .txt("missing.example", "v=spf1 include:nothing.example -all")
+            .txt("lost.example", "v=spf1 redirect=nothing.example")
+            .txt("broken.example", "v=spf1 include:dead.example -all")
+            .failing("dead.example")
+    };
+    let from = |domain: &str| format!("sender@{}", domain);
+
+    // A fail inside the include is only no match
+    assert_eq!(
+        check(resolver(), "192.0.2.1", &from("sender.example")).await,
+        SpfResult::SoftFail
+    );
+    assert_eq!(
+        check(resolver(), "192.0.2.1", &from("redirected.example")).await,
+        SpfResult::SoftFail
+    );
+    assert_eq!(
+        check(resolver(), "192.0.2.1", &from("missing.example")).await,
+        SpfResult::PermError
+    );
+    assert_eq!(
+        check(resolver(), "192.0.2.1", &from("lost.example")).await,
+        SpfResult::PermError
+    );
+    assert_eq!(
+        check(resolver(), "192.0.2.1", &from("broken.example")).await,
+        SpfResult::TempError
+    );
+    assert_eq!(
+        check(resolver(), "192.0.2.1", &from("dead.example")).await,
+        SpfResult::TempError
+    );
+}
+
+#[tokio::test]
+async fn broken_records() {
+    let resolver = StubResolver::default()
+        .txt("two.example", "v=spf1 -all")
+        .txt("two.example", "v=spf1 +all")
+        .txt("unknown.example", "v=spf1 foo:bar -all")
+        .txt("cidr.example", "v=spf1 ip4:192.0.2.0/33 -all")
+        .txt(
+            "modifier.example",
+            "v=spf1 -all redirect=a.example redirect=b.example",
+        )
+        .txt(
+            "void.example",
+            "v=spf1 a:a.example a:b.example a:c.example +all",
+        )
+        .txt("loop.example", "v=spf1 include:loop.example -all")
+        // Unknown modifiers and other versions are ignored
+        .txt("future.example", "v=spf10 -all")
+        .txt("future.example", "v=spf1 ip4:192.0.2.1 note=hi -all");
+    let resolver = Arc::new(resolver);
+    let spf = Spf::new(resolver.clone());
+    let check = |domain: &'static str| {
+        let spf = &spf;
+        async move {
+            spf.check("192.0.2.1".parse().unwrap(), "sender", domain, None)
+                .await
+        }
+    };
+
+    assert_eq!(check("two.example").await, SpfResult::PermError);
+    assert_eq!(check("unknown.example").await, SpfResult::PermError);
+    assert_eq!(check("cidr.example").await, SpfResult::PermError);
+    assert_eq!(check("modifier.example").await, SpfResult::PermError);
+    assert_eq!(check("void.example").await, SpfResult::PermError);
+
+    let before = resolver.lookups();
+    assert_eq!(check("loop.example").await, SpfResult::PermError);
+    // The record itself and ten includes, then it gives up
+    assert_eq!(resolver.lookups() - before, 11);
+
+    assert_eq!(check("future.example").await, SpfResult::Pass);
+}
+
+#[tokio::test]
+async fn limits() {
+    let hosts = |count: usize| {
+        (1..=count)
+            .map(|n| format!("a:h{}.example", n))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    let mut resolver = StubResolver::default()
+        .txt("ten.example", &format!("v=spf1 {} -all", hosts(10)))
+        .txt("eleven.example", &format!("v=spf1 {} -all", hosts(11)))
+        .txt(
+            "two-void.example",
+            "v=spf1 a:void1.example a:void2.example +all",
+        )
+        .txt("mx-void.example", "v=spf1 mx -all")
+        .mx("mx-void.example", "mx1.void.example")
+        .mx("mx-void.example", "mx2.void.example")
+        .mx("mx-void.example", "mx3.void.example")
+        .txt("mx-two-void.example", "v=spf1 mx -all")
+        .mx("mx-two-void.example", "mx1.void.example")
+        .mx("mx-two-void.example", "mx2.void.example")
+        .mx("mx-two-void.example", "mx.sender.example")
+        .address("mx.sender.example", "192.0.2.1")
+        .txt("ping.example", "v=spf1 include:pong.example -all")
+        .txt("pong.example", "v=spf1 include:ping.example -all")
+        .txt("redirects.example", "v=spf1 redirect=r1.example")
+        .txt("r11.example", "v=spf1 +all");
+    for n in 1..=11 {
+        resolver = resolver.address(&format!("h{}.example", n), "198.51.100.1");
+    }
+    for n in 1..=10 {
+        resolver = resolver.txt(
+            &format!("r{}.example", n),
+            &format!("v=spf1 redirect=r{}.example", n + 1),
+        );
+    }
+    let resolver = Arc::new(resolver);
+    let spf = Spf::new(resolver);
+
+    for (domain, expected) in [
+        // Ten lookups are allowed, the eleventh is one too many
+        ("ten.example", SpfResult::Fail),
+        ("eleven.example", SpfResult::PermError),
+        // As are two lookups that find nothing, but not three, counting the
+        // addresses of MX exchanges
+        ("two-void.example", SpfResult::Pass),
+        ("mx-void.example", SpfResult::PermError),
+        ("mx-two-void.example", SpfResult::Pass),
+        // Includes of each other, until the lookups run out
+        ("ping.example", SpfResult::PermError),
+        // Eleven redirects to get to a pass
+        ("redirects.example", SpfResult::PermError),
+    ] {
+        let result = spf
+            .check("192.0.2.1".parse().unwrap(), "sender", domain, None)
+            .await;
+        assert_eq!(result, expected, "{}", domain);
+    }
+}
+
+#[tokio::test]
+async fn modifiers() {
+    let resolver = Arc::new(
+        StubResolver::default()
+            // all always matches, so the redirect is never followed
+            .txt("all.example", "v=spf1 -all redirect=pass.example")
+            .txt(
+                "other.example",
+                "v=spf1 ip4:198.51.100.1 redirect=pass.example",
+            )
+            .txt("pass.example", "v=spf1 +all")
+            // Explanations are only parsed, never looked up
+            .txt("exp.example", "v=spf1 -all exp=explain.%{d}")
+            .txt("two-exp.example", "v=spf1 -all exp=a.example exp=b.example")
+            .txt("empty-redirect.example", "v=spf1 redirect="),
+    );
+    let spf = Spf::new(resolver.clone());
+
+    for (domain, expected, lookups) in [
+        ("all.example", SpfResult::Fail, 1),
+        ("other.example", SpfResult::Pass, 2),
+        ("exp.example", SpfResult::Fail, 1),
+        ("two-exp.example", SpfResult::PermError, 1),
+        ("empty-redirect.example", SpfResult::PermError, 1),
+    ] {
+        let before = resolver.lookups();
+        let result = spf
+            .check("192.0.2.1".parse().unwrap(), "sender", domain, None)
+            .await;
+        assert_eq!(result, expected, "{}", domain);
+        assert_eq!(resolver.lookups() - before, lookups, "{}", domain);
+    }
+}
+
+#[tokio::test]
+async fn macros() {
+    // Along the lines of the examples in RFC 7208 section 7.4
+    let resolver = || {
+        StubResolver::default()
+            .txt(
+                "email.example.com",
+                "v=spf1 exists:%{ir}.%{l1r+-}._spf.%{d} exists:%{d2}.%{v}.%{L}.test -all",
+            )
+            .address("3.2.0.192.strong._spf.email.example.com", "127.0.0.2")
+            .address("example.com.ip6.good%2Bguy.test", "127.0.0.2")
+    };
+
+    assert_eq!(
+        check(resolver(), "192.0.2.3", "strong-bad@email.example.com").await,
+        SpfResult::Pass
+    );
+    assert_eq!(
+        check(resolver(), "192.0.2.4", "strong-bad@email.example.com").await,
+        SpfResult::Fail
+    );
+    // Upper case letters are URL-escaped
+    assert_eq!(
+        check(resolver(), "2001:db8::cb01", "good+guy@email.example.com").await,
+        SpfResult::Pass
+    );
+    assert_eq!(
+        check(resolver(), "192.0.2.3", "good+guy@email.example.com").await,
+        SpfResult::Fail
+    );
+
+    // Escapes expand to themselves, anything else after % is an error
+    let resolver = || {
+        StubResolver::default()
+            .txt("escapes.example", "v=spf1 exists:%%%_%-.%{d} -all")
+            .address("% %20.escapes.example", "127.0.0.2")
+            .txt("letter.example", "v=spf1 exists:%{z}.%{d} -all")
+            .txt("zero.example", "v=spf1 exists:%{d0}.test -all")
+            .txt("open.example", "v=spf1 exists:%{d -all")
+            .txt("trailing.example", "v=spf1 exists:%{d}% -all")
+            .txt("delimiter.example", "v=spf1 exists:%{l!}.%{d} -all")
+    };
+    for (domain, expected) in [
+        ("escapes.example", SpfResult::Pass),
+        ("letter.example", SpfResult::PermError),
+        ("zero.example", SpfResult::PermError),
+        ("open.example", SpfResult::PermError),
+        ("trailing.example", SpfResult::PermError),
+        ("delimiter.example", SpfResult::PermError),
+    ] {
+        assert_eq!(
+            check(resolver(), "192.0.2.1", &format!("sender@{}", domain)).await,
+            expected,
+            "{}",
+            domain
+        );
+    }
+}
+
+#[test]
+fn settings() {
+    let settings = common::settings_with("spf", |config| {
+        config
+            + r#"
+[spf]
+trusted_domains = [ "Provider.Example." ]
+on_fail = "Reject"
+"#
+    });
+
+    assert!(settings.get_spf_enabled());
+    let policy = Policy::from_settings(&settings);
+    assert_eq!(
+        policy,
+        Policy {
+            trusted_domains: vec!["provider.example".to_string()],
+            known_good_by_domain: true,
+            on_fail: SpfFailAction::Reject,
+            fail_greylist_time_seconds: 3600,
+        }
+    );
+    assert!(policy.is_trusted("provider.example"));
+    assert!(policy.is_trusted("mail.PROVIDER.example"));
+    assert!(!policy.is_trusted("otherprovider.example"));
+    assert!(!policy.is_trusted("example"));
+
+    let settings = common::settings_with("spf", |config| config);
+    assert!(!settings.get_spf_enabled());
+    assert_eq!(settings.get_spf_on_fail(), SpfFailAction::Delay);
+}
@@ -0,0 +1,91 @@
+//! What the milter tells systemd through `NOTIFY_SOCKET`. The socket is
+//! process-wide, so everything is checked from the one test.
+
+#![cfg(feature = "sqlite")]
+
+mod common;
+
+use std::{env, process, time::Duration};
+
+use tokio::{net::UnixDatagram, time::timeout};
+
+// The next notification other than a watchdog keep-alive
+async fn next(socket: &UnixDatagram) -> String {
+    loop {
+        let message = next_or_watchdog(socket).await;
+        if message != "WATCHDOG=1\n" {
+            return message;
+        }
+    }
+}
+
+async fn next_or_watchdog(socket: &UnixDatagram) -> String {
+    let mut buf = [0; 512];
+    let len = timeout(Duration::from_secs(15), socket.recv(&mut buf))
+        .await
+        .expect("No notification from the milter")
+        .unwrap();
+    String::from_utf8(buf[..len].to_vec()).unwrap()
+}
+
+#[tokio::test]
+async fn notifications() {
+    let path = common::temp_path("systemd.sock");
+    let _ = std::fs::remove_file(&path);
+    let socket = UnixDatagram::bind(&path).unwrap();
+    env::set_var("NOTIFY_SOCKET", &path);
+    // Keep-alives every 100ms
+    env::set_var("WATCHDOG_USEC", "200000");
+    env::set_var("WATCHDOG_PID", process::id().to_string());
+
+    let (conn, milter) = common::setup().await;
+    conn.close().await.unwrap();
+    assert_eq!(next(&socket).await, "READY=1\nSTATUS=Ready\n");
+    // Only sent while the database answers
+    assert_eq!(next_or_watchdog(&socket).await, "WATCHDOG=1\n");
+    milter.shutdown().await;
+    assert_eq!(next(&socket).await, "STOPPING=1\n");
+
+    #[cfg(feature = "embedded")]
+    degraded_start(&socket).await;
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+// Ready straight away, saying why it's degraded until the database is up
+#[cfg(feature = "embedded")]
+async fn degraded_start(socket: &UnixDatagram) {
+    use sql_greylist_milter::store;
+
+    let path = common::temp_path("systemd-degraded.redb");
+    let embedded = |config: String| {
+        config
+            .replace("type = \"sqlite\"", "type = \"embedded\"")
+            .replace(
+                "db_name = \":memory:\"",
+                &format!("db_name = \"{}\"", path.display()),
+            )
+    };
+    // Holding the file keeps the milter from opening it
+    let holder = store::open(&common::settings_with("systemd-degraded", embedded))
+        .await
+        .unwrap();
+
+    let (conn, milter) = common::setup_with(|config| {
+        embedded(config).replace("port = 0", "port = 0\ndegraded_start = true")
+    })
+    .await;
+    conn.close().await.unwrap();
+    assert_eq!(
+        next(socket).await,
+        "READY=1\nSTATUS=Degraded, failing closed until the database is available\n"
+    );
+
+    holder.close().await.unwrap();
+    drop(holder);
+    assert_eq!(next(socket).await, "STATUS=Ready\n");
+
+    milter.shutdown().await;
+    assert_eq!(next(socket).await, "STOPPING=1\n");
+    std::fs::remove_file(&path).unwrap();
+}
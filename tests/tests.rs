@@ -1,11 +1,34 @@
+//! Runs against SQLite, like the stores in `common`
+
+#![cfg(feature = "sqlite")]
+
 mod common;
+mod dns_stub;
 
+use std::time::Duration;
+
+use dns_stub::StubResolver;
+use entity::{
+    decision,
+    decision_rule::DecisionRule,
+    delivery_attempt,
+    email_status::EmailStatus,
+    prelude::{DecisionEntity, DeliveryAttemptEntity, MailEntity},
+    spf_result::SpfResult,
+};
 use indymilter::{Actions, MacroStage};
 use indymilter_test::*;
+use sea_orm::{ConnectionTrait, EntityTrait, PaginatorTrait, QueryOrder};
+#[cfg(feature = "embedded")]
+use sql_greylist_milter::store;
+use tokio::{
+    task::JoinHandle,
+    time::{sleep, timeout, Instant},
+};
 
 #[tokio::test]
 async fn greylist() {
-    let (mut conn, shutdown_sender) = common::setup().await;
+    let (mut conn, milter) = common::setup().await;
 
     assert_eq!(
         conn.negotiated_actions(),
@@ -35,12 +58,74 @@ async fn greylist() {
 
     conn.close().await.unwrap();
 
-    common::shutdown(shutdown_sender);
+    milter.shutdown().await;
+}
+
+// Some senders go past the RFC 5321 limits, e.g. with long VERP addresses.
+// Cut short, those would be stored as the same recipient, so they're refused.
+#[tokio::test]
+async fn long_recipient() {
+    let (mut conn, milter) = common::setup().await;
+
+    let status = conn
+        .connect("client.test.example", [123, 123, 123, 123])
+        .await
+        .unwrap();
+    assert_eq!(status, Status::Continue);
+
+    let status = conn.mail(["<from@test.example>"]).await.unwrap();
+    assert_eq!(status, Status::Continue);
+
+    let recipient = format!("<bounces+{}@test.example>", "0123456789".repeat(10));
+    let status = conn.rcpt([recipient.as_str()]).await.unwrap();
+    assert_eq!(status, Status::Reject { message: None });
+
+    conn.close().await.unwrap();
+
+    milter.shutdown().await;
+}
+
+#[tokio::test]
+async fn sender_domain_lowercased() {
+    let (mut conn, milter) = common::setup().await;
+
+    let status = conn
+        .connect("client.test.example", [123, 123, 123, 123])
+        .await
+        .unwrap();
+    assert_eq!(status, Status::Continue);
+
+    let status = conn.mail(["<From@Test.EXAMPLE>"]).await.unwrap();
+    assert_eq!(status, Status::Continue);
+
+    let status = conn.rcpt(["<to@test.example>"]).await.unwrap();
+    assert_eq!(status, Status::Continue);
+
+    let status = conn
+        .header("Message-Id", "<test_sender_domain@example.org>")
+        .await
+        .unwrap();
+    assert_eq!(status, Status::Continue);
+
+    let status = conn.eoh().await.unwrap();
+    assert_eq!(status, Status::Tempfail { message: None });
+
+    conn.close().await.unwrap();
+
+    let mail = MailEntity::find()
+        .one(&milter.db().await)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(mail.sender_local_part, "From");
+    assert_eq!(mail.sender_domain, "test.example");
+
+    milter.shutdown().await;
 }
 
 #[tokio::test]
 async fn ip_accept() {
-    let (mut conn, shutdown_sender) = common::setup().await;
+    let (mut conn, milter) = common::setup().await;
 
     assert_eq!(
         conn.negotiated_actions(),
@@ -73,12 +158,12 @@ async fn ip_accept() {
 
     conn.close().await.unwrap();
 
-    common::shutdown(shutdown_sender);
+    milter.shutdown().await;
 }
 
 #[tokio::test]
 async fn auth_accept() {
-    let (mut conn, shutdown_sender) = common::setup().await;
+    let (mut conn, milter) = common::setup().await;
 
     assert_eq!(
         conn.negotiated_actions(),
@@ -115,5 +200,925 @@ async fn auth_accept() {
 
     conn.close().await.unwrap();
 
-    common::shutdown(shutdown_sender);
+    milter.shutdown().await;
+}
+
+#[tokio::test]
+async fn greylist_retry_too_soon() {
+    let (mut conn, milter) = common::setup().await;
+
+    for _ in 0..2 {
+        let status = conn
+            .connect("client.test.example", [123, 123, 123, 124])
+            .await
+            .unwrap();
+        assert_eq!(status, Status::Continue);
+
+        let status = conn.mail(["<from@test.example>"]).await.unwrap();
+        assert_eq!(status, Status::Continue);
+
+        let status = conn.rcpt(["<to@test.example>"]).await.unwrap();
+        assert_eq!(status, Status::Continue);
+
+        let status = conn
+            .header("Message-Id", "<test_greylist_retry_too_soon@example.org>")
+            .await
+            .unwrap();
+        assert_eq!(status, Status::Continue);
+
+        let status = conn.eoh().await.unwrap();
+        assert_eq!(status, Status::Tempfail { message: None });
+
+        conn.close().await.unwrap();
+        conn = milter.connect().await;
+    }
+
+    conn.close().await.unwrap();
+
+    assert_eq!(
+        decisions(&milter).await,
+        [
+            (EmailStatus::Greylisted, DecisionRule::Greylisted),
+            (EmailStatus::Greylisted, DecisionRule::ExistingMessage),
+        ]
+    );
+
+    milter.shutdown().await;
+}
+
+#[tokio::test]
+async fn delivery_attempts() {
+    let (conn, milter) = common::setup().await;
+    conn.close().await.unwrap();
+
+    // The same message, tried again from another of the sender's servers
+    for client in [[123, 123, 127, 1], [123, 123, 127, 2]] {
+        let status = send(
+            &milter,
+            client,
+            "<from@test.example>",
+            &["<to@test.example>"],
+            "<test_delivery_attempts@example.org>",
+        )
+        .await;
+        assert_eq!(status, Status::Tempfail { message: None });
+    }
+
+    let db = milter.db().await;
+    let mail = MailEntity::find().one(&db).await.unwrap().unwrap();
+    assert_eq!(mail.sending_ip, "123.123.127.1");
+    let attempts = DeliveryAttemptEntity::find()
+        .order_by_asc(delivery_attempt::Column::Id)
+        .all(&db)
+        .await
+        .unwrap();
+    assert_eq!(
+        attempts
+            .iter()
+            .map(|attempt| (
+                attempt.mail_id,
+                attempt.sending_ip.as_str(),
+                attempt.sending_host_name.as_deref(),
+                attempt.status.clone(),
+            ))
+            .collect::<Vec<_>>(),
+        [
+            (
+                mail.id,
+                "123.123.127.1",
+                Some("client.test.example"),
+                EmailStatus::Greylisted
+            ),
+            (
+                mail.id,
+                "123.123.127.2",
+                Some("client.test.example"),
+                EmailStatus::Greylisted
+            ),
+        ]
+    );
+
+    milter.shutdown().await;
+}
+
+#[tokio::test]
+async fn session_metadata() {
+    let (mut conn, milter) = common::setup().await;
+
+    let status = conn
+        .connect("client.test.example", [123, 123, 123, 125])
+        .await
+        .unwrap();
+    assert_eq!(status, Status::Continue);
+
+    let status = conn.helo("mx.test.example").await.unwrap();
+    assert_eq!(status, Status::Continue);
+
+    conn.macros(
+        MacroStage::Mail,
+        [
+            ("{auth_authen}", "user"),
+            ("{i}", "4ABCD1234"),
+            ("{tls_version}", "TLSv1.3"),
+            ("{cipher}", "TLS_AES_256_GCM_SHA384"),
+            ("{daemon_name}", "smtpd"),
+        ],
+    )
+    .await
+    .unwrap();
+
+    let status = conn
+        .mail(["<from@test.example>", "SIZE=12345", "BODY=8BITMIME"])
+        .await
+        .unwrap();
+    assert_eq!(status, Status::Continue);
+
+    let status = conn.rcpt(["<to@test.example>"]).await.unwrap();
+    assert_eq!(status, Status::Continue);
+
+    let status = conn
+        .header("Message-Id", "<test_session_metadata@example.org>")
+        .await
+        .unwrap();
+    assert_eq!(status, Status::Continue);
+
+    let status = conn.eoh().await.unwrap();
+    assert_eq!(status, Status::Tempfail { message: None });
+
+    conn.close().await.unwrap();
+
+    let mail = MailEntity::find()
+        .one(&milter.db().await)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(mail.helo_name.as_deref(), Some("mx.test.example"));
+    assert_eq!(mail.queue_id.as_deref(), Some("4ABCD1234"));
+    assert_eq!(mail.auth_user.as_deref(), Some("user"));
+    assert_eq!(mail.tls_version.as_deref(), Some("TLSv1.3"));
+    assert_eq!(mail.tls_cipher.as_deref(), Some("TLS_AES_256_GCM_SHA384"));
+    assert_eq!(mail.message_size, Some(12345));
+    assert_eq!(mail.daemon_name.as_deref(), Some("smtpd"));
+
+    milter.shutdown().await;
+}
+
+#[tokio::test]
+async fn database_errors() {
+    for (fail_mode, expected) in [
+        ("Open", Status::Continue),
+        ("Closed", Status::Tempfail { message: None }),
+    ] {
+        let (mut conn, milter) = common::setup_with(|config| {
+            config.replace(
+                "port = 0",
+                &format!("port = 0\nfail_mode = \"{}\"", fail_mode),
+            )
+        })
+        .await;
+        conn.close().await.unwrap();
+        let db = milter.db().await;
+
+        // Nowhere to record the decision
+        db.execute_unprepared("ALTER TABLE decision RENAME TO decision_gone")
+            .await
+            .unwrap();
+        let status = send(
+            &milter,
+            [123, 123, 125, 1],
+            "<from@test.example>",
+            &["<to@test.example>"],
+            "<test_database_errors@example.org>",
+        )
+        .await;
+        assert_eq!(status, expected, "{}", fail_mode);
+        assert_eq!(MailEntity::find().count(&db).await.unwrap(), 0);
+
+        // Nor to store recipients
+        db.execute_unprepared("ALTER TABLE recipient RENAME TO recipient_gone")
+            .await
+            .unwrap();
+        conn = milter.connect().await;
+
+        let status = conn
+            .connect("client.test.example", [123, 123, 125, 2])
+            .await
+            .unwrap();
+        assert_eq!(status, Status::Continue);
+
+        let status = conn.mail(["<from@test.example>"]).await.unwrap();
+        assert_eq!(status, Status::Continue);
+
+        let status = conn.rcpt(["<new@test.example>"]).await.unwrap();
+        assert_eq!(status, expected, "{}", fail_mode);
+
+        // Failing open, the rest of the session goes without the database
+        if status == Status::Continue {
+            let status = conn
+                .header("Message-Id", "<test_database_errors2@example.org>")
+                .await
+                .unwrap();
+            assert_eq!(status, Status::Continue);
+
+            let status = conn.eoh().await.unwrap();
+            assert_eq!(status, Status::Continue);
+        }
+
+        conn.close().await.unwrap();
+        assert_eq!(MailEntity::find().count(&db).await.unwrap(), 0);
+
+        milter.shutdown().await;
+    }
+}
+
+// The database can't be opened at first, here because the test holds it
+#[cfg(feature = "embedded")]
+#[tokio::test]
+async fn degraded_start() {
+    let path = common::temp_path("degraded.redb");
+    let embedded = |config: String| {
+        config
+            .replace("type = \"sqlite\"", "type = \"embedded\"")
+            .replace(
+                "db_name = \":memory:\"",
+                &format!("db_name = \"{}\"", path.display()),
+            )
+    };
+    let holder = store::open(&common::settings_with("degraded", embedded))
+        .await
+        .unwrap();
+
+    let (conn, milter) = common::setup_with(|config| {
+        embedded(config).replace("port = 0", "port = 0\ndegraded_start = true")
+    })
+    .await;
+    conn.close().await.unwrap();
+
+    // Failing closed, recipients are turned away until there's a database
+    async fn rcpt(milter: &common::Milter) -> Status {
+        let mut conn = milter.connect().await;
+        conn.connect("client.test.example", [10, 255, 2, 1])
+            .await
+            .unwrap();
+        conn.mail(["<from@test.example>"]).await.unwrap();
+        let status = conn.rcpt(["<to@test.example>"]).await.unwrap();
+        conn.close().await.unwrap();
+        status
+    }
+    assert_eq!(rcpt(&milter).await, Status::Tempfail { message: None });
+
+    holder.close().await.unwrap();
+    drop(holder);
+    let mut tries = 0;
+    while rcpt(&milter).await != Status::Continue {
+        assert!(tries < 50, "Still degraded");
+        sleep(Duration::from_millis(200)).await;
+        tries += 1;
+    }
+
+    // From an allowed network, so accepted now it's recorded
+    let status = send(
+        &milter,
+        [10, 255, 2, 1],
+        "<from@test.example>",
+        &["<to@test.example>"],
+        "<test_degraded_start@example.org>",
+    )
+    .await;
+    assert_eq!(status, Status::Continue);
+
+    milter.shutdown().await;
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn concurrent_first_attempts() {
+    let (conn, milter) = common::setup().await;
+    let mut conns = vec![conn, milter.connect().await, milter.connect().await];
+
+    for (i, conn) in (1u8..).zip(conns.iter_mut()) {
+        let status = conn
+            .connect("client.test.example", [123, 123, 124, i])
+            .await
+            .unwrap();
+        assert_eq!(status, Status::Continue);
+
+        let status = conn.mail(["<from@test.example>"]).await.unwrap();
+        assert_eq!(status, Status::Continue);
+
+        let status = conn.rcpt(["<to@test.example>"]).await.unwrap();
+        assert_eq!(status, Status::Continue);
+
+        let status = conn
+            .header("Message-Id", "<test_concurrent_first_attempts@example.org>")
+            .await
+            .unwrap();
+        assert_eq!(status, Status::Continue);
+    }
+
+    // Whichever session records the message first greylists it, and the
+    // others find it already there or lose the race on the unique index
+    let [first, second, third] = &mut conns[..] else {
+        unreachable!()
+    };
+    let statuses = tokio::join!(first.eoh(), second.eoh(), third.eoh());
+    for status in [statuses.0, statuses.1, statuses.2] {
+        assert_eq!(status.unwrap(), Status::Tempfail { message: None });
+    }
+
+    for conn in conns {
+        conn.close().await.unwrap();
+    }
+
+    milter.shutdown().await;
+}
+
+#[tokio::test]
+async fn reinjected_message() {
+    let (mut conn, milter) = common::setup().await;
+
+    // Accepted from an allowed network, then handed back by a content filter
+    // on loopback, then sent again by an authenticated user
+    for (address, auth_type, expected) in [
+        ([10, 255, 2, 123], None, Status::Continue),
+        ([127, 0, 0, 1], None, Status::Accept),
+        ([123, 123, 123, 126], Some("sasl"), Status::Accept),
+    ] {
+        let status = conn.connect("client.test.example", address).await.unwrap();
+        assert_eq!(status, Status::Continue);
+
+        let status = conn.mail(["<from@test.example>"]).await.unwrap();
+        assert_eq!(status, Status::Continue);
+
+        let status = conn.rcpt(["<to@test.example>"]).await.unwrap();
+        assert_eq!(status, Status::Continue);
+
+        let status = conn
+            .header("Message-Id", "<test_reinjected_message@example.org>")
+            .await
+            .unwrap();
+        assert_eq!(status, Status::Continue);
+
+        if let Some(auth_type) = auth_type {
+            conn.macros(MacroStage::Eoh, [("{auth_type}", auth_type)])
+                .await
+                .unwrap();
+        }
+
+        let status = conn.eoh().await.unwrap();
+        assert_eq!(status, expected);
+
+        conn.close().await.unwrap();
+        conn = milter.connect().await;
+    }
+
+    conn.close().await.unwrap();
+
+    milter.shutdown().await;
+}
+
+/// One message from `client` up to the end of headers, on a connection of
+/// its own, giving the milter's answer
+async fn send(
+    milter: &common::Milter,
+    client: [u8; 4],
+    sender: &str,
+    recipients: &[&str],
+    message_id: &str,
+) -> Status {
+    let mut conn = milter.connect().await;
+
+    let status = conn.connect("client.test.example", client).await.unwrap();
+    assert_eq!(status, Status::Continue);
+
+    let status = conn.mail([sender]).await.unwrap();
+    assert_eq!(status, Status::Continue);
+
+    for recipient in recipients {
+        let status = conn.rcpt([*recipient]).await.unwrap();
+        assert_eq!(status, Status::Continue);
+    }
+
+    let status = conn.header("Message-Id", message_id).await.unwrap();
+    assert_eq!(status, Status::Continue);
+
+    let status = conn.eoh().await.unwrap();
+    conn.close().await.unwrap();
+    status
+}
+
+/// The status and rule of every decision taken, oldest first
+async fn decisions(milter: &common::Milter) -> Vec<(EmailStatus, DecisionRule)> {
+    DecisionEntity::find()
+        .order_by_asc(decision::Column::Id)
+        .all(&milter.db().await)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|decision| (decision.status, decision.rule))
+        .collect()
+}
+
+fn with_dns_lists(config: String) -> String {
+    config.replace(
+        "greylist_time_seconds = 300",
+        r#"greylist_time_seconds = 300
+allow_recipients = [ "postmaster@" ]"#,
+    ) + r#"
+[dns_lists]
+zones = [ { zone = "block.test", kind = "Block", weight = 2 },
+          { zone = "weak-block.test", kind = "Block" },
+          { zone = "allow.test", kind = "Allow", weight = 3 } ]
+reject_score = 2
+"#
+}
+
+fn dns_lists_stub() -> StubResolver {
+    StubResolver::default()
+        .listed("1.2.0.192.block.test", [127, 0, 0, 2])
+        .listed("2.2.0.192.weak-block.test", [127, 0, 0, 2])
+        .listed("3.2.0.192.allow.test", [127, 0, 0, 2])
+}
+
+#[tokio::test]
+async fn dns_lists() {
+    let (conn, milter) = common::setup_with_resolver(with_dns_lists, dns_lists_stub()).await;
+    conn.close().await.unwrap();
+    let sender = "<from@test.example>";
+    let recipient = "<to@test.example>";
+
+    let status = send(
+        &milter,
+        [192, 0, 2, 1],
+        sender,
+        &[recipient],
+        "<dns1@example.org>",
+    )
+    .await;
+    assert_eq!(status, Status::Reject { message: None });
+    let status = send(
+        &milter,
+        [192, 0, 2, 2],
+        sender,
+        &[recipient],
+        "<dns2@example.org>",
+    )
+    .await;
+    assert_eq!(status, Status::Tempfail { message: None });
+    let status = send(
+        &milter,
+        [192, 0, 2, 3],
+        sender,
+        &[recipient],
+        "<dns3@example.org>",
+    )
+    .await;
+    assert_eq!(status, Status::Continue);
+    let status = send(
+        &milter,
+        [192, 0, 2, 4],
+        sender,
+        &[recipient],
+        "<dns4@example.org>",
+    )
+    .await;
+    assert_eq!(status, Status::Tempfail { message: None });
+
+    // Rejecting comes before the recipient allow list, delaying after it
+    let postmaster = "<postmaster@test.example>";
+    let status = send(
+        &milter,
+        [192, 0, 2, 1],
+        sender,
+        &[postmaster],
+        "<dns5@example.org>",
+    )
+    .await;
+    assert_eq!(status, Status::Reject { message: None });
+    let status = send(
+        &milter,
+        [192, 0, 2, 2],
+        sender,
+        &[postmaster],
+        "<dns6@example.org>",
+    )
+    .await;
+    assert_eq!(status, Status::Continue);
+
+    // A retry of rejected mail is rejected again, not silently dropped
+    let status = send(
+        &milter,
+        [192, 0, 2, 1],
+        sender,
+        &[recipient],
+        "<dns1@example.org>",
+    )
+    .await;
+    assert_eq!(status, Status::Reject { message: None });
+
+    assert_eq!(
+        decisions(&milter).await,
+        [
+            (EmailStatus::Rejected, DecisionRule::DnsBlocklisted),
+            (EmailStatus::Greylisted, DecisionRule::DnsBlocklisted),
+            (EmailStatus::IpAccepted, DecisionRule::DnsAllowlisted),
+            (EmailStatus::Greylisted, DecisionRule::Greylisted),
+            (EmailStatus::Rejected, DecisionRule::DnsBlocklisted),
+            (
+                EmailStatus::AllowlistAccepted,
+                DecisionRule::AllowedRecipient
+            ),
+            (EmailStatus::Rejected, DecisionRule::ExistingMessage),
+        ]
+    );
+
+    milter.shutdown().await;
+}
+
+#[tokio::test]
+async fn spf() {
+    let resolver = StubResolver::default()
+        .txt("fail.example", "v=spf1 -all")
+        .txt("trusted.example", "v=spf1 ip4:192.0.2.0/24 -all");
+    let (conn, milter) = common::setup_with_resolver(
+        |config| {
+            config.replace(
+                "greylist_time_seconds = 300",
+                r#"greylist_time_seconds = 300
+allow_recipients = [ "postmaster@" ]"#,
+            ) + r#"
+[spf]
+trusted_domains = [ "trusted.example" ]
+on_fail = "Reject"
+"#
+        },
+        resolver,
+    )
+    .await;
+    conn.close().await.unwrap();
+    let client = [192, 0, 2, 10];
+    let recipient = "<to@test.example>";
+
+    let status = send(
+        &milter,
+        client,
+        "<from@fail.example>",
+        &[recipient],
+        "<spf1@example.org>",
+    )
+    .await;
+    assert_eq!(status, Status::Reject { message: None });
+    let status = send(
+        &milter,
+        client,
+        "<from@trusted.example>",
+        &[recipient],
+        "<spf2@example.org>",
+    )
+    .await;
+    assert_eq!(status, Status::Continue);
+    // Not trusted if the client may not send for it
+    let status = send(
+        &milter,
+        [198, 51, 100, 10],
+        "<from@trusted.example>",
+        &[recipient],
+        "<spf3@example.org>",
+    )
+    .await;
+    assert_eq!(status, Status::Reject { message: None });
+    // Rejecting comes before the recipient allow list
+    let status = send(
+        &milter,
+        client,
+        "<from@fail.example>",
+        &["<postmaster@test.example>"],
+        "<spf4@example.org>",
+    )
+    .await;
+    assert_eq!(status, Status::Reject { message: None });
+
+    assert_eq!(
+        decisions(&milter).await,
+        [
+            (EmailStatus::Rejected, DecisionRule::SpfFail),
+            (EmailStatus::SpfAccepted, DecisionRule::SpfTrusted),
+            (EmailStatus::Rejected, DecisionRule::SpfFail),
+            (EmailStatus::Rejected, DecisionRule::SpfFail),
+        ]
+    );
+
+    milter.shutdown().await;
+}
+
+#[tokio::test]
+async fn hostnames() {
+    let resolver = StubResolver::default()
+        .ptr("192.0.2.20", "dyn-192-0-2-20.isp.example")
+        .address("dyn-192-0-2-20.isp.example", "192.0.2.20")
+        .ptr("192.0.2.22", "mail-1.outbound.protection.outlook.com")
+        .address("mail-1.outbound.protection.outlook.com", "192.0.2.22")
+        .ptr("192.0.2.23", "mail.example.org")
+        .address("mail.example.org", "192.0.2.23")
+        .ptr("192.0.2.24", "mx.partner.example")
+        .address("mx.partner.example", "192.0.2.24")
+        .ptr("192.0.2.25", "mx.forged.example")
+        .address("mx.forged.example", "198.51.100.25");
+    let (conn, milter) = common::setup_with_resolver(
+        |config| {
+            config
+                + r#"
+[hostnames]
+trusted_suffixes = [ "outbound.protection.outlook.com" ]
+"#
+        },
+        resolver,
+    )
+    .await;
+    conn.close().await.unwrap();
+    // As imported from postgrey
+    milter
+        .db()
+        .await
+        .execute_unprepared(
+            "INSERT INTO allowed_address (address, rule, source, time_added)
+             VALUES ('partner.example', 'trusted_hostname', 'postgrey', CURRENT_TIMESTAMP),
+                    ('forged.example', 'trusted_hostname', 'postgrey', CURRENT_TIMESTAMP)",
+        )
+        .await
+        .unwrap();
+    let sender = "<from@test.example>";
+    let recipient = "<to@test.example>";
+
+    for (i, (client, expected)) in [
+        ([192, 0, 2, 20], Status::Tempfail { message: None }),
+        ([192, 0, 2, 21], Status::Tempfail { message: None }),
+        ([192, 0, 2, 22], Status::Continue),
+        ([192, 0, 2, 23], Status::Tempfail { message: None }),
+        ([192, 0, 2, 24], Status::Continue),
+        // Only once the name resolves back to the client
+        ([192, 0, 2, 25], Status::Tempfail { message: None }),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let message_id = format!("<hostname{}@example.org>", i);
+        let status = send(&milter, client, sender, &[recipient], &message_id).await;
+        assert_eq!(status, expected);
+    }
+
+    assert_eq!(
+        decisions(&milter).await,
+        [
+            (EmailStatus::Greylisted, DecisionRule::DynamicHostname),
+            (EmailStatus::Greylisted, DecisionRule::NoReverseDns),
+            (EmailStatus::IpAccepted, DecisionRule::TrustedHostname),
+            (EmailStatus::Greylisted, DecisionRule::Greylisted),
+            (EmailStatus::IpAccepted, DecisionRule::TrustedHostname),
+            (EmailStatus::Greylisted, DecisionRule::Greylisted),
+        ]
+    );
+
+    milter.shutdown().await;
+}
+
+#[tokio::test]
+async fn allowlists() {
+    let resolver = StubResolver::default().txt("partner.example", "v=spf1 ip4:192.0.2.0/24 -all");
+    let (conn, milter) = common::setup_with_resolver(
+        |config| {
+            config.replace(
+                "greylist_time_seconds = 300",
+                r#"greylist_time_seconds = 300
+allow_sender_domains = [ "partner.example" ]
+allow_recipients = [ "postmaster@" ]"#,
+            ) + r#"
+[spf]
+"#
+        },
+        resolver,
+    )
+    .await;
+    conn.close().await.unwrap();
+    let client = [192, 0, 2, 30];
+    let sender = "<from@test.example>";
+    let postmaster = "<postmaster@test.example>";
+    let recipient = "<to@test.example>";
+
+    let status = send(
+        &milter,
+        client,
+        sender,
+        &[postmaster],
+        "<allow1@example.org>",
+    )
+    .await;
+    assert_eq!(status, Status::Continue);
+    // Not if there are other recipients too
+    let status = send(
+        &milter,
+        client,
+        sender,
+        &[postmaster, recipient],
+        "<allow2@example.org>",
+    )
+    .await;
+    assert_eq!(status, Status::Tempfail { message: None });
+    let status = send(
+        &milter,
+        client,
+        "<from@partner.example>",
+        &[recipient],
+        "<allow3@example.org>",
+    )
+    .await;
+    assert_eq!(status, Status::Continue);
+    // Nor from a client the sender domain doesn't allow
+    let status = send(
+        &milter,
+        [198, 51, 100, 30],
+        "<from@partner.example>",
+        &[recipient],
+        "<allow4@example.org>",
+    )
+    .await;
+    assert_eq!(status, Status::Tempfail { message: None });
+
+    assert_eq!(
+        decisions(&milter).await,
+        [
+            (
+                EmailStatus::AllowlistAccepted,
+                DecisionRule::AllowedRecipient
+            ),
+            (EmailStatus::Greylisted, DecisionRule::Greylisted),
+            (EmailStatus::AllowlistAccepted, DecisionRule::AllowedSender),
+            (EmailStatus::Greylisted, DecisionRule::SpfFail),
+        ]
+    );
+
+    milter.shutdown().await;
+}
+
+#[tokio::test]
+async fn concurrent_listed_attempts() {
+    // Slow lookups keep both sessions waiting until they decide together
+    let resolver = dns_lists_stub().delayed(Duration::from_millis(200));
+    let (conn, milter) = common::setup_with_resolver(with_dns_lists, resolver).await;
+    conn.close().await.unwrap();
+    let sender = "<from@test.example>";
+
+    // Whichever session records the message first decides, and the other
+    // goes by what it decided, whether it finds the message or loses the
+    // race on the unique index
+    for (client, recipient, message_id, first, again) in [
+        (
+            [192, 0, 2, 1],
+            "<to@test.example>",
+            "<listed1@example.org>",
+            Status::Reject { message: None },
+            Status::Reject { message: None },
+        ),
+        (
+            [192, 0, 2, 2],
+            "<postmaster@test.example>",
+            "<listed2@example.org>",
+            Status::Continue,
+            Status::Accept,
+        ),
+    ] {
+        let recipients = [recipient];
+        let (one, other) = tokio::join!(
+            send(&milter, client, sender, &recipients, message_id),
+            send(&milter, client, sender, &recipients, message_id),
+        );
+        let mut statuses = [one, other];
+        if statuses[0] != first {
+            statuses.reverse();
+        }
+        assert_eq!(statuses, [first, again]);
+    }
+
+    let db = milter.db().await;
+    assert_eq!(MailEntity::find().count(&db).await.unwrap(), 2);
+    let decisions = decisions(&milter).await;
+    assert_eq!(decisions.len(), 4);
+    for decision in [
+        (EmailStatus::Rejected, DecisionRule::DnsBlocklisted),
+        (EmailStatus::Rejected, DecisionRule::ExistingMessage),
+        (
+            EmailStatus::AllowlistAccepted,
+            DecisionRule::AllowedRecipient,
+        ),
+        (
+            EmailStatus::AllowlistAccepted,
+            DecisionRule::ExistingMessage,
+        ),
+    ] {
+        assert!(decisions.contains(&decision), "{:?}", decision);
+    }
+
+    milter.shutdown().await;
+}
+
+#[tokio::test]
+async fn slow_dns() {
+    // Would reject the client on both counts, if the answers came in time
+    let resolver = dns_lists_stub()
+        .txt("test.example", "v=spf1 -all")
+        .delayed(Duration::from_secs(3));
+    let (conn, milter) = common::setup_with_resolver(
+        |config| {
+            with_dns_lists(config).replace("port = 0", "port = 0\ndecision_timeout_seconds = 1")
+                + r#"
+[dns]
+check_timeout_seconds = 1
+
+[spf]
+on_fail = "Reject"
+
+[hostnames]
+"#
+        },
+        resolver,
+    )
+    .await;
+    conn.close().await.unwrap();
+
+    // Greylisted as if none of the checks had an answer, rather than failed
+    // for want of one from the database
+    let status = send(
+        &milter,
+        [192, 0, 2, 1],
+        "<from@test.example>",
+        &["<to@test.example>"],
+        "<slow1@example.org>",
+    )
+    .await;
+    assert_eq!(status, Status::Tempfail { message: None });
+    assert_eq!(
+        decisions(&milter).await,
+        [(EmailStatus::Greylisted, DecisionRule::Greylisted)]
+    );
+    let mail = MailEntity::find()
+        .one(&milter.db().await)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(mail.spf_result, Some(SpfResult::TempError));
+
+    milter.shutdown().await;
+}
+
+// A decision still being made when shutdown starts gets the drain period to
+// finish, then its session is closed regardless
+#[tokio::test]
+async fn shutdown_drain() {
+    async fn start(drain_seconds: u64) -> (common::Milter, JoinHandle<Status>) {
+        // SPF answers take 3 seconds to come back
+        let resolver = StubResolver::default()
+            .txt("test.example", "v=spf1 -all")
+            .delayed(Duration::from_secs(3));
+        let (mut conn, milter) = common::setup_with_resolver(
+            |config| {
+                config.replace(
+                    "listen_address = \"[::1]:9876\"",
+                    &format!(
+                        "listen_address = \"[::1]:9876\"\nshutdown_timeout_seconds = {}",
+                        drain_seconds
+                    ),
+                ) + "\n[spf]\n"
+            },
+            resolver,
+        )
+        .await;
+        conn.connect("client.test.example", [192, 0, 2, 1])
+            .await
+            .unwrap();
+        conn.mail(["<from@test.example>"]).await.unwrap();
+        conn.rcpt(["<to@test.example>"]).await.unwrap();
+        conn.header("Message-Id", "<test_shutdown_drain@example.org>")
+            .await
+            .unwrap();
+        let eoh = tokio::spawn(async move { conn.eoh().await.unwrap() });
+        sleep(Duration::from_millis(200)).await;
+        (milter, eoh)
+    }
+
+    // Given up on once the drain period is over. The session itself goes
+    // with the runtime, which outlives the milter here.
+    let (milter, eoh) = start(1).await;
+    let started = Instant::now();
+    milter.shutdown().await;
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_secs(1), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(2500), "{:?}", elapsed);
+    eoh.abort();
+
+    // Finished within it, so the decision is made and sent
+    let (milter, eoh) = start(30).await;
+    timeout(Duration::from_secs(10), milter.shutdown())
+        .await
+        .expect("Shutdown waited for the whole drain period");
+    assert_eq!(eoh.await.unwrap(), Status::Tempfail { message: None });
 }
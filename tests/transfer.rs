@@ -0,0 +1,243 @@
+//! Runs against SQLite, like the stores in `common`
+
+#![cfg(feature = "sqlite")]
+
+mod common;
+
+use chrono::{DateTime, Duration, Utc};
+use common::TestStore;
+use entity::{
+    decision_rule::DecisionRule,
+    email_status::EmailStatus,
+    prelude::{DecisionActive, MailActive},
+};
+use sea_orm::Set;
+use sql_greylist_milter::{
+    store::GreylistStore,
+    transfer::{self, ExportFilter, Format, ImportSummary, TransferError},
+};
+
+async fn export(store: &TestStore, filter: &ExportFilter, format: Format) -> String {
+    let mut output = vec![];
+    transfer::export(
+        store.db.sql_connection().unwrap(),
+        filter,
+        format,
+        &mut output,
+    )
+    .await
+    .unwrap();
+    String::from_utf8(output).unwrap()
+}
+
+async fn import(
+    store: &TestStore,
+    input: &str,
+    format: Format,
+) -> Result<ImportSummary, TransferError> {
+    transfer::import(store.db.as_ref(), input.as_bytes(), format, false).await
+}
+
+async fn add_mail(
+    db: &dyn GreylistStore,
+    message_id: &str,
+    received: DateTime<Utc>,
+    status: EmailStatus,
+) {
+    let mut recipient_ids = vec![];
+    for (local_part, domain) in [("one", "x.example"), ("two", "")] {
+        let recipient = db
+            .upsert_recipient(local_part.to_string(), domain.to_string())
+            .await
+            .unwrap();
+        recipient_ids.push(recipient.id);
+    }
+    db.insert_mail(
+        MailActive {
+            sender_local_part: Set("sender".to_string()),
+            sender_domain: Set("a.example".to_string()),
+            message_id: Set(message_id.to_string()),
+            sending_host_name: Set(Some("mx.a.example".to_string())),
+            sending_ip: Set("192.0.2.1".to_string()),
+            time_received: Set(received.into()),
+            status: Set(status.clone()),
+            message_size: Set(Some(1234)),
+            ..Default::default()
+        },
+        recipient_ids,
+        DecisionActive {
+            time_decided: Set(received.into()),
+            status: Set(status),
+            rule: Set(DecisionRule::Greylisted),
+            detail: Set(None),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn export_and_import() {
+    let source = TestStore::new("transfer-source").await;
+    let start = DateTime::parse_from_rfc3339("2026-10-01T10:00:00Z")
+        .unwrap()
+        .with_timezone(&Utc);
+    add_mail(
+        source.db.as_ref(),
+        "<1@a.example>",
+        start,
+        EmailStatus::Greylisted,
+    )
+    .await;
+    add_mail(
+        source.db.as_ref(),
+        "<2@a.example>",
+        start + Duration::days(1),
+        EmailStatus::PassedGreylistAccepted,
+    )
+    .await;
+    add_mail(
+        source.db.as_ref(),
+        "<3@a.example>",
+        start + Duration::days(2),
+        EmailStatus::Greylisted,
+    )
+    .await;
+
+    let everything = ExportFilter::default();
+    let ndjson = export(&source, &everything, Format::Ndjson).await;
+    let lines: Vec<&str> = ndjson.lines().collect();
+    assert_eq!(lines.len(), 3);
+    let first: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+    assert_eq!(first["schema_version"], transfer::SCHEMA_VERSION);
+    assert_eq!(first["message_id"], "<1@a.example>");
+    assert_eq!(first["status"], "Greylisted");
+    assert_eq!(
+        first["recipients"],
+        serde_json::json!(["one@x.example", "two"])
+    );
+
+    let filtered = export(
+        &source,
+        &ExportFilter {
+            since: Some(start + Duration::hours(1)),
+            until: None,
+            statuses: vec![EmailStatus::Greylisted],
+        },
+        Format::Ndjson,
+    )
+    .await;
+    assert_eq!(filtered.lines().count(), 1);
+    assert!(filtered.contains("<3@a.example>"));
+
+    let csv = export(&source, &everything, Format::Csv).await;
+    assert_eq!(csv.lines().count(), 4);
+    assert!(csv.lines().nth(1).unwrap().contains("one@x.example two"));
+
+    // Both formats load back to the same thing, and loading again adds nothing
+    for format in [Format::Ndjson, Format::Csv] {
+        let target = TestStore::new("transfer-target").await;
+        let input = match format {
+            Format::Ndjson => &ndjson,
+            Format::Csv => &csv,
+        };
+        assert_eq!(
+            import(&target, input, format).await.unwrap(),
+            ImportSummary {
+                imported: 3,
+                skipped: 0
+            }
+        );
+        assert_eq!(
+            import(&target, input, format).await.unwrap(),
+            ImportSummary {
+                imported: 0,
+                skipped: 3
+            }
+        );
+        assert_eq!(export(&target, &everything, Format::Ndjson).await, ndjson);
+        target.close().await;
+    }
+
+    source.close().await;
+}
+
+#[tokio::test]
+async fn import_normalizes() {
+    let target = TestStore::new("transfer-normalize").await;
+    let record = r#"{"schema_version":1,"message_id":"<n@a.example>","sender_local_part":"sender","sender_domain":"a.example","recipients":["<One@X.Example>","one@x.example"],"sending_ip":"::ffff:192.0.2.9","time_received":"2026-10-01T10:00:00+00:00","status":"Greylisted"}"#;
+    import(&target, record, Format::Ndjson).await.unwrap();
+
+    let mail = target.db.find_mail("<n@a.example>").await.unwrap().unwrap();
+    assert_eq!(mail.sending_ip, "192.0.2.9");
+    let exported = export(&target, &ExportFilter::default(), Format::Ndjson).await;
+    assert!(
+        exported.contains(r#""recipients":["One@x.example","one@x.example"]"#),
+        "{}",
+        exported
+    );
+
+    target.close().await;
+}
+
+// Senders are stored as the milter stores them, whatever the other side did
+#[tokio::test]
+async fn import_normalizes_senders() {
+    let target = TestStore::new("transfer-normalize-senders").await;
+    let local_part = "Sender".repeat(10);
+    let record = format!(
+        r#"{{"schema_version":1,"message_id":"<s@a.example>","sender_local_part":"{}","sender_domain":"Mail.A.Example","recipients":[],"sending_ip":"192.0.2.9","time_received":"2026-10-01T10:00:00+00:00","status":"Greylisted"}}"#,
+        local_part
+    );
+    transfer::import(target.db.as_ref(), record.as_bytes(), Format::Ndjson, true)
+        .await
+        .unwrap();
+
+    let mail = target.db.find_mail("<s@a.example>").await.unwrap().unwrap();
+    assert_eq!(mail.sender_domain, "mail.a.example");
+    assert_eq!(mail.sender_local_part, local_part.to_lowercase());
+
+    target.close().await;
+}
+
+#[tokio::test]
+async fn import_rejects_bad_records() {
+    let target = TestStore::new("transfer-reject").await;
+    let valid = r#"{"schema_version":1,"message_id":"<v@a.example>","sender_local_part":"s","sender_domain":"a.example","recipients":[],"sending_ip":"192.0.2.1","time_received":"2026-10-01T10:00:00+00:00","status":"Greylisted"}"#;
+
+    for (bad, reason) in [
+        (
+            valid.replace(r#""schema_version":1"#, r#""schema_version":99"#),
+            "newer",
+        ),
+        (
+            valid.replace("192.0.2.1", "not an address"),
+            "client address",
+        ),
+        (valid.replace("Greylisted", "Lost"), "Unknown status"),
+        (
+            valid.replace(r#""s""#, &format!(r#""{}""#, "s".repeat(65))),
+            "Local part is 65 bytes long",
+        ),
+        (
+            valid
+                .replace("<v@", "<r@")
+                .replace("[]", &format!(r#"["r@{}.example"]"#, "a".repeat(250))),
+            "Domain is 258 bytes long",
+        ),
+    ] {
+        let input = format!("{}\n{}\n", valid, bad);
+        match import(&target, &input, Format::Ndjson).await {
+            Err(TransferError::Invalid {
+                record: 2,
+                reason: message,
+            }) => {
+                assert!(message.contains(reason), "{}", message)
+            }
+            other => panic!("Expected record 2 to be rejected, got {:?}", other),
+        }
+    }
+
+    target.close().await;
+}